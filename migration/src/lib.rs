@@ -2,6 +2,14 @@ pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_user_table;
 mod m20230228_234019_create_mail_table;
+mod m20261018_000001_create_queue_table;
+mod m20261018_000002_add_uid_to_mail_table;
+mod m20261018_000003_add_apop_secret_to_user_table;
+mod m20261018_000004_create_login_failure_table;
+mod m20261018_000005_add_retrieved_at_to_mail_table;
+mod m20261018_000006_create_mailbox_table;
+mod m20261018_000007_create_mail_flag_table;
+mod m20261018_000008_add_modseq_to_mail_table;
 
 pub struct Migrator;
 
@@ -11,6 +19,14 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20220101_000001_create_user_table::Migration),
             Box::new(m20230228_234019_create_mail_table::Migration),
+            Box::new(m20261018_000001_create_queue_table::Migration),
+            Box::new(m20261018_000002_add_uid_to_mail_table::Migration),
+            Box::new(m20261018_000003_add_apop_secret_to_user_table::Migration),
+            Box::new(m20261018_000004_create_login_failure_table::Migration),
+            Box::new(m20261018_000005_add_retrieved_at_to_mail_table::Migration),
+            Box::new(m20261018_000006_create_mailbox_table::Migration),
+            Box::new(m20261018_000007_create_mail_flag_table::Migration),
+            Box::new(m20261018_000008_add_modseq_to_mail_table::Migration),
         ]
     }
 }
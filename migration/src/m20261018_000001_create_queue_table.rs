@@ -0,0 +1,58 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Queue::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Queue::Id)
+                            .big_integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Queue::Sender).text().not_null())
+                    .col(ColumnDef::new(Queue::Recipients).text().not_null())
+                    .col(ColumnDef::new(Queue::Content).text().not_null())
+                    .col(ColumnDef::new(Queue::QueuedAt).big_integer().not_null())
+                    .col(ColumnDef::new(Queue::NextAttempt).big_integer().not_null())
+                    .col(ColumnDef::new(Queue::Attempts).integer().not_null())
+                    .col(ColumnDef::new(Queue::LastError).text())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Queue::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Queue {
+    Table,
+    Id,
+    /// The reverse-path of the message. Empty for the null reverse-path
+    Sender,
+    /// The recipients that haven't received the message yet, one per line
+    Recipients,
+    Content,
+    /// When the message was queued, as a Unix timestamp
+    QueuedAt,
+    /// When delivery should next be attempted, as a Unix timestamp
+    NextAttempt,
+    /// The number of failed delivery attempts
+    Attempts,
+    /// Why the last delivery attempt failed
+    LastError,
+}
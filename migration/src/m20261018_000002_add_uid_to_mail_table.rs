@@ -0,0 +1,50 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .add_column(ColumnDef::new(Mail::Uid).text().not_null().default(""))
+                    .to_owned(),
+            )
+            .await?;
+
+        // Messages that are already stored use their message ID
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Mail::Table)
+                    .value(Mail::Uid, Expr::col(Mail::MessageId))
+                    .and_where(Expr::col(Mail::Uid).eq(""))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .drop_column(Mail::Uid)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Mail {
+    Table,
+    MessageId,
+    /// The POP3 unique-id of the message (RFC 1939 section 7). Never changes
+    /// once the message is stored
+    Uid,
+}
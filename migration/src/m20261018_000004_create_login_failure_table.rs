@@ -0,0 +1,54 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(LoginFailure::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(LoginFailure::Ip).text().not_null())
+                    .col(ColumnDef::new(LoginFailure::Account).text().not_null())
+                    .col(ColumnDef::new(LoginFailure::Failures).integer().not_null())
+                    .col(
+                        ColumnDef::new(LoginFailure::LastFailure)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(LoginFailure::LockedUntil).big_integer())
+                    .primary_key(
+                        Index::create()
+                            .col(LoginFailure::Ip)
+                            .col(LoginFailure::Account),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(LoginFailure::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum LoginFailure {
+    Table,
+    /// The IP address that the failed logins came from
+    Ip,
+    /// The username that the client tried to log in as
+    Account,
+    /// The number of failed logins since the last lockout
+    Failures,
+    /// When the last login failed, as a Unix timestamp
+    LastFailure,
+    /// Logins aren't allowed until this Unix timestamp
+    LockedUntil,
+}
@@ -0,0 +1,39 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .add_column(ColumnDef::new(Mail::RetrievedAt).big_integer())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .drop_column(Mail::RetrievedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Mail {
+    Table,
+    /// When a POP3 client first downloaded the message, as a Unix
+    /// timestamp. Messages expire a number of days after this (see the
+    /// EXPIRE capability in RFC 2449)
+    RetrievedAt,
+}
@@ -0,0 +1,326 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Mailbox::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Mailbox::Id)
+                            .big_integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Mailbox::Owner).text().not_null())
+                    .col(ColumnDef::new(Mailbox::Name).text().not_null())
+                    .col(ColumnDef::new(Mailbox::Parent).big_integer())
+                    .col(ColumnDef::new(Mailbox::UidValidity).big_integer().not_null())
+                    .col(ColumnDef::new(Mailbox::UidNext).big_integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(Mailbox::Table, Mailbox::Parent)
+                            .to(Mailbox::Table, Mailbox::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-mailbox-owner-name")
+                    .table(Mailbox::Table)
+                    .col(Mailbox::Owner)
+                    .col(Mailbox::Name)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        // Every user that has mail gets an INBOX to keep it in
+        manager
+            .exec_stmt(
+                Query::insert()
+                    .into_table(Mailbox::Table)
+                    .columns([
+                        Mailbox::Owner,
+                        Mailbox::Name,
+                        Mailbox::UidValidity,
+                        Mailbox::UidNext,
+                    ])
+                    .select_from(
+                        Query::select()
+                            .distinct()
+                            .column(Mail::BelongsTo)
+                            .expr(Expr::val("INBOX"))
+                            .expr(Expr::val(now()))
+                            .expr(Expr::val(1))
+                            .from(Mail::Table)
+                            .to_owned(),
+                    )
+                    .unwrap()
+                    .to_owned(),
+            )
+            .await?;
+
+        // SQLite can't add a foreign key to an existing table, so the mail
+        // table is rebuilt with a reference to the mailbox instead of the
+        // owner's address
+        manager
+            .create_table(
+                Table::create()
+                    .table(NewMail::Table)
+                    .col(
+                        ColumnDef::new(Mail::MessageId)
+                            .text()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Mail::Subject).text().not_null())
+                    .col(ColumnDef::new(Mail::Date).date().not_null())
+                    .col(ColumnDef::new(Mail::From).text().not_null())
+                    .col(ColumnDef::new(Mail::Recipients).text().not_null())
+                    .col(ColumnDef::new(Mail::MailboxId).big_integer().not_null())
+                    .col(ColumnDef::new(Mail::Content).text().not_null())
+                    .col(ColumnDef::new(Mail::Uid).text().not_null())
+                    .col(ColumnDef::new(Mail::ImapUid).big_integer().not_null())
+                    .col(ColumnDef::new(Mail::RetrievedAt).big_integer())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(NewMail::Table, Mail::MailboxId)
+                            .to(Mailbox::Table, Mailbox::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // The messages in each INBOX are numbered in the order that they
+        // were stored
+        manager
+            .exec_stmt(
+                Query::insert()
+                    .into_table(NewMail::Table)
+                    .columns([
+                        Mail::MessageId,
+                        Mail::Subject,
+                        Mail::Date,
+                        Mail::From,
+                        Mail::Recipients,
+                        Mail::MailboxId,
+                        Mail::Content,
+                        Mail::Uid,
+                        Mail::ImapUid,
+                        Mail::RetrievedAt,
+                    ])
+                    .select_from(
+                        Query::select()
+                            .columns([
+                                (Mail::Table, Mail::MessageId),
+                                (Mail::Table, Mail::Subject),
+                                (Mail::Table, Mail::Date),
+                                (Mail::Table, Mail::From),
+                                (Mail::Table, Mail::Recipients),
+                            ])
+                            .column((Mailbox::Table, Mailbox::Id))
+                            .columns([(Mail::Table, Mail::Content), (Mail::Table, Mail::Uid)])
+                            .expr(Expr::cust(
+                                "(SELECT COUNT(*) FROM mail AS older \
+                                WHERE older.belongs_to = mail.belongs_to AND older.uid <= mail.uid)",
+                            ))
+                            .column((Mail::Table, Mail::RetrievedAt))
+                            .from(Mail::Table)
+                            .inner_join(
+                                Mailbox::Table,
+                                Expr::col((Mailbox::Table, Mailbox::Owner))
+                                    .equals((Mail::Table, Mail::BelongsTo))
+                                    .and(Expr::col((Mailbox::Table, Mailbox::Name)).eq("INBOX")),
+                            )
+                            .to_owned(),
+                    )
+                    .unwrap()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Mail::Table).to_owned())
+            .await?;
+        manager
+            .rename_table(
+                Table::rename()
+                    .table(NewMail::Table, Mail::Table)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-mail-mailbox-uid")
+                    .table(Mail::Table)
+                    .col(Mail::MailboxId)
+                    .col(Mail::ImapUid)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Mailbox::Table)
+                    .value(
+                        Mailbox::UidNext,
+                        Expr::cust(
+                            "(SELECT COUNT(*) FROM mail WHERE mail.mailbox_id = mailbox.id) + 1",
+                        ),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Every message goes back to its owner's mailbox, whichever folder
+        // it was in
+        manager
+            .create_table(
+                Table::create()
+                    .table(NewMail::Table)
+                    .col(
+                        ColumnDef::new(Mail::MessageId)
+                            .text()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Mail::Subject).text().not_null())
+                    .col(ColumnDef::new(Mail::Date).date().not_null())
+                    .col(ColumnDef::new(Mail::From).text().not_null())
+                    .col(ColumnDef::new(Mail::Recipients).text().not_null())
+                    .col(ColumnDef::new(Mail::BelongsTo).text().not_null())
+                    .col(ColumnDef::new(Mail::Content).text().not_null())
+                    .col(ColumnDef::new(Mail::Uid).text().not_null().default(""))
+                    .col(ColumnDef::new(Mail::RetrievedAt).big_integer())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .exec_stmt(
+                Query::insert()
+                    .into_table(NewMail::Table)
+                    .columns([
+                        Mail::MessageId,
+                        Mail::Subject,
+                        Mail::Date,
+                        Mail::From,
+                        Mail::Recipients,
+                        Mail::BelongsTo,
+                        Mail::Content,
+                        Mail::Uid,
+                        Mail::RetrievedAt,
+                    ])
+                    .select_from(
+                        Query::select()
+                            .columns([
+                                (Mail::Table, Mail::MessageId),
+                                (Mail::Table, Mail::Subject),
+                                (Mail::Table, Mail::Date),
+                                (Mail::Table, Mail::From),
+                                (Mail::Table, Mail::Recipients),
+                            ])
+                            .column((Mailbox::Table, Mailbox::Owner))
+                            .columns([
+                                (Mail::Table, Mail::Content),
+                                (Mail::Table, Mail::Uid),
+                                (Mail::Table, Mail::RetrievedAt),
+                            ])
+                            .from(Mail::Table)
+                            .inner_join(
+                                Mailbox::Table,
+                                Expr::col((Mailbox::Table, Mailbox::Id))
+                                    .equals((Mail::Table, Mail::MailboxId)),
+                            )
+                            .to_owned(),
+                    )
+                    .unwrap()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Mail::Table).to_owned())
+            .await?;
+        manager
+            .rename_table(
+                Table::rename()
+                    .table(NewMail::Table, Mail::Table)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Mailbox::Table).to_owned())
+            .await
+    }
+}
+
+/// The current time as a Unix timestamp
+fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Mailbox {
+    Table,
+    Id,
+    /// The address of the user that the mailbox belongs to
+    Owner,
+    /// The full name of the mailbox, with the names of its parents
+    /// separated by "/" (like "INBOX" or "Receipts/2026")
+    Name,
+    /// The mailbox that this one is inside of, if any
+    Parent,
+    /// The IMAP UIDVALIDITY of the mailbox (RFC 3501 section 2.3.1.1)
+    UidValidity,
+    /// The IMAP UID that the next message in the mailbox will get
+    UidNext,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Mail {
+    Table,
+    MessageId,
+    Subject,
+    Date,
+    From,
+    Recipients,
+    BelongsTo,
+    /// The mailbox that the message is in
+    MailboxId,
+    Content,
+    Uid,
+    /// The IMAP UID of the message, which is unique within its mailbox
+    ImapUid,
+    RetrievedAt,
+}
+
+#[derive(Iden)]
+enum NewMail {
+    #[iden = "mail_new"]
+    Table,
+}
@@ -0,0 +1,65 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(MailFlag::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(MailFlag::MessageId).text().not_null())
+                    .col(ColumnDef::new(MailFlag::Flag).text().not_null())
+                    .primary_key(
+                        Index::create()
+                            .col(MailFlag::MessageId)
+                            .col(MailFlag::Flag),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from(MailFlag::Table, MailFlag::MessageId)
+                            .to(Mail::Table, Mail::MessageId)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-mail_flag-flag")
+                    .table(MailFlag::Table)
+                    .col(MailFlag::Flag)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(MailFlag::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum MailFlag {
+    Table,
+    /// The message that the flag is set on
+    MessageId,
+    /// A system flag like "\Seen", or a keyword like "$Junk" (RFC 3501
+    /// section 2.3.2)
+    Flag,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Mail {
+    Table,
+    MessageId,
+}
@@ -0,0 +1,188 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ModSequence::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ModSequence::Owner)
+                            .text()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ModSequence::HighestModseq)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Messages that are already stored all count as changed in the
+        // first modification
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .add_column(
+                        ColumnDef::new(Mail::Modseq)
+                            .big_integer()
+                            .not_null()
+                            .default(1),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .exec_stmt(
+                Query::insert()
+                    .into_table(ModSequence::Table)
+                    .columns([ModSequence::Owner, ModSequence::HighestModseq])
+                    .select_from(
+                        Query::select()
+                            .distinct()
+                            .column(Mailbox::Owner)
+                            .expr(Expr::val(1))
+                            .from(Mailbox::Table)
+                            .to_owned(),
+                    )
+                    .unwrap()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-mail-modseq")
+                    .table(Mail::Table)
+                    .col(Mail::Modseq)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(MailTombstone::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(MailTombstone::MessageId)
+                            .text()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(MailTombstone::Owner).text().not_null())
+                    .col(
+                        ColumnDef::new(MailTombstone::MailboxId)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(MailTombstone::Uid).text().not_null())
+                    .col(
+                        ColumnDef::new(MailTombstone::ImapUid)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(MailTombstone::Modseq)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-mail_tombstone-owner-modseq")
+                    .table(MailTombstone::Table)
+                    .col(MailTombstone::Owner)
+                    .col(MailTombstone::Modseq)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(MailTombstone::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-mail-modseq")
+                    .table(Mail::Table)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Mail::Table)
+                    .drop_column(Mail::Modseq)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(ModSequence::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum ModSequence {
+    Table,
+    /// The address of the user that the sequence belongs to
+    Owner,
+    /// The modification sequence of the user's most recent change
+    HighestModseq,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Mail {
+    Table,
+    /// The modification sequence of the last change to the message: when
+    /// it was stored or its flags were last changed
+    Modseq,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum MailTombstone {
+    Table,
+    /// The message that was removed
+    MessageId,
+    /// The address of the user that the message belonged to
+    Owner,
+    /// The mailbox that the message was in. It may have been removed too.
+    MailboxId,
+    /// The POP3 unique-id of the message
+    Uid,
+    /// The IMAP UID of the message
+    ImapUid,
+    /// The modification sequence of the removal
+    Modseq,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Mailbox {
+    Table,
+    Owner,
+}
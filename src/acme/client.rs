@@ -0,0 +1,499 @@
+//! A client for ACME servers (RFC 8555). Every request is a JSON Web
+//! Signature (RFC 7515) made with the account key, which is an ECDSA P-256
+//! key.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use log::{info, trace};
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::{Client, Response};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::{sleep, Duration};
+
+use super::{http01, write_private_file, AcmeError};
+
+/// How long to wait for the ACME server to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait between checks on a pending authorization or order
+const POLL_DELAY: Duration = Duration::from_secs(2);
+
+/// How many times to check on a pending authorization or order before
+/// giving up
+const POLL_ATTEMPTS: u32 = 30;
+
+/// A certificate chain and its private key, in PEM format
+pub struct IssuedCertificate {
+    pub chain: String,
+    pub private_key: String,
+}
+
+/// The URLs of the ACME server's resources (RFC 8555 section 7.1.1)
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// RFC 8555 section 7.1.3
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+/// RFC 8555 section 7.1.4
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    challenge_type: String,
+    url: String,
+    token: Option<String>,
+    error: Option<Value>,
+}
+
+/// An account on an ACME server, which certificates are ordered with
+pub struct AcmeClient {
+    http: Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// The account URL, which identifies the account in every request
+    /// after it is created
+    account: Option<String>,
+    /// A nonce from the last response, which the next request can use
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetch the directory of the ACME server at `directory_url`, and log
+    /// in to the account of the key in `account_key`. The key and the
+    /// account are created if they don't exist.
+    ///
+    /// `ca_certificate` is a PEM file of extra root certificates to trust,
+    /// for test servers.
+    pub async fn connect(
+        directory_url: &str,
+        contact: Option<&str>,
+        account_key: &Path,
+        ca_certificate: Option<&str>,
+    ) -> Result<Self, AcmeError> {
+        let mut http = Client::builder()
+            .use_rustls_tls()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(concat!("mailroom/", env!("CARGO_PKG_VERSION")));
+        if let Some(path) = ca_certificate {
+            let pem = fs::read(path).map_err(|error| AcmeError::Io {
+                path: path.to_owned(),
+                error,
+            })?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem)? {
+                http = http.add_root_certificate(certificate);
+            }
+        }
+        let http = http.build()?;
+
+        let directory = http
+            .get(directory_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let rng = SystemRandom::new();
+        let key = load_account_key(account_key, &rng)?;
+
+        let mut client = Self {
+            http,
+            directory,
+            key,
+            rng,
+            account: None,
+            nonce: None,
+        };
+
+        // Creating an account that already exists returns the existing one
+        // (RFC 8555 section 7.3.1)
+        let mut account = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = contact {
+            account["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+        let url = client.directory.new_account.clone();
+        let response = client.post(&url, Some(&account)).await?;
+        client.account = Some(location(&response)?);
+
+        Ok(client)
+    }
+
+    /// Order a certificate for `names`, proving control of each of them
+    /// with an HTTP-01 challenge
+    pub async fn order_certificate(
+        &mut self,
+        names: &[String],
+    ) -> Result<IssuedCertificate, AcmeError> {
+        let identifiers: Vec<Value> = names
+            .iter()
+            .map(|name| json!({ "type": "dns", "value": name }))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(&response)?;
+        let order: Order = parse(response).await?;
+
+        for authorization in &order.authorizations {
+            self.authorize(authorization).await?;
+        }
+
+        // Finalize the order with a signing request for a new key
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(names.to_vec())?;
+        params.distinguished_name = DistinguishedName::new();
+        let csr = params.serialize_request(&key)?;
+        self.post(
+            &order.finalize,
+            Some(&json!({ "csr": BASE64URL.encode(csr.der()) })),
+        )
+        .await?;
+
+        let order: Order = self
+            .poll(&order_url, |order: &Order| order.status.as_str())
+            .await?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => {
+                return Err(AcmeError::InvalidResponse(format!(
+                    "order is {} instead of valid",
+                    status
+                )))
+            }
+        };
+
+        let chain = self.post(&certificate, None).await?.text().await?;
+        Ok(IssuedCertificate {
+            chain,
+            private_key: key.serialize_pem(),
+        })
+    }
+
+    /// Prove control of the host name of an authorization
+    async fn authorize(&mut self, url: &str) -> Result<(), AcmeError> {
+        let authorization: Authorization = parse(self.post(url, None).await?).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let host = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|c| c.challenge_type == "http-01")
+            .ok_or_else(|| AcmeError::ChallengeFailed {
+                host: host.clone(),
+                detail: "the server didn't offer an HTTP-01 challenge".to_owned(),
+            })?;
+        let token = challenge.token.ok_or_else(|| {
+            AcmeError::InvalidResponse("HTTP-01 challenge has no token".to_owned())
+        })?;
+
+        info!("Answering the ACME HTTP-01 challenge for {}", host);
+        http01::publish(&token, self.key_authorization(&token));
+
+        // An empty object tells the server that the challenge is ready
+        // (RFC 8555 section 7.5.1)
+        let result = match self.post(&challenge.url, Some(&json!({}))).await {
+            Ok(_) => self.poll(url, |a: &Authorization| a.status.as_str()).await,
+            Err(e) => Err(e),
+        };
+        http01::withdraw(&token);
+
+        let authorization = result?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let detail = authorization
+            .challenges
+            .iter()
+            .find_map(|c| c.error.as_ref())
+            .and_then(|e| e["detail"].as_str())
+            .unwrap_or("no reason given")
+            .to_owned();
+        Err(AcmeError::ChallengeFailed { host, detail })
+    }
+
+    /// Fetch a resource until its status isn't "pending" or "processing"
+    async fn poll<T, F>(&mut self, url: &str, status: F) -> Result<T, AcmeError>
+    where
+        T: for<'de> Deserialize<'de>,
+        F: Fn(&T) -> &str,
+    {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: T = parse(self.post(url, None).await?).await?;
+            if !matches!(status(&resource), "pending" | "processing") {
+                return Ok(resource);
+            }
+            sleep(POLL_DELAY).await;
+        }
+
+        Err(AcmeError::Timeout)
+    }
+
+    /// Send a signed request. A `payload` of `None` is a "POST-as-GET"
+    /// request, which fetches a resource (RFC 8555 section 6.3).
+    ///
+    /// Requests that are refused because of a bad nonce are sent once
+    /// more with a new one.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, AcmeError> {
+        match self.try_post(url, payload).await {
+            Err(e) if e.is_bad_nonce() => self.try_post(url, payload).await,
+            result => result,
+        }
+    }
+
+    async fn try_post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<Response, AcmeError> {
+        trace!("Sending ACME request to {}", url);
+
+        let nonce = match self.nonce.take() {
+            Some(nonce) => nonce,
+            None => self.new_nonce().await?,
+        };
+        let body = self.sign(url, &nonce, payload);
+
+        let response = self
+            .http
+            .post(url)
+            .header(CONTENT_TYPE, "application/jose+json")
+            .body(body.to_string())
+            .send()
+            .await?;
+
+        self.nonce = response
+            .headers()
+            .get("Replay-Nonce")
+            .and_then(|n| n.to_str().ok())
+            .map(str::to_owned);
+
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        // Errors are problem documents (RFC 8555 section 6.7)
+        let status = response.status().as_u16();
+        let problem: Value = response.json().await.unwrap_or_default();
+        Err(AcmeError::Problem {
+            status,
+            problem_type: problem["type"].as_str().unwrap_or_default().to_owned(),
+            detail: problem["detail"].as_str().unwrap_or_default().to_owned(),
+        })
+    }
+
+    async fn new_nonce(&self) -> Result<String, AcmeError> {
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        response
+            .headers()
+            .get("Replay-Nonce")
+            .and_then(|n| n.to_str().ok())
+            .map(str::to_owned)
+            .ok_or_else(|| AcmeError::InvalidResponse("no Replay-Nonce header".to_owned()))
+    }
+
+    /// Make the flattened JWS for a request (RFC 8555 section 6.2). The
+    /// account key itself is sent until the account has a URL.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Value {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.account {
+            Some(account) => protected["kid"] = json!(account),
+            None => protected["jwk"] = jwk(self.key.public_key().as_ref()),
+        }
+
+        let protected = BASE64URL.encode(protected.to_string());
+        let payload = match payload {
+            Some(payload) => BASE64URL.encode(payload.to_string()),
+            None => String::new(),
+        };
+
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .expect("signing with an ECDSA key doesn't fail");
+
+        json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": BASE64URL.encode(signature.as_ref()),
+        })
+    }
+
+    /// The response to a challenge: its token and the thumbprint of the
+    /// account key (RFC 8555 section 8.1)
+    fn key_authorization(&self, token: &str) -> String {
+        format!(
+            "{}.{}",
+            token,
+            jwk_thumbprint(self.key.public_key().as_ref())
+        )
+    }
+}
+
+/// Load the account key from a PKCS #8 PEM file, or generate one and save
+/// it there if the file doesn't exist
+fn load_account_key(path: &Path, rng: &SystemRandom) -> Result<EcdsaKeyPair, AcmeError> {
+    let io_error = |error| AcmeError::Io {
+        path: path.display().to_string(),
+        error,
+    };
+
+    let pem = match fs::read_to_string(path) {
+        Ok(pem) => pem,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("Generating a new ACME account key at {}", path.display());
+            let pem = KeyPair::generate()?.serialize_pem();
+            write_private_file(path, pem.as_bytes()).map_err(io_error)?;
+            pem
+        }
+        Err(e) => return Err(io_error(e)),
+    };
+
+    let der = rustls_pemfile::private_key(&mut pem.as_bytes())
+        .map_err(io_error)?
+        .ok_or_else(|| AcmeError::Key(format!("no private key found in {}", path.display())))?;
+
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, der.secret_der(), rng)
+        .map_err(|e| AcmeError::Key(format!("{} isn't a P-256 key: {}", path.display(), e)))
+}
+
+/// The JSON Web Key of a P-256 public key, given as an uncompressed point
+/// (RFC 7518 section 6.2.1)
+fn jwk(public_key: &[u8]) -> Value {
+    let (x, y) = public_key[1..].split_at(32);
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": BASE64URL.encode(x),
+        "y": BASE64URL.encode(y),
+    })
+}
+
+/// The SHA-256 thumbprint of a P-256 public key (RFC 7638). The members
+/// of the key are hashed in lexicographic order, without whitespace.
+fn jwk_thumbprint(public_key: &[u8]) -> String {
+    let (x, y) = public_key[1..].split_at(32);
+    let canonical = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        BASE64URL.encode(x),
+        BASE64URL.encode(y)
+    );
+    BASE64URL.encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        canonical.as_bytes(),
+    ))
+}
+
+/// The URL of a resource that was just created
+fn location(response: &Response) -> Result<String, AcmeError> {
+    response
+        .headers()
+        .get(LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .map(str::to_owned)
+        .ok_or_else(|| AcmeError::InvalidResponse("no Location header".to_owned()))
+}
+
+async fn parse<T: for<'de> Deserialize<'de>>(response: Response) -> Result<T, AcmeError> {
+    let body = response.text().await?;
+    serde_json::from_str(&body).map_err(|e| AcmeError::InvalidResponse(e.to_string()))
+}
+
+#[test]
+fn jwk_members_in_thumbprint_order() {
+    let mut point = vec![4];
+    point.extend([1; 32]);
+    point.extend([2; 32]);
+
+    // The JWK that is sent has the same form as the one that is hashed
+    let canonical = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        BASE64URL.encode([1; 32]),
+        BASE64URL.encode([2; 32])
+    );
+    assert_eq!(jwk(&point).to_string(), canonical);
+    assert_eq!(
+        jwk_thumbprint(&point),
+        BASE64URL.encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            canonical.as_bytes()
+        ))
+    );
+}
+
+/// Orders a certificate from a local Pebble server
+/// (https://github.com/letsencrypt/pebble). Start it with
+/// `PEBBLE_VA_ALWAYS_VALID=1 pebble -config test/config/pebble-config.json`
+/// and set `PEBBLE_CA` to the path of `test/certs/pebble.minica.pem`.
+#[test]
+#[ignore]
+fn pebble_issues_certificate() {
+    let ca = std::env::var("PEBBLE_CA").expect("PEBBLE_CA is not set");
+    let account_key = std::env::temp_dir().join("mailroom-pebble-account.key");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let issued = runtime.block_on(async {
+        let mut client = AcmeClient::connect(
+            "https://localhost:14000/dir",
+            Some("postmaster@example.com"),
+            &account_key,
+            Some(&ca),
+        )
+        .await
+        .unwrap();
+
+        client
+            .order_certificate(&["example.com".to_owned(), "mail.example.com".to_owned()])
+            .await
+            .unwrap()
+    });
+
+    assert!(crate::tls::certificate_expiry(issued.chain.as_bytes()).is_some());
+    assert!(
+        rustls_pemfile::private_key(&mut issued.private_key.as_bytes())
+            .unwrap()
+            .is_some()
+    );
+}
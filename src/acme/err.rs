@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::tls::TlsError;
+
+/// Reasons that a certificate couldn't be issued
+#[derive(Debug)]
+pub enum AcmeError {
+    /// Couldn't read or write the account key or a certificate
+    Io { path: String, error: io::Error },
+    /// Couldn't reach the ACME server
+    Http(reqwest::Error),
+    /// The ACME server refused a request (RFC 8555 section 6.7)
+    Problem {
+        status: u16,
+        problem_type: String,
+        detail: String,
+    },
+    /// The ACME server sent a response that doesn't follow RFC 8555
+    InvalidResponse(String),
+    /// The ACME server couldn't validate a challenge for a host name
+    ChallengeFailed { host: String, detail: String },
+    /// The ACME server took too long to validate an order
+    Timeout,
+    /// Couldn't generate a key or a certificate signing request
+    Key(String),
+    /// The certificate that was issued can't be used
+    Certificate(TlsError),
+}
+
+impl AcmeError {
+    /// Whether the server rejected the request's nonce, which means that
+    /// the request can be sent again with a new one (RFC 8555 section
+    /// 6.5)
+    pub fn is_bad_nonce(&self) -> bool {
+        matches!(self, AcmeError::Problem { problem_type, .. }
+            if problem_type == "urn:ietf:params:acme:error:badNonce")
+    }
+}
+
+impl Error for AcmeError {}
+
+impl fmt::Display for AcmeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AcmeError::*;
+
+        match self {
+            Io { path, error } => write!(f, "couldn't access {}: {}", path, error),
+            Http(e) => write!(f, "couldn't reach the ACME server: {}", e),
+            Problem {
+                status,
+                problem_type,
+                detail,
+            } => write!(
+                f,
+                "ACME server refused the request ({} {}): {}",
+                status, problem_type, detail
+            ),
+            InvalidResponse(message) => {
+                write!(f, "invalid response from the ACME server: {}", message)
+            }
+            ChallengeFailed { host, detail } => {
+                write!(f, "couldn't validate {}: {}", host, detail)
+            }
+            Timeout => write!(f, "ACME server took too long to validate the order"),
+            Key(message) => write!(f, "couldn't generate a key: {}", message),
+            Certificate(e) => write!(f, "issued certificate is unusable: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for AcmeError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<rcgen::Error> for AcmeError {
+    fn from(e: rcgen::Error) -> Self {
+        Self::Key(e.to_string())
+    }
+}
+
+impl From<TlsError> for AcmeError {
+    fn from(e: TlsError) -> Self {
+        Self::Certificate(e)
+    }
+}
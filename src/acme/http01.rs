@@ -0,0 +1,138 @@
+//! Answers HTTP-01 challenges (RFC 8555 section 8.3). The ACME server
+//! proves that we control a host name by fetching
+//! `http://<host>/.well-known/acme-challenge/<token>` and checking that
+//! the response is the key authorization for the token.
+//!
+//! This is the only thing the HTTP server does. Every other request gets a
+//! 404.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+use lazy_static::lazy_static;
+use log::trace;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+
+use crate::connection_handler::{AsyncStream, ConnectionHandler, TlsState};
+
+/// The longest request accepted from the ACME server, including headers
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long the ACME server has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+lazy_static! {
+    /// The key authorizations of the challenges in progress, by token
+    static ref CHALLENGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Start answering the challenge with `token`
+pub fn publish(token: &str, key_authorization: String) {
+    CHALLENGES
+        .lock()
+        .unwrap()
+        .insert(token.to_owned(), key_authorization);
+}
+
+/// Stop answering the challenge with `token`, once it has been validated
+/// or has failed
+pub fn withdraw(token: &str) {
+    CHALLENGES.lock().unwrap().remove(token);
+}
+
+/// Handles one HTTP request from the ACME server
+pub struct Http01Connection {
+    stream: Box<dyn AsyncStream>,
+    buffer: BytesMut,
+}
+
+impl ConnectionHandler for Http01Connection {
+    fn protocol_name() -> &'static str {
+        "HTTP"
+    }
+
+    fn from_stream<S: AsyncStream + 'static>(stream: S, _peer: SocketAddr, _tls: TlsState) -> Self {
+        Self {
+            stream: Box::new(stream),
+            buffer: BytesMut::new(),
+        }
+    }
+
+    async fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        // Only the request line matters, but the whole request is read so
+        // that the client doesn't see the connection reset
+        while !self.buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+            if self.buffer.len() > MAX_REQUEST {
+                return Err("HTTP request too long".into());
+            }
+            match timeout(REQUEST_TIMEOUT, self.stream.read_buf(&mut self.buffer)).await {
+                Ok(Ok(0)) | Err(_) => return Ok(()),
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        }
+
+        let request = String::from_utf8_lossy(&self.buffer);
+        let request_line = request.lines().next().unwrap_or_default();
+        trace!("Received HTTP request: {}", request_line);
+
+        let key_authorization = challenge_token(request_line)
+            .and_then(|token| CHALLENGES.lock().unwrap().get(token).cloned());
+
+        let response = match key_authorization {
+            Some(body) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_owned(),
+        };
+        self.stream.write_all(response.as_bytes()).await?;
+        self.stream.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+/// Get the token from the request line of a challenge request, like
+/// `GET /.well-known/acme-challenge/<token> HTTP/1.1`
+fn challenge_token(request_line: &str) -> Option<&str> {
+    let mut parts = request_line.split(' ');
+    let (method, target) = (parts.next()?, parts.next()?);
+    if method != "GET" {
+        return None;
+    }
+
+    target
+        .strip_prefix(CHALLENGE_PATH)
+        .filter(|token| !token.is_empty() && !token.contains('/'))
+}
+
+#[test]
+fn challenge_request_token() {
+    assert_eq!(
+        challenge_token("GET /.well-known/acme-challenge/LoqXcYV8q5ONbJQx HTTP/1.1"),
+        Some("LoqXcYV8q5ONbJQx")
+    );
+    assert_eq!(
+        challenge_token("GET /.well-known/acme-challenge/ HTTP/1.1"),
+        None
+    );
+    assert_eq!(challenge_token("GET /index.html HTTP/1.1"), None);
+    assert_eq!(
+        challenge_token("POST /.well-known/acme-challenge/abc HTTP/1.1"),
+        None
+    );
+    assert_eq!(
+        challenge_token("GET /.well-known/acme-challenge/a/b HTTP/1.1"),
+        None
+    );
+    assert_eq!(challenge_token(""), None);
+}
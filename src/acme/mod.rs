@@ -0,0 +1,271 @@
+//! Gets certificates from an ACME server like Let's Encrypt (RFC 8555) for
+//! the domains with `tls_settings = "acme"`, and renews them before they
+//! expire.
+//!
+//! Each certificate covers the domain and the mail server's host name (its
+//! selector and the domain). They are stored in the `storage` directory
+//! from the `[acme]` section of the configuration file, next to the
+//! account key:
+//!
+//! ```text
+//! storage/
+//!     account.key
+//!     example.com/
+//!         certificate.pem
+//!         private_key.pem
+//! ```
+//!
+//! New certificates are installed in the running listeners straight away.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rcgen::{CertificateParams, KeyPair};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+use crate::config::{DomainCfg, TlsSettings};
+use crate::tls;
+use crate::CONFIG;
+
+mod client;
+pub use client::*;
+
+mod err;
+pub use err::*;
+
+mod http01;
+pub use http01::Http01Connection;
+
+/// How often to check whether certificates need to be renewed
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long to wait before trying again after a certificate couldn't be
+/// issued
+const RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Check if any domain gets its certificate with ACME
+pub fn is_enabled() -> bool {
+    CONFIG
+        .domains
+        .iter()
+        .any(|d| matches!(d.tls_settings, TlsSettings::Acme))
+}
+
+/// Start issuing and renewing certificates in a new tokio thread. The
+/// HTTP-01 challenges are answered by an `Http01Connection` listener,
+/// which has to be started separately.
+///
+/// Returns a handle to the worker thread, which never finishes.
+pub fn start_acme_worker() -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let delay = if renew_certificates().await {
+                CHECK_INTERVAL
+            } else {
+                RETRY_DELAY
+            };
+            sleep(delay).await;
+        }
+    })
+}
+
+/// Issue a certificate for every ACME domain that doesn't have one, or
+/// whose certificate expires soon. Returns `false` if any of them
+/// couldn't be issued.
+async fn renew_certificates() -> bool {
+    let now = Utc::now();
+    let due: Vec<&DomainCfg> = CONFIG
+        .domains
+        .iter()
+        .filter(|d| matches!(d.tls_settings, TlsSettings::Acme))
+        .filter(|d| needs_renewal(stored_expiry(d), now, CONFIG.acme.renew_before))
+        .collect();
+    if due.is_empty() {
+        return true;
+    }
+
+    let storage = Path::new(&CONFIG.acme.storage);
+    let client = match create_dir(storage) {
+        Ok(()) => {
+            AcmeClient::connect(
+                &CONFIG.acme.directory,
+                CONFIG.acme.contact.as_deref(),
+                &storage.join("account.key"),
+                CONFIG.acme.ca_certificate.as_deref(),
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    let mut client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Couldn't log in to the ACME server: {}", e);
+            return false;
+        }
+    };
+
+    let mut all_issued = true;
+    for domain in due {
+        if let Err(e) = renew_certificate(&mut client, domain).await {
+            warn!("Couldn't get a certificate for {}: {}", domain.name, e);
+            all_issued = false;
+        }
+    }
+    all_issued
+}
+
+/// Issue a certificate for a domain, store it, and start using it
+async fn renew_certificate(client: &mut AcmeClient, domain: &DomainCfg) -> Result<(), AcmeError> {
+    info!(
+        "Requesting a certificate for {} from the ACME server",
+        domain.name
+    );
+    let issued = client.order_certificate(&tls::host_names(domain)).await?;
+
+    let (certificate, private_key) = certificate_paths(domain);
+    if let Some(dir) = certificate.parent() {
+        create_dir(dir)?;
+    }
+    write_private_file(&private_key, issued.private_key.as_bytes())
+        .map_err(|error| io_error(&private_key, error))?;
+    write_private_file(&certificate, issued.chain.as_bytes())
+        .map_err(|error| io_error(&certificate, error))?;
+
+    let key = tls::load_certified_key(&path_str(&certificate), &path_str(&private_key))?;
+    tls::install_certificate(domain, key);
+    match tls::certificate_expiry(issued.chain.as_bytes()) {
+        Some(expiry) => info!(
+            "Installed a new certificate for {}, which expires on {}",
+            domain.name, expiry
+        ),
+        None => info!("Installed a new certificate for {}", domain.name),
+    }
+
+    Ok(())
+}
+
+/// Check if a certificate that expires at `expiry` should be renewed.
+/// Domains without a certificate need one.
+fn needs_renewal(
+    expiry: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    renew_before_days: u32,
+) -> bool {
+    match expiry {
+        Some(expiry) => expiry - now < chrono::Duration::days(renew_before_days.into()),
+        None => true,
+    }
+}
+
+/// When a domain's stored certificate expires, if it has one
+fn stored_expiry(domain: &DomainCfg) -> Option<DateTime<Utc>> {
+    let (certificate, _) = certificate_paths(domain);
+    tls::certificate_expiry(&fs::read(certificate).ok()?)
+}
+
+/// Load a domain's stored certificate. If it hasn't been issued yet, or
+/// can't be loaded, a self-signed certificate is made to use until the
+/// ACME server issues one.
+pub fn stored_or_placeholder(domain: &DomainCfg) -> CertifiedKey {
+    let (certificate, private_key) = certificate_paths(domain);
+    if certificate.exists() {
+        match tls::load_certified_key(&path_str(&certificate), &path_str(&private_key)) {
+            Ok(key) => return key,
+            Err(e) => warn!(
+                "Couldn't load the stored certificate of {}: {}",
+                domain.name, e
+            ),
+        }
+    }
+
+    info!(
+        "Using a self-signed certificate for {} until one is issued",
+        domain.name
+    );
+    self_signed(&tls::host_names(domain)).expect("generating a self-signed certificate failed")
+}
+
+fn self_signed(names: &[String]) -> Result<CertifiedKey, AcmeError> {
+    let key = KeyPair::generate()?;
+    let certificate = CertificateParams::new(names.to_vec())?.self_signed(&key)?;
+
+    let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+    let signing_key =
+        ring::sign::any_supported_type(&private_key).map_err(|e| AcmeError::Key(e.to_string()))?;
+
+    Ok(CertifiedKey::new(
+        vec![CertificateDer::from(certificate.der().to_vec())],
+        signing_key,
+    ))
+}
+
+/// Where a domain's certificate chain and private key are stored
+pub fn certificate_paths(domain: &DomainCfg) -> (PathBuf, PathBuf) {
+    let dir = Path::new(&CONFIG.acme.storage).join(domain.name.to_ascii_lowercase());
+    (dir.join("certificate.pem"), dir.join("private_key.pem"))
+}
+
+/// Write a file that only the server can read. The contents are written
+/// to a temporary file first, so that a half written file is never
+/// loaded.
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(temporary, path)
+}
+
+fn create_dir(path: &Path) -> Result<(), AcmeError> {
+    fs::create_dir_all(path).map_err(|error| io_error(path, error))
+}
+
+fn io_error(path: &Path, error: io::Error) -> AcmeError {
+    AcmeError::Io {
+        path: path.display().to_string(),
+        error,
+    }
+}
+
+fn path_str(path: &Path) -> String {
+    path.display().to_string()
+}
+
+#[test]
+fn renewal_window() {
+    let now = Utc::now();
+    let days = |n| Some(now + chrono::Duration::days(n));
+
+    assert!(needs_renewal(None, now, 30));
+    assert!(needs_renewal(days(-1), now, 30));
+    assert!(needs_renewal(days(29), now, 30));
+    assert!(!needs_renewal(days(31), now, 30));
+    assert!(!needs_renewal(days(89), now, 30));
+}
+
+#[test]
+fn placeholder_covers_host_names() {
+    let names = ["example.com".to_owned(), "mail.example.com".to_owned()];
+    let key = self_signed(&names).unwrap();
+
+    let (_, certificate) = x509_parser::parse_x509_certificate(&key.cert[0]).unwrap();
+    let alt_names = certificate.subject_alternative_name().unwrap().unwrap();
+    assert_eq!(alt_names.value.general_names.len(), 2);
+    assert!(key.keys_match().is_ok());
+}
@@ -1,4 +1,12 @@
-use clap::Command;
+use chrono::{Local, TimeZone};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use crossterm::style::Stylize;
+use email_address::EmailAddress;
+
+use crate::database::{modseq_database, queue_database, user_database};
 
 /// Generate the command line interface via clap
 pub fn cli() -> Command {
@@ -8,4 +16,155 @@ pub fn cli() -> Command {
         .arg_required_else_help(false)
         .allow_external_subcommands(false)
         .subcommand(Command::new("config").about("View and edit the server configuration."))
+        .subcommand(
+            Command::new("queue").about("View the messages waiting to be sent to other domains."),
+        )
+        .subcommand(
+            Command::new("changes")
+                .about("View the changes to a user's mail since a modification sequence.")
+                .arg(
+                    Arg::new("address")
+                        .required(true)
+                        .help("The user's email address"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_parser(clap::value_parser!(i64))
+                        .default_value("0")
+                        .help("Only show changes after this modification sequence"),
+                ),
+        )
+        .subcommand(
+            Command::new("apop")
+                .about("Set the secret that a user logs in with using APOP or CRAM-MD5.")
+                .arg(
+                    Arg::new("address")
+                        .required(true)
+                        .help("The user's email address"),
+                )
+                .arg(
+                    Arg::new("disable")
+                        .long("disable")
+                        .action(ArgAction::SetTrue)
+                        .help("Stop the user from logging in with APOP and CRAM-MD5"),
+                ),
+        )
+}
+
+/// Print every message in the outgoing mail queue
+pub async fn print_queue() {
+    let messages = match queue_database::all_messages().await {
+        Ok(messages) => messages,
+        Err(e) => {
+            println!("Couldn't read the queue: {}", e);
+            return;
+        }
+    };
+
+    let format_time = |timestamp: i64| match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%m/%d/%Y %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    };
+
+    println!("{} message(s) in the queue", messages.len());
+
+    for message in messages {
+        let sender = match message.sender.as_str() {
+            "" => "<>",
+            sender => sender,
+        };
+
+        println!();
+        println!("{} from {}", format!("#{}", message.id).bold(), sender);
+        println!("  To:           {}", message.recipients.replace('\n', ", "));
+        println!("  Queued:       {}", format_time(message.queued_at));
+        println!("  Attempts:     {}", message.attempts);
+        println!("  Next attempt: {}", format_time(message.next_attempt));
+        if let Some(error) = message.last_error {
+            println!("  Last error:   {}", error.red());
+        }
+    }
+}
+
+/// Print the messages of a user that were stored, changed, or removed
+/// after a modification sequence
+pub async fn print_changes(args: &ArgMatches) {
+    let address = args.get_one::<String>("address").unwrap();
+    let address = match EmailAddress::from_str(address) {
+        Ok(address) => address,
+        Err(e) => {
+            println!("Invalid address {}: {}", address, e);
+            return;
+        }
+    };
+    let since = *args.get_one::<i64>("since").unwrap();
+
+    let changes = match modseq_database::changed_since(&address, since).await {
+        Ok(changes) => changes,
+        Err(e) => {
+            println!("Couldn't read the database: {}", e);
+            return;
+        }
+    };
+
+    println!(
+        "{} changed and {} removed message(s) since {} (now at {})",
+        changes.changed.len(),
+        changes.removed.len(),
+        since,
+        changes.highest_modseq
+    );
+
+    for message in changes.changed {
+        println!(
+            "{} {} {}",
+            format!("#{}", message.modseq).bold(),
+            "changed".green(),
+            message.message_id
+        );
+    }
+    for tombstone in changes.removed {
+        println!(
+            "{} {} {}",
+            format!("#{}", tombstone.modseq).bold(),
+            "removed".red(),
+            tombstone.message_id
+        );
+    }
+}
+
+/// Enable or disable APOP for a user. The secret is read from standard
+/// input.
+pub async fn configure_apop(args: &ArgMatches) {
+    let address = args.get_one::<String>("address").unwrap();
+    let address = match EmailAddress::from_str(address) {
+        Ok(address) => address,
+        Err(e) => {
+            println!("Invalid address {}: {}", address, e);
+            return;
+        }
+    };
+
+    let secret = if args.get_flag("disable") {
+        None
+    } else {
+        print!("APOP secret for {}: ", address);
+        io::stdout().flush().unwrap();
+
+        let mut secret = String::new();
+        io::stdin().read_line(&mut secret).unwrap();
+        let secret = secret.trim_end_matches(['\r', '\n']).to_owned();
+        if secret.is_empty() {
+            println!("The secret can't be empty");
+            return;
+        }
+        Some(secret)
+    };
+
+    match user_database::set_apop_secret(&address, secret.as_deref()).await {
+        Ok(true) => println!("Updated APOP for {}", address),
+        Ok(false) => println!("User {} does not exist", address),
+        Err(e) => println!("Couldn't update the database: {}", e),
+    }
 }
@@ -11,6 +11,12 @@ pub struct Config {
     pub bind_address: Ipv4Addr,
     pub database: DatabaseCfg,
     pub domains: Vec<DomainCfg>,
+    #[serde(default)]
+    pub queue: QueueCfg,
+    #[serde(default)]
+    pub login: LoginCfg,
+    #[serde(default)]
+    pub acme: AcmeCfg,
 }
 
 /// Looks for a file named "log4rs.yaml" in the same directory as the
@@ -38,6 +44,147 @@ pub struct DatabaseCfg {
     pub url: String,
 }
 
+/// Settings for the queue of messages waiting to be delivered to other
+/// domains
+#[derive(Deserialize, Serialize)]
+pub struct QueueCfg {
+    /// How long to keep retrying a message before giving up, in hours
+    #[serde(default = "default_message_lifetime")]
+    pub message_lifetime: u64,
+}
+
+/// RFC 5321 section 4.5.4.1 suggests giving up after 4-5 days
+fn default_message_lifetime() -> u64 {
+    5 * 24
+}
+
+impl Default for QueueCfg {
+    fn default() -> Self {
+        Self {
+            message_lifetime: default_message_lifetime(),
+        }
+    }
+}
+
+/// Limits on failed logins, to slow down clients that guess passwords
+#[derive(Deserialize, Serialize)]
+pub struct LoginCfg {
+    /// How many failed logins a client can make before the connection is
+    /// closed
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// How long to wait before telling the client that a login failed, in
+    /// seconds
+    #[serde(default = "default_failure_delay")]
+    pub failure_delay: u64,
+    /// How many failed logins to one account from one IP address are
+    /// allowed before that address is locked out of the account
+    #[serde(default = "default_lockout_threshold")]
+    pub lockout_threshold: u32,
+    /// How long a lockout lasts, in minutes. Failed logins older than this
+    /// are forgotten.
+    #[serde(default = "default_lockout_duration")]
+    pub lockout_duration: u64,
+    /// The shortest time allowed between two POP3 logins to the same
+    /// account, in seconds (the LOGIN-DELAY capability from RFC 2449). 0
+    /// means there is no limit.
+    #[serde(default)]
+    pub login_delay: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_failure_delay() -> u64 {
+    2
+}
+
+fn default_lockout_threshold() -> u32 {
+    10
+}
+
+fn default_lockout_duration() -> u64 {
+    15
+}
+
+impl Default for LoginCfg {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            failure_delay: default_failure_delay(),
+            lockout_threshold: default_lockout_threshold(),
+            lockout_duration: default_lockout_duration(),
+            login_delay: 0,
+        }
+    }
+}
+
+/// Settings for getting certificates automatically from an ACME server
+/// like Let's Encrypt (RFC 8555). Used by domains with
+/// `tls_settings = "acme"`.
+#[derive(Deserialize, Serialize)]
+pub struct AcmeCfg {
+    /// The directory URL of the ACME server
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    /// An email address that the ACME server can send notices about the
+    /// account to, like certificates that are about to expire
+    #[serde(default)]
+    pub contact: Option<String>,
+    /// The directory that the account key and certificates are stored in
+    #[serde(default = "default_acme_storage")]
+    pub storage: String,
+    /// The port that HTTP-01 challenges are answered on. ACME servers
+    /// connect to port 80, so this only needs to be changed when testing,
+    /// or when port 80 is forwarded from somewhere else.
+    #[serde(default = "default_acme_http_port")]
+    pub http_port: u16,
+    /// A PEM file of root certificates to trust when connecting to the
+    /// ACME server, in addition to the usual ones. Only needed for test
+    /// servers like Pebble.
+    #[serde(default)]
+    pub ca_certificate: Option<String>,
+    /// How many days before a certificate expires to renew it
+    #[serde(default = "default_renew_before")]
+    pub renew_before: u32,
+}
+
+/// Let's Encrypt's production server
+fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+/// A directory named "acme" next to the server executable
+fn default_acme_storage() -> String {
+    let mut dir = current_exe().unwrap();
+    dir.set_file_name("acme");
+    dir.as_path().to_str().unwrap().to_owned()
+}
+
+fn default_acme_http_port() -> u16 {
+    80
+}
+
+/// Let's Encrypt recommends renewing certificates when a third of their
+/// 90 day lifetime is left
+fn default_renew_before() -> u32 {
+    30
+}
+
+impl Default for AcmeCfg {
+    fn default() -> Self {
+        Self {
+            directory: default_acme_directory(),
+            contact: None,
+            storage: default_acme_storage(),
+            http_port: default_acme_http_port(),
+            ca_certificate: None,
+            renew_before: default_renew_before(),
+        }
+    }
+}
+
 fn default_postgres_host() -> String {
     "localhost".into()
 }
@@ -46,14 +193,86 @@ fn default_postgres_host() -> String {
 pub struct DomainCfg {
     /// Domain name
     pub name: String,
+    /// The subdomain that the mail server is reachable at. The "mail" part
+    /// of "mail.example.com"
+    #[serde(default)]
+    pub selector: Option<String>,
     pub tls_settings: TlsSettings,
     pub users: Vec<String>,
+    /// How many days messages are kept after a POP3 client downloads them
+    /// (the EXPIRE capability from RFC 2449). 0 means that they are deleted
+    /// at the end of the session. If this isn't set, messages are kept
+    /// until the client deletes them.
+    #[serde(default)]
+    pub expire: Option<u32>,
+    /// Refuse to log in this domain's users over plain text connections.
+    /// POP3 USER/PASS, POP3 or SMTP AUTH, and IMAP LOGIN or AUTHENTICATE
+    /// are only allowed once the connection is encrypted. IMAP clients
+    /// have to connect to port 993, since there is no IMAP STARTTLS.
+    #[serde(default)]
+    pub require_tls: bool,
+    /// The key that this domain's outgoing mail is signed with. Mail isn't
+    /// signed if this isn't set.
+    #[serde(default)]
+    pub dkim: Option<DkimCfg>,
+}
+
+/// How a domain's outgoing mail is signed with DKIM (RFC 6376)
+#[derive(Deserialize, Serialize)]
+pub struct DkimCfg {
+    /// The name of the key. Its public key has to be published in a TXT
+    /// record at `<selector>._domainkey.<domain>`. This isn't the same as
+    /// the domain's `selector`.
+    pub selector: String,
+    /// The private key, in a PEM file. RSA keys (PKCS #1 or PKCS #8) sign
+    /// with rsa-sha256, and Ed25519 keys (PKCS #8) with ed25519-sha256
+    /// (RFC 8463).
+    pub private_key: String,
+    /// The header fields that are signed. A field that is listed more
+    /// times than it appears in a message is signed as missing, so that
+    /// it can't be added on the way.
+    #[serde(default = "default_dkim_headers")]
+    pub headers: Vec<String>,
+}
+
+/// Some of the fields that RFC 6376 section 5.4.1 recommends signing
+fn default_dkim_headers() -> Vec<String> {
+    [
+        "From",
+        "Reply-To",
+        "Subject",
+        "Date",
+        "To",
+        "Cc",
+        "Message-ID",
+        "In-Reply-To",
+        "References",
+        "MIME-Version",
+        "Content-Type",
+        "Content-Transfer-Encoding",
+    ]
+    .map(str::to_owned)
+    .to_vec()
 }
 
+/// How a domain's TLS certificate is found. The certificate is sent to
+/// clients that ask for the domain (or its selector) with SNI.
 #[derive(Deserialize, Serialize)]
 pub enum TlsSettings {
     #[serde(rename = "disabled")]
     Disabled,
+    /// Load the certificate and private key from PEM files
+    #[serde(rename = "files")]
+    Files {
+        /// The certificate chain, with the domain's certificate first
+        certificate: String,
+        /// The private key (PKCS #1, PKCS #8, or SEC1)
+        private_key: String,
+    },
+    /// Get a certificate from the ACME server in the `[acme]` section, and
+    /// renew it before it expires
+    #[serde(rename = "acme")]
+    Acme,
 }
 
 impl Default for TlsSettings {
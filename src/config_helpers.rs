@@ -1,5 +1,6 @@
 use std::str::FromStr;
 
+use crate::config::DomainCfg;
 use crate::CONFIG;
 use email_address::EmailAddress;
 
@@ -17,3 +18,58 @@ pub fn get_all_addresses() -> Vec<EmailAddress> {
     }
     out
 }
+
+/// Find the address in the configuration file that matches `address`.
+/// The comparison is case insensitive, but the returned address is
+/// spelled the same way as in the configuration file (which is how it is
+/// stored in the database). Returns `None` if the address is not hosted
+/// on this server.
+pub fn find_local_address(address: &EmailAddress) -> Option<EmailAddress> {
+    get_all_addresses()
+        .into_iter()
+        .find(|a| a.as_str().eq_ignore_ascii_case(address.as_str()))
+}
+
+/// Check if mail for `domain` is handled by this server.
+pub fn is_local_domain(domain: &str) -> bool {
+    CONFIG
+        .domains
+        .iter()
+        .any(|d| d.name.eq_ignore_ascii_case(domain))
+}
+
+/// Get the configuration of a domain hosted on this server. The
+/// comparison is case insensitive.
+pub fn domain_config(domain: &str) -> Option<&'static DomainCfg> {
+    CONFIG
+        .domains
+        .iter()
+        .find(|d| d.name.eq_ignore_ascii_case(domain))
+}
+
+/// Check if the users of `domain` may only log in over TLS. Domains that
+/// aren't hosted on this server don't have a policy.
+pub fn requires_tls(domain: &str) -> bool {
+    domain_config(domain).is_some_and(|d| d.require_tls)
+}
+
+/// The name of the first domain in the configuration file.
+pub fn primary_domain() -> String {
+    match CONFIG.domains.first() {
+        Some(d) => d.name.clone(),
+        None => "localhost".to_owned(),
+    }
+}
+
+/// The fully qualified host name of this server, used in SMTP greetings
+/// and trace headers. This is the selector and name of the first domain
+/// in the configuration file (e.g. "mail.example.com").
+pub fn server_hostname() -> String {
+    match CONFIG.domains.first() {
+        Some(d) => match &d.selector {
+            Some(selector) => format!("{}.{}", selector, d.name),
+            None => d.name.clone(),
+        },
+        None => "localhost".to_owned(),
+    }
+}
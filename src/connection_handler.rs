@@ -1,11 +1,77 @@
 use log::{info, warn};
 use std::error::Error;
 use std::future::Future;
-use tokio::net::{TcpListener, TcpStream};
+use std::net::SocketAddr;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::TlsAcceptor;
 
 use crate::CONFIG;
 
+/// How long a client has to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A stream that a connection handler talks to its client over. This is a
+/// `TcpStream`, or a TLS stream wrapped around one.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> AsyncStream for S {}
+
+/// How a listener uses TLS
+#[derive(Clone)]
+pub enum TlsMode {
+    /// Connections are always plain text
+    Disabled,
+    /// Connections start in plain text, and clients can upgrade them with
+    /// STARTTLS or STLS
+    StartTls(TlsAcceptor),
+    /// Every connection starts with a TLS handshake (implicit TLS, as on
+    /// ports 465, 993, and 995)
+    Implicit(TlsAcceptor),
+}
+
+/// Whether a connection is encrypted
+#[derive(Clone)]
+pub enum TlsState {
+    /// The connection is plain text, and there is no certificate to
+    /// upgrade it with
+    Unavailable,
+    /// The connection is plain text, but the client can upgrade it
+    Available(TlsAcceptor),
+    /// The connection is encrypted
+    Active,
+}
+
+impl TlsState {
+    pub fn is_active(&self) -> bool {
+        matches!(self, TlsState::Active)
+    }
+}
+
+/// Start TLS on a plain text stream, after the client asked for it with
+/// STARTTLS or STLS. If the handshake fails, the stream can't be used
+/// anymore and the connection should be closed.
+///
+/// Anything the client sent before the handshake must be thrown away by
+/// the caller, so that it can't be mistaken for commands sent over TLS.
+pub async fn start_tls(
+    stream: &mut Box<dyn AsyncStream>,
+    acceptor: &TlsAcceptor,
+) -> Result<(), io::Error> {
+    let plain = std::mem::replace(stream, Box::new(io::join(io::empty(), io::sink())));
+
+    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(plain)).await {
+        Ok(Ok(encrypted)) => {
+            *stream = Box::new(encrypted);
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+    }
+}
+
 /// A trait for structs that handle incoming TCP connections.
 pub trait ConnectionHandler
 where
@@ -21,11 +87,14 @@ where
     /// implementation handles each connection separately in its own tokio
     /// thread.
     ///
+    /// `tls` decides whether connections start with a TLS handshake, or
+    /// can be upgraded to TLS later.
+    ///
     /// Returns a handle to the listener thread. The handle only joins when the
     /// connection listener encounters a fatal error.
     ///
     /// TODO: Think of a more descriptive function name?
-    async fn start_listening(port: u16) -> JoinHandle<()> {
+    async fn start_listening(port: u16, tls: TlsMode) -> JoinHandle<()> {
         // TODO: Consider not using `unwrap()`. Are the errors worth crashing the whole server?
         let listener = TcpListener::bind((CONFIG.bind_address, port))
             .await
@@ -40,9 +109,29 @@ where
                     Self::protocol_name(),
                     addr
                 );
-                let mut connection = Self::from_stream(socket);
+                let tls = tls.clone();
 
                 tokio::spawn(async move {
+                    let mut connection = match tls {
+                        TlsMode::Disabled => Self::from_stream(socket, addr, TlsState::Unavailable),
+                        TlsMode::StartTls(acceptor) => {
+                            Self::from_stream(socket, addr, TlsState::Available(acceptor))
+                        }
+                        TlsMode::Implicit(acceptor) => {
+                            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                                Ok(Ok(stream)) => Self::from_stream(stream, addr, TlsState::Active),
+                                Ok(Err(e)) => {
+                                    warn!("TLS handshake with {} failed: {}", addr, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    warn!("TLS handshake with {} timed out", addr);
+                                    return;
+                                }
+                            }
+                        }
+                    };
+
                     // Begin communication with the client
                     match connection.begin().await {
                         Ok(()) => {
@@ -65,9 +154,10 @@ where
         handle
     }
 
-    /// Create a connection handler from a tokio `TcpStream`. The handler has
-    /// ownership of the stream.
-    fn from_stream(stream: TcpStream) -> Self;
+    /// Create a connection handler from a stream, like a tokio `TcpStream`.
+    /// The handler has ownership of the stream. `peer` is the client's
+    /// address, and `tls` says whether the stream is encrypted.
+    fn from_stream<S: AsyncStream + 'static>(stream: S, peer: SocketAddr, tls: TlsState) -> Self;
 
     /// Async function. Begin the transaction with the client.
     ///
@@ -0,0 +1,230 @@
+//! Represents the flags that are set on stored messages (RFC 3501 section
+//! 2.3.2). Flags that start with a backslash, like `\Seen`, are system
+//! flags. The rest are keywords, which are defined by clients.
+//!
+//! Every change happens in a transaction, so clients that change the same
+//! messages at the same time always see a consistent set of flags.
+
+use std::collections::HashMap;
+
+use sea_orm::{
+    sea_query::OnConflict, ActiveValue, ColumnTrait, ConnectionTrait, DbErr, EntityTrait,
+    QueryFilter, QueryOrder, TransactionTrait,
+};
+
+use super::user_database::db_connection;
+use super::*;
+use crate::events::{self, MailEvent};
+
+/// The message has been read
+pub const SEEN: &str = "\\Seen";
+/// The message will be removed by the next IMAP `EXPUNGE`
+pub const DELETED: &str = "\\Deleted";
+
+/// How a set of flags is applied to messages
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FlagChange {
+    /// Set the flags, leaving the other flags alone
+    Add,
+    /// Clear the flags, leaving the other flags alone
+    Remove,
+    /// Set the flags and clear every other flag
+    Replace,
+}
+
+/// Get the flags of every message in a mailbox that has any, by message
+/// ID
+pub async fn get_mailbox_flags(mailbox_id: i64) -> Result<HashMap<String, Vec<String>>, DbErr> {
+    let db = db_connection().await?;
+
+    let entries = MailFlag::find()
+        .inner_join(Mail)
+        .filter(mail::Column::MailboxId.eq(mailbox_id))
+        .order_by_asc(mail_flag::Column::Flag)
+        .all(&db)
+        .await?;
+
+    let mut flags: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in entries {
+        flags.entry(entry.message_id).or_default().push(entry.flag);
+    }
+
+    Ok(flags)
+}
+
+/// Set flags on messages
+pub async fn add_flags(
+    message_ids: &[String],
+    flags: &[String],
+) -> Result<HashMap<String, Vec<String>>, DbErr> {
+    change_flags(message_ids, FlagChange::Add, flags).await
+}
+
+/// Change the flags of messages. Either every message is changed or none
+/// of them are.
+///
+/// Returns the flags that each message has afterwards, by message ID.
+pub async fn change_flags(
+    message_ids: &[String],
+    change: FlagChange,
+    flags: &[String],
+) -> Result<HashMap<String, Vec<String>>, DbErr> {
+    if message_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let db = db_connection().await?;
+    let txn = db.begin().await?;
+
+    if change != FlagChange::Add {
+        let mut delete = MailFlag::delete_many()
+            .filter(mail_flag::Column::MessageId.is_in(message_ids.iter().cloned()));
+        if change == FlagChange::Remove {
+            delete = delete.filter(mail_flag::Column::Flag.is_in(flags.iter().cloned()));
+        }
+        delete.exec(&txn).await?;
+    }
+
+    if change != FlagChange::Remove && !flags.is_empty() {
+        let new_flags = message_ids.iter().flat_map(|message_id| {
+            flags.iter().map(|flag| mail_flag::ActiveModel {
+                message_id: ActiveValue::Set(message_id.clone()),
+                flag: ActiveValue::Set(flag.clone()),
+            })
+        });
+
+        // Flags that are already set are left alone
+        MailFlag::insert_many(new_flags)
+            .on_conflict(
+                OnConflict::columns([mail_flag::Column::MessageId, mail_flag::Column::Flag])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+    }
+
+    modseq_database::touch_messages(&txn, message_ids).await?;
+    let flags = flags_in(&txn, message_ids).await?;
+
+    txn.commit().await?;
+
+    events::publish(MailEvent::FlagsChanged {
+        flags: flags.clone(),
+    });
+
+    Ok(flags)
+}
+
+/// Get the messages in a mailbox that have a flag set, ordered by UID
+pub async fn messages_with_flag(mailbox_id: i64, flag: &str) -> Result<Vec<mail::Model>, DbErr> {
+    let db = db_connection().await?;
+
+    Mail::find()
+        .inner_join(MailFlag)
+        .filter(mail::Column::MailboxId.eq(mailbox_id))
+        .filter(mail_flag::Column::Flag.eq(flag))
+        .order_by_asc(mail::Column::ImapUid)
+        .all(&db)
+        .await
+}
+
+async fn flags_in<C: ConnectionTrait>(
+    db: &C,
+    message_ids: &[String],
+) -> Result<HashMap<String, Vec<String>>, DbErr> {
+    let mut flags: HashMap<String, Vec<String>> = message_ids
+        .iter()
+        .map(|message_id| (message_id.clone(), vec![]))
+        .collect();
+
+    let entries = MailFlag::find()
+        .filter(mail_flag::Column::MessageId.is_in(message_ids.iter().cloned()))
+        .order_by_asc(mail_flag::Column::Flag)
+        .all(db)
+        .await?;
+    for entry in entries {
+        flags.entry(entry.message_id).or_default().push(entry.flag);
+    }
+
+    Ok(flags)
+}
+
+#[test]
+fn flags_are_set_cleared_and_queried() {
+    use std::str::FromStr;
+
+    use email_address::EmailAddress;
+
+    use crate::testing::run;
+
+    run(async {
+        let owner = EmailAddress::from_str("flags@example.com").unwrap();
+        for subject in ["one", "two", "three"] {
+            let message = format!("Subject: {}\r\n\r\nhello\r\n", subject);
+            mail_database::deliver_mail(std::slice::from_ref(&owner), message)
+                .await
+                .unwrap();
+        }
+        let inbox = mailbox_database::find_mailbox(&owner, mailbox_database::INBOX)
+            .await
+            .unwrap()
+            .unwrap();
+        let ids: Vec<String> = mail_database::get_messages(inbox.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.message_id)
+            .collect();
+        let with_flag = |flag: &'static str| async move {
+            messages_with_flag(inbox.id, flag)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|m| m.message_id)
+                .collect::<Vec<String>>()
+        };
+        let strings = |flags: &[&str]| flags.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+
+        // Setting a flag twice is the same as setting it once
+        add_flags(&ids[..2], &strings(&[SEEN])).await.unwrap();
+        let flags = add_flags(&ids[..1], &strings(&[SEEN, "$Important"]))
+            .await
+            .unwrap();
+        assert_eq!(flags[&ids[0]], strings(&["$Important", SEEN]));
+        assert_eq!(with_flag(SEEN).await, ids[..2]);
+        assert_eq!(with_flag("$Important").await, ids[..1]);
+        assert!(with_flag(DELETED).await.is_empty());
+
+        // Clearing a flag leaves the others alone
+        let flags = change_flags(&ids[..1], FlagChange::Remove, &strings(&[SEEN]))
+            .await
+            .unwrap();
+        assert_eq!(flags[&ids[0]], strings(&["$Important"]));
+        assert_eq!(with_flag(SEEN).await, ids[1..2]);
+
+        // Replacing sets the flags and clears every other one
+        let flags = change_flags(&ids[1..], FlagChange::Replace, &strings(&[DELETED]))
+            .await
+            .unwrap();
+        assert_eq!(flags[&ids[1]], strings(&[DELETED]));
+        assert_eq!(flags[&ids[2]], strings(&[DELETED]));
+        assert!(with_flag(SEEN).await.is_empty());
+        assert_eq!(with_flag(DELETED).await, ids[1..]);
+
+        let flags = change_flags(&ids[..1], FlagChange::Replace, &[])
+            .await
+            .unwrap();
+        assert!(flags[&ids[0]].is_empty());
+
+        // Messages without flags aren't listed
+        let flags = get_mailbox_flags(inbox.id).await.unwrap();
+        assert_eq!(flags.len(), 2);
+        assert!(!flags.contains_key(&ids[0]));
+
+        assert!(change_flags(&[], FlagChange::Add, &strings(&[SEEN]))
+            .await
+            .unwrap()
+            .is_empty());
+    });
+}
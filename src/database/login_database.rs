@@ -0,0 +1,141 @@
+//! Represents the record of failed logins. A client that fails to log in
+//! to an account too many times is locked out of that account for a while.
+//! Failures are counted per IP address and account, so that someone
+//! guessing passwords can't lock the real user out.
+
+use std::net::IpAddr;
+
+use chrono::Utc;
+use email_address::EmailAddress;
+use sea_orm::{ActiveModelTrait, ActiveValue, DbErr, EntityTrait};
+
+use super::user_database::db_connection;
+use super::*;
+use crate::events::{self, MailEvent};
+use crate::CONFIG;
+
+/// Check whether `ip` is currently locked out of `account`
+pub async fn is_locked_out(ip: IpAddr, account: &EmailAddress) -> Result<bool, DbErr> {
+    let db = db_connection().await?;
+
+    let entry = LoginFailure::find_by_id((ip.to_string(), account_key(account)))
+        .one(&db)
+        .await?;
+
+    let now = Utc::now().timestamp();
+    Ok(entry
+        .and_then(|e| e.locked_until)
+        .is_some_and(|locked_until| locked_until > now))
+}
+
+/// Record a failed login to `account` from `ip`. Returns `true` if this
+/// failure locked `ip` out of the account.
+pub async fn record_failed_login(ip: IpAddr, account: &EmailAddress) -> Result<bool, DbErr> {
+    let db = db_connection().await?;
+
+    let id = (ip.to_string(), account_key(account));
+    let previous = LoginFailure::find_by_id(id.clone()).one(&db).await?;
+
+    let now = Utc::now().timestamp();
+    let (failures, locked_until) = next_failure_state(
+        previous.as_ref(),
+        now,
+        CONFIG.login.lockout_threshold,
+        CONFIG.login.lockout_duration as i64 * 60,
+    );
+
+    let entry = login_failure::ActiveModel {
+        ip: ActiveValue::Set(id.0),
+        account: ActiveValue::Set(id.1),
+        failures: ActiveValue::Set(failures),
+        last_failure: ActiveValue::Set(now),
+        locked_until: ActiveValue::Set(locked_until),
+    };
+    match previous {
+        Some(_) => {
+            entry.update(&db).await?;
+        }
+        None => {
+            LoginFailure::insert(entry).exec(&db).await?;
+        }
+    }
+
+    let locked_out = failures == 0;
+    events::publish(MailEvent::AuthFailed {
+        ip,
+        account: account.to_string(),
+        locked_out,
+    });
+
+    Ok(locked_out)
+}
+
+/// Forget the failed logins to `account` from `ip`, after a successful
+/// login
+pub async fn clear_failed_logins(ip: IpAddr, account: &EmailAddress) -> Result<(), DbErr> {
+    let db = db_connection().await?;
+
+    LoginFailure::delete_by_id((ip.to_string(), account_key(account)))
+        .exec(&db)
+        .await?;
+    Ok(())
+}
+
+/// Addresses are compared case insensitively
+fn account_key(account: &EmailAddress) -> String {
+    account.to_string().to_ascii_lowercase()
+}
+
+/// Work out the failure count and lockout time after another failed login
+/// at `now`. Failures more than `duration` seconds old are forgotten. When
+/// the count reaches `threshold`, it is reset and a lockout of `duration`
+/// seconds starts.
+fn next_failure_state(
+    previous: Option<&login_failure::Model>,
+    now: i64,
+    threshold: u32,
+    duration: i64,
+) -> (i32, Option<i64>) {
+    let (failures, locked_until) = match previous {
+        Some(p) if p.last_failure + duration > now => (p.failures + 1, p.locked_until),
+        _ => (1, None),
+    };
+
+    if failures as u32 >= threshold {
+        (0, Some(now + duration))
+    } else {
+        (failures, locked_until.filter(|t| *t > now))
+    }
+}
+
+#[test]
+fn lockout_after_threshold() {
+    let entry = |failures, last_failure, locked_until| login_failure::Model {
+        ip: "192.0.2.1".to_owned(),
+        account: "tim@example.com".to_owned(),
+        failures,
+        last_failure,
+        locked_until,
+    };
+
+    assert_eq!(next_failure_state(None, 1000, 3, 900), (1, None));
+    assert_eq!(
+        next_failure_state(Some(&entry(1, 900, None)), 1000, 3, 900),
+        (2, None)
+    );
+    // The third failure starts a lockout
+    assert_eq!(
+        next_failure_state(Some(&entry(2, 900, None)), 1000, 3, 900),
+        (0, Some(1900))
+    );
+    // Old failures are forgotten
+    assert_eq!(
+        next_failure_state(Some(&entry(2, 50, None)), 1000, 3, 900),
+        (1, None)
+    );
+    // A lockout that is still going on isn't cut short
+    assert_eq!(
+        next_failure_state(Some(&entry(0, 900, Some(1800))), 1000, 3, 900),
+        (1, Some(1800))
+    );
+}
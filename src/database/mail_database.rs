@@ -0,0 +1,214 @@
+//! Represents the database that stores users' mail.
+
+use email_address::EmailAddress;
+use log::info;
+use rand_core::{OsRng, RngCore};
+use sea_orm::{
+    sea_query::Expr, ActiveValue, ColumnTrait, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    TransactionTrait,
+};
+
+use super::user_database::db_connection;
+use super::*;
+use crate::config_helpers::server_hostname;
+use crate::events::{self, MailEvent};
+use crate::imf::Mail as ImfMail;
+
+/// Store a message in the INBOX of each of the `recipients`. The
+/// recipients must be users on this server. Either every recipient gets
+/// the message or none of them do.
+///
+/// `message` is the whole RFC 5322 message, headers included.
+pub async fn deliver_mail(recipients: &[EmailAddress], message: String) -> Result<(), DbErr> {
+    // The subject, date, and author are stored in their own columns. If the
+    // message is malformed, then store it anyway with those columns empty.
+    let parsed = ImfMail::try_from(message.clone()).ok();
+    let header = |name: &str| -> String {
+        parsed
+            .as_ref()
+            .and_then(|m| m.headers.get(name))
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let recipient_list = recipients
+        .iter()
+        .map(|r| r.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let db = db_connection().await?;
+    let txn = db.begin().await?;
+
+    let mut delivered = Vec::new();
+    for recipient in recipients {
+        let inbox = mailbox_database::inbox(&txn, recipient).await?;
+        let imap_uid = mailbox_database::allocate_uid(&txn, inbox.id).await?;
+        let modseq = modseq_database::next_modseq(&txn, recipient.as_ref()).await?;
+
+        let uid = generate_uid();
+        let message_id = format!("{}@{}", uid, server_hostname());
+        let new_mail = mail::ActiveModel {
+            message_id: ActiveValue::Set(message_id.clone()),
+            subject: ActiveValue::Set(header("Subject")),
+            date: ActiveValue::Set(header("Date")),
+            from: ActiveValue::Set(header("From")),
+            recipients: ActiveValue::Set(recipient_list.clone()),
+            mailbox_id: ActiveValue::Set(inbox.id),
+            content: ActiveValue::Set(message.clone()),
+            uid: ActiveValue::Set(uid),
+            imap_uid: ActiveValue::Set(imap_uid),
+            retrieved_at: ActiveValue::Set(None),
+            modseq: ActiveValue::Set(modseq),
+        };
+        Mail::insert(new_mail).exec(&txn).await?;
+
+        delivered.push(MailEvent::Delivered {
+            owner: recipient.to_string(),
+            mailbox: inbox.name,
+            message_id,
+        });
+    }
+
+    txn.commit().await?;
+
+    info!("Delivered message to {}", recipient_list);
+
+    for event in delivered {
+        events::publish(event);
+    }
+
+    Ok(())
+}
+
+/// Get every message in a user's INBOX, oldest first. This is the POP3
+/// maildrop.
+pub async fn get_mailbox(owner: &EmailAddress) -> Result<Vec<mail::Model>, DbErr> {
+    let db = db_connection().await?;
+
+    Mail::find()
+        .inner_join(Mailbox)
+        .filter(mailbox::Column::Owner.eq(owner.to_string()))
+        .filter(mailbox::Column::Name.eq(mailbox_database::INBOX))
+        .order_by_asc(mail::Column::ImapUid)
+        .all(&db)
+        .await
+}
+
+/// Get every message in a mailbox, ordered by UID
+pub async fn get_messages(mailbox_id: i64) -> Result<Vec<mail::Model>, DbErr> {
+    let db = db_connection().await?;
+
+    Mail::find()
+        .filter(mail::Column::MailboxId.eq(mailbox_id))
+        .order_by_asc(mail::Column::ImapUid)
+        .all(&db)
+        .await
+}
+
+/// Remove messages from the database, leaving tombstones. Either every
+/// message is removed or none of them are.
+pub async fn delete_messages(message_ids: &[String]) -> Result<(), DbErr> {
+    if message_ids.is_empty() {
+        return Ok(());
+    }
+
+    let db = db_connection().await?;
+    let txn = db.begin().await?;
+
+    modseq_database::bury_messages(&txn, message_ids).await?;
+    Mail::delete_many()
+        .filter(mail::Column::MessageId.is_in(message_ids.iter().cloned()))
+        .exec(&txn)
+        .await?;
+
+    txn.commit().await?;
+
+    info!("Deleted {} messages", message_ids.len());
+
+    events::publish(MailEvent::Deleted {
+        message_ids: message_ids.to_vec(),
+    });
+
+    Ok(())
+}
+
+/// Record that messages have been downloaded by a POP3 client. Messages
+/// that were downloaded before keep their original time.
+pub async fn mark_retrieved(message_ids: &[String]) -> Result<(), DbErr> {
+    if message_ids.is_empty() {
+        return Ok(());
+    }
+
+    let db = db_connection().await?;
+
+    Mail::update_many()
+        .col_expr(
+            mail::Column::RetrievedAt,
+            Expr::value(chrono::Utc::now().timestamp()),
+        )
+        .filter(mail::Column::MessageId.is_in(message_ids.iter().cloned()))
+        .filter(mail::Column::RetrievedAt.is_null())
+        .exec(&db)
+        .await?;
+
+    Ok(())
+}
+
+/// Remove the messages in a user's INBOX that were downloaded by a POP3
+/// client before `retrieved_before` (a Unix timestamp), leaving
+/// tombstones. Returns the number of messages removed.
+pub async fn expire_messages(owner: &EmailAddress, retrieved_before: i64) -> Result<u64, DbErr> {
+    let db = db_connection().await?;
+
+    let inbox = match mailbox_database::find_mailbox(owner, mailbox_database::INBOX).await? {
+        Some(inbox) => inbox,
+        None => return Ok(0),
+    };
+
+    let expired: Vec<String> = Mail::find()
+        .filter(mail::Column::MailboxId.eq(inbox.id))
+        .filter(mail::Column::RetrievedAt.lt(retrieved_before))
+        .all(&db)
+        .await?
+        .into_iter()
+        .map(|m| m.message_id)
+        .collect();
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    let txn = db.begin().await?;
+
+    modseq_database::bury_messages(&txn, &expired).await?;
+    let result = Mail::delete_many()
+        .filter(mail::Column::MessageId.is_in(expired.iter().cloned()))
+        .exec(&txn)
+        .await?;
+
+    txn.commit().await?;
+
+    info!(
+        "Expired {} messages from the INBOX of {}",
+        result.rows_affected, owner
+    );
+
+    events::publish(MailEvent::Deleted {
+        message_ids: expired,
+    });
+
+    Ok(result.rows_affected)
+}
+
+/// Generate a unique ID for a stored message. Every copy of a message
+/// gets its own ID, so the `Message-ID` header can't be used.
+///
+/// The ID is also the message's POP3 unique-id, so it must be at most 70
+/// printable ASCII characters (RFC 1939 section 7).
+fn generate_uid() -> String {
+    format!(
+        "{}.{:08x}",
+        chrono::Utc::now().timestamp_micros(),
+        OsRng.next_u32()
+    )
+}
@@ -0,0 +1,249 @@
+//! Represents the mailboxes (folders) that each user's mail is kept in.
+//! Mailboxes can be inside other mailboxes. The full name of a mailbox
+//! has the names of its parents before it, separated by [`DELIMITER`].
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use email_address::EmailAddress;
+use log::info;
+use sea_orm::{
+    sea_query::Expr, ActiveValue, ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter,
+    QueryOrder, TransactionTrait,
+};
+
+use super::user_database::db_connection;
+use super::*;
+use crate::events::{self, MailEvent};
+
+/// The separator between the levels of the mailbox hierarchy
+pub const DELIMITER: char = '/';
+
+/// The mailbox that new mail is delivered to. Its name is case
+/// insensitive.
+pub const INBOX: &str = "INBOX";
+
+/// The mailboxes that every user has
+pub const DEFAULT_MAILBOXES: [&str; 5] = [INBOX, "Sent", "Drafts", "Trash", "Junk"];
+
+/// Make sure that a user has each of the [`DEFAULT_MAILBOXES`]
+pub async fn create_default_mailboxes(owner: &EmailAddress) -> Result<(), DbErr> {
+    for name in DEFAULT_MAILBOXES {
+        if create_mailbox(owner, name).await?.is_some() {
+            info!("Created mailbox {} for {}", name, owner);
+        }
+    }
+
+    Ok(())
+}
+
+/// Get every mailbox that belongs to a user, ordered by name
+pub async fn get_mailboxes(owner: &EmailAddress) -> Result<Vec<mailbox::Model>, DbErr> {
+    let db = db_connection().await?;
+
+    Mailbox::find()
+        .filter(mailbox::Column::Owner.eq(owner.to_string()))
+        .order_by_asc(mailbox::Column::Name)
+        .all(&db)
+        .await
+}
+
+/// Look up one of a user's mailboxes by its full name
+pub async fn find_mailbox(
+    owner: &EmailAddress,
+    name: &str,
+) -> Result<Option<mailbox::Model>, DbErr> {
+    let db = db_connection().await?;
+
+    find_mailbox_in(&db, owner, &normalize_name(name)).await
+}
+
+/// Create a mailbox, along with any of its parents that don't exist yet.
+/// Returns `None` if the mailbox already exists.
+pub async fn create_mailbox(
+    owner: &EmailAddress,
+    name: &str,
+) -> Result<Option<mailbox::Model>, DbErr> {
+    let name = normalize_name(name);
+
+    let db = db_connection().await?;
+    let txn = db.begin().await?;
+
+    if find_mailbox_in(&txn, owner, &name).await?.is_some() {
+        return Ok(None);
+    }
+
+    // Create the mailboxes from the top of the hierarchy down, so that
+    // each one knows its parent
+    let mut parent: Option<mailbox::Model> = None;
+    for (end, _) in name
+        .match_indices(DELIMITER)
+        .chain(std::iter::once((name.len(), "")))
+    {
+        let path = &name[..end];
+        parent = Some(match find_mailbox_in(&txn, owner, path).await? {
+            Some(mailbox) => mailbox,
+            None => insert_mailbox(&txn, owner, path, parent.map(|p| p.id)).await?,
+        });
+    }
+
+    txn.commit().await?;
+
+    Ok(parent)
+}
+
+/// Remove a mailbox, along with every mailbox inside of it and all of
+/// their messages. The messages leave tombstones.
+pub async fn delete_mailbox(mailbox: &mailbox::Model) -> Result<(), DbErr> {
+    let db = db_connection().await?;
+    let txn = db.begin().await?;
+
+    // Remember which messages are removed, for their tombstones and so that
+    // subscribers can be told
+    let mut removed = vec![mailbox.id];
+    let mut others = Mailbox::find()
+        .filter(mailbox::Column::Owner.eq(mailbox.owner.as_str()))
+        .all(&txn)
+        .await?;
+    while let Some(child) = others
+        .iter()
+        .position(|m| m.parent.is_some_and(|parent| removed.contains(&parent)))
+    {
+        removed.push(others.swap_remove(child).id);
+    }
+    let message_ids: Vec<String> = Mail::find()
+        .filter(mail::Column::MailboxId.is_in(removed))
+        .all(&txn)
+        .await?
+        .into_iter()
+        .map(|m| m.message_id)
+        .collect();
+
+    // The mailboxes inside of it and their messages are removed by the
+    // database (ON DELETE CASCADE)
+    modseq_database::bury_messages(&txn, &message_ids).await?;
+    Mailbox::delete_by_id(mailbox.id).exec(&txn).await?;
+
+    txn.commit().await?;
+
+    info!("Deleted mailbox {} of {}", mailbox.name, mailbox.owner);
+
+    if !message_ids.is_empty() {
+        events::publish(MailEvent::Deleted { message_ids });
+    }
+
+    Ok(())
+}
+
+/// Get a user's INBOX, creating it if it doesn't exist yet. `db` can be a
+/// connection or a transaction.
+pub async fn inbox<C: ConnectionTrait>(
+    db: &C,
+    owner: &EmailAddress,
+) -> Result<mailbox::Model, DbErr> {
+    match find_mailbox_in(db, owner, INBOX).await? {
+        Some(inbox) => Ok(inbox),
+        None => insert_mailbox(db, owner, INBOX, None).await,
+    }
+}
+
+/// Take the next UID of a mailbox for a new message. `db` should be a
+/// transaction that also stores the message, so that the UID isn't used
+/// up if storing the message fails.
+pub async fn allocate_uid<C: ConnectionTrait>(db: &C, mailbox_id: i64) -> Result<i64, DbErr> {
+    Mailbox::update_many()
+        .col_expr(
+            mailbox::Column::UidNext,
+            Expr::col(mailbox::Column::UidNext).add(1),
+        )
+        .filter(mailbox::Column::Id.eq(mailbox_id))
+        .exec(db)
+        .await?;
+
+    let mailbox = Mailbox::find_by_id(mailbox_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound(format!("mailbox {}", mailbox_id)))?;
+
+    Ok(mailbox.uid_next - 1)
+}
+
+/// Spell INBOX the same way no matter how the client spelled it, even
+/// when it is the parent of another mailbox
+pub fn normalize_name(name: &str) -> String {
+    let top = name.split(DELIMITER).next().unwrap_or_default();
+
+    if top.eq_ignore_ascii_case(INBOX) {
+        format!("{}{}", INBOX, &name[top.len()..])
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Check whether a name can be given to a new mailbox. None of the levels
+/// of the hierarchy can be empty.
+pub fn is_valid_name(name: &str) -> bool {
+    name.split(DELIMITER).all(|level| !level.is_empty())
+}
+
+async fn find_mailbox_in<C: ConnectionTrait>(
+    db: &C,
+    owner: &EmailAddress,
+    name: &str,
+) -> Result<Option<mailbox::Model>, DbErr> {
+    Mailbox::find()
+        .filter(mailbox::Column::Owner.eq(owner.to_string()))
+        .filter(mailbox::Column::Name.eq(name))
+        .one(db)
+        .await
+}
+
+async fn insert_mailbox<C: ConnectionTrait>(
+    db: &C,
+    owner: &EmailAddress,
+    name: &str,
+    parent: Option<i64>,
+) -> Result<mailbox::Model, DbErr> {
+    let new_mailbox = mailbox::ActiveModel {
+        id: ActiveValue::NotSet,
+        owner: ActiveValue::Set(owner.to_string()),
+        name: ActiveValue::Set(name.to_owned()),
+        parent: ActiveValue::Set(parent),
+        uid_validity: ActiveValue::Set(generate_uid_validity()),
+        uid_next: ActiveValue::Set(1),
+    };
+    let id = Mailbox::insert(new_mailbox).exec(db).await?.last_insert_id;
+
+    Mailbox::find_by_id(id)
+        .one(db)
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound(format!("mailbox {}", id)))
+}
+
+/// Generate the UIDVALIDITY of a new mailbox. It is the current time, but
+/// never the same twice, so that a mailbox that is deleted and created
+/// again gets a different one (RFC 3501 section 2.3.1.1).
+fn generate_uid_validity() -> i64 {
+    static LAST_UID_VALIDITY: AtomicI64 = AtomicI64::new(0);
+
+    let now = chrono::Utc::now().timestamp();
+    let previous = LAST_UID_VALIDITY
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap_or_default();
+
+    now.max(previous + 1)
+}
+
+#[test]
+fn mailbox_names() {
+    assert_eq!(normalize_name("inbox"), "INBOX");
+    assert_eq!(normalize_name("Inbox/Receipts"), "INBOX/Receipts");
+    assert_eq!(normalize_name("Inboxes"), "Inboxes");
+    assert_eq!(normalize_name("Archive/inbox"), "Archive/inbox");
+
+    assert!(is_valid_name("Receipts/2026"));
+    assert!(!is_valid_name(""));
+    assert!(!is_valid_name("Receipts//2026"));
+    assert!(!is_valid_name("/Receipts"));
+}
@@ -1,4 +1,10 @@
 mod models;
 pub use models::{prelude::*, *};
 
+pub mod flag_database;
+pub mod login_database;
+pub mod mail_database;
+pub mod mailbox_database;
+pub mod modseq_database;
+pub mod queue_database;
 pub mod user_database;
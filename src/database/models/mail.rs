@@ -11,11 +11,38 @@ pub struct Model {
     pub date: String,
     pub from: String,
     pub recipients: String,
-    pub belongs_to: String,
+    pub mailbox_id: i64,
     pub content: String,
+    pub uid: String,
+    pub imap_uid: i64,
+    pub retrieved_at: Option<i64>,
+    pub modseq: i64,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(has_many = "super::mail_flag::Entity")]
+    MailFlag,
+    #[sea_orm(
+        belongs_to = "super::mailbox::Entity",
+        from = "Column::MailboxId",
+        to = "super::mailbox::Column::Id",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    Mailbox,
+}
+
+impl Related<super::mail_flag::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::MailFlag.def()
+    }
+}
+
+impl Related<super::mailbox::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Mailbox.def()
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
@@ -2,5 +2,11 @@
 
 pub mod prelude;
 
+pub mod login_failure;
 pub mod mail;
+pub mod mail_flag;
+pub mod mail_tombstone;
+pub mod mailbox;
+pub mod mod_sequence;
+pub mod queue;
 pub mod user;
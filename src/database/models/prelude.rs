@@ -1,4 +1,10 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.0
 
+pub use super::login_failure::Entity as LoginFailure;
 pub use super::mail::Entity as Mail;
+pub use super::mail_flag::Entity as MailFlag;
+pub use super::mail_tombstone::Entity as MailTombstone;
+pub use super::mailbox::Entity as Mailbox;
+pub use super::mod_sequence::Entity as ModSequence;
+pub use super::queue::Entity as Queue;
 pub use super::user::Entity as User;
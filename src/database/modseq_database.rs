@@ -0,0 +1,252 @@
+//! Keeps track of what has changed in each user's mail, so that clients
+//! can sync without listing every mailbox again.
+//!
+//! Every user has a modification sequence (modseq) that goes up with each
+//! change to their mail. A message remembers the modseq of its last
+//! change: when it was stored, or when its flags were last changed. A
+//! message that is removed leaves a tombstone with the modseq of its
+//! removal.
+
+use std::collections::HashMap;
+
+use email_address::EmailAddress;
+use sea_orm::{
+    sea_query::Expr, ActiveValue, ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter,
+    QueryOrder, TransactionTrait,
+};
+
+use super::user_database::db_connection;
+use super::*;
+
+/// The number of tombstones that are inserted in one statement. Each one
+/// takes six of the 999 parameters that older versions of SQLite allow.
+const TOMBSTONE_BATCH: usize = 100;
+
+/// Everything that has changed in a user's mail since a modseq
+#[derive(PartialEq, Debug, Clone)]
+pub struct Changes {
+    /// Messages that were stored or had their flags changed, oldest change
+    /// first
+    pub changed: Vec<mail::Model>,
+    /// Messages that were removed, oldest removal first
+    pub removed: Vec<mail_tombstone::Model>,
+    /// The modseq of the user's latest change, or 0 if their mail has never
+    /// changed. The next call can ask for the changes after this one.
+    pub highest_modseq: i64,
+}
+
+/// Find out what has changed in a user's mail after `modseq`
+pub async fn changed_since(owner: &EmailAddress, modseq: i64) -> Result<Changes, DbErr> {
+    let owner = owner.to_string();
+
+    // Read everything in one transaction, so that a change that happens in
+    // the middle isn't missed by the next call
+    let db = db_connection().await?;
+    let txn = db.begin().await?;
+
+    let changed = Mail::find()
+        .inner_join(Mailbox)
+        .filter(mailbox::Column::Owner.eq(owner.as_str()))
+        .filter(mail::Column::Modseq.gt(modseq))
+        .order_by_asc(mail::Column::Modseq)
+        .all(&txn)
+        .await?;
+
+    let removed = MailTombstone::find()
+        .filter(mail_tombstone::Column::Owner.eq(owner.as_str()))
+        .filter(mail_tombstone::Column::Modseq.gt(modseq))
+        .order_by_asc(mail_tombstone::Column::Modseq)
+        .all(&txn)
+        .await?;
+
+    let highest_modseq = highest_modseq_in(&txn, &owner).await?;
+
+    txn.commit().await?;
+
+    Ok(Changes {
+        changed,
+        removed,
+        highest_modseq,
+    })
+}
+
+/// Take the next modseq of a user for a change. `db` should be a
+/// transaction that also makes the change.
+pub async fn next_modseq<C: ConnectionTrait>(db: &C, owner: &str) -> Result<i64, DbErr> {
+    let result = ModSequence::update_many()
+        .col_expr(
+            mod_sequence::Column::HighestModseq,
+            Expr::col(mod_sequence::Column::HighestModseq).add(1),
+        )
+        .filter(mod_sequence::Column::Owner.eq(owner))
+        .exec(db)
+        .await?;
+
+    if result.rows_affected == 0 {
+        let sequence = mod_sequence::ActiveModel {
+            owner: ActiveValue::Set(owner.to_owned()),
+            highest_modseq: ActiveValue::Set(1),
+        };
+        ModSequence::insert(sequence).exec(db).await?;
+        return Ok(1);
+    }
+
+    highest_modseq_in(db, owner).await
+}
+
+/// Give messages a new modseq after their flags were changed. Each owner's
+/// messages share one modseq.
+pub async fn touch_messages<C: ConnectionTrait>(
+    db: &C,
+    message_ids: &[String],
+) -> Result<(), DbErr> {
+    for (owner, messages) in messages_by_owner(db, message_ids).await? {
+        let modseq = next_modseq(db, &owner).await?;
+
+        Mail::update_many()
+            .col_expr(mail::Column::Modseq, Expr::value(modseq))
+            .filter(mail::Column::MessageId.is_in(messages.into_iter().map(|m| m.message_id)))
+            .exec(db)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Leave tombstones for messages that are about to be removed. `db` should
+/// be a transaction that also removes the messages.
+pub async fn bury_messages<C: ConnectionTrait>(
+    db: &C,
+    message_ids: &[String],
+) -> Result<(), DbErr> {
+    for (owner, messages) in messages_by_owner(db, message_ids).await? {
+        let modseq = next_modseq(db, &owner).await?;
+
+        for batch in messages.chunks(TOMBSTONE_BATCH) {
+            let tombstones = batch.iter().map(|m| mail_tombstone::ActiveModel {
+                message_id: ActiveValue::Set(m.message_id.clone()),
+                owner: ActiveValue::Set(owner.clone()),
+                mailbox_id: ActiveValue::Set(m.mailbox_id),
+                uid: ActiveValue::Set(m.uid.clone()),
+                imap_uid: ActiveValue::Set(m.imap_uid),
+                modseq: ActiveValue::Set(modseq),
+            });
+            MailTombstone::insert_many(tombstones)
+                .exec_without_returning(db)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn highest_modseq_in<C: ConnectionTrait>(db: &C, owner: &str) -> Result<i64, DbErr> {
+    Ok(ModSequence::find_by_id(owner.to_owned())
+        .one(db)
+        .await?
+        .map(|sequence| sequence.highest_modseq)
+        .unwrap_or_default())
+}
+
+async fn messages_by_owner<C: ConnectionTrait>(
+    db: &C,
+    message_ids: &[String],
+) -> Result<HashMap<String, Vec<mail::Model>>, DbErr> {
+    let mut owners: HashMap<String, Vec<mail::Model>> = HashMap::new();
+    if message_ids.is_empty() {
+        return Ok(owners);
+    }
+
+    let messages = Mail::find()
+        .find_also_related(Mailbox)
+        .filter(mail::Column::MessageId.is_in(message_ids.iter().cloned()))
+        .all(db)
+        .await?;
+    for (message, mailbox) in messages {
+        if let Some(mailbox) = mailbox {
+            owners.entry(mailbox.owner).or_default().push(message);
+        }
+    }
+
+    Ok(owners)
+}
+
+#[test]
+fn changes_are_tracked_by_modseq() {
+    use std::str::FromStr;
+
+    use crate::testing::run;
+
+    run(async {
+        let owner = EmailAddress::from_str("modseq@example.com").unwrap();
+        let changes = changed_since(&owner, 0).await.unwrap();
+        assert!(changes.changed.is_empty() && changes.removed.is_empty());
+        assert_eq!(changes.highest_modseq, 0);
+
+        // Each stored message gets the next modseq
+        for subject in ["one", "two"] {
+            let message = format!("Subject: {}\r\n\r\nhello\r\n", subject);
+            mail_database::deliver_mail(std::slice::from_ref(&owner), message)
+                .await
+                .unwrap();
+        }
+        let changes = changed_since(&owner, 0).await.unwrap();
+        let modseqs: Vec<i64> = changes.changed.iter().map(|m| m.modseq).collect();
+        assert_eq!(modseqs, [1, 2]);
+        assert_eq!(changes.highest_modseq, 2);
+        let (first, second) = (&changes.changed[0], &changes.changed[1]);
+
+        // Changing flags moves a message to the end
+        flag_database::add_flags(
+            std::slice::from_ref(&first.message_id),
+            &[flag_database::SEEN.to_owned()],
+        )
+        .await
+        .unwrap();
+        let changes = changed_since(&owner, 2).await.unwrap();
+        assert_eq!(changes.changed.len(), 1);
+        assert_eq!(changes.changed[0].message_id, first.message_id);
+        assert_eq!(changes.changed[0].modseq, 3);
+        assert!(changes.removed.is_empty());
+        assert_eq!(changes.highest_modseq, 3);
+
+        // A removed message leaves a tombstone
+        mail_database::delete_messages(std::slice::from_ref(&second.message_id))
+            .await
+            .unwrap();
+        let changes = changed_since(&owner, 3).await.unwrap();
+        assert!(changes.changed.is_empty());
+        assert_eq!(changes.highest_modseq, 4);
+        assert_eq!(
+            changes.removed,
+            [mail_tombstone::Model {
+                message_id: second.message_id.clone(),
+                owner: owner.to_string(),
+                mailbox_id: second.mailbox_id,
+                uid: second.uid.clone(),
+                imap_uid: second.imap_uid,
+                modseq: 4,
+            }]
+        );
+
+        // Older changes are still reported to clients that are further
+        // behind
+        let changes = changed_since(&owner, 0).await.unwrap();
+        assert_eq!(changes.changed.len(), 1);
+        assert_eq!(changes.removed.len(), 1);
+
+        // Touching and burying directly work the same way
+        let db = db_connection().await.unwrap();
+        touch_messages(&db, std::slice::from_ref(&first.message_id))
+            .await
+            .unwrap();
+        assert_eq!(changed_since(&owner, 4).await.unwrap().changed[0].modseq, 5);
+        touch_messages(&db, &[]).await.unwrap();
+        bury_messages(&db, &[]).await.unwrap();
+        assert_eq!(next_modseq(&db, owner.as_str()).await.unwrap(), 6);
+
+        // A user's first change starts their sequence
+        assert_eq!(next_modseq(&db, "unused@example.com").await.unwrap(), 1);
+        assert_eq!(next_modseq(&db, "unused@example.com").await.unwrap(), 2);
+    });
+}
@@ -0,0 +1,207 @@
+//! Represents the queue of messages waiting to be delivered to other
+//! domains.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use email_address::EmailAddress;
+use log::info;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DbErr, EntityTrait, QueryFilter, QueryOrder,
+};
+
+use super::user_database::db_connection;
+use super::*;
+
+/// Add a message to the queue. Delivery will be attempted as soon as
+/// possible.
+///
+/// `sender` is `None` for the null reverse-path. `message` is the whole
+/// RFC 5322 message, headers included.
+pub async fn enqueue(
+    sender: Option<&EmailAddress>,
+    recipients: &[EmailAddress],
+    message: String,
+) -> Result<(), DbErr> {
+    let db = db_connection().await?;
+
+    let now = Utc::now().timestamp();
+    let new_entry = queue::ActiveModel {
+        id: ActiveValue::NotSet,
+        sender: ActiveValue::Set(sender.map(|s| s.to_string()).unwrap_or_default()),
+        recipients: ActiveValue::Set(join_recipients(recipients)),
+        content: ActiveValue::Set(message),
+        queued_at: ActiveValue::Set(now),
+        next_attempt: ActiveValue::Set(now),
+        attempts: ActiveValue::Set(0),
+        last_error: ActiveValue::Set(None),
+    };
+    let result = Queue::insert(new_entry).exec(&db).await?;
+
+    info!(
+        "Queued message {} for {}",
+        result.last_insert_id,
+        join_recipients(recipients).replace('\n', ", ")
+    );
+
+    Ok(())
+}
+
+/// Get every queued message whose next delivery attempt is due
+pub async fn due_messages() -> Result<Vec<queue::Model>, DbErr> {
+    let db = db_connection().await?;
+
+    Queue::find()
+        .filter(queue::Column::NextAttempt.lte(Utc::now().timestamp()))
+        .order_by_asc(queue::Column::NextAttempt)
+        .all(&db)
+        .await
+}
+
+/// Get every queued message, oldest first
+pub async fn all_messages() -> Result<Vec<queue::Model>, DbErr> {
+    let db = db_connection().await?;
+
+    Queue::find()
+        .order_by_asc(queue::Column::QueuedAt)
+        .all(&db)
+        .await
+}
+
+/// Record a failed delivery attempt. Only the `remaining` recipients will
+/// be tried again, at `next_attempt`.
+pub async fn record_failed_attempt(
+    message: queue::Model,
+    remaining: &[EmailAddress],
+    next_attempt: DateTime<Utc>,
+    error: String,
+) -> Result<(), DbErr> {
+    let db = db_connection().await?;
+
+    let attempts = message.attempts + 1;
+    let mut entry: queue::ActiveModel = message.into();
+    entry.recipients = ActiveValue::Set(join_recipients(remaining));
+    entry.next_attempt = ActiveValue::Set(next_attempt.timestamp());
+    entry.attempts = ActiveValue::Set(attempts);
+    entry.last_error = ActiveValue::Set(Some(error));
+    entry.update(&db).await?;
+
+    Ok(())
+}
+
+/// Remove a message from the queue
+pub async fn remove(id: i64) -> Result<(), DbErr> {
+    let db = db_connection().await?;
+
+    Queue::delete_by_id(id).exec(&db).await?;
+    Ok(())
+}
+
+/// Parse the `sender` column of a queued message. Returns `None` for the
+/// null reverse-path.
+pub fn sender(message: &queue::Model) -> Option<EmailAddress> {
+    EmailAddress::from_str(&message.sender).ok()
+}
+
+/// Parse the `recipients` column of a queued message
+pub fn recipients(message: &queue::Model) -> Vec<EmailAddress> {
+    message
+        .recipients
+        .lines()
+        .filter_map(|r| EmailAddress::from_str(r).ok())
+        .collect()
+}
+
+/// Recipients are stored one per line, since a quoted local part can
+/// contain a comma.
+fn join_recipients(recipients: &[EmailAddress]) -> String {
+    recipients
+        .iter()
+        .map(|r| r.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[test]
+fn queued_message_is_retried_then_removed() {
+    use crate::testing::run;
+
+    run(async {
+        let sender = EmailAddress::from_str("queue@example.com").unwrap();
+        let recipients = [
+            EmailAddress::from_str("first@example.org").unwrap(),
+            EmailAddress::from_str("second@example.net").unwrap(),
+        ];
+        enqueue(
+            Some(&sender),
+            &recipients,
+            "Subject: queued\r\n\r\nhi\r\n".to_owned(),
+        )
+        .await
+        .unwrap();
+
+        // Only this test sends from this address
+        let find = |messages: Vec<queue::Model>| {
+            messages
+                .into_iter()
+                .find(|m| m.sender == "queue@example.com")
+        };
+
+        // New messages are due right away
+        let message = find(due_messages().await.unwrap()).unwrap();
+        assert_eq!(self::sender(&message), Some(sender));
+        assert_eq!(self::recipients(&message), recipients);
+        assert_eq!(message.attempts, 0);
+        assert_eq!(message.last_error, None);
+
+        // A failed attempt isn't retried until its next attempt is due
+        let next_attempt = Utc::now() + chrono::Duration::minutes(5);
+        record_failed_attempt(message, &recipients[1..], next_attempt, "busy".to_owned())
+            .await
+            .unwrap();
+        assert!(find(due_messages().await.unwrap()).is_none());
+
+        let message = find(all_messages().await.unwrap()).unwrap();
+        assert_eq!(self::recipients(&message), recipients[1..]);
+        assert_eq!(message.attempts, 1);
+        assert_eq!(message.next_attempt, next_attempt.timestamp());
+        assert_eq!(message.last_error.as_deref(), Some("busy"));
+
+        let next_attempt = Utc::now() - chrono::Duration::seconds(1);
+        record_failed_attempt(message, &recipients[1..], next_attempt, "busy".to_owned())
+            .await
+            .unwrap();
+        let message = find(due_messages().await.unwrap()).unwrap();
+        assert_eq!(message.attempts, 2);
+
+        remove(message.id).await.unwrap();
+        assert!(find(all_messages().await.unwrap()).is_none());
+    });
+}
+
+#[test]
+fn null_sender_is_stored_empty() {
+    use crate::testing::run;
+
+    run(async {
+        let recipient = EmailAddress::from_str("null-sender@example.org").unwrap();
+        enqueue(
+            None,
+            std::slice::from_ref(&recipient),
+            "Subject: bounce\r\n\r\n".to_owned(),
+        )
+        .await
+        .unwrap();
+
+        let message = all_messages()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|m| m.recipients == "null-sender@example.org")
+            .unwrap();
+        assert_eq!(message.sender, "");
+        assert_eq!(self::sender(&message), None);
+
+        remove(message.id).await.unwrap();
+    });
+}
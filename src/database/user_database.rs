@@ -5,8 +5,10 @@ use argon2::{
     Argon2,
 };
 use email_address::EmailAddress;
+use hmac::{Hmac, Mac};
 use log::{info, trace};
-use sea_orm::{ActiveValue, Database, DatabaseConnection, DbErr, EntityTrait};
+use md5::{Digest, Md5};
+use sea_orm::{ActiveModelTrait, ActiveValue, Database, DatabaseConnection, DbErr, EntityTrait};
 
 //use super::err::DbError;
 use super::*;
@@ -46,12 +48,15 @@ pub async fn initialize_db() -> Result<DatabaseConnection, DbErr> {
                 let new_user = user::ActiveModel {
                     email_address: ActiveValue::Set(user.to_string()),
                     password: ActiveValue::Set(password_hash),
+                    apop_secret: ActiveValue::Set(None),
                 };
                 User::insert(new_user).exec(&db).await?;
 
                 info!("Created user with address {}", user);
             }
         }
+
+        mailbox_database::create_default_mailboxes(&user).await?;
     }
 
     Ok(db)
@@ -93,3 +98,121 @@ pub async fn authenticate_user(
         Ok(None)
     }
 }
+
+/// Look up a user in the database and check an APOP digest (RFC 1939
+/// section 7). `timestamp` is the one that was sent in the POP3 greeting.
+/// If the user does not exist, hasn't enabled APOP, or the digest is
+/// wrong, `None` is returned
+pub async fn authenticate_user_apop(
+    address: &EmailAddress,
+    timestamp: &str,
+    digest: &str,
+) -> Result<Option<user::Model>, DbErr> {
+    let user = match get_user(address).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let secret = match &user.apop_secret {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+
+    let expected = hex::encode(Md5::digest(format!("{}{}", timestamp, secret)));
+
+    if constant_time_eq(expected.as_bytes(), digest.to_ascii_lowercase().as_bytes()) {
+        Ok(Some(user))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Look up a user in the database and check a SASL CRAM-MD5 digest (RFC
+/// 2195). The digest is an HMAC-MD5 of `challenge` keyed with the same
+/// shared secret that is used for APOP. If the user does not exist, hasn't
+/// set a shared secret, or the digest is wrong, `None` is returned
+pub async fn authenticate_user_cram_md5(
+    address: &EmailAddress,
+    challenge: &str,
+    digest: &str,
+) -> Result<Option<user::Model>, DbErr> {
+    let user = match get_user(address).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let secret = match &user.apop_secret {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+
+    let expected = hex::encode(hmac_md5(secret.as_bytes(), challenge.as_bytes()));
+
+    if constant_time_eq(expected.as_bytes(), digest.to_ascii_lowercase().as_bytes()) {
+        Ok(Some(user))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Set the shared secret that a user logs in with over APOP and
+/// CRAM-MD5. `None` disables both for the user. Returns `false` if the
+/// user does not exist
+pub async fn set_apop_secret(address: &EmailAddress, secret: Option<&str>) -> Result<bool, DbErr> {
+    let db = db_connection().await?;
+
+    let user = match User::find_by_id(address.to_string()).one(&db).await? {
+        Some(user) => user,
+        None => return Ok(false),
+    };
+
+    let mut user: user::ActiveModel = user.into();
+    user.apop_secret = ActiveValue::Set(secret.map(|s| s.to_owned()));
+    user.update(&db).await?;
+
+    info!(
+        "{} APOP for {}",
+        if secret.is_some() {
+            "Enabled"
+        } else {
+            "Disabled"
+        },
+        address
+    );
+
+    Ok(true)
+}
+
+fn hmac_md5(key: &[u8], message: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Md5>::new_from_slice(key).unwrap();
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Compare two byte strings in an amount of time that doesn't depend on
+/// where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn apop_digest() {
+    // The example from RFC 1939 section 7
+    let digest = hex::encode(Md5::digest(
+        "<1896.697170952@dbc.mtview.ca.us>tanstaaf".as_bytes(),
+    ));
+    assert_eq!(digest, "c4c9334bac560ecc979e58001b3e22fb");
+
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"abcd"));
+}
+
+#[test]
+fn cram_md5_digest() {
+    // The example from RFC 2195 section 2
+    let digest = hex::encode(hmac_md5(
+        b"tanstaaftanstaaf",
+        b"<1896.697170952@postoffice.reston.mci.net>",
+    ));
+    assert_eq!(digest, "b913a602c7eda7a495b4e6e7334d3890");
+}
@@ -0,0 +1,84 @@
+//! The "relaxed" canonicalization algorithms from RFC 6376 section 3.4,
+//! which undo the whitespace changes that servers commonly make to
+//! messages on the way.
+
+/// Canonicalize a header field, including its folded lines. The result
+/// ends with CRLF.
+pub fn relaxed_header(field: &str) -> String {
+    let (name, value) = field.split_once(':').unwrap_or((field, ""));
+
+    format!(
+        "{}:{}\r\n",
+        name.trim_end_matches(is_wsp).to_ascii_lowercase(),
+        compress_whitespace(&value.replace("\r\n", "")).trim_start()
+    )
+}
+
+/// Canonicalize a message body. Empty lines at the end are removed, and
+/// a body that isn't empty always ends with CRLF.
+pub fn relaxed_body(body: &str) -> String {
+    let mut out = String::new();
+
+    // Empty lines are only written once a line with text follows them
+    let mut empty_lines = 0;
+    for line in body.split("\r\n") {
+        let line = compress_whitespace(line);
+        if line.is_empty() {
+            empty_lines += 1;
+            continue;
+        }
+
+        out += &"\r\n".repeat(empty_lines);
+        out += &line;
+        out += "\r\n";
+        empty_lines = 0;
+    }
+
+    out
+}
+
+/// Replace every run of whitespace with a single space, and remove the
+/// whitespace at the end
+fn compress_whitespace(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut whitespace = false;
+    for c in line.chars() {
+        if is_wsp(c) {
+            whitespace = true;
+            continue;
+        }
+        if whitespace {
+            out.push(' ');
+            whitespace = false;
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Whitespace as defined in RFC 5234
+fn is_wsp(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+#[test]
+fn relaxed_header_canonicalization() {
+    // From RFC 6376 section 3.4.5
+    assert_eq!(relaxed_header("A: X"), "a:X\r\n");
+    assert_eq!(relaxed_header("B : Y\t\r\n\tZ  "), "b:Y Z\r\n");
+    assert_eq!(
+        relaxed_header("Subject:  Re: \t lunch?"),
+        "subject:Re: lunch?\r\n"
+    );
+}
+
+#[test]
+fn relaxed_body_canonicalization() {
+    // From RFC 6376 section 3.4.5
+    assert_eq!(relaxed_body(" C \r\nD \t E\r\n\r\n\r\n"), " C\r\nD E\r\n");
+
+    assert_eq!(relaxed_body(""), "");
+    assert_eq!(relaxed_body("\r\n\r\n"), "");
+    assert_eq!(relaxed_body("no newline"), "no newline\r\n");
+    assert_eq!(relaxed_body("a\r\n \r\n\r\nb"), "a\r\n\r\n\r\nb\r\n");
+}
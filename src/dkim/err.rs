@@ -0,0 +1,33 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Reasons that a message couldn't be signed
+#[derive(Debug)]
+pub enum DkimError {
+    /// Couldn't read the private key file
+    Io { path: String, error: io::Error },
+    /// The key file doesn't have a PEM private key in it
+    NoPrivateKey { path: String },
+    /// The private key isn't an RSA or Ed25519 key, or is too weak
+    InvalidKey { path: String, error: String },
+    /// Computing the signature failed
+    Signing,
+}
+
+impl Error for DkimError {}
+
+impl fmt::Display for DkimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DkimError::*;
+
+        match self {
+            Io { path, error } => write!(f, "couldn't read {}: {}", path, error),
+            NoPrivateKey { path } => write!(f, "no private key found in {}", path),
+            InvalidKey { path, error } => {
+                write!(f, "private key in {} can't be used: {}", path, error)
+            }
+            Signing => write!(f, "couldn't compute the signature"),
+        }
+    }
+}
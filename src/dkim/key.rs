@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, RsaKeyPair, RSA_PKCS1_SHA256};
+use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+use super::DkimError;
+
+/// A private key that messages are signed with
+pub enum SigningKey {
+    /// Signs with rsa-sha256 (RFC 6376 section 3.3.1)
+    Rsa(RsaKeyPair),
+    /// Signs with ed25519-sha256 (RFC 8463)
+    Ed25519(Ed25519KeyPair),
+}
+
+impl SigningKey {
+    /// Read a private key from a PEM file
+    pub fn load(path: &str) -> Result<Self, DkimError> {
+        let io_error = |error| DkimError::Io {
+            path: path.to_owned(),
+            error,
+        };
+
+        let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+        let key = rustls_pemfile::private_key(&mut reader)
+            .map_err(io_error)?
+            .ok_or_else(|| DkimError::NoPrivateKey {
+                path: path.to_owned(),
+            })?;
+
+        Self::from_der(&key).map_err(|error| DkimError::InvalidKey {
+            path: path.to_owned(),
+            error,
+        })
+    }
+
+    fn from_der(key: &PrivateKeyDer) -> Result<Self, String> {
+        match key {
+            PrivateKeyDer::Pkcs1(key) => RsaKeyPair::from_der(key.secret_pkcs1_der())
+                .map(Self::Rsa)
+                .map_err(|e| e.to_string()),
+            PrivateKeyDer::Pkcs8(key) => {
+                let der = key.secret_pkcs8_der();
+                match RsaKeyPair::from_pkcs8(der) {
+                    Ok(key) => Ok(Self::Rsa(key)),
+                    Err(rsa_error) => Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+                        .map(Self::Ed25519)
+                        .map_err(|_| rsa_error.to_string()),
+                }
+            }
+            _ => Err("only RSA and Ed25519 keys are supported".to_owned()),
+        }
+    }
+
+    /// The name of the signing algorithm, for the `a=` tag
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Self::Rsa(_) => "rsa-sha256",
+            Self::Ed25519(_) => "ed25519-sha256",
+        }
+    }
+
+    /// Sign the canonicalized header fields of a message
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, DkimError> {
+        match self {
+            Self::Rsa(key) => {
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(
+                    &RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    data,
+                    &mut signature,
+                )
+                .map_err(|_| DkimError::Signing)?;
+                Ok(signature)
+            }
+            // Ed25519 signs the hash of the data instead of the data
+            // itself (RFC 8463 section 3)
+            Self::Ed25519(key) => Ok(key.sign(digest(&SHA256, data).as_ref()).as_ref().to_vec()),
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Signs outgoing mail with DKIM (RFC 6376), so that the servers that
+//! receive it can check that it was sent by the domain it claims to be
+//! from.
+//!
+//! A message is signed with the key of the domain in its From header
+//! field, or of the domain that it is a subdomain of (bounces come from
+//! `MAILER-DAEMON@mail.example.com`, which is signed by example.com).
+//! Header fields and the body both use relaxed canonicalization.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use ring::digest::{digest, SHA256};
+
+use crate::config::DkimCfg;
+use crate::smtp::to_crlf;
+use crate::CONFIG;
+
+mod canonicalization;
+use canonicalization::*;
+
+mod err;
+pub use err::*;
+
+mod key;
+pub use key::*;
+
+/// The longest that the lines of a `DKIM-Signature` header field are made
+const LINE_LENGTH: usize = 76;
+
+lazy_static! {
+    /// The name, DKIM settings, and key of every domain that signs its mail
+    static ref KEYS: Vec<(&'static str, &'static DkimCfg, SigningKey)> = CONFIG
+        .domains
+        .iter()
+        .filter_map(|domain| {
+            let dkim = domain.dkim.as_ref()?;
+            match SigningKey::load(&dkim.private_key) {
+                Ok(key) => {
+                    info!(
+                        "Loaded the DKIM key of {} (selector {}, {})",
+                        domain.name,
+                        dkim.selector,
+                        key.algorithm()
+                    );
+                    Some((domain.name.as_str(), dkim, key))
+                }
+                Err(e) => {
+                    warn!(
+                        "Couldn't load the DKIM key of {}, so its mail won't be signed: {}",
+                        domain.name, e
+                    );
+                    None
+                }
+            }
+        })
+        .collect();
+}
+
+/// Load the DKIM keys now instead of when the first message is sent, so
+/// that problems with them are logged at startup
+pub fn load_keys() {
+    lazy_static::initialize(&KEYS);
+}
+
+/// Sign a message that is about to leave this server. The
+/// `DKIM-Signature` header field is added to the top of the message.
+///
+/// Messages from domains that don't have a DKIM key, or that can't be
+/// signed, are returned unchanged.
+pub fn sign_message(message: &str) -> String {
+    // The header fields can only be found once every line ends in CRLF
+    let message = &to_crlf(message);
+    let (fields, _) = split_message(message);
+    let Some(author_domain) = author_domain(&fields) else {
+        return message.to_owned();
+    };
+
+    // The most specific domain that covers the author's
+    let Some((domain, dkim, key)) = KEYS
+        .iter()
+        .filter(|(domain, _, _)| covers(domain, &author_domain))
+        .max_by_key(|(domain, _, _)| domain.len())
+    else {
+        return message.to_owned();
+    };
+
+    match sign(message, domain, dkim, key, Utc::now().timestamp()) {
+        Ok(signed) => signed,
+        Err(e) => {
+            warn!("Couldn't sign a message from {}: {}", author_domain, e);
+            message.to_owned()
+        }
+    }
+}
+
+/// Sign a message for `domain` at `timestamp` (in seconds since the Unix
+/// epoch), and add the `DKIM-Signature` header field to the top of it.
+/// Lines are converted to end in CRLF first, since that is how the
+/// message is sent and how the receiver checks it.
+fn sign(
+    message: &str,
+    domain: &str,
+    dkim: &DkimCfg,
+    key: &SigningKey,
+    timestamp: i64,
+) -> Result<String, DkimError> {
+    let message = to_crlf(message);
+    let (fields, body) = split_message(&message);
+
+    // The From field always has to be signed (RFC 6376 section 5.4)
+    let mut names: Vec<&str> = dkim.headers.iter().map(String::as_str).collect();
+    if !names.iter().any(|n| n.eq_ignore_ascii_case("From")) {
+        names.insert(0, "From");
+    }
+
+    let body_hash = BASE64.encode(digest(&SHA256, relaxed_body(body).as_bytes()));
+
+    // The signature goes last, and is left empty while it is computed
+    let mut header = format!(
+        "DKIM-Signature: v=1; a={}; c=relaxed/relaxed;\r\n\td={}; s={}; t={};\r\n\th={};\r\n\tbh={};\r\n\tb=",
+        key.algorithm(),
+        domain,
+        dkim.selector,
+        timestamp,
+        fold(&names, ":", "\th=".len()),
+        body_hash
+    );
+
+    let mut data = signed_fields(&fields, &names).concat();
+    data += relaxed_header(&header).trim_end_matches("\r\n");
+
+    let signature = BASE64.encode(key.sign(data.as_bytes())?);
+    let chunks: Vec<&str> = signature
+        .as_bytes()
+        .chunks(64)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap())
+        .collect();
+    header += &fold(&chunks, "", "\tb=".len());
+    header += "\r\n";
+
+    Ok(header + &message)
+}
+
+/// Split a message into its header fields, each with its folded lines,
+/// and its body
+fn split_message(message: &str) -> (Vec<&str>, &str) {
+    let (header, body) = match message.split_once("\r\n\r\n") {
+        Some(v) => v,
+        None => (message.trim_end_matches("\r\n"), ""),
+    };
+
+    let mut fields = vec![];
+    let mut start = 0;
+    for (i, _) in header.match_indices("\r\n") {
+        // Lines that start with whitespace continue the field before them
+        if !header[i + 2..].starts_with([' ', '\t']) {
+            fields.push(&header[start..i]);
+            start = i + 2;
+        }
+    }
+    fields.push(&header[start..]);
+
+    (fields, body)
+}
+
+/// Canonicalize the fields named in `names`. A name that appears more than
+/// once picks the fields from the bottom up, and a name that doesn't have
+/// a field left is skipped (RFC 6376 section 5.4.2).
+fn signed_fields(fields: &[&str], names: &[&str]) -> Vec<String> {
+    let mut used = vec![false; fields.len()];
+
+    names
+        .iter()
+        .filter_map(|name| {
+            let i = (0..fields.len())
+                .rev()
+                .find(|&i| !used[i] && field_name(fields[i]).eq_ignore_ascii_case(name))?;
+            used[i] = true;
+            Some(relaxed_header(fields[i]))
+        })
+        .collect()
+}
+
+fn field_name(field: &str) -> &str {
+    field
+        .split_once(':')
+        .map_or(field, |(name, _)| name.trim_end())
+}
+
+/// The domain of the address in the From field
+fn author_domain(fields: &[&str]) -> Option<String> {
+    let from = fields
+        .iter()
+        .find(|f| field_name(f).eq_ignore_ascii_case("From"))?;
+    let (_, value) = from.split_once(':')?;
+
+    // The address is in angle brackets if the field has a display name
+    let address = match value.rsplit_once('<') {
+        Some((_, address)) => address.split('>').next()?,
+        None => value,
+    };
+    let (_, domain) = address.trim().rsplit_once('@')?;
+
+    // Without the comment, if there is one after the address
+    let domain = domain.split([' ', '\t', '(']).next()?;
+
+    Some(domain.to_ascii_lowercase())
+}
+
+/// Check if `domain` can sign mail from `author_domain`: it is the same
+/// domain, or a parent of it
+fn covers(domain: &str, author_domain: &str) -> bool {
+    let domain = domain.to_ascii_lowercase();
+    author_domain == domain || author_domain.ends_with(&format!(".{}", domain))
+}
+
+/// Join `pieces` with `separator`, starting a new line where the next
+/// piece wouldn't fit on the current one. `indent` is how much of the
+/// first line is already used.
+fn fold(pieces: &[&str], separator: &str, indent: usize) -> String {
+    let mut out = String::new();
+    let mut line_length = indent;
+    for (i, piece) in pieces.iter().enumerate() {
+        if i > 0 {
+            out += separator;
+            line_length += separator.len();
+            if line_length + piece.len() > LINE_LENGTH {
+                out += "\r\n\t";
+                line_length = 1;
+            }
+        }
+        out += piece;
+        line_length += piece.len();
+    }
+    out
+}
+
+/// The message from RFC 8463 appendix A, without its signatures
+#[cfg(test)]
+const EXAMPLE_MESSAGE: &str = "From: Joe SixPack <joe@football.example.com>\r\nTo: Suzie Q <suzie@shopping.example.net>\r\nSubject: Is dinner ready?\r\nDate: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\nMessage-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\r\nHi.\r\n\r\nWe lost the game.  Are you hungry yet?\r\n\r\nJoe.\r\n";
+
+/// The public key from RFC 8463 appendix A
+#[cfg(test)]
+const EXAMPLE_PUBLIC_KEY: &str = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+/// Get the data that a signed message's first `DKIM-Signature` covers,
+/// and the signature, the way that a verifier would
+#[cfg(test)]
+fn signed_data(message: &str) -> (String, Vec<u8>) {
+    let (fields, _) = split_message(message);
+    let header = fields[0];
+    let tag = |name: &str| -> String {
+        let value = header.split_once(':').unwrap().1;
+        let tag = value
+            .split(';')
+            .find(|t| t.trim().starts_with(&format!("{}=", name)))
+            .unwrap();
+        tag.split_once('=').unwrap().1.to_owned()
+    };
+    let strip = |s: String| s.split_whitespace().collect::<String>();
+
+    let h = strip(tag("h"));
+    let names: Vec<&str> = h.split(':').collect();
+    let signature = BASE64.decode(strip(tag("b"))).unwrap();
+
+    // The b= tag is the last one, and its value is left out
+    let last_tag = header.rfind(';').unwrap();
+    let b = last_tag + header[last_tag..].find("b=").unwrap();
+    let mut data = signed_fields(&fields[1..], &names).concat();
+    data += relaxed_header(&header[..b + 2]).trim_end_matches("\r\n");
+
+    (data, signature)
+}
+
+#[cfg(test)]
+fn verify_ed25519(message: &str, public_key: &str) {
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    let (data, signature) = signed_data(message);
+    UnparsedPublicKey::new(&ED25519, BASE64.decode(public_key).unwrap())
+        .verify(digest(&SHA256, data.as_bytes()).as_ref(), &signature)
+        .expect("signature is invalid");
+}
+
+#[test]
+fn rfc_8463_example_verifies() {
+    let signature = "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n d=football.example.com; i=@football.example.com;\r\n q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n subject : date : message-id : from : subject : date;\r\n bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n";
+
+    verify_ed25519(
+        &format!("{}{}", signature, EXAMPLE_MESSAGE),
+        EXAMPLE_PUBLIC_KEY,
+    );
+}
+
+/// Sign a message with the private key from RFC 8463 appendix A
+#[cfg(test)]
+fn sign_example(message: &str) -> String {
+    use ring::signature::Ed25519KeyPair;
+
+    let seed = BASE64
+        .decode("nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=")
+        .unwrap();
+    let key = SigningKey::Ed25519(Ed25519KeyPair::from_seed_unchecked(&seed).unwrap());
+    let dkim = DkimCfg {
+        selector: "brisbane".to_owned(),
+        private_key: String::new(),
+        headers: ["From", "To", "Subject", "Date", "Message-ID", "Cc"]
+            .map(str::to_owned)
+            .to_vec(),
+    };
+
+    sign(message, "football.example.com", &dkim, &key, 1528637909).unwrap()
+}
+
+#[test]
+fn signature_survives_mail_parsing() {
+    use crate::imf::Mail;
+
+    let signed = sign_example(EXAMPLE_MESSAGE);
+    assert!(signed.ends_with(EXAMPLE_MESSAGE));
+    assert!(signed.split("\r\n").all(|line| line.len() <= LINE_LENGTH));
+    verify_ed25519(&signed, EXAMPLE_PUBLIC_KEY);
+
+    let parsed = Mail::try_from(signed).unwrap();
+    let unsigned = Mail::try_from(EXAMPLE_MESSAGE.to_owned()).unwrap();
+    assert_eq!(parsed.content, unsigned.content);
+    assert_eq!(parsed.headers["Subject"], unsigned.headers["Subject"]);
+
+    let header = &parsed.headers["DKIM-Signature"];
+    assert!(header.starts_with("v=1; a=ed25519-sha256; c=relaxed/relaxed;"));
+    assert!(header.contains("d=football.example.com; s=brisbane; t=1528637909;"));
+    assert!(header.contains("h=From:To:Subject:Date:Message-ID:Cc;"));
+    assert!(header.contains("bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;"));
+}
+
+#[test]
+fn bare_lf_is_signed_as_crlf() {
+    // read_data accepts lines that end in a bare LF, but the message is
+    // sent with CRLF
+    let signed = sign_example(&EXAMPLE_MESSAGE.replace("\r\n", "\n"));
+    assert!(signed.ends_with(EXAMPLE_MESSAGE));
+    assert!(!signed.replace("\r\n", "").contains('\n'));
+    assert!(signed.contains("bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;"));
+    verify_ed25519(&signed, EXAMPLE_PUBLIC_KEY);
+}
+
+#[test]
+fn signing_domain() {
+    let domain = |from: &str| author_domain(&[from]);
+    assert_eq!(
+        domain("From: Joe SixPack <Joe@Football.Example.com>"),
+        Some("football.example.com".to_owned())
+    );
+    assert_eq!(
+        domain("from : joe@example.com (Joe)"),
+        Some("example.com".to_owned())
+    );
+    assert_eq!(
+        domain("From: joe@example.com"),
+        Some("example.com".to_owned())
+    );
+    assert_eq!(domain("Subject: joe@example.com"), None);
+
+    assert!(covers("Example.com", "example.com"));
+    assert!(covers("example.com", "mail.example.com"));
+    assert!(!covers("example.com", "notexample.com"));
+    assert!(!covers("mail.example.com", "example.com"));
+}
@@ -0,0 +1,161 @@
+//! Tells the rest of the server about changes to the mail store. The
+//! database layer publishes an event after each change is committed, and
+//! any number of subscribers can react to it.
+//!
+//! The bus holds a limited number of events. A subscriber that falls too
+//! far behind misses the oldest ones instead of holding up delivery.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+
+use lazy_static::lazy_static;
+use log::warn;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tokio::task::JoinHandle;
+
+/// The number of events that are kept for subscribers that haven't
+/// received them yet
+const CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref BUS: EventBus = EventBus::new(CAPACITY);
+}
+
+/// Something that happened to the mail store
+#[derive(PartialEq, Debug, Clone)]
+pub enum MailEvent {
+    /// A message was stored in one of a user's mailboxes
+    Delivered {
+        owner: String,
+        mailbox: String,
+        message_id: String,
+    },
+
+    /// Messages were removed
+    Deleted { message_ids: Vec<String> },
+
+    /// The flags of messages were changed. Has the flags that each message
+    /// has now, by message ID.
+    FlagsChanged { flags: HashMap<String, Vec<String>> },
+
+    /// Someone failed to log in to an account
+    AuthFailed {
+        ip: IpAddr,
+        account: String,
+        /// True if this failure locked `ip` out of the account
+        locked_out: bool,
+    },
+}
+
+impl fmt::Display for MailEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use MailEvent::*;
+
+        match self {
+            Delivered {
+                owner,
+                mailbox,
+                message_id,
+            } => write!(f, "Delivered {} to {} of {}", message_id, mailbox, owner),
+            Deleted { message_ids } => write!(f, "Deleted {}", message_ids.join(", ")),
+            FlagsChanged { flags } => {
+                let changes: Vec<String> = flags
+                    .iter()
+                    .map(|(message_id, flags)| format!("{} ({})", message_id, flags.join(" ")))
+                    .collect();
+                write!(f, "Changed the flags of {}", changes.join(", "))
+            }
+            AuthFailed {
+                ip,
+                account,
+                locked_out,
+            } => {
+                write!(f, "Failed login to {} from {}", account, ip)?;
+                if *locked_out {
+                    write!(f, " (locked out)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A bounded broadcast channel of [`MailEvent`]s
+struct EventBus {
+    sender: Sender<MailEvent>,
+}
+
+impl EventBus {
+    fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    fn publish(&self, event: MailEvent) {
+        // Sending only fails when nobody is subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+
+    fn subscribe(&self) -> Receiver<MailEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Tell every subscriber about an event. Never waits for the subscribers.
+pub fn publish(event: MailEvent) {
+    BUS.publish(event);
+}
+
+/// Get a receiver for every event that is published from now on
+pub fn subscribe() -> Receiver<MailEvent> {
+    BUS.subscribe()
+}
+
+/// Call `handler` with every event that is published from now on, in a
+/// new tokio thread. `name` is used in log messages about the subscriber
+/// missing events.
+///
+/// Returns a handle to the subscriber's thread, which never finishes.
+pub fn add_subscriber<F, Fut>(name: &'static str, mut handler: F) -> JoinHandle<()>
+where
+    F: FnMut(MailEvent) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let mut receiver = subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => handler(event).await,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("The {} subscriber missed {} events", name, missed)
+                }
+                // The bus is a static, so it is never closed
+                Err(RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+#[test]
+fn slow_subscribers_miss_old_events() {
+    use broadcast::error::TryRecvError;
+
+    let bus = EventBus::new(2);
+    let mut receiver = bus.subscribe();
+    let deleted = |id: &str| MailEvent::Deleted {
+        message_ids: vec![id.to_owned()],
+    };
+
+    for id in ["a", "b", "c"] {
+        bus.publish(deleted(id));
+    }
+
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Lagged(1)));
+    assert_eq!(receiver.try_recv(), Ok(deleted("b")));
+    assert_eq!(receiver.try_recv(), Ok(deleted("c")));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+}
@@ -0,0 +1,614 @@
+use std::fmt;
+
+use chrono::NaiveDate;
+
+use super::err::IMAPCommandParseError;
+use super::parser;
+
+/// A command from the client along with the tag that the client chose for
+/// it. The server's completion response for the command carries the same
+/// tag.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TaggedCommand {
+    pub tag: String,
+    pub command: IMAPCommand,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum IMAPCommand {
+    /*
+     * Commands that are valid in any state (RFC 3501 section 6.1)
+     */
+    /// `CAPABILITY`; List the capabilities that the server supports.
+    Capability,
+
+    /// `NOOP`; Do nothing. Used by clients to poll for new messages.
+    Noop,
+
+    /// `LOGOUT`; End the session.
+    Logout,
+
+    /*
+     * Commands that are valid in the not authenticated state (RFC 3501
+     * section 6.2)
+     */
+    /// `AUTHENTICATE`; Log in with a SASL mechanism. The initial response
+    /// is from the SASL-IR extension (RFC 4959).
+    Authenticate {
+        mechanism: String,
+        initial_response: Option<String>,
+    },
+
+    /// `LOGIN`; Log in with a username and a plaintext password.
+    Login { username: String, password: String },
+
+    /*
+     * Commands that are valid in the authenticated state (RFC 3501 section
+     * 6.3)
+     */
+    /// `SELECT`; Open a mailbox for reading and writing.
+    Select { mailbox: String },
+
+    /// `EXAMINE`; Open a mailbox for reading only.
+    Examine { mailbox: String },
+
+    /// `CREATE`; Create a mailbox, along with any of its parents that
+    /// don't exist.
+    Create { mailbox: String },
+
+    /// `DELETE`; Remove a mailbox and the messages in it.
+    Delete { mailbox: String },
+
+    /// `LIST`; List the mailboxes that match `pattern`. The pattern may
+    /// contain the wildcards `*` and `%`.
+    List { reference: String, pattern: String },
+
+    /// `LSUB`; List the subscribed mailboxes that match `pattern`.
+    Lsub { reference: String, pattern: String },
+
+    /// `STATUS`; Get information about a mailbox without selecting it.
+    Status {
+        mailbox: String,
+        items: Vec<StatusItem>,
+    },
+
+    /*
+     * Commands that are valid in the selected state (RFC 3501 section 6.4)
+     */
+    /// `CHECK`; Checkpoint the mailbox.
+    Check,
+
+    /// `CLOSE`; Remove the messages flagged as deleted and leave the
+    /// selected state.
+    Close,
+
+    /// `UNSELECT`; Leave the selected state without removing anything
+    /// (RFC 3691).
+    Unselect,
+
+    /// `EXPUNGE`; Remove the messages flagged as deleted.
+    Expunge,
+
+    /// `SEARCH`; Find the messages that match every one of the `criteria`.
+    /// `uid` is true for `UID SEARCH`.
+    Search {
+        charset: Option<String>,
+        criteria: Vec<SearchKey>,
+        uid: bool,
+    },
+
+    /// `FETCH`; Get data about messages. `uid` is true for `UID FETCH`,
+    /// where the sequence set contains UIDs instead of message sequence
+    /// numbers.
+    Fetch {
+        sequence_set: SequenceSet,
+        items: Vec<FetchItem>,
+        uid: bool,
+    },
+
+    /// `STORE`; Change the flags of messages. `uid` is true for
+    /// `UID STORE`.
+    Store {
+        sequence_set: SequenceSet,
+        action: StoreAction,
+        silent: bool,
+        flags: Vec<Flag>,
+        uid: bool,
+    },
+}
+
+/// A message flag (RFC 3501 section 2.3.2). Flags that don't start with
+/// a backslash are keywords, which are defined by the client.
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub enum Flag {
+    Seen,
+    Answered,
+    Flagged,
+    Deleted,
+    Draft,
+    Keyword(String),
+}
+
+impl Flag {
+    /// The flags defined by RFC 3501, which every mailbox supports
+    pub const SYSTEM: [Flag; 5] = [
+        Flag::Answered,
+        Flag::Flagged,
+        Flag::Deleted,
+        Flag::Seen,
+        Flag::Draft,
+    ];
+}
+
+/// Read a flag the way that it is stored. The names of system flags are
+/// case insensitive.
+impl From<&str> for Flag {
+    fn from(name: &str) -> Self {
+        Flag::SYSTEM
+            .into_iter()
+            .find(|flag| flag.to_string().eq_ignore_ascii_case(name))
+            .unwrap_or_else(|| Flag::Keyword(name.to_owned()))
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Flag::*;
+
+        match self {
+            Seen => write!(f, "\\Seen"),
+            Answered => write!(f, "\\Answered"),
+            Flagged => write!(f, "\\Flagged"),
+            Deleted => write!(f, "\\Deleted"),
+            Draft => write!(f, "\\Draft"),
+            Keyword(keyword) => write!(f, "{}", keyword),
+        }
+    }
+}
+
+/// A set of message sequence numbers or UIDs, like `1:4,7,10:*`
+pub type SequenceSet = Vec<SequenceRange>;
+
+/// A range of message numbers. The ends may be in either order, and a
+/// single number is a range where both ends are the same.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SequenceRange {
+    pub start: SequenceNumber,
+    pub end: SequenceNumber,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SequenceNumber {
+    Value(u32),
+    /// `*`; The largest number in use
+    Largest,
+}
+
+/// An item that the client wants with `FETCH`. The `ALL`, `FAST` and
+/// `FULL` macros are expanded by the parser.
+#[derive(PartialEq, Debug, Clone)]
+pub enum FetchItem {
+    Envelope,
+    Flags,
+    InternalDate,
+    /// `RFC822`; The whole message. Like `BODY[]`, but the response uses
+    /// the old name.
+    Rfc822,
+    Rfc822Header,
+    Rfc822Size,
+    Rfc822Text,
+    /// `BODY`; The MIME structure of the message, without extension data
+    Body,
+    BodyStructure,
+    Uid,
+    /// `BODY[<section>]<<partial>>`. Fetching a section sets `\Seen`
+    /// unless `peek` is true (`BODY.PEEK`). `partial` is the first octet
+    /// and the number of octets.
+    BodySection {
+        section: Section,
+        partial: Option<(u32, u32)>,
+        peek: bool,
+    },
+}
+
+/// A part of a message (RFC 3501 section 6.4.5). `part` is the list of
+/// MIME part numbers, which is empty for the whole message.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Section {
+    pub part: Vec<u32>,
+    pub text: Option<SectionText>,
+}
+
+/// Format a section the way it is sent back in a `FETCH` response, like
+/// `1.2.HEADER.FIELDS (From Subject)`
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let part: Vec<String> = self.part.iter().map(u32::to_string).collect();
+        write!(f, "{}", part.join("."))?;
+
+        let text = match &self.text {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+        if !self.part.is_empty() {
+            write!(f, ".")?;
+        }
+        match text {
+            SectionText::Header => write!(f, "HEADER"),
+            SectionText::HeaderFields(names) => write!(f, "HEADER.FIELDS ({})", names.join(" ")),
+            SectionText::HeaderFieldsNot(names) => {
+                write!(f, "HEADER.FIELDS.NOT ({})", names.join(" "))
+            }
+            SectionText::Text => write!(f, "TEXT"),
+            SectionText::Mime => write!(f, "MIME"),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum SectionText {
+    Header,
+    HeaderFields(Vec<String>),
+    HeaderFieldsNot(Vec<String>),
+    Text,
+    Mime,
+}
+
+/// How `STORE` changes the flags
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum StoreAction {
+    /// `FLAGS`; Replace the flags
+    Replace,
+    /// `+FLAGS`
+    Add,
+    /// `-FLAGS`
+    Remove,
+}
+
+/// A search criterion (RFC 3501 section 6.4.4)
+#[derive(PartialEq, Debug, Clone)]
+pub enum SearchKey {
+    All,
+    Answered,
+    Deleted,
+    Draft,
+    Flagged,
+    Seen,
+    Unanswered,
+    Undeleted,
+    Undraft,
+    Unflagged,
+    Unseen,
+    New,
+    Old,
+    Recent,
+    Keyword(String),
+    Unkeyword(String),
+    Bcc(String),
+    Body(String),
+    Cc(String),
+    From(String),
+    Subject(String),
+    Text(String),
+    To(String),
+    Header(String, String),
+    Before(NaiveDate),
+    On(NaiveDate),
+    Since(NaiveDate),
+    SentBefore(NaiveDate),
+    SentOn(NaiveDate),
+    SentSince(NaiveDate),
+    Larger(u32),
+    Smaller(u32),
+    Uid(SequenceSet),
+    SequenceSet(SequenceSet),
+    Not(Box<SearchKey>),
+    Or(Box<SearchKey>, Box<SearchKey>),
+    /// A parenthesized list of keys, which all have to match
+    And(Vec<SearchKey>),
+}
+
+/// An item that the client wants with `STATUS`
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum StatusItem {
+    Messages,
+    Recent,
+    UidNext,
+    UidValidity,
+    Unseen,
+}
+
+impl fmt::Display for StatusItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use StatusItem::*;
+
+        let name = match self {
+            Messages => "MESSAGES",
+            Recent => "RECENT",
+            UidNext => "UIDNEXT",
+            UidValidity => "UIDVALIDITY",
+            Unseen => "UNSEEN",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Try to parse a command line from the client. The line must end with a
+/// CRLF, and any literals in it must be included.
+impl TryFrom<&[u8]> for TaggedCommand {
+    type Error = IMAPCommandParseError;
+
+    fn try_from(input: &[u8]) -> Result<Self, Self::Error> {
+        use IMAPCommandParseError::*;
+
+        let (rest, tag) = parser::tag_and_sp(input).map_err(|_| InvalidTag)?;
+        let tag = String::from_utf8_lossy(tag).into_owned();
+
+        let name = match parser::command_name(rest) {
+            Ok((_, name)) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+            Err(_) => return Err(UnknownCommand { tag }),
+        };
+
+        let command_parser = match name.as_str() {
+            "CAPABILITY" => parser::capability,
+            "NOOP" => parser::noop,
+            "LOGOUT" => parser::logout,
+            "AUTHENTICATE" => parser::authenticate,
+            "LOGIN" => parser::login,
+            "SELECT" => parser::select,
+            "EXAMINE" => parser::examine,
+            "CREATE" => parser::create,
+            "DELETE" => parser::delete,
+            "LIST" => parser::list,
+            "LSUB" => parser::lsub,
+            "STATUS" => parser::status,
+            "CHECK" => parser::check,
+            "CLOSE" => parser::close,
+            "UNSELECT" => parser::unselect,
+            "EXPUNGE" => parser::expunge,
+            "SEARCH" => parser::search,
+            "FETCH" => parser::fetch,
+            "STORE" => parser::store,
+            "UID" => parser::uid,
+            _ => return Err(UnknownCommand { tag }),
+        };
+
+        match command_parser(rest) {
+            Ok((b"", command)) => Ok(Self { tag, command }),
+            _ => Err(InvalidArguments { tag }),
+        }
+    }
+}
+
+#[test]
+fn parse_imap_command() {
+    use IMAPCommandParseError::*;
+
+    let parse = |s: &str| TaggedCommand::try_from(s.as_bytes());
+    let command = |tag: &str, command: IMAPCommand| {
+        Ok(TaggedCommand {
+            tag: tag.to_owned(),
+            command,
+        })
+    };
+    let number = |n| SequenceRange {
+        start: SequenceNumber::Value(n),
+        end: SequenceNumber::Value(n),
+    };
+
+    assert_eq!(
+        parse("a001 capability\r\n"),
+        command("a001", IMAPCommand::Capability)
+    );
+    assert_eq!(
+        parse("a002 LOGIN \"mark@example.com\" {6}\r\nsecret\r\n"),
+        command(
+            "a002",
+            IMAPCommand::Login {
+                username: "mark@example.com".to_owned(),
+                password: "secret".to_owned(),
+            }
+        )
+    );
+    assert_eq!(
+        parse("a003 select inbox\r\n"),
+        command(
+            "a003",
+            IMAPCommand::Select {
+                mailbox: "INBOX".to_owned()
+            }
+        )
+    );
+    assert_eq!(
+        parse("A004 CREATE \"Receipts/2026\"\r\n"),
+        command(
+            "A004",
+            IMAPCommand::Create {
+                mailbox: "Receipts/2026".to_owned()
+            }
+        )
+    );
+    assert_eq!(
+        parse("a004 LIST \"\" %\r\n"),
+        command(
+            "a004",
+            IMAPCommand::List {
+                reference: "".to_owned(),
+                pattern: "%".to_owned(),
+            }
+        )
+    );
+    assert_eq!(
+        parse("a005 FETCH 1:*,3 (FLAGS BODY.PEEK[HEADER.FIELDS (From Subject)]<0.100>)\r\n"),
+        command(
+            "a005",
+            IMAPCommand::Fetch {
+                sequence_set: vec![
+                    SequenceRange {
+                        start: SequenceNumber::Value(1),
+                        end: SequenceNumber::Largest,
+                    },
+                    number(3),
+                ],
+                items: vec![
+                    FetchItem::Flags,
+                    FetchItem::BodySection {
+                        section: Section {
+                            part: vec![],
+                            text: Some(SectionText::HeaderFields(vec![
+                                "From".to_owned(),
+                                "Subject".to_owned()
+                            ])),
+                        },
+                        partial: Some((0, 100)),
+                        peek: true,
+                    },
+                ],
+                uid: false,
+            }
+        )
+    );
+    assert_eq!(
+        parse("a006 UID fetch 7 fast\r\n"),
+        command(
+            "a006",
+            IMAPCommand::Fetch {
+                sequence_set: vec![number(7)],
+                items: vec![
+                    FetchItem::Flags,
+                    FetchItem::InternalDate,
+                    FetchItem::Rfc822Size
+                ],
+                uid: true,
+            }
+        )
+    );
+    assert_eq!(
+        parse("a007 FETCH 2 BODY[1.2.MIME]\r\n"),
+        command(
+            "a007",
+            IMAPCommand::Fetch {
+                sequence_set: vec![number(2)],
+                items: vec![FetchItem::BodySection {
+                    section: Section {
+                        part: vec![1, 2],
+                        text: Some(SectionText::Mime),
+                    },
+                    partial: None,
+                    peek: false,
+                }],
+                uid: false,
+            }
+        )
+    );
+    assert_eq!(
+        parse("a008 STORE 2:4 +FLAGS.SILENT (\\Deleted $Junk)\r\n"),
+        command(
+            "a008",
+            IMAPCommand::Store {
+                sequence_set: vec![SequenceRange {
+                    start: SequenceNumber::Value(2),
+                    end: SequenceNumber::Value(4),
+                }],
+                action: StoreAction::Add,
+                silent: true,
+                flags: vec![Flag::Deleted, Flag::Keyword("$Junk".to_owned())],
+                uid: false,
+            }
+        )
+    );
+    assert_eq!(
+        parse("a009 SEARCH CHARSET UTF-8 UNSEEN OR FROM \"Mark G\" (SINCE 1-Feb-1994 NOT LARGER 100)\r\n"),
+        command(
+            "a009",
+            IMAPCommand::Search {
+                charset: Some("UTF-8".to_owned()),
+                criteria: vec![
+                    SearchKey::Unseen,
+                    SearchKey::Or(
+                        Box::new(SearchKey::From("Mark G".to_owned())),
+                        Box::new(SearchKey::And(vec![
+                            SearchKey::Since(NaiveDate::from_ymd_opt(1994, 2, 1).unwrap()),
+                            SearchKey::Not(Box::new(SearchKey::Larger(100))),
+                        ])),
+                    ),
+                ],
+                uid: false,
+            }
+        )
+    );
+    assert_eq!(
+        parse("a010 UID SEARCH 1:3 UID 5\r\n"),
+        command(
+            "a010",
+            IMAPCommand::Search {
+                charset: None,
+                criteria: vec![
+                    SearchKey::SequenceSet(vec![SequenceRange {
+                        start: SequenceNumber::Value(1),
+                        end: SequenceNumber::Value(3),
+                    }]),
+                    SearchKey::Uid(vec![number(5)]),
+                ],
+                uid: true,
+            }
+        )
+    );
+    assert_eq!(
+        parse("a011 STATUS INBOX (MESSAGES UIDNEXT)\r\n"),
+        command(
+            "a011",
+            IMAPCommand::Status {
+                mailbox: "INBOX".to_owned(),
+                items: vec![StatusItem::Messages, StatusItem::UidNext],
+            }
+        )
+    );
+    assert_eq!(
+        parse("a012 AUTHENTICATE plain AG1hcmsAc2VjcmV0\r\n"),
+        command(
+            "a012",
+            IMAPCommand::Authenticate {
+                mechanism: "PLAIN".to_owned(),
+                initial_response: Some("AG1hcmsAc2VjcmV0".to_owned()),
+            }
+        )
+    );
+
+    // Errors
+    assert_eq!(parse("\r\n"), Err(InvalidTag));
+    assert_eq!(parse("a+b NOOP\r\n"), Err(InvalidTag));
+    assert_eq!(
+        parse("a013 FROB\r\n"),
+        Err(UnknownCommand {
+            tag: "a013".to_owned()
+        })
+    );
+    assert_eq!(
+        parse("a014 FETCH 0 FLAGS\r\n"),
+        Err(InvalidArguments {
+            tag: "a014".to_owned()
+        })
+    );
+    assert_eq!(
+        parse("a015 NOOP extra\r\n"),
+        Err(InvalidArguments {
+            tag: "a015".to_owned()
+        })
+    );
+    assert_eq!(
+        parse("a016 LOGIN mark {10}\r\nshort\r\n"),
+        Err(InvalidArguments {
+            tag: "a016".to_owned()
+        })
+    );
+}
+
+#[test]
+fn stored_flags() {
+    assert_eq!(Flag::from("\\Seen"), Flag::Seen);
+    assert_eq!(Flag::from("\\DRAFT"), Flag::Draft);
+    assert_eq!(Flag::from("$Junk"), Flag::Keyword("$Junk".to_owned()));
+    assert_eq!(Flag::from(Flag::Answered.to_string().as_str()), Flag::Answered);
+}
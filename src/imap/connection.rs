@@ -0,0 +1,1087 @@
+//! Manages the IMAP connection by wrapping a TCP or TLS stream and parsing
+//! every command from the client. Keeps track of the session's state and sends
+//! the responses.
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use bytes::{Bytes, BytesMut};
+use email_address::EmailAddress;
+use log::{trace, warn};
+use sea_orm::DbErr;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use super::err::IMAPCommandParseError;
+use super::mailbox::{self, SelectedMailbox};
+use super::response::{flag_list, string};
+use super::*;
+use crate::config_helpers::requires_tls;
+use crate::connection_handler::{AsyncStream, ConnectionHandler, TlsState};
+use crate::database::flag_database::{self, FlagChange};
+use crate::database::mailbox_database::{self, DELIMITER, INBOX};
+use crate::database::*;
+use crate::sasl::{self, SaslError};
+use IMAPStatus::*;
+
+/// The longest command line that the server accepts, including literals
+const MAX_LINE: usize = 64 * 1024;
+
+/// The state of an IMAP session (RFC 3501 section 3)
+#[derive(Debug)]
+enum IMAPState {
+    NotAuthenticated,
+    Authenticated,
+    Selected(SelectedMailbox),
+    Logout,
+}
+
+pub struct IMAPConnection {
+    // Socket state
+    stream: Box<dyn AsyncStream>,
+    buffer: BytesMut,
+    /// Whether the connection is encrypted
+    tls: TlsState,
+
+    // Connection state
+    state: IMAPState,
+    user: Option<user::Model>,
+    /// The failed logins on this connection
+    logins: sasl::LoginAttempts,
+}
+
+impl ConnectionHandler for IMAPConnection {
+    fn protocol_name() -> &'static str {
+        "IMAP"
+    }
+
+    fn from_stream<S: AsyncStream + 'static>(stream: S, peer: SocketAddr, tls: TlsState) -> Self {
+        Self {
+            stream: Box::new(stream),
+            buffer: BytesMut::new(),
+            tls,
+            state: IMAPState::NotAuthenticated,
+            user: None,
+            logins: sasl::LoginAttempts::new(peer.ip()),
+        }
+    }
+
+    async fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send_response(
+            IMAPResponse::untagged(Ok, "mailroom IMAP server ready")
+                .with_code(IMAPResponseCode::Capability(self.capabilities())),
+        )
+        .await?;
+
+        loop {
+            let line = match self.read_command_line().await? {
+                Some(line) => line,
+                None => continue,
+            };
+
+            let response = match TaggedCommand::try_from(&line[..]) {
+                Result::Ok(TaggedCommand { tag, command }) => self.handle(&tag, command).await?,
+                Err(IMAPCommandParseError::InvalidTag) => {
+                    IMAPResponse::untagged(Bad, "missing or invalid tag")
+                }
+                Err(IMAPCommandParseError::UnknownCommand { tag }) => {
+                    IMAPResponse::tagged(&tag, Bad, "unknown command")
+                }
+                Err(IMAPCommandParseError::InvalidArguments { tag }) => {
+                    IMAPResponse::tagged(&tag, Bad, "invalid arguments")
+                }
+            };
+            self.send_response(response).await?;
+
+            if let IMAPState::Logout = self.state {
+                trace!("IMAP connection finished");
+                self.close().await?;
+                return Result::Ok(());
+            }
+        }
+    }
+}
+
+/// SASL challenges are sent in command continuation requests (RFC 3501
+/// section 6.2.2)
+impl sasl::Transport for IMAPConnection {
+    async fn send_challenge(&mut self, challenge: &str) -> io::Result<()> {
+        self.send_response(IMAPResponse::Continuation(challenge.to_owned()))
+            .await
+    }
+
+    async fn read_response(&mut self) -> io::Result<String> {
+        let line = self.read_raw_line().await?;
+        Result::Ok(String::from_utf8_lossy(&line[..line.len() - 2]).into_owned())
+    }
+
+    fn login_attempts(&mut self) -> &mut sasl::LoginAttempts {
+        &mut self.logins
+    }
+}
+
+impl IMAPConnection {
+    /// Send a response to the client
+    pub async fn send_response(&mut self, response: IMAPResponse) -> Result<(), io::Error> {
+        write_response(&mut self.stream, response).await
+    }
+
+    /// Read a command line from the client, along with any literals in it.
+    /// A continuation request is sent before each literal.
+    ///
+    /// Returns `None` if the command was too long. The client has already
+    /// been told in that case.
+    async fn read_command_line(&mut self) -> Result<Option<Bytes>, io::Error> {
+        let mut line = BytesMut::new();
+
+        loop {
+            let part = self.read_raw_line().await?;
+            line.extend_from_slice(&part);
+
+            let (length, synchronizing) = match literal_length(&part) {
+                Some(literal) => literal,
+                None => return Result::Ok(Some(line.freeze())),
+            };
+
+            // A huge length from the client mustn't overflow
+            if length > MAX_LINE.saturating_sub(line.len()) {
+                let response = match parser::tag_and_sp(&line) {
+                    Result::Ok((_, tag)) => {
+                        IMAPResponse::tagged(&String::from_utf8_lossy(tag), Bad, "command too long")
+                    }
+                    Err(_) => IMAPResponse::untagged(Bad, "command too long"),
+                };
+                self.send_response(response).await?;
+
+                // A client that didn't wait for the continuation request is
+                // already sending the literal, so there is no way to recover
+                if !synchronizing {
+                    self.send_response(IMAPResponse::untagged(Bye, "command too long"))
+                        .await?;
+                    self.close().await?;
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                return Result::Ok(None);
+            }
+
+            if synchronizing {
+                self.send_response(IMAPResponse::Continuation(
+                    "ready for literal data".to_owned(),
+                ))
+                .await?;
+            }
+
+            while self.buffer.len() < length {
+                self.read_more().await?;
+            }
+            line.extend_from_slice(&self.buffer.split_to(length));
+        }
+    }
+
+    /// Take the next line from the buffer, including the CRLF, reading
+    /// from the client until there is one
+    async fn read_raw_line(&mut self) -> Result<Bytes, io::Error> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                return Result::Ok(self.buffer.split_to(end + 2).freeze());
+            }
+
+            if self.buffer.len() > MAX_LINE {
+                self.send_response(IMAPResponse::untagged(Bye, "line too long"))
+                    .await?;
+                self.close().await?;
+                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            }
+
+            self.read_more().await?;
+        }
+    }
+
+    /// Read more data from the client into the buffer
+    async fn read_more(&mut self) -> Result<(), io::Error> {
+        if self.stream.read_buf(&mut self.buffer).await? == 0 {
+            self.close().await?;
+            return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
+        }
+        Result::Ok(())
+    }
+
+    /// Carry out a command. Returns the tagged response that completes it;
+    /// untagged responses are sent along the way.
+    async fn handle(
+        &mut self,
+        tag: &str,
+        command: IMAPCommand,
+    ) -> Result<IMAPResponse, Box<dyn Error>> {
+        use IMAPCommand::*;
+
+        let authenticated = self.user.is_some();
+        let selected = matches!(self.state, IMAPState::Selected(_));
+
+        let response = match command {
+            Capability => {
+                let capabilities = format!("CAPABILITY {}", self.capabilities().join(" "));
+                self.send_response(IMAPResponse::data(capabilities)).await?;
+                IMAPResponse::tagged(tag, Ok, "CAPABILITY completed")
+            }
+            Noop => {
+                if selected {
+                    self.refresh().await?;
+                }
+                IMAPResponse::tagged(tag, Ok, "NOOP completed")
+            }
+            Logout => {
+                self.send_response(IMAPResponse::untagged(Bye, "signing off"))
+                    .await?;
+                self.state = IMAPState::Logout;
+                IMAPResponse::tagged(tag, Ok, "LOGOUT completed")
+            }
+            Login { username, password } if !authenticated => {
+                let address = EmailAddress::from_str(&username).ok();
+                let login = match &address {
+                    Some(address) if self.tls_required(address) => {
+                        Err(SaslError::EncryptionRequired)
+                    }
+                    _ => {
+                        self.logins
+                            .check(address.as_ref(), async {
+                                match &address {
+                                    Some(address) => {
+                                        user_database::authenticate_user(address, &password).await
+                                    }
+                                    None => Result::Ok(None),
+                                }
+                            })
+                            .await
+                    }
+                };
+                self.finish_login(tag, login).await?
+            }
+            Authenticate {
+                mechanism,
+                initial_response,
+            } if !authenticated => {
+                self.authenticate(tag, &mechanism, initial_response.as_deref())
+                    .await?
+            }
+            Select { mailbox } if authenticated => self.select(tag, &mailbox, false).await?,
+            Examine { mailbox } if authenticated => self.select(tag, &mailbox, true).await?,
+            Create { mailbox } if authenticated => self.create(tag, &mailbox).await?,
+            Delete { mailbox } if authenticated => self.delete(tag, &mailbox).await?,
+            List { reference, pattern } if authenticated => {
+                self.list(tag, "LIST", &reference, &pattern).await?
+            }
+            // Every mailbox is subscribed
+            Lsub { reference, pattern } if authenticated => {
+                self.list(tag, "LSUB", &reference, &pattern).await?
+            }
+            Status { mailbox, items } if authenticated => {
+                self.status(tag, &mailbox, &items).await?
+            }
+            Check if selected => {
+                self.refresh().await?;
+                IMAPResponse::tagged(tag, Ok, "CHECK completed")
+            }
+            Close if selected => {
+                // CLOSE removes the deleted messages without telling the
+                // client which ones
+                if let Some(response) = self.expunge(tag, false).await? {
+                    return Result::Ok(response);
+                }
+                self.state = IMAPState::Authenticated;
+                IMAPResponse::tagged(tag, Ok, "CLOSE completed")
+            }
+            Unselect if selected => {
+                self.state = IMAPState::Authenticated;
+                IMAPResponse::tagged(tag, Ok, "UNSELECT completed")
+            }
+            Expunge if selected => match self.expunge(tag, true).await? {
+                Some(response) => response,
+                None => IMAPResponse::tagged(tag, Ok, "EXPUNGE completed"),
+            },
+            Search {
+                charset,
+                criteria,
+                uid,
+            } if selected => self.search(tag, charset.as_deref(), &criteria, uid).await?,
+            Fetch {
+                sequence_set,
+                items,
+                uid,
+            } if selected => self.fetch(tag, &sequence_set, items, uid).await?,
+            Store {
+                sequence_set,
+                action,
+                silent,
+                flags,
+                uid,
+            } if selected => {
+                self.store(tag, &sequence_set, action, silent, &flags, uid)
+                    .await?
+            }
+            _ => IMAPResponse::tagged(tag, Bad, "command not valid in this state"),
+        };
+
+        Result::Ok(response)
+    }
+
+    /// The capabilities of the server (RFC 3501 section 7.2.1). The SASL
+    /// mechanisms are only listed before the client logs in.
+    fn capabilities(&self) -> Vec<String> {
+        let mut capabilities = vec![
+            "IMAP4rev1".to_owned(),
+            "CHILDREN".to_owned(),
+            "SASL-IR".to_owned(),
+            "SPECIAL-USE".to_owned(),
+            "UNSELECT".to_owned(),
+        ];
+
+        if self.user.is_none() {
+            for mechanism in sasl::MECHANISMS {
+                capabilities.push(format!("AUTH={}", mechanism));
+            }
+        }
+
+        capabilities
+    }
+
+    /// The address of the user that is logged in
+    fn owner(&self) -> EmailAddress {
+        EmailAddress::new_unchecked(&self.user.as_ref().unwrap().email_address)
+    }
+
+    /// `AUTHENTICATE`; Carry out a SASL exchange and log in with the
+    /// credentials that the client gives
+    async fn authenticate(
+        &mut self,
+        tag: &str,
+        mechanism: &str,
+        initial_response: Option<&str>,
+    ) -> Result<IMAPResponse, Box<dyn Error>> {
+        let encrypted = self.tls.is_active();
+        let login = match sasl::authenticate(self, mechanism, initial_response, encrypted).await {
+            Err(SaslError::UnsupportedMechanism) => {
+                return Result::Ok(IMAPResponse::tagged(
+                    tag,
+                    No,
+                    "unsupported authentication mechanism",
+                ))
+            }
+            Err(SaslError::Cancelled) => {
+                return Result::Ok(IMAPResponse::tagged(tag, Bad, "authentication cancelled"))
+            }
+            Err(SaslError::MalformedResponse) => {
+                return Result::Ok(IMAPResponse::tagged(
+                    tag,
+                    Bad,
+                    "invalid authentication response",
+                ))
+            }
+            login => login,
+        };
+
+        self.finish_login(tag, login).await
+    }
+
+    /// Check whether `account` belongs to a domain that only allows logins
+    /// over TLS, and the connection isn't encrypted
+    fn tls_required(&self, account: &EmailAddress) -> bool {
+        !self.tls.is_active() && requires_tls(account.domain())
+    }
+
+    /// Move to the authenticated state if a login worked. Otherwise, tell
+    /// the client why it didn't. The connection is closed after too many
+    /// failures.
+    async fn finish_login(
+        &mut self,
+        tag: &str,
+        login: Result<user::Model, SaslError>,
+    ) -> Result<IMAPResponse, Box<dyn Error>> {
+        let response = match login {
+            Result::Ok(user) => {
+                trace!("IMAP connection authenticated");
+                self.user = Some(user);
+                self.state = IMAPState::Authenticated;
+
+                // The capabilities change after logging in (RFC 3501
+                // section 6.2.3)
+                return Result::Ok(
+                    IMAPResponse::tagged(tag, Ok, "logged in")
+                        .with_code(IMAPResponseCode::Capability(self.capabilities())),
+                );
+            }
+            Err(SaslError::Io(e)) => return Err(e.into()),
+            Err(SaslError::Database(e)) => return Err(e.into()),
+            // Refused before the credentials were checked, so this isn't a
+            // failed login
+            Err(SaslError::EncryptionRequired) => {
+                return Result::Ok(
+                    IMAPResponse::tagged(tag, No, "TLS is required to log in to this account")
+                        .with_code(IMAPResponseCode::PrivacyRequired),
+                )
+            }
+            Err(SaslError::LockedOut) => {
+                IMAPResponse::tagged(tag, No, "too many failed logins, try again later")
+                    .with_code(IMAPResponseCode::Unavailable)
+            }
+            Err(_) => IMAPResponse::tagged(tag, No, "authentication failed")
+                .with_code(IMAPResponseCode::AuthenticationFailed),
+        };
+
+        if self.logins.exhausted() {
+            self.send_response(IMAPResponse::untagged(
+                Bye,
+                "too many failed logins, closing connection",
+            ))
+            .await?;
+            self.state = IMAPState::Logout;
+        }
+
+        Result::Ok(response)
+    }
+
+    /// `SELECT` and `EXAMINE`; Open a mailbox and tell the client about it
+    async fn select(
+        &mut self,
+        tag: &str,
+        name: &str,
+        read_only: bool,
+    ) -> Result<IMAPResponse, io::Error> {
+        // A failed SELECT closes the mailbox that was selected before
+        self.state = IMAPState::Authenticated;
+
+        let mailbox = match mailbox_database::find_mailbox(&self.owner(), name).await {
+            Result::Ok(Some(mailbox)) => mailbox,
+            Result::Ok(None) => return Result::Ok(nonexistent(tag)),
+            Err(e) => return Result::Ok(database_error(tag, e)),
+        };
+        let mailbox = match SelectedMailbox::open(mailbox, read_only).await {
+            Result::Ok(mailbox) => mailbox,
+            Err(e) => return Result::Ok(database_error(tag, e)),
+        };
+
+        trace!("IMAP connection selected {}", mailbox.mailbox.name);
+
+        let mut flags = Flag::SYSTEM.to_vec();
+        flags.extend(mailbox.keywords());
+
+        let mut responses = vec![
+            IMAPResponse::data(format!("FLAGS {}", flag_list(&flags))),
+            IMAPResponse::data(format!("{} EXISTS", mailbox.messages.len())),
+            IMAPResponse::data("0 RECENT"),
+            IMAPResponse::untagged(Ok, "flags are permanent")
+                .with_code(IMAPResponseCode::PermanentFlags(Flag::SYSTEM.to_vec())),
+            IMAPResponse::untagged(Ok, "UIDs valid").with_code(IMAPResponseCode::UidValidity(
+                mailbox.mailbox.uid_validity as u32,
+            )),
+            IMAPResponse::untagged(Ok, "predicted next UID")
+                .with_code(IMAPResponseCode::UidNext(mailbox.uid_next())),
+        ];
+        if let Some(unseen) = mailbox.first_unseen() {
+            responses.push(
+                IMAPResponse::untagged(Ok, "first unseen message")
+                    .with_code(IMAPResponseCode::Unseen(unseen)),
+            );
+        }
+        for response in responses {
+            self.send_response(response).await?;
+        }
+
+        let response = if read_only {
+            IMAPResponse::tagged(tag, Ok, "EXAMINE completed").with_code(IMAPResponseCode::ReadOnly)
+        } else {
+            IMAPResponse::tagged(tag, Ok, "SELECT completed").with_code(IMAPResponseCode::ReadWrite)
+        };
+        self.state = IMAPState::Selected(mailbox);
+
+        Result::Ok(response)
+    }
+
+    /// `LIST` and `LSUB`; List the mailboxes whose names match a pattern
+    async fn list(
+        &mut self,
+        tag: &str,
+        command: &str,
+        reference: &str,
+        pattern: &str,
+    ) -> Result<IMAPResponse, io::Error> {
+        // An empty pattern asks for the hierarchy delimiter
+        if pattern.is_empty() {
+            self.send_response(IMAPResponse::data(format!(
+                "{} (\\Noselect) {} \"\"",
+                command,
+                string(&DELIMITER.to_string())
+            )))
+            .await?;
+            return Result::Ok(IMAPResponse::tagged(
+                tag,
+                Ok,
+                format!("{} completed", command),
+            ));
+        }
+
+        let mailboxes = match mailbox_database::get_mailboxes(&self.owner()).await {
+            Result::Ok(mailboxes) => mailboxes,
+            Err(e) => return Result::Ok(database_error(tag, e)),
+        };
+
+        let pattern = mailbox_database::normalize_name(&format!("{}{}", reference, pattern));
+
+        for entry in &mailboxes {
+            if !mailbox::matches_pattern(&pattern, &entry.name, DELIMITER) {
+                continue;
+            }
+
+            // The CHILDREN (RFC 3348) and SPECIAL-USE (RFC 6154) attributes
+            let mut attributes = vec![];
+            if mailboxes.iter().any(|m| m.parent == Some(entry.id)) {
+                attributes.push("\\HasChildren");
+            } else {
+                attributes.push("\\HasNoChildren");
+            }
+            if let Some(special_use) = special_use(&entry.name) {
+                attributes.push(special_use);
+            }
+
+            self.send_response(IMAPResponse::data(format!(
+                "{} ({}) {} {}",
+                command,
+                attributes.join(" "),
+                string(&DELIMITER.to_string()),
+                string(&entry.name)
+            )))
+            .await?;
+        }
+
+        Result::Ok(IMAPResponse::tagged(
+            tag,
+            Ok,
+            format!("{} completed", command),
+        ))
+    }
+
+    /// `CREATE`; Make a new mailbox
+    async fn create(&mut self, tag: &str, name: &str) -> Result<IMAPResponse, io::Error> {
+        // A trailing delimiter only says that the client means to put
+        // other mailboxes inside this one (RFC 3501 section 6.3.3)
+        let name = name.strip_suffix(DELIMITER).unwrap_or(name);
+        if !mailbox_database::is_valid_name(name) {
+            return Result::Ok(
+                IMAPResponse::tagged(tag, No, "invalid mailbox name")
+                    .with_code(IMAPResponseCode::Cannot),
+            );
+        }
+
+        match mailbox_database::create_mailbox(&self.owner(), name).await {
+            Result::Ok(Some(_)) => Result::Ok(IMAPResponse::tagged(tag, Ok, "CREATE completed")),
+            Result::Ok(None) => Result::Ok(
+                IMAPResponse::tagged(tag, No, "mailbox already exists")
+                    .with_code(IMAPResponseCode::AlreadyExists),
+            ),
+            Err(e) => Result::Ok(database_error(tag, e)),
+        }
+    }
+
+    /// `DELETE`; Remove a mailbox and the messages in it. INBOX and
+    /// mailboxes that have other mailboxes inside of them can't be removed.
+    async fn delete(&mut self, tag: &str, name: &str) -> Result<IMAPResponse, io::Error> {
+        let mailboxes = match mailbox_database::get_mailboxes(&self.owner()).await {
+            Result::Ok(mailboxes) => mailboxes,
+            Err(e) => return Result::Ok(database_error(tag, e)),
+        };
+
+        let name = mailbox_database::normalize_name(name);
+        let mailbox = match mailboxes.iter().find(|m| m.name == name) {
+            Some(mailbox) => mailbox,
+            None => return Result::Ok(nonexistent(tag)),
+        };
+
+        if mailbox.name == INBOX {
+            return Result::Ok(
+                IMAPResponse::tagged(tag, No, "INBOX can't be deleted")
+                    .with_code(IMAPResponseCode::Cannot),
+            );
+        }
+        if mailboxes.iter().any(|m| m.parent == Some(mailbox.id)) {
+            return Result::Ok(
+                IMAPResponse::tagged(tag, No, "mailbox has other mailboxes inside of it")
+                    .with_code(IMAPResponseCode::Cannot),
+            );
+        }
+
+        if let Err(e) = mailbox_database::delete_mailbox(mailbox).await {
+            return Result::Ok(database_error(tag, e));
+        }
+
+        Result::Ok(IMAPResponse::tagged(tag, Ok, "DELETE completed"))
+    }
+
+    /// `STATUS`; Tell the client about a mailbox without selecting it
+    async fn status(
+        &mut self,
+        tag: &str,
+        name: &str,
+        items: &[StatusItem],
+    ) -> Result<IMAPResponse, io::Error> {
+        let mailbox = match mailbox_database::find_mailbox(&self.owner(), name).await {
+            Result::Ok(Some(mailbox)) => mailbox,
+            Result::Ok(None) => return Result::Ok(nonexistent(tag)),
+            Err(e) => return Result::Ok(database_error(tag, e)),
+        };
+        let messages = match mailbox::load_messages(&mailbox).await {
+            Result::Ok(messages) => messages,
+            Err(e) => return Result::Ok(database_error(tag, e)),
+        };
+
+        let values: Vec<String> = items
+            .iter()
+            .map(|item| {
+                let value = match item {
+                    StatusItem::Messages => messages.len() as u32,
+                    StatusItem::Recent => 0,
+                    StatusItem::UidNext => mailbox.uid_next as u32,
+                    StatusItem::UidValidity => mailbox.uid_validity as u32,
+                    StatusItem::Unseen => messages
+                        .iter()
+                        .filter(|m| !m.flags.contains(&Flag::Seen))
+                        .count() as u32,
+                };
+                format!("{} {}", item, value)
+            })
+            .collect();
+
+        self.send_response(IMAPResponse::data(format!(
+            "STATUS {} ({})",
+            string(&mailbox.name),
+            values.join(" ")
+        )))
+        .await?;
+
+        Result::Ok(IMAPResponse::tagged(tag, Ok, "STATUS completed"))
+    }
+
+    /// Tell the client about messages that were added to or removed from
+    /// the selected mailbox by other sessions
+    async fn refresh(&mut self) -> Result<(), io::Error> {
+        let owner = self.owner();
+        let mailbox = match &mut self.state {
+            IMAPState::Selected(mailbox) => mailbox,
+            _ => return Result::Ok(()),
+        };
+
+        let latest = match mailbox_database::find_mailbox(&owner, &mailbox.mailbox.name).await {
+            // The mailbox hasn't been deleted by another session
+            Result::Ok(Some(latest)) if latest.id == mailbox.mailbox.id => latest,
+            Result::Ok(_) => return Result::Ok(()),
+            Err(e) => {
+                warn!("Couldn't refresh mailbox for {}: {}", owner, e);
+                return Result::Ok(());
+            }
+        };
+        let messages = match mailbox::load_messages(&latest).await {
+            Result::Ok(messages) => messages,
+            Err(e) => {
+                warn!("Couldn't refresh mailbox for {}: {}", owner, e);
+                return Result::Ok(());
+            }
+        };
+
+        // Go backwards, so that the sequence numbers of the messages that
+        // haven't been checked yet don't change
+        for index in (0..mailbox.messages.len()).rev() {
+            let uid = mailbox.messages[index].uid;
+            if !messages.iter().any(|m| m.uid == uid) {
+                mailbox.messages.remove(index);
+                write_response(
+                    &mut self.stream,
+                    IMAPResponse::data(format!("{} EXPUNGE", index + 1)),
+                )
+                .await?;
+            }
+        }
+
+        // Flags that were changed by other sessions
+        for (index, message) in mailbox.messages.iter_mut().enumerate() {
+            let latest = match messages.iter().find(|m| m.uid == message.uid) {
+                Some(latest) => latest,
+                None => continue,
+            };
+            if latest.flags != message.flags {
+                message.flags = latest.flags.clone();
+                write_response(
+                    &mut self.stream,
+                    IMAPResponse::data(format!(
+                        "{} FETCH (FLAGS {})",
+                        index + 1,
+                        flag_list(&message.flags)
+                    )),
+                )
+                .await?;
+            }
+        }
+
+        let new_messages: Vec<mailbox::Message> = messages
+            .into_iter()
+            .filter(|m| m.uid >= mailbox.uid_next())
+            .collect();
+        mailbox.mailbox = latest;
+        if !new_messages.is_empty() {
+            mailbox.messages.extend(new_messages);
+            write_response(
+                &mut self.stream,
+                IMAPResponse::data(format!("{} EXISTS", mailbox.messages.len())),
+            )
+            .await?;
+        }
+
+        Result::Ok(())
+    }
+
+    /// `EXPUNGE` and `CLOSE`; Remove the messages with the `\Deleted` flag.
+    /// If `send_updates` is true, the client is told the sequence number of
+    /// each message that was removed.
+    ///
+    /// Returns a response if the messages couldn't be removed.
+    async fn expunge(
+        &mut self,
+        tag: &str,
+        send_updates: bool,
+    ) -> Result<Option<IMAPResponse>, io::Error> {
+        let mailbox = match &mut self.state {
+            IMAPState::Selected(mailbox) => mailbox,
+            _ => return Result::Ok(None),
+        };
+
+        if mailbox.read_only {
+            // CLOSE doesn't remove anything from a read-only mailbox
+            return Result::Ok(
+                send_updates.then(|| IMAPResponse::tagged(tag, No, "mailbox is read-only")),
+            );
+        }
+
+        // Other sessions may have changed the flags. Messages that this
+        // session doesn't know about yet are left alone.
+        let flagged =
+            match flag_database::messages_with_flag(mailbox.mailbox.id, flag_database::DELETED)
+                .await
+            {
+                Result::Ok(flagged) => flagged,
+                Err(e) => return Result::Ok(Some(database_error(tag, e))),
+            };
+        let deleted: Vec<usize> = (0..mailbox.messages.len())
+            .filter(|i| {
+                let message_id = &mailbox.messages[*i].mail.message_id;
+                flagged.iter().any(|m| &m.message_id == message_id)
+            })
+            .collect();
+        let ids: Vec<String> = deleted
+            .iter()
+            .map(|i| mailbox.messages[*i].mail.message_id.clone())
+            .collect();
+
+        if let Err(e) = mail_database::delete_messages(&ids).await {
+            return Result::Ok(Some(database_error(tag, e)));
+        }
+
+        for index in deleted.into_iter().rev() {
+            mailbox.messages.remove(index);
+            if send_updates {
+                write_response(
+                    &mut self.stream,
+                    IMAPResponse::data(format!("{} EXPUNGE", index + 1)),
+                )
+                .await?;
+            }
+        }
+
+        Result::Ok(None)
+    }
+
+    /// `SEARCH`; Find the messages that match the criteria
+    async fn search(
+        &mut self,
+        tag: &str,
+        charset: Option<&str>,
+        criteria: &[SearchKey],
+        uid: bool,
+    ) -> Result<IMAPResponse, io::Error> {
+        if let Some(charset) = charset {
+            if !charset.eq_ignore_ascii_case("UTF-8") && !charset.eq_ignore_ascii_case("US-ASCII") {
+                return Result::Ok(
+                    IMAPResponse::tagged(tag, No, "unsupported charset")
+                        .with_code(IMAPResponseCode::BadCharset),
+                );
+            }
+        }
+
+        let mailbox = match &self.state {
+            IMAPState::Selected(mailbox) => mailbox,
+            _ => return Result::Ok(IMAPResponse::tagged(tag, Bad, "no mailbox selected")),
+        };
+
+        let mut data = "SEARCH".to_owned();
+        for index in mailbox.search(criteria) {
+            let number = if uid {
+                mailbox.messages[index].uid as usize
+            } else {
+                index + 1
+            };
+            data += &format!(" {}", number);
+        }
+        self.send_response(IMAPResponse::data(data)).await?;
+
+        Result::Ok(IMAPResponse::tagged(tag, Ok, "SEARCH completed"))
+    }
+
+    /// `FETCH`; Send data about messages. Fetching the body of a message
+    /// sets its `\Seen` flag, unless the mailbox is read-only.
+    async fn fetch(
+        &mut self,
+        tag: &str,
+        sequence_set: &SequenceSet,
+        mut items: Vec<FetchItem>,
+        uid: bool,
+    ) -> Result<IMAPResponse, io::Error> {
+        let mailbox = match &mut self.state {
+            IMAPState::Selected(mailbox) => mailbox,
+            _ => return Result::Ok(IMAPResponse::tagged(tag, Bad, "no mailbox selected")),
+        };
+
+        // UID FETCH always sends the UIDs (RFC 3501 section 6.4.8)
+        if uid && !items.contains(&FetchItem::Uid) {
+            items.insert(0, FetchItem::Uid);
+        }
+        let sets_seen = !mailbox.read_only
+            && items.iter().any(|item| {
+                matches!(
+                    item,
+                    FetchItem::Rfc822
+                        | FetchItem::Rfc822Text
+                        | FetchItem::BodySection { peek: false, .. }
+                )
+            });
+
+        let indices = mailbox.resolve(sequence_set, uid);
+
+        let unseen: Vec<String> = indices
+            .iter()
+            .map(|i| &mailbox.messages[*i])
+            .filter(|m| sets_seen && !m.flags.contains(&Flag::Seen))
+            .map(|m| m.mail.message_id.clone())
+            .collect();
+        let mut new_flags =
+            match flag_database::add_flags(&unseen, &[flag_database::SEEN.to_owned()]).await {
+                Result::Ok(flags) => flags,
+                Err(e) => return Result::Ok(database_error(tag, e)),
+            };
+
+        for index in indices {
+            let message = &mut mailbox.messages[index];
+
+            let flags_changed = match new_flags.remove(&message.mail.message_id) {
+                Some(flags) => {
+                    message.flags = mailbox::to_flags(flags);
+                    true
+                }
+                None => false,
+            };
+
+            let data = message.fetch(index + 1, &items, flags_changed);
+            write_response(&mut self.stream, IMAPResponse::data(data)).await?;
+        }
+
+        Result::Ok(IMAPResponse::tagged(tag, Ok, "FETCH completed"))
+    }
+
+    /// `STORE`; Change the flags of messages
+    async fn store(
+        &mut self,
+        tag: &str,
+        sequence_set: &SequenceSet,
+        action: StoreAction,
+        silent: bool,
+        flags: &[Flag],
+        uid: bool,
+    ) -> Result<IMAPResponse, io::Error> {
+        let mailbox = match &mut self.state {
+            IMAPState::Selected(mailbox) => mailbox,
+            _ => return Result::Ok(IMAPResponse::tagged(tag, Bad, "no mailbox selected")),
+        };
+
+        if mailbox.read_only {
+            return Result::Ok(IMAPResponse::tagged(tag, No, "mailbox is read-only"));
+        }
+
+        let indices = mailbox.resolve(sequence_set, uid);
+
+        let ids: Vec<String> = indices
+            .iter()
+            .map(|i| mailbox.messages[*i].mail.message_id.clone())
+            .collect();
+        let change = match action {
+            StoreAction::Replace => FlagChange::Replace,
+            StoreAction::Add => FlagChange::Add,
+            StoreAction::Remove => FlagChange::Remove,
+        };
+        let flags: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+        let mut new_flags = match flag_database::change_flags(&ids, change, &flags).await {
+            Result::Ok(flags) => flags,
+            Err(e) => return Result::Ok(database_error(tag, e)),
+        };
+
+        for index in indices {
+            let message = &mut mailbox.messages[index];
+            if let Some(flags) = new_flags.remove(&message.mail.message_id) {
+                message.flags = mailbox::to_flags(flags);
+            }
+
+            if !silent {
+                // UID STORE always sends the UIDs (RFC 3501 section 6.4.8)
+                let uid = match uid {
+                    true => format!("UID {} ", message.uid),
+                    false => String::new(),
+                };
+                write_response(
+                    &mut self.stream,
+                    IMAPResponse::data(format!(
+                        "{} FETCH ({}FLAGS {})",
+                        index + 1,
+                        uid,
+                        flag_list(&message.flags)
+                    )),
+                )
+                .await?;
+            }
+        }
+
+        Result::Ok(IMAPResponse::tagged(tag, Ok, "STORE completed"))
+    }
+
+    /// Close the connection
+    pub async fn close(&mut self) -> Result<(), io::Error> {
+        self.stream.shutdown().await?;
+        Result::Ok(())
+    }
+}
+
+/// Write a response to the stream. This is a function instead of a method
+/// so that the stream can be borrowed while the selected mailbox is.
+async fn write_response<S: AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    response: IMAPResponse,
+) -> Result<(), io::Error> {
+    let bytes = &Bytes::from(response)[..];
+    stream.write_all(bytes).await?;
+
+    Result::Ok(())
+}
+
+/// The response to a command on a mailbox that doesn't exist
+fn nonexistent(tag: &str) -> IMAPResponse {
+    IMAPResponse::tagged(tag, No, "no such mailbox").with_code(IMAPResponseCode::Nonexistent)
+}
+
+/// The response to a command that failed because the database couldn't
+/// be used
+fn database_error(tag: &str, error: DbErr) -> IMAPResponse {
+    warn!("IMAP command failed: {}", error);
+    IMAPResponse::tagged(tag, No, "mailbox unavailable, try again later")
+        .with_code(IMAPResponseCode::Unavailable)
+}
+
+/// The special-use attribute (RFC 6154) of one of the default mailboxes
+fn special_use(name: &str) -> Option<&'static str> {
+    match name {
+        "Sent" => Some("\\Sent"),
+        "Drafts" => Some("\\Drafts"),
+        "Trash" => Some("\\Trash"),
+        "Junk" => Some("\\Junk"),
+        _ => None,
+    }
+}
+
+/// If a line ends with the start of a literal, like `{42}` or `{42+}`,
+/// get the length of the literal and whether the client is waiting for a
+/// continuation request before sending it
+fn literal_length(line: &[u8]) -> Option<(usize, bool)> {
+    let line = line.strip_suffix(b"}\r\n")?;
+    let start = line.iter().rposition(|c| *c == b'{')?;
+    let length = &line[start + 1..];
+
+    let (length, synchronizing) = match length.strip_suffix(b"+") {
+        Some(length) => (length, false),
+        None => (length, true),
+    };
+    let length = std::str::from_utf8(length).ok()?.parse().ok()?;
+
+    Some((length, synchronizing))
+}
+
+#[test]
+fn literal_lengths() {
+    assert_eq!(literal_length(b"a001 LOGIN {16}\r\n"), Some((16, true)));
+    assert_eq!(literal_length(b"fred {4+}\r\n"), Some((4, false)));
+    assert_eq!(literal_length(b"a001 LOGIN fred blurdybloop\r\n"), None);
+    assert_eq!(literal_length(b"a001 LOGIN \"{4}\"\r\n"), None);
+    assert_eq!(literal_length(b"a001 LOGIN {x}\r\n"), None);
+}
+
+#[test]
+fn huge_literal_is_refused() {
+    use crate::testing::{run, session};
+
+    run(session::<IMAPConnection, _, _>(
+        TlsState::Unavailable,
+        |mut client| async move {
+            client.read_line().await;
+            assert_eq!(
+                client.command("a1 LOGIN {18446744073709551615}").await,
+                "a1 BAD command too long"
+            );
+            // The client didn't get a continuation request, so it doesn't
+            // send the literal and can carry on
+            client.send("a2 NOOP").await;
+            assert_eq!(client.read_line().await, "a2 OK NOOP completed");
+        },
+    ));
+}
+
+#[test]
+fn login_requires_tls_for_some_domains() {
+    use crate::testing::{run, session};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let privacy_required = |tag: &str| {
+        format!(
+            "{} NO [PRIVACYREQUIRED] TLS is required to log in to this account",
+            tag
+        )
+    };
+    let authenticate = format!(
+        "a2 AUTHENTICATE PLAIN {}",
+        STANDARD.encode("\0alice@secure.example\0password")
+    );
+
+    run(async {
+        session::<IMAPConnection, _, _>(TlsState::Unavailable, |mut client| async move {
+            client.read_line().await;
+            assert_eq!(
+                client
+                    .command("a1 LOGIN alice@secure.example password")
+                    .await,
+                privacy_required("a1")
+            );
+            assert_eq!(client.command(&authenticate).await, privacy_required("a2"));
+        })
+        .await;
+
+        session::<IMAPConnection, _, _>(TlsState::Active, |mut client| async move {
+            client.read_line().await;
+            assert!(client
+                .command("a1 LOGIN alice@secure.example password")
+                .await
+                .ends_with("] logged in"));
+        })
+        .await;
+    });
+}
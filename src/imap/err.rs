@@ -0,0 +1,33 @@
+use std::error::Error;
+use std::fmt;
+
+/// Reasons that a command line from the client couldn't be parsed. The
+/// tag is kept when it could be read, so that the `BAD` response can
+/// carry it.
+#[derive(PartialEq, Debug)]
+pub enum IMAPCommandParseError {
+    /// The line doesn't start with a valid tag
+    InvalidTag,
+    UnknownCommand {
+        tag: String,
+    },
+    InvalidArguments {
+        tag: String,
+    },
+}
+
+impl Error for IMAPCommandParseError {}
+
+impl fmt::Display for IMAPCommandParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use IMAPCommandParseError::*;
+
+        let err_message = match self {
+            InvalidTag => "IMAP command tag is missing or invalid",
+            UnknownCommand { .. } => "IMAP command is unknown or unsupported",
+            InvalidArguments { .. } => "IMAP command arguments are invalid",
+        };
+
+        write!(f, "{}", err_message)
+    }
+}
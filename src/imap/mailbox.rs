@@ -0,0 +1,376 @@
+//! The mailbox that an IMAP session has selected, and the messages in it
+
+use bytes::{BufMut, BytesMut};
+use chrono::{DateTime, Utc};
+use sea_orm::DbErr;
+
+use super::message::{crlf, Part};
+use super::response::flag_list;
+use super::*;
+use crate::database::{flag_database, mail, mail_database, mailbox};
+
+/// A message in a mailbox, along with its IMAP state
+#[derive(Debug)]
+pub struct Message {
+    pub mail: mail::Model,
+    pub uid: u32,
+    pub flags: Vec<Flag>,
+}
+
+/// Get every message in a mailbox, ordered by UID
+pub async fn load_messages(mailbox: &mailbox::Model) -> Result<Vec<Message>, DbErr> {
+    let mail = mail_database::get_messages(mailbox.id).await?;
+    let mut flags = flag_database::get_mailbox_flags(mailbox.id).await?;
+
+    Ok(mail
+        .into_iter()
+        .map(|mail| Message {
+            uid: mail.imap_uid as u32,
+            flags: to_flags(flags.remove(&mail.message_id).unwrap_or_default()),
+            mail,
+        })
+        .collect())
+}
+
+/// Convert flags from the way that they are stored
+pub fn to_flags(names: Vec<String>) -> Vec<Flag> {
+    names.iter().map(|name| Flag::from(name.as_str())).collect()
+}
+
+/// The mailbox that is open in the selected state
+#[derive(Debug)]
+pub struct SelectedMailbox {
+    pub mailbox: mailbox::Model,
+    /// True if the mailbox was opened with `EXAMINE`
+    pub read_only: bool,
+    /// Message sequence number `n` is `messages[n - 1]`
+    pub messages: Vec<Message>,
+}
+
+impl SelectedMailbox {
+    /// Open a mailbox and load its messages
+    pub async fn open(mailbox: mailbox::Model, read_only: bool) -> Result<Self, DbErr> {
+        let messages = load_messages(&mailbox).await?;
+
+        Ok(Self {
+            mailbox,
+            read_only,
+            messages,
+        })
+    }
+
+    /// The UID that the next new message will get
+    pub fn uid_next(&self) -> u32 {
+        self.mailbox.uid_next as u32
+    }
+
+    /// The sequence number of the first message without the `\Seen` flag
+    pub fn first_unseen(&self) -> Option<usize> {
+        self.messages
+            .iter()
+            .position(|m| !m.flags.contains(&Flag::Seen))
+            .map(|i| i + 1)
+    }
+
+    /// The keywords that are set on any message, for the `FLAGS` response
+    pub fn keywords(&self) -> Vec<Flag> {
+        let mut keywords: Vec<Flag> = vec![];
+        for flag in self.messages.iter().flat_map(|m| &m.flags) {
+            if matches!(flag, Flag::Keyword(_)) && !keywords.contains(flag) {
+                keywords.push(flag.clone());
+            }
+        }
+        keywords
+    }
+
+    /// Get the indices in `messages` of the messages in a sequence set, in
+    /// order. `uid` is true if the set contains UIDs instead of sequence
+    /// numbers.
+    pub fn resolve(&self, set: &SequenceSet, uid: bool) -> Vec<usize> {
+        (0..self.messages.len())
+            .filter(|i| set.iter().any(|range| self.in_range(range, uid, *i)))
+            .collect()
+    }
+
+    /// Check whether a message is in a range of sequence numbers or UIDs
+    fn in_range(&self, range: &SequenceRange, uid: bool, index: usize) -> bool {
+        let (number, largest) = if uid {
+            (
+                self.messages[index].uid,
+                self.messages.last().map(|m| m.uid).unwrap_or_default(),
+            )
+        } else {
+            (index as u32 + 1, self.messages.len() as u32)
+        };
+
+        let value = |n: SequenceNumber| match n {
+            SequenceNumber::Value(n) => n,
+            SequenceNumber::Largest => largest,
+        };
+        let (start, end) = (value(range.start), value(range.end));
+
+        (start.min(end)..=start.max(end)).contains(&number)
+    }
+
+    /// Get the indices in `messages` of the messages that match all of the
+    /// `criteria`
+    pub fn search(&self, criteria: &[SearchKey]) -> Vec<usize> {
+        (0..self.messages.len())
+            .filter(|i| {
+                let content = crlf(&self.messages[*i].mail.content);
+                let message = Part::parse(&content);
+                criteria.iter().all(|key| self.matches(*i, &message, key))
+            })
+            .collect()
+    }
+
+    /// Check whether a message matches a search key. String comparisons
+    /// are case insensitive.
+    fn matches(&self, index: usize, message: &Part, key: &SearchKey) -> bool {
+        use SearchKey::*;
+
+        let flags = &self.messages[index].flags;
+        let has_flag = |flag: Flag| flags.contains(&flag);
+        let header_contains = |name: &str, s: &str| {
+            message
+                .header_value(name)
+                .is_some_and(|value| contains_ignore_case(&value, s))
+        };
+        let sent_date = || {
+            message
+                .header_value("Date")
+                .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+                .map(|date| date.date_naive())
+        };
+        let internal_date = self.messages[index].internal_date().date_naive();
+        let size = message.raw.len() as u32;
+
+        match key {
+            All => true,
+            Answered => has_flag(Flag::Answered),
+            Deleted => has_flag(Flag::Deleted),
+            Draft => has_flag(Flag::Draft),
+            Flagged => has_flag(Flag::Flagged),
+            Seen => has_flag(Flag::Seen),
+            Unanswered => !has_flag(Flag::Answered),
+            Undeleted => !has_flag(Flag::Deleted),
+            Undraft => !has_flag(Flag::Draft),
+            Unflagged => !has_flag(Flag::Flagged),
+            Unseen => !has_flag(Flag::Seen),
+            // No message is ever \Recent, because the flag would have to be
+            // shared between sessions
+            New | Recent => false,
+            Old => true,
+            Keyword(keyword) => has_flag(Flag::Keyword(keyword.clone())),
+            Unkeyword(keyword) => !has_flag(Flag::Keyword(keyword.clone())),
+            Bcc(s) => header_contains("Bcc", s),
+            Cc(s) => header_contains("Cc", s),
+            From(s) => header_contains("From", s),
+            Subject(s) => header_contains("Subject", s),
+            To(s) => header_contains("To", s),
+            Header(name, s) => header_contains(name, s),
+            Body(s) => contains_ignore_case(message.body, s),
+            Text(s) => contains_ignore_case(message.raw, s),
+            Before(date) => internal_date < *date,
+            On(date) => internal_date == *date,
+            Since(date) => internal_date >= *date,
+            SentBefore(date) => sent_date().is_some_and(|d| d < *date),
+            SentOn(date) => sent_date().is_some_and(|d| d == *date),
+            SentSince(date) => sent_date().is_some_and(|d| d >= *date),
+            Larger(n) => size > *n,
+            Smaller(n) => size < *n,
+            Uid(set) => set.iter().any(|range| self.in_range(range, true, index)),
+            SequenceSet(set) => set.iter().any(|range| self.in_range(range, false, index)),
+            Not(key) => !self.matches(index, message, key),
+            Or(a, b) => self.matches(index, message, a) || self.matches(index, message, b),
+            And(keys) => keys.iter().all(|key| self.matches(index, message, key)),
+        }
+    }
+}
+
+impl Message {
+    /// The time that the message was stored. Stored messages have IDs that
+    /// start with the time in microseconds.
+    pub fn internal_date(&self) -> DateTime<Utc> {
+        self.mail
+            .uid
+            .split('.')
+            .next()
+            .and_then(|micros| micros.parse().ok())
+            .and_then(DateTime::from_timestamp_micros)
+            .unwrap_or_default()
+    }
+
+    /// Format the data for a `FETCH` response, without the leading `* `.
+    /// The flags are added if `add_flags` is true and they aren't one of
+    /// the `items`.
+    pub fn fetch(&self, sequence_number: usize, items: &[FetchItem], add_flags: bool) -> BytesMut {
+        use FetchItem::*;
+
+        let content = crlf(&self.mail.content);
+        let message = Part::parse(&content);
+
+        let mut out = BytesMut::new();
+        out.put_slice(format!("{} FETCH (", sequence_number).as_bytes());
+
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                out.put_u8(b' ');
+            }
+
+            match item {
+                Envelope => out.put_slice(format!("ENVELOPE {}", message.envelope()).as_bytes()),
+                Flags => out.put_slice(format!("FLAGS {}", flag_list(&self.flags)).as_bytes()),
+                InternalDate => out.put_slice(
+                    format!(
+                        "INTERNALDATE \"{}\"",
+                        self.internal_date().format("%d-%b-%Y %H:%M:%S %z")
+                    )
+                    .as_bytes(),
+                ),
+                Rfc822 => put_literal(&mut out, "RFC822", content.as_bytes()),
+                Rfc822Header => put_literal(&mut out, "RFC822.HEADER", message.header.as_bytes()),
+                Rfc822Size => out.put_slice(format!("RFC822.SIZE {}", content.len()).as_bytes()),
+                Rfc822Text => put_literal(&mut out, "RFC822.TEXT", message.body.as_bytes()),
+                Body => out.put_slice(format!("BODY {}", message.body_structure(false)).as_bytes()),
+                BodyStructure => out.put_slice(
+                    format!("BODYSTRUCTURE {}", message.body_structure(true)).as_bytes(),
+                ),
+                Uid => out.put_slice(format!("UID {}", self.uid).as_bytes()),
+                BodySection {
+                    section, partial, ..
+                } => {
+                    let mut name = format!("BODY[{}]", section);
+                    match message.section(section) {
+                        Some(data) => {
+                            let mut data = data.as_bytes();
+                            if let Some((start, length)) = partial {
+                                name += &format!("<{}>", start);
+                                let start = (*start as usize).min(data.len());
+                                let end = start.saturating_add(*length as usize).min(data.len());
+                                data = &data[start..end];
+                            }
+                            put_literal(&mut out, &name, data);
+                        }
+                        None => out.put_slice(format!("{} NIL", name).as_bytes()),
+                    }
+                }
+            }
+        }
+
+        if add_flags && !items.contains(&Flags) {
+            out.put_slice(format!(" FLAGS {}", flag_list(&self.flags)).as_bytes());
+        }
+
+        out.put_u8(b')');
+        out
+    }
+}
+
+/// Add a fetch item whose value is sent as a literal
+fn put_literal(out: &mut BytesMut, name: &str, data: &[u8]) {
+    out.put_slice(format!("{} {{{}}}\r\n", name, data.len()).as_bytes());
+    out.put_slice(data);
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+/// Check whether a mailbox name matches a `LIST` pattern. `*` matches
+/// anything and `%` matches anything except the hierarchy delimiter.
+pub fn matches_pattern(pattern: &str, name: &str, delimiter: char) -> bool {
+    let mut pattern_chars = pattern.chars();
+    match pattern_chars.next() {
+        None => name.is_empty(),
+        Some(wildcard @ ('*' | '%')) => {
+            let rest = pattern_chars.as_str();
+            // Try every possible length for the part that the wildcard matches
+            let mut position = 0;
+            loop {
+                if matches_pattern(rest, &name[position..], delimiter) {
+                    return true;
+                }
+                match name[position..].chars().next() {
+                    Some(c) if wildcard == '*' || c != delimiter => position += c.len_utf8(),
+                    _ => return false,
+                }
+            }
+        }
+        Some(p) => {
+            let mut name_chars = name.chars();
+            name_chars.next() == Some(p)
+                && matches_pattern(pattern_chars.as_str(), name_chars.as_str(), delimiter)
+        }
+    }
+}
+
+#[test]
+fn list_patterns() {
+    assert!(matches_pattern("*", "INBOX", '/'));
+    assert!(matches_pattern("%", "INBOX", '/'));
+    assert!(matches_pattern("IN*", "INBOX", '/'));
+    assert!(matches_pattern("INBOX", "INBOX", '/'));
+    assert!(!matches_pattern("INBOX", "INBOX/Receipts", '/'));
+    assert!(matches_pattern("INBOX/%", "INBOX/Receipts", '/'));
+    assert!(!matches_pattern("%", "INBOX/Receipts", '/'));
+    assert!(matches_pattern("*s", "INBOX/Receipts", '/'));
+    assert!(!matches_pattern("", "INBOX", '/'));
+}
+
+#[test]
+fn sequence_sets() {
+    use SequenceNumber::*;
+
+    let message = |uid: u32| Message {
+        mail: mail::Model {
+            message_id: String::new(),
+            subject: String::new(),
+            date: String::new(),
+            from: String::new(),
+            recipients: String::new(),
+            mailbox_id: 1,
+            content: String::new(),
+            uid: String::new(),
+            imap_uid: uid as i64,
+            retrieved_at: None,
+            modseq: 1,
+        },
+        uid,
+        flags: vec![],
+    };
+    let mailbox = SelectedMailbox {
+        mailbox: mailbox::Model {
+            id: 1,
+            owner: String::new(),
+            name: "INBOX".to_owned(),
+            parent: None,
+            uid_validity: 1,
+            uid_next: 20,
+        },
+        read_only: false,
+        messages: vec![message(3), message(4), message(10), message(12)],
+    };
+    let range = |start, end| SequenceRange { start, end };
+
+    assert_eq!(
+        mailbox.resolve(&vec![range(Value(2), Value(2))], false),
+        [1]
+    );
+    assert_eq!(
+        mailbox.resolve(
+            &vec![range(Largest, Value(3)), range(Value(1), Value(1))],
+            false
+        ),
+        [0, 2, 3]
+    );
+    assert_eq!(
+        mailbox.resolve(&vec![range(Value(4), Value(11))], true),
+        [1, 2]
+    );
+    // A UID range that ends with "*" always includes the last message
+    assert_eq!(mailbox.resolve(&vec![range(Value(50), Largest)], true), [3]);
+    assert!(mailbox
+        .resolve(&vec![range(Value(7), Value(7))], false)
+        .is_empty());
+}
@@ -0,0 +1,611 @@
+//! The parts of a stored message that IMAP clients can fetch: header
+//! fields, MIME body parts, the envelope and the body structure.
+//!
+//! See RFC 3501 section 7.4.2 for the formats, and RFC 2045 and RFC 2046
+//! for MIME.
+
+use std::borrow::Cow;
+
+use super::response::{nstring, string};
+use super::{Section, SectionText};
+
+/// Multipart messages nested deeper than this are treated as a single
+/// part, so that a malicious message can't exhaust the stack
+const MAX_DEPTH: usize = 20;
+
+/// A message or a MIME body part, split into its header and body
+#[derive(Debug)]
+pub struct Part<'a> {
+    /// The whole part: the header followed by the body
+    pub raw: &'a str,
+    /// The header, including the empty line that ends it
+    pub header: &'a str,
+    pub body: &'a str,
+    pub content_type: ContentType,
+    pub kind: PartKind<'a>,
+}
+
+#[derive(Debug)]
+pub enum PartKind<'a> {
+    /// Any part that isn't multipart or an encapsulated message
+    Single,
+    /// The parts of a `multipart/*` part, in order
+    Multipart(Vec<Part<'a>>),
+    /// The message inside a `message/rfc822` part
+    Message(Box<Part<'a>>),
+}
+
+/// A parsed `Content-Type` header field. The type and subtype are in
+/// uppercase.
+#[derive(PartialEq, Debug)]
+pub struct ContentType {
+    pub media_type: String,
+    pub subtype: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl ContentType {
+    /// Parse the value of a `Content-Type` field. `default` is the type
+    /// and subtype to use if the field is missing or invalid.
+    fn parse(value: Option<&str>, default: (&str, &str)) -> Self {
+        let value = value.unwrap_or_default();
+        let mut fields = split_outside_quotes(value, ';').into_iter();
+
+        let (media_type, subtype) = fields
+            .next()
+            .and_then(|t| t.split_once('/'))
+            .unwrap_or(default);
+
+        Self {
+            media_type: media_type.trim().to_ascii_uppercase(),
+            subtype: subtype.trim().to_ascii_uppercase(),
+            parameters: parameters(fields),
+        }
+    }
+
+    /// Get a parameter by its name (case insensitive)
+    fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl<'a> Part<'a> {
+    /// Parse a message. The message must use CRLF line endings (see
+    /// [`crlf`]).
+    pub fn parse(message: &'a str) -> Self {
+        Self::parse_part(message, ("TEXT", "PLAIN"), 0)
+    }
+
+    /// Parse a message or body part. `default_type` is the content type
+    /// of a part without a `Content-Type` field, which is
+    /// `message/rfc822` in a `multipart/digest` and `text/plain`
+    /// everywhere else (RFC 2046 section 5.1.5).
+    fn parse_part(raw: &'a str, default_type: (&str, &str), depth: usize) -> Self {
+        let (header, body) = split_header(raw);
+
+        let mut part = Self {
+            raw,
+            header,
+            body,
+            content_type: ContentType::parse(None, default_type),
+            kind: PartKind::Single,
+        };
+        let content_type = part.header_value("Content-Type");
+        part.content_type = ContentType::parse(content_type.as_deref(), default_type);
+
+        if depth >= MAX_DEPTH {
+            return part;
+        }
+
+        part.kind = match (
+            part.content_type.media_type.as_str(),
+            part.content_type.subtype.as_str(),
+        ) {
+            ("MULTIPART", subtype) => match part.content_type.parameter("boundary") {
+                Some(boundary) => {
+                    let default_type = match subtype {
+                        "DIGEST" => ("MESSAGE", "RFC822"),
+                        _ => ("TEXT", "PLAIN"),
+                    };
+                    PartKind::Multipart(
+                        split_multipart(body, boundary)
+                            .into_iter()
+                            .map(|p| Self::parse_part(p, default_type, depth + 1))
+                            .collect(),
+                    )
+                }
+                None => PartKind::Single,
+            },
+            ("MESSAGE", "RFC822") => PartKind::Message(Box::new(Self::parse_part(
+                body,
+                ("TEXT", "PLAIN"),
+                depth + 1,
+            ))),
+            _ => PartKind::Single,
+        };
+
+        part
+    }
+
+    /// Get the value of the first header field with the given name (case
+    /// insensitive), unfolded and without surrounding whitespace
+    pub fn header_value(&self, name: &str) -> Option<String> {
+        header_fields(self.header)
+            .into_iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, field)| {
+                let value = field.split_once(':').map(|(_, v)| v).unwrap_or_default();
+                value.replace("\r\n", "").trim().to_owned()
+            })
+    }
+
+    /// Get the header fields with one of the given names, or without any of
+    /// them if `not` is true, followed by an empty line. This is
+    /// `HEADER.FIELDS` and `HEADER.FIELDS.NOT`.
+    fn header_subset(&self, names: &[String], not: bool) -> String {
+        let mut out = String::new();
+        for (name, field) in header_fields(self.header) {
+            if names.iter().any(|n| n.eq_ignore_ascii_case(name)) != not {
+                out += field;
+            }
+        }
+        out + "\r\n"
+    }
+
+    /// Find the body part with the given part number, like `[1, 2]` for
+    /// part "1.2" (RFC 3501 section 6.4.5). A message that isn't multipart
+    /// has only part 1, which is its body.
+    pub fn find(&self, path: &[u32]) -> Option<&Part<'a>> {
+        let mut part = self;
+        for (i, n) in path.iter().enumerate() {
+            // The numbers after a message/rfc822 part refer to the parts of
+            // the encapsulated message
+            if i > 0 {
+                if let PartKind::Message(message) = &part.kind {
+                    part = message;
+                }
+            }
+
+            part = match &part.kind {
+                PartKind::Multipart(parts) => parts.get(*n as usize - 1)?,
+                _ if *n == 1 => part,
+                _ => return None,
+            };
+        }
+        Some(part)
+    }
+
+    /// Get a section of the message for `BODY[<section>]`. Returns `None`
+    /// if the section doesn't exist.
+    pub fn section(&self, section: &Section) -> Option<Cow<'a, str>> {
+        let part = self.find(&section.part)?;
+
+        // HEADER, TEXT and HEADER.FIELDS are about a message: either this
+        // one or the one inside a message/rfc822 part
+        let message = match &part.kind {
+            _ if section.part.is_empty() => Some(part),
+            PartKind::Message(message) => Some(message.as_ref()),
+            _ => None,
+        };
+
+        let section = match &section.text {
+            None if section.part.is_empty() => self.raw.into(),
+            None => part.body.into(),
+            Some(SectionText::Mime) => part.header.into(),
+            Some(SectionText::Header) => message?.header.into(),
+            Some(SectionText::Text) => message?.body.into(),
+            Some(SectionText::HeaderFields(names)) => message?.header_subset(names, false).into(),
+            Some(SectionText::HeaderFieldsNot(names)) => message?.header_subset(names, true).into(),
+        };
+        Some(section)
+    }
+
+    /// The envelope structure of the message (RFC 3501 section 7.4.2)
+    pub fn envelope(&self) -> String {
+        let from = self.header_value("From");
+        let sender = self.header_value("Sender").or_else(|| from.clone());
+        let reply_to = self.header_value("Reply-To").or_else(|| from.clone());
+
+        let fields = [
+            nstring(self.header_value("Date").as_deref()),
+            nstring(self.header_value("Subject").as_deref()),
+            address_list(from.as_deref()),
+            address_list(sender.as_deref()),
+            address_list(reply_to.as_deref()),
+            address_list(self.header_value("To").as_deref()),
+            address_list(self.header_value("Cc").as_deref()),
+            address_list(self.header_value("Bcc").as_deref()),
+            nstring(self.header_value("In-Reply-To").as_deref()),
+            nstring(self.header_value("Message-ID").as_deref()),
+        ];
+        format!("({})", fields.join(" "))
+    }
+
+    /// The MIME structure of the message. `extensible` adds the extension
+    /// data that `BODYSTRUCTURE` has and `BODY` doesn't.
+    pub fn body_structure(&self, extensible: bool) -> String {
+        let content_type = &self.content_type;
+
+        if let PartKind::Multipart(parts) = &self.kind {
+            let mut out = "(".to_owned();
+            for part in parts {
+                out += &part.body_structure(extensible);
+            }
+            out += " ";
+            out += &string(&content_type.subtype);
+            if extensible {
+                out += " ";
+                out += &parameter_list(&content_type.parameters);
+                out += " ";
+                out += &self.disposition();
+            }
+            return out + ")";
+        }
+
+        let mut parameters = content_type.parameters.clone();
+        if content_type.media_type == "TEXT" && content_type.parameter("charset").is_none() {
+            parameters.push(("CHARSET".to_owned(), "US-ASCII".to_owned()));
+        }
+        let encoding = self
+            .header_value("Content-Transfer-Encoding")
+            .map(|e| e.to_ascii_uppercase())
+            .unwrap_or_else(|| "7BIT".to_owned());
+
+        let mut fields = vec![
+            string(&content_type.media_type),
+            string(&content_type.subtype),
+            parameter_list(&parameters),
+            nstring(self.header_value("Content-ID").as_deref()),
+            nstring(self.header_value("Content-Description").as_deref()),
+            string(&encoding),
+            self.body.len().to_string(),
+        ];
+
+        match &self.kind {
+            PartKind::Message(message) => {
+                fields.push(message.envelope());
+                fields.push(message.body_structure(extensible));
+                fields.push(line_count(self.body).to_string());
+            }
+            _ if content_type.media_type == "TEXT" => {
+                fields.push(line_count(self.body).to_string());
+            }
+            _ => (),
+        }
+
+        if extensible {
+            // The MD5 of the body isn't calculated
+            fields.push("NIL".to_owned());
+            fields.push(self.disposition());
+        }
+
+        format!("({})", fields.join(" "))
+    }
+
+    /// The `Content-Disposition` field (RFC 2183) in the body structure
+    /// format, like `("ATTACHMENT" ("FILENAME" "report.pdf"))`
+    fn disposition(&self) -> String {
+        let value = match self.header_value("Content-Disposition") {
+            Some(value) => value,
+            None => return "NIL".to_owned(),
+        };
+
+        let mut fields = split_outside_quotes(&value, ';').into_iter();
+        let disposition = fields
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_uppercase();
+        format!(
+            "({} {})",
+            string(&disposition),
+            parameter_list(&parameters(fields))
+        )
+    }
+}
+
+/// Convert bare LFs in a stored message to CRLF, so that sizes and
+/// offsets are the same as in the data sent to the client
+pub fn crlf(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut previous = None;
+    for c in content.chars() {
+        if c == '\n' && previous != Some('\r') {
+            out.push('\r');
+        }
+        out.push(c);
+        previous = Some(c);
+    }
+    out
+}
+
+/// Split a message or body part into its header (including the empty line
+/// after it) and its body
+fn split_header(raw: &str) -> (&str, &str) {
+    if raw.starts_with("\r\n") {
+        return raw.split_at(2);
+    }
+    match raw.find("\r\n\r\n") {
+        Some(end) => raw.split_at(end + 4),
+        None => (raw, ""),
+    }
+}
+
+/// Split a header into its fields. Each field is returned with its name
+/// and all of its lines, including the final CRLF.
+fn header_fields(header: &str) -> Vec<(&str, &str)> {
+    let mut fields = vec![];
+    let mut start = 0;
+    let mut position = 0;
+
+    for line in header.split_inclusive('\n') {
+        let is_continuation = line.starts_with([' ', '\t']);
+        if !is_continuation && position > start {
+            fields.push(&header[start..position]);
+        }
+        if !is_continuation {
+            start = position;
+        }
+        if line.trim_end().is_empty() && !is_continuation {
+            // The empty line that ends the header
+            start = header.len();
+            break;
+        }
+        position += line.len();
+    }
+    if position > start {
+        fields.push(&header[start..position]);
+    }
+
+    fields
+        .into_iter()
+        .filter_map(|field| field.split_once(':').map(|(name, _)| (name.trim(), field)))
+        .collect()
+}
+
+/// Split the body of a multipart part at the boundary delimiter lines (RFC
+/// 2046 section 5.1.1). The preamble and epilogue are dropped.
+fn split_multipart<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = vec![];
+    let mut start = None;
+    let mut position = 0;
+
+    for line in body.split_inclusive('\n') {
+        let line_start = position;
+        position += line.len();
+
+        let rest = match line.trim_end().strip_prefix(delimiter.as_str()) {
+            Some(rest) if rest.is_empty() || rest == "--" => rest,
+            _ => continue,
+        };
+
+        // The CRLF before a delimiter belongs to the delimiter
+        if let Some(start) = start {
+            let end = line_start.saturating_sub(2).max(start);
+            parts.push(&body[start..end]);
+        }
+        if rest == "--" {
+            return parts;
+        }
+        start = Some(position);
+    }
+
+    // The closing delimiter is missing
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Split `s` at every `separator` that isn't inside double quotes
+fn split_outside_quotes(s: &str, separator: char) -> Vec<&str> {
+    let mut out = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                out.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    out.push(&s[start..]);
+    out
+}
+
+/// Parse `name=value` parameters from a `Content-Type` or
+/// `Content-Disposition` field (RFC 2045 section 5.1). The names are
+/// converted to uppercase.
+fn parameters<'a>(fields: impl Iterator<Item = &'a str>) -> Vec<(String, String)> {
+    fields
+        .filter_map(|p| p.split_once('='))
+        .map(|(name, value)| (name.trim().to_ascii_uppercase(), unquote(value.trim())))
+        .collect()
+}
+
+/// Remove the double quotes around a string, and the backslashes that
+/// escape characters inside it
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::new();
+            let mut escaped = false;
+            for c in inner.chars() {
+                if c == '\\' && !escaped {
+                    escaped = true;
+                } else {
+                    out.push(c);
+                    escaped = false;
+                }
+            }
+            out
+        }
+        None => s.to_owned(),
+    }
+}
+
+/// The number of lines in a body
+fn line_count(body: &str) -> usize {
+    body.matches('\n').count()
+}
+
+/// Format parameters as a body structure parameter list, like
+/// `("CHARSET" "UTF-8")`. An empty list is `NIL`.
+fn parameter_list(parameters: &[(String, String)]) -> String {
+    if parameters.is_empty() {
+        return "NIL".to_owned();
+    }
+
+    let parameters: Vec<String> = parameters
+        .iter()
+        .map(|(name, value)| format!("{} {}", string(name), string(value)))
+        .collect();
+    format!("({})", parameters.join(" "))
+}
+
+/// Parse an address list header field (RFC 5322 section 3.4) into the
+/// envelope format: a list of `(name adl mailbox host)`. Groups are
+/// flattened into their members.
+fn address_list(value: Option<&str>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return "NIL".to_owned(),
+    };
+
+    let mut addresses = String::new();
+    for address in split_outside_quotes(value, ',') {
+        let mut address = address.trim();
+
+        // Remove the group name and the end of the group
+        if let Some((group, members)) = address.split_once(':') {
+            if !group.contains(['<', '@', '"']) {
+                address = members.trim();
+            }
+        }
+        address = address.trim_end_matches(';').trim();
+        if address.is_empty() {
+            continue;
+        }
+
+        let (name, addr_spec) = match (address.rfind('<'), address.rfind('>')) {
+            (Some(open), Some(close)) if open < close => {
+                let name = unquote(address[..open].trim());
+                (
+                    Some(name).filter(|n| !n.is_empty()),
+                    &address[open + 1..close],
+                )
+            }
+            _ => (None, address),
+        };
+        let (mailbox, host) = addr_spec.rsplit_once('@').unwrap_or((addr_spec, ""));
+
+        addresses += &format!(
+            "({} NIL {} {})",
+            nstring(name.as_deref()),
+            string(mailbox.trim()),
+            string(host.trim())
+        );
+    }
+
+    if addresses.is_empty() {
+        "NIL".to_owned()
+    } else {
+        format!("({})", addresses)
+    }
+}
+
+#[test]
+fn message_sections() {
+    let message = crlf(
+        "From: Mark <mark@example.com>\n\
+         Subject: Multipart\n \
+         test\n\
+         Content-Type: multipart/mixed; boundary=\"xyz\"\n\
+         \n\
+         preamble\n\
+         --xyz\n\
+         Content-Type: text/plain\n\
+         \n\
+         Hello\n\
+         --xyz\n\
+         Content-Type: message/rfc822\n\
+         \n\
+         Subject: Inner\n\
+         \n\
+         Inner body\n\
+         --xyz--\n\
+         epilogue\n",
+    );
+    let message = Part::parse(&message);
+
+    let section = |part: Vec<u32>, text: Option<SectionText>| Section { part, text };
+
+    assert_eq!(
+        message.header_value("subject").as_deref(),
+        Some("Multipart test")
+    );
+    assert_eq!(message.section(&section(vec![1], None)).unwrap(), "Hello");
+    assert_eq!(
+        message
+            .section(&section(vec![1], Some(SectionText::Mime)))
+            .unwrap(),
+        "Content-Type: text/plain\r\n\r\n"
+    );
+    assert_eq!(
+        message
+            .section(&section(vec![2], Some(SectionText::Header)))
+            .unwrap(),
+        "Subject: Inner\r\n\r\n"
+    );
+    assert_eq!(
+        message.section(&section(vec![2, 1], None)).unwrap(),
+        "Inner body"
+    );
+    assert_eq!(message.section(&section(vec![3], None)), None);
+    assert_eq!(
+        message
+            .section(&section(
+                vec![],
+                Some(SectionText::HeaderFields(vec!["SUBJECT".to_owned()]))
+            ))
+            .unwrap(),
+        "Subject: Multipart\r\n test\r\n\r\n"
+    );
+}
+
+#[test]
+fn message_structure() {
+    let message = crlf(
+        "From: \"Ghebrial, Mark\" <mark@example.com>\n\
+         To: team: a@example.com, b@example.org;\n\
+         Subject: hi\n\
+         \n\
+         Hello\n",
+    );
+    let message = Part::parse(&message);
+
+    assert_eq!(
+        message.envelope(),
+        "(NIL \"hi\" \
+         ((\"Ghebrial, Mark\" NIL \"mark\" \"example.com\")) \
+         ((\"Ghebrial, Mark\" NIL \"mark\" \"example.com\")) \
+         ((\"Ghebrial, Mark\" NIL \"mark\" \"example.com\")) \
+         ((NIL NIL \"a\" \"example.com\")(NIL NIL \"b\" \"example.org\")) \
+         NIL NIL NIL NIL)"
+    );
+    assert_eq!(
+        message.body_structure(false),
+        "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"US-ASCII\") NIL NIL \"7BIT\" 7 1)"
+    );
+}
@@ -0,0 +1,16 @@
+mod command;
+pub use command::*;
+
+mod connection;
+pub use connection::*;
+
+pub mod err;
+
+mod mailbox;
+
+mod message;
+
+mod parser;
+
+mod response;
+pub use response::*;
@@ -0,0 +1,806 @@
+//! nom parsers for IMAP commands
+//!
+//! See RFC 3501 section 9 for the IMAP syntax specifications. The grammar
+//! is reproduced above each parser. Unlike the SMTP parsers, these are
+//! complete parsers: the connection reads a whole command line, including
+//! any literals, before parsing it.
+//!
+//! https://datatracker.ietf.org/doc/html/rfc3501#section-9
+
+use std::str;
+
+use chrono::NaiveDate;
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, tag_no_case, take_while1, take_while_m_n},
+    character::complete::{char, digit1},
+    combinator::{map, map_res, opt, recognize, value, verify},
+    multi::{length_data, many0, many1, separated_list0, separated_list1},
+    number::complete::u8 as byte,
+    sequence::{delimited, preceded, separated_pair, terminated},
+    IResult, Parser,
+};
+
+use super::*;
+
+/// `CRLF = %d13.10`
+pub fn crlf(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    tag("\r\n").parse(i)
+}
+
+/// `SP = %x20`
+pub fn sp(i: &[u8]) -> IResult<&[u8], char> {
+    char(' ').parse(i)
+}
+
+/// ```text
+/// ATOM-CHAR = <any CHAR except atom-specials>
+/// atom-specials = "(" / ")" / "{" / SP / CTL / list-wildcards /
+///                 quoted-specials / resp-specials
+/// ```
+fn is_atom_char(c: u8) -> bool {
+    (0x21..0x7f).contains(&c) && !b"(){%*\"\\]".contains(&c)
+}
+
+/// `ASTRING-CHAR = ATOM-CHAR / resp-specials`
+fn is_astring_char(c: u8) -> bool {
+    is_atom_char(c) || c == b']'
+}
+
+/// `list-char = ATOM-CHAR / list-wildcards / resp-specials`
+fn is_list_char(c: u8) -> bool {
+    is_astring_char(c) || c == b'%' || c == b'*'
+}
+
+/// `tag = 1*<any ASTRING-CHAR except "+">`, followed by a space
+pub fn tag_and_sp(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    terminated(take_while1(|c| is_astring_char(c) && c != b'+'), sp).parse(i)
+}
+
+/// The command name after the tag. Used to decide which command parser to
+/// run.
+pub fn command_name(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    take_while1(|c: u8| c.is_ascii_alphabetic()).parse(i)
+}
+
+/// `atom = 1*ATOM-CHAR`
+fn atom(i: &[u8]) -> IResult<&[u8], String> {
+    map_res(take_while1(is_atom_char), |a: &[u8]| {
+        String::from_utf8(a.to_vec())
+    })
+    .parse(i)
+}
+
+/// `number = 1*DIGIT`, which must fit in 32 bits
+fn number(i: &[u8]) -> IResult<&[u8], u32> {
+    map_res(digit1, |d: &[u8]| str::from_utf8(d).unwrap().parse::<u32>()).parse(i)
+}
+
+/// `nz-number = digit-nz *DIGIT`
+fn nz_number(i: &[u8]) -> IResult<&[u8], u32> {
+    verify(number, |n| *n != 0).parse(i)
+}
+
+/// ```text
+/// quoted = DQUOTE *QUOTED-CHAR DQUOTE
+/// QUOTED-CHAR = <any TEXT-CHAR except quoted-specials> /
+///               "\" quoted-specials
+/// ```
+///
+/// Returns the contents of the string with the quoting removed. UTF-8 is
+/// allowed, as in IMAP4rev2.
+fn quoted(i: &[u8]) -> IResult<&[u8], String> {
+    map_res(
+        delimited(
+            char('"'),
+            many0(alt((
+                preceded(char('\\'), verify(byte, |c| *c == b'"' || *c == b'\\')),
+                verify(byte, |c| !b"\"\\\r\n\0".contains(c)),
+            ))),
+            char('"'),
+        ),
+        String::from_utf8,
+    )
+    .parse(i)
+}
+
+/// `literal = "{" number ["+"] "}" CRLF *CHAR8`
+///
+/// The connection has already sent the continuation request and read the
+/// literal's octets.
+fn literal(i: &[u8]) -> IResult<&[u8], String> {
+    map_res(
+        length_data(delimited(
+            char('{'),
+            number,
+            (opt(char('+')), char('}'), crlf),
+        )),
+        |l: &[u8]| String::from_utf8(l.to_vec()),
+    )
+    .parse(i)
+}
+
+/// `string = quoted / literal`
+fn string(i: &[u8]) -> IResult<&[u8], String> {
+    alt((quoted, literal)).parse(i)
+}
+
+/// `astring = 1*ASTRING-CHAR / string`
+fn astring(i: &[u8]) -> IResult<&[u8], String> {
+    alt((
+        map_res(take_while1(is_astring_char), |a: &[u8]| {
+            String::from_utf8(a.to_vec())
+        }),
+        string,
+    ))
+    .parse(i)
+}
+
+/// `mailbox = "INBOX" / astring`
+///
+/// INBOX is case insensitive, so it is always returned in uppercase.
+fn mailbox(i: &[u8]) -> IResult<&[u8], String> {
+    map(astring, |name| {
+        if name.eq_ignore_ascii_case("INBOX") {
+            "INBOX".to_owned()
+        } else {
+            name
+        }
+    })
+    .parse(i)
+}
+
+/// `list-mailbox = 1*list-char / string`
+fn list_mailbox(i: &[u8]) -> IResult<&[u8], String> {
+    alt((
+        map_res(take_while1(is_list_char), |l: &[u8]| {
+            String::from_utf8(l.to_vec())
+        }),
+        string,
+    ))
+    .parse(i)
+}
+
+/// ```text
+/// sequence-set = (seq-number / seq-range) *("," sequence-set)
+/// seq-range = seq-number ":" seq-number
+/// seq-number = nz-number / "*"
+/// ```
+fn sequence_set(i: &[u8]) -> IResult<&[u8], SequenceSet> {
+    let seq_number = || {
+        alt((
+            value(SequenceNumber::Largest, char('*')),
+            map(nz_number, SequenceNumber::Value),
+        ))
+    };
+
+    separated_list1(
+        char(','),
+        map(
+            (seq_number(), opt(preceded(char(':'), seq_number()))),
+            |(start, end)| SequenceRange {
+                start,
+                end: end.unwrap_or(start),
+            },
+        ),
+    )
+    .parse(i)
+}
+
+/// ```text
+/// flag = "\Answered" / "\Flagged" / "\Deleted" / "\Seen" / "\Draft" /
+///        flag-keyword
+/// flag-keyword = atom
+/// ```
+///
+/// Flag extensions (other flags that start with a backslash) aren't
+/// supported.
+fn flag(i: &[u8]) -> IResult<&[u8], Flag> {
+    alt((
+        value(Flag::Answered, tag_no_case("\\Answered")),
+        value(Flag::Flagged, tag_no_case("\\Flagged")),
+        value(Flag::Deleted, tag_no_case("\\Deleted")),
+        value(Flag::Seen, tag_no_case("\\Seen")),
+        value(Flag::Draft, tag_no_case("\\Draft")),
+        map(atom, Flag::Keyword),
+    ))
+    .parse(i)
+}
+
+/// `flag-list = "(" [flag *(SP flag)] ")"`
+fn flag_list(i: &[u8]) -> IResult<&[u8], Vec<Flag>> {
+    delimited(char('('), separated_list0(sp, flag), char(')')).parse(i)
+}
+
+/// ```text
+/// date = date-text / DQUOTE date-text DQUOTE
+/// date-text = date-day "-" date-month "-" date-year
+/// ```
+fn date(i: &[u8]) -> IResult<&[u8], NaiveDate> {
+    let date_text = || {
+        map_res(
+            recognize((
+                take_while_m_n(1, 2, |c: u8| c.is_ascii_digit()),
+                char('-'),
+                take_while_m_n(3, 3, |c: u8| c.is_ascii_alphabetic()),
+                char('-'),
+                take_while_m_n(4, 4, |c: u8| c.is_ascii_digit()),
+            )),
+            |d: &[u8]| NaiveDate::parse_from_str(str::from_utf8(d).unwrap(), "%d-%b-%Y"),
+        )
+    };
+
+    alt((date_text(), delimited(char('"'), date_text(), char('"')))).parse(i)
+}
+
+/// A command that is only its name, like `NOOP`
+fn bare_command<'a>(
+    name: &'static str,
+    command: IMAPCommand,
+) -> impl Parser<&'a [u8], Output = IMAPCommand, Error = nom::error::Error<&'a [u8]>> {
+    value(command, (tag_no_case(name), crlf))
+}
+
+/// `capability = "CAPABILITY"`
+pub fn capability(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    bare_command("CAPABILITY", IMAPCommand::Capability).parse(i)
+}
+
+/// `"NOOP"`
+pub fn noop(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    bare_command("NOOP", IMAPCommand::Noop).parse(i)
+}
+
+/// `"LOGOUT"`
+pub fn logout(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    bare_command("LOGOUT", IMAPCommand::Logout).parse(i)
+}
+
+/// ```text
+/// authenticate = "AUTHENTICATE" SP auth-type [SP (base64 / "=")]
+/// auth-type = atom
+/// ```
+///
+/// The initial response is from RFC 4959 (SASL-IR). The mechanism name is
+/// converted to uppercase.
+pub fn authenticate(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited(
+            (tag_no_case("AUTHENTICATE"), sp),
+            (
+                atom,
+                opt(preceded(
+                    sp,
+                    map_res(
+                        take_while1(|c: u8| c.is_ascii_alphanumeric() || b"+/=".contains(&c)),
+                        |r: &[u8]| String::from_utf8(r.to_vec()),
+                    ),
+                )),
+            ),
+            crlf,
+        ),
+        |(mechanism, initial_response)| IMAPCommand::Authenticate {
+            mechanism: mechanism.to_ascii_uppercase(),
+            initial_response,
+        },
+    )
+    .parse(i)
+}
+
+/// `login = "LOGIN" SP userid SP password`
+pub fn login(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited(
+            (tag_no_case("LOGIN"), sp),
+            separated_pair(astring, sp, astring),
+            crlf,
+        ),
+        |(username, password)| IMAPCommand::Login { username, password },
+    )
+    .parse(i)
+}
+
+/// `select = "SELECT" SP mailbox`
+pub fn select(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited((tag_no_case("SELECT"), sp), mailbox, crlf),
+        |mailbox| IMAPCommand::Select { mailbox },
+    )
+    .parse(i)
+}
+
+/// `examine = "EXAMINE" SP mailbox`
+pub fn examine(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited((tag_no_case("EXAMINE"), sp), mailbox, crlf),
+        |mailbox| IMAPCommand::Examine { mailbox },
+    )
+    .parse(i)
+}
+
+/// `create = "CREATE" SP mailbox`
+pub fn create(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited((tag_no_case("CREATE"), sp), mailbox, crlf),
+        |mailbox| IMAPCommand::Create { mailbox },
+    )
+    .parse(i)
+}
+
+/// `delete = "DELETE" SP mailbox`
+pub fn delete(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited((tag_no_case("DELETE"), sp), mailbox, crlf),
+        |mailbox| IMAPCommand::Delete { mailbox },
+    )
+    .parse(i)
+}
+
+/// `list = "LIST" SP mailbox SP list-mailbox`
+pub fn list(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited(
+            (tag_no_case("LIST"), sp),
+            separated_pair(mailbox, sp, list_mailbox),
+            crlf,
+        ),
+        |(reference, pattern)| IMAPCommand::List { reference, pattern },
+    )
+    .parse(i)
+}
+
+/// `lsub = "LSUB" SP mailbox SP list-mailbox`
+pub fn lsub(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited(
+            (tag_no_case("LSUB"), sp),
+            separated_pair(mailbox, sp, list_mailbox),
+            crlf,
+        ),
+        |(reference, pattern)| IMAPCommand::Lsub { reference, pattern },
+    )
+    .parse(i)
+}
+
+/// ```text
+/// status = "STATUS" SP mailbox SP "(" status-att *(SP status-att) ")"
+/// status-att = "MESSAGES" / "RECENT" / "UIDNEXT" / "UIDVALIDITY" /
+///              "UNSEEN"
+/// ```
+pub fn status(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    let status_att = alt((
+        value(StatusItem::Messages, tag_no_case("MESSAGES")),
+        value(StatusItem::Recent, tag_no_case("RECENT")),
+        value(StatusItem::UidNext, tag_no_case("UIDNEXT")),
+        value(StatusItem::UidValidity, tag_no_case("UIDVALIDITY")),
+        value(StatusItem::Unseen, tag_no_case("UNSEEN")),
+    ));
+
+    map(
+        delimited(
+            (tag_no_case("STATUS"), sp),
+            separated_pair(
+                mailbox,
+                sp,
+                delimited(char('('), separated_list1(sp, status_att), char(')')),
+            ),
+            crlf,
+        ),
+        |(mailbox, items)| IMAPCommand::Status { mailbox, items },
+    )
+    .parse(i)
+}
+
+/// `"CHECK"`
+pub fn check(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    bare_command("CHECK", IMAPCommand::Check).parse(i)
+}
+
+/// `"CLOSE"`
+pub fn close(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    bare_command("CLOSE", IMAPCommand::Close).parse(i)
+}
+
+/// `"UNSELECT"` (RFC 3691)
+pub fn unselect(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    bare_command("UNSELECT", IMAPCommand::Unselect).parse(i)
+}
+
+/// `"EXPUNGE"`
+pub fn expunge(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    bare_command("EXPUNGE", IMAPCommand::Expunge).parse(i)
+}
+
+/// `search = "SEARCH" [SP "CHARSET" SP astring] 1*(SP search-key)`
+pub fn search(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        delimited(
+            tag_no_case("SEARCH"),
+            (
+                opt(preceded((sp, tag_no_case("CHARSET"), sp), astring)),
+                many_keys,
+            ),
+            crlf,
+        ),
+        |(charset, criteria)| IMAPCommand::Search {
+            charset,
+            criteria,
+            uid: false,
+        },
+    )
+    .parse(i)
+}
+
+/// `1*(SP search-key)`
+fn many_keys(i: &[u8]) -> IResult<&[u8], Vec<SearchKey>> {
+    many1(preceded(sp, search_key)).parse(i)
+}
+
+/// ```text
+/// search-key = "ALL" / "ANSWERED" / "BCC" SP astring /
+///              "BEFORE" SP date / "BODY" SP astring /
+///              "CC" SP astring / "DELETED" / "FLAGGED" /
+///              "FROM" SP astring / "KEYWORD" SP flag-keyword /
+///              "NEW" / "OLD" / "ON" SP date / "RECENT" / "SEEN" /
+///              "SINCE" SP date / "SUBJECT" SP astring /
+///              "TEXT" SP astring / "TO" SP astring /
+///              "UNANSWERED" / "UNDELETED" / "UNFLAGGED" /
+///              "UNKEYWORD" SP flag-keyword / "UNSEEN" /
+///              "DRAFT" / "HEADER" SP header-fld-name SP astring /
+///              "LARGER" SP number / "NOT" SP search-key /
+///              "OR" SP search-key SP search-key /
+///              "SENTBEFORE" SP date / "SENTON" SP date /
+///              "SENTSINCE" SP date / "SMALLER" SP number /
+///              "UID" SP sequence-set / "UNDRAFT" / sequence-set /
+///              "(" search-key *(SP search-key) ")"
+/// ```
+fn search_key(i: &[u8]) -> IResult<&[u8], SearchKey> {
+    use SearchKey::*;
+
+    let flags = alt((
+        value(All, tag_no_case("ALL")),
+        value(Answered, tag_no_case("ANSWERED")),
+        value(Deleted, tag_no_case("DELETED")),
+        value(Draft, tag_no_case("DRAFT")),
+        value(Flagged, tag_no_case("FLAGGED")),
+        value(New, tag_no_case("NEW")),
+        value(Old, tag_no_case("OLD")),
+        value(Recent, tag_no_case("RECENT")),
+        value(Seen, tag_no_case("SEEN")),
+        value(Unanswered, tag_no_case("UNANSWERED")),
+        value(Undeleted, tag_no_case("UNDELETED")),
+        value(Undraft, tag_no_case("UNDRAFT")),
+        value(Unflagged, tag_no_case("UNFLAGGED")),
+        value(Unseen, tag_no_case("UNSEEN")),
+        map(preceded((tag_no_case("KEYWORD"), sp), atom), Keyword),
+        map(preceded((tag_no_case("UNKEYWORD"), sp), atom), Unkeyword),
+    ));
+
+    let strings = alt((
+        map(preceded((tag_no_case("BCC"), sp), astring), Bcc),
+        map(preceded((tag_no_case("BODY"), sp), astring), Body),
+        map(preceded((tag_no_case("CC"), sp), astring), Cc),
+        map(preceded((tag_no_case("FROM"), sp), astring), From),
+        map(preceded((tag_no_case("SUBJECT"), sp), astring), Subject),
+        map(preceded((tag_no_case("TEXT"), sp), astring), Text),
+        map(preceded((tag_no_case("TO"), sp), astring), To),
+        map(
+            preceded(
+                (tag_no_case("HEADER"), sp),
+                separated_pair(astring, sp, astring),
+            ),
+            |(name, value)| Header(name, value),
+        ),
+    ));
+
+    let dates = alt((
+        map(preceded((tag_no_case("BEFORE"), sp), date), Before),
+        map(preceded((tag_no_case("ON"), sp), date), On),
+        map(preceded((tag_no_case("SINCE"), sp), date), Since),
+        map(preceded((tag_no_case("SENTBEFORE"), sp), date), SentBefore),
+        map(preceded((tag_no_case("SENTON"), sp), date), SentOn),
+        map(preceded((tag_no_case("SENTSINCE"), sp), date), SentSince),
+    ));
+
+    let others = alt((
+        map(preceded((tag_no_case("LARGER"), sp), number), Larger),
+        map(preceded((tag_no_case("SMALLER"), sp), number), Smaller),
+        map(preceded((tag_no_case("UID"), sp), sequence_set), Uid),
+        map(preceded((tag_no_case("NOT"), sp), search_key), |key| {
+            Not(Box::new(key))
+        }),
+        map(
+            preceded(
+                (tag_no_case("OR"), sp),
+                separated_pair(search_key, sp, search_key),
+            ),
+            |(a, b)| Or(Box::new(a), Box::new(b)),
+        ),
+        map(sequence_set, SequenceSet),
+        map(
+            delimited(char('('), separated_list1(sp, search_key), char(')')),
+            And,
+        ),
+    ));
+
+    alt((flags, strings, dates, others)).parse(i)
+}
+
+/// ```text
+/// fetch = "FETCH" SP sequence-set SP ("ALL" / "FULL" / "FAST" /
+///         fetch-att / "(" fetch-att *(SP fetch-att) ")")
+/// ```
+pub fn fetch(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    use FetchItem::*;
+
+    let items = alt((
+        value(
+            vec![Flags, InternalDate, Rfc822Size, Envelope],
+            tag_no_case("ALL"),
+        ),
+        value(
+            vec![Flags, InternalDate, Rfc822Size, Envelope, Body],
+            tag_no_case("FULL"),
+        ),
+        value(vec![Flags, InternalDate, Rfc822Size], tag_no_case("FAST")),
+        map(fetch_att, |item| vec![item]),
+        delimited(char('('), separated_list1(sp, fetch_att), char(')')),
+    ));
+
+    map(
+        delimited(
+            (tag_no_case("FETCH"), sp),
+            separated_pair(sequence_set, sp, items),
+            crlf,
+        ),
+        |(sequence_set, items)| IMAPCommand::Fetch {
+            sequence_set,
+            items,
+            uid: false,
+        },
+    )
+    .parse(i)
+}
+
+/// ```text
+/// fetch-att = "ENVELOPE" / "FLAGS" / "INTERNALDATE" /
+///             "RFC822" [".HEADER" / ".SIZE" / ".TEXT"] /
+///             "BODY" ["STRUCTURE"] / "UID" /
+///             "BODY" section ["<" number "." nz-number ">"] /
+///             "BODY.PEEK" section ["<" number "." nz-number ">"]
+/// ```
+fn fetch_att(i: &[u8]) -> IResult<&[u8], FetchItem> {
+    use FetchItem::*;
+
+    let partial = || {
+        opt(delimited(
+            char('<'),
+            separated_pair(number, char('.'), nz_number),
+            char('>'),
+        ))
+    };
+
+    alt((
+        value(Envelope, tag_no_case("ENVELOPE")),
+        value(Flags, tag_no_case("FLAGS")),
+        value(InternalDate, tag_no_case("INTERNALDATE")),
+        value(Rfc822Header, tag_no_case("RFC822.HEADER")),
+        value(Rfc822Size, tag_no_case("RFC822.SIZE")),
+        value(Rfc822Text, tag_no_case("RFC822.TEXT")),
+        value(Rfc822, tag_no_case("RFC822")),
+        map(
+            preceded(tag_no_case("BODY.PEEK"), (section, partial())),
+            |(section, partial)| BodySection {
+                section,
+                partial,
+                peek: true,
+            },
+        ),
+        map(
+            preceded(tag_no_case("BODY"), (section, partial())),
+            |(section, partial)| BodySection {
+                section,
+                partial,
+                peek: false,
+            },
+        ),
+        value(BodyStructure, tag_no_case("BODYSTRUCTURE")),
+        value(Body, tag_no_case("BODY")),
+        value(Uid, tag_no_case("UID")),
+    ))
+    .parse(i)
+}
+
+/// ```text
+/// section = "[" [section-spec] "]"
+/// section-spec = section-msgtext / (section-part ["." section-text])
+/// section-part = nz-number *("." nz-number)
+/// section-text = section-msgtext / "MIME"
+/// ```
+fn section(i: &[u8]) -> IResult<&[u8], Section> {
+    let section_spec = alt((
+        map(section_msgtext, |text| Section {
+            part: vec![],
+            text: Some(text),
+        }),
+        map(
+            (
+                separated_list1(char('.'), nz_number),
+                opt(preceded(
+                    char('.'),
+                    alt((
+                        section_msgtext,
+                        value(SectionText::Mime, tag_no_case("MIME")),
+                    )),
+                )),
+            ),
+            |(part, text)| Section { part, text },
+        ),
+    ));
+
+    map(delimited(char('['), opt(section_spec), char(']')), |s| {
+        s.unwrap_or_default()
+    })
+    .parse(i)
+}
+
+/// ```text
+/// section-msgtext = "HEADER" / "HEADER.FIELDS" [".NOT"] SP header-list /
+///                   "TEXT"
+/// header-list = "(" header-fld-name *(SP header-fld-name) ")"
+/// ```
+fn section_msgtext(i: &[u8]) -> IResult<&[u8], SectionText> {
+    let header_list = || delimited(char('('), separated_list1(sp, astring), char(')'));
+
+    alt((
+        map(
+            preceded((tag_no_case("HEADER.FIELDS.NOT"), sp), header_list()),
+            SectionText::HeaderFieldsNot,
+        ),
+        map(
+            preceded((tag_no_case("HEADER.FIELDS"), sp), header_list()),
+            SectionText::HeaderFields,
+        ),
+        value(SectionText::Header, tag_no_case("HEADER")),
+        value(SectionText::Text, tag_no_case("TEXT")),
+    ))
+    .parse(i)
+}
+
+/// ```text
+/// store = "STORE" SP sequence-set SP store-att-flags
+/// store-att-flags = (["+" / "-"] "FLAGS" [".SILENT"]) SP
+///                   (flag-list / (flag *(SP flag)))
+/// ```
+pub fn store(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    let action = alt((
+        value(StoreAction::Add, char('+')),
+        value(StoreAction::Remove, char('-')),
+    ));
+
+    map(
+        delimited(
+            (tag_no_case("STORE"), sp),
+            (
+                terminated(sequence_set, sp),
+                opt(action),
+                preceded(tag_no_case("FLAGS"), opt(tag_no_case(".SILENT"))),
+                preceded(sp, alt((flag_list, separated_list1(sp, flag)))),
+            ),
+            crlf,
+        ),
+        |(sequence_set, action, silent, flags)| IMAPCommand::Store {
+            sequence_set,
+            action: action.unwrap_or(StoreAction::Replace),
+            silent: silent.is_some(),
+            flags,
+            uid: false,
+        },
+    )
+    .parse(i)
+}
+
+/// `uid = "UID" SP (copy / fetch / search / store)`
+///
+/// `UID COPY` isn't supported.
+pub fn uid(i: &[u8]) -> IResult<&[u8], IMAPCommand> {
+    map(
+        preceded((tag_no_case("UID"), sp), alt((fetch, search, store))),
+        |command| match command {
+            IMAPCommand::Fetch {
+                sequence_set,
+                items,
+                ..
+            } => IMAPCommand::Fetch {
+                sequence_set,
+                items,
+                uid: true,
+            },
+            IMAPCommand::Search {
+                charset, criteria, ..
+            } => IMAPCommand::Search {
+                charset,
+                criteria,
+                uid: true,
+            },
+            IMAPCommand::Store {
+                sequence_set,
+                action,
+                silent,
+                flags,
+                ..
+            } => IMAPCommand::Store {
+                sequence_set,
+                action,
+                silent,
+                flags,
+                uid: true,
+            },
+            command => command,
+        },
+    )
+    .parse(i)
+}
+
+#[test]
+fn parse_strings() {
+    assert_eq!(
+        astring(b"mark@example.com rest"),
+        Ok((&b" rest"[..], "mark@example.com".to_owned()))
+    );
+    assert_eq!(
+        astring(b"\"a \\\"quoted\\\" \\\\ string\""),
+        Ok((&b""[..], "a \"quoted\" \\ string".to_owned()))
+    );
+    assert_eq!(
+        astring(b"{5}\r\nh\xc3\xa9llo"),
+        Ok((&b"o"[..], "h\u{e9}ll".to_owned()))
+    );
+    assert!(astring(b"\"unterminated").is_err());
+    assert!(astring(b"(paren)").is_err());
+}
+
+#[test]
+fn parse_sequence_set() {
+    use SequenceNumber::*;
+
+    assert_eq!(
+        sequence_set(b"2,4:7,9,12:*"),
+        Ok((
+            &b""[..],
+            vec![
+                SequenceRange {
+                    start: Value(2),
+                    end: Value(2)
+                },
+                SequenceRange {
+                    start: Value(4),
+                    end: Value(7)
+                },
+                SequenceRange {
+                    start: Value(9),
+                    end: Value(9)
+                },
+                SequenceRange {
+                    start: Value(12),
+                    end: Largest
+                },
+            ]
+        ))
+    );
+    assert!(sequence_set(b"0").is_err());
+    assert!(sequence_set(b"4294967296").is_err());
+}
+
+#[test]
+fn parse_date() {
+    assert_eq!(
+        date(b"1-Feb-1994"),
+        Ok((&b""[..], NaiveDate::from_ymd_opt(1994, 2, 1).unwrap()))
+    );
+    assert_eq!(
+        date(b"\"17-jul-1996\""),
+        Ok((&b""[..], NaiveDate::from_ymd_opt(1996, 7, 17).unwrap()))
+    );
+    assert!(date(b"30-Feb-1994").is_err());
+}
@@ -0,0 +1,278 @@
+use bytes::{Bytes, BytesMut};
+use std::fmt;
+
+use super::Flag;
+
+/// The status of a status response (RFC 3501 section 7.1)
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum IMAPStatus {
+    /// The command succeeded
+    Ok,
+    /// The command failed
+    No,
+    /// The command was invalid
+    Bad,
+    /// The greeting for a connection that is already authenticated
+    PreAuth,
+    /// The server is about to close the connection
+    Bye,
+}
+
+impl fmt::Display for IMAPStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use IMAPStatus::*;
+
+        let status = match self {
+            Ok => "OK",
+            No => "NO",
+            Bad => "BAD",
+            PreAuth => "PREAUTH",
+            Bye => "BYE",
+        };
+
+        write!(f, "{}", status)
+    }
+}
+
+/// Response codes, which give the client more information about a status
+/// response. They are sent in square brackets before the text. See RFC
+/// 3501 section 7.1 and RFC 5530.
+#[derive(PartialEq, Debug, Clone)]
+pub enum IMAPResponseCode {
+    /// `[ALERT]`; The text must be shown to the user
+    Alert,
+    /// `[ALREADYEXISTS]`; A mailbox with that name already exists
+    AlreadyExists,
+    /// `[AUTHENTICATIONFAILED]`; The credentials are wrong
+    AuthenticationFailed,
+    /// `[BADCHARSET]`; The `SEARCH` charset isn't supported
+    BadCharset,
+    /// `[CAPABILITY ...]`; The server's capabilities, so the client
+    /// doesn't have to ask for them
+    Capability(Vec<String>),
+    /// `[CANNOT]`; The server will never carry out the command
+    Cannot,
+    /// `[NONEXISTENT]`; The mailbox doesn't exist
+    Nonexistent,
+    /// `[PRIVACYREQUIRED]`; The command is only allowed over TLS (RFC
+    /// 5530 section 3)
+    PrivacyRequired,
+    /// `[PERMANENTFLAGS (...)]`; The flags that the client can change
+    /// permanently. The client can also create new keywords (`\\*`).
+    PermanentFlags(Vec<Flag>),
+    /// `[READ-ONLY]`; The mailbox was selected with `EXAMINE`
+    ReadOnly,
+    /// `[READ-WRITE]`; The mailbox was selected with `SELECT`
+    ReadWrite,
+    /// `[SERVERBUG]`; Something went wrong on the server
+    ServerBug,
+    /// `[UIDNEXT n]`; The UID that the next message will get
+    UidNext(u32),
+    /// `[UIDVALIDITY n]`; Changes when the mailbox's UIDs are no longer
+    /// valid
+    UidValidity(u32),
+    /// `[UNAVAILABLE]`; A temporary problem on the server. The client
+    /// should try again later
+    Unavailable,
+    /// `[UNSEEN n]`; The sequence number of the first message without the
+    /// `\Seen` flag
+    Unseen(usize),
+}
+
+impl fmt::Display for IMAPResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use IMAPResponseCode::*;
+
+        match self {
+            Alert => write!(f, "[ALERT]"),
+            AlreadyExists => write!(f, "[ALREADYEXISTS]"),
+            AuthenticationFailed => write!(f, "[AUTHENTICATIONFAILED]"),
+            BadCharset => write!(f, "[BADCHARSET]"),
+            Capability(capabilities) => write!(f, "[CAPABILITY {}]", capabilities.join(" ")),
+            Cannot => write!(f, "[CANNOT]"),
+            Nonexistent => write!(f, "[NONEXISTENT]"),
+            PermanentFlags(flags) => {
+                let mut flags: Vec<String> = flags.iter().map(Flag::to_string).collect();
+                flags.push("\\*".to_owned());
+                write!(f, "[PERMANENTFLAGS ({})]", flags.join(" "))
+            }
+            PrivacyRequired => write!(f, "[PRIVACYREQUIRED]"),
+            ReadOnly => write!(f, "[READ-ONLY]"),
+            ReadWrite => write!(f, "[READ-WRITE]"),
+            ServerBug => write!(f, "[SERVERBUG]"),
+            UidNext(uid) => write!(f, "[UIDNEXT {}]", uid),
+            UidValidity(uid_validity) => write!(f, "[UIDVALIDITY {}]", uid_validity),
+            Unavailable => write!(f, "[UNAVAILABLE]"),
+            Unseen(n) => write!(f, "[UNSEEN {}]", n),
+        }
+    }
+}
+
+/// A response from the server (RFC 3501 section 7)
+#[derive(PartialEq, Debug)]
+pub enum IMAPResponse {
+    /// A status response. The completion response for a command has the
+    /// command's tag; other status responses are untagged (`*`).
+    Status {
+        tag: Option<String>,
+        status: IMAPStatus,
+        code: Option<IMAPResponseCode>,
+        text: String,
+    },
+
+    /// Untagged data, like `* 3 EXISTS`. The data may contain literals.
+    Data(Bytes),
+
+    /// A command continuation request (`+`), which asks the client for
+    /// more of its command
+    Continuation(String),
+}
+
+impl IMAPResponse {
+    /// Create the completion response for the command with `tag`
+    pub fn tagged<T: Into<String>>(tag: &str, status: IMAPStatus, text: T) -> Self {
+        Self::Status {
+            tag: Some(tag.to_owned()),
+            status,
+            code: None,
+            text: text.into(),
+        }
+    }
+
+    /// Create an untagged status response
+    pub fn untagged<T: Into<String>>(status: IMAPStatus, text: T) -> Self {
+        Self::Status {
+            tag: None,
+            status,
+            code: None,
+            text: text.into(),
+        }
+    }
+
+    /// Create untagged data
+    pub fn data<T: Into<Bytes>>(data: T) -> Self {
+        Self::Data(data.into())
+    }
+
+    /// Add a response code to a status response
+    pub fn with_code(self, code: IMAPResponseCode) -> Self {
+        match self {
+            Self::Status {
+                tag, status, text, ..
+            } => Self::Status {
+                tag,
+                status,
+                code: Some(code),
+                text,
+            },
+            response => response,
+        }
+    }
+}
+
+/// Convert an IMAPResponse to Bytes
+impl From<IMAPResponse> for Bytes {
+    fn from(response: IMAPResponse) -> Bytes {
+        let mut out = BytesMut::new();
+
+        match response {
+            IMAPResponse::Status {
+                tag,
+                status,
+                code,
+                text,
+            } => {
+                out.extend_from_slice(tag.as_deref().unwrap_or("*").as_bytes());
+                out.extend_from_slice(format!(" {}", status).as_bytes());
+                if let Some(code) = code {
+                    out.extend_from_slice(format!(" {}", code).as_bytes());
+                }
+                // The text is required by the grammar, even if it's empty
+                out.extend_from_slice(b" ");
+                out.extend_from_slice(text.as_bytes());
+            }
+            IMAPResponse::Data(data) => {
+                out.extend_from_slice(b"* ");
+                out.extend_from_slice(&data);
+            }
+            IMAPResponse::Continuation(text) => {
+                out.extend_from_slice(b"+ ");
+                out.extend_from_slice(text.as_bytes());
+            }
+        }
+
+        out.extend_from_slice(b"\r\n");
+        out.into()
+    }
+}
+
+/// Format a list of flags in parentheses, like `(\Seen \Deleted)`
+pub fn flag_list(flags: &[Flag]) -> String {
+    let flags: Vec<String> = flags.iter().map(Flag::to_string).collect();
+    format!("({})", flags.join(" "))
+}
+
+/// Format a string for a response. Strings that can't be quoted are sent
+/// as literals (RFC 3501 section 4.3).
+pub fn string(s: &str) -> String {
+    if s.bytes()
+        .any(|c| c == b'\r' || c == b'\n' || c == 0 || c > 0x7f)
+    {
+        format!("{{{}}}\r\n{}", s.len(), s)
+    } else {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Format a string that may be missing. Missing strings are `NIL`.
+pub fn nstring(s: Option<&str>) -> String {
+    match s {
+        Some(s) => string(s),
+        None => "NIL".to_owned(),
+    }
+}
+
+#[test]
+fn imap_response_to_bytes() {
+    assert_eq!(
+        Bytes::from(IMAPResponse::tagged(
+            "a001",
+            IMAPStatus::Ok,
+            "LOGIN completed"
+        )),
+        Bytes::from("a001 OK LOGIN completed\r\n")
+    );
+    assert_eq!(
+        Bytes::from(
+            IMAPResponse::tagged("a002", IMAPStatus::Ok, "SELECT completed")
+                .with_code(IMAPResponseCode::ReadWrite)
+        ),
+        Bytes::from("a002 OK [READ-WRITE] SELECT completed\r\n")
+    );
+    assert_eq!(
+        Bytes::from(IMAPResponse::untagged(IMAPStatus::Ok, "").with_code(
+            IMAPResponseCode::PermanentFlags(vec![
+                Flag::Deleted,
+                Flag::Keyword("$Junk".to_owned())
+            ])
+        )),
+        Bytes::from("* OK [PERMANENTFLAGS (\\Deleted $Junk \\*)] \r\n")
+    );
+    assert_eq!(
+        Bytes::from(IMAPResponse::data("3 EXISTS")),
+        Bytes::from("* 3 EXISTS\r\n")
+    );
+    assert_eq!(
+        Bytes::from(IMAPResponse::Continuation("".to_owned())),
+        Bytes::from("+ \r\n")
+    );
+}
+
+#[test]
+fn format_strings() {
+    assert_eq!(string("hello"), "\"hello\"");
+    assert_eq!(string("say \"hi\" \\"), "\"say \\\"hi\\\" \\\\\"");
+    assert_eq!(string("two\r\nlines"), "{10}\r\ntwo\r\nlines");
+    assert_eq!(string("h\u{e9}llo"), "{6}\r\nh\u{e9}llo");
+    assert_eq!(nstring(None), "NIL");
+}
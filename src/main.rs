@@ -1,52 +1,61 @@
+mod acme;
 mod cli;
 mod config;
 mod config_editor;
 mod config_helpers;
 mod connection_handler;
 mod database;
+mod dkim;
+mod events;
+mod imap;
 mod imf;
 mod pop3;
+mod sasl;
 mod smtp;
+mod tls;
+
+#[cfg(test)]
+mod testing;
 
 use cli::*;
 use config::*;
 
 use crossterm::style::Stylize;
 
-use connection_handler::ConnectionHandler;
+use connection_handler::{ConnectionHandler, TlsMode};
 use database::user_database::*;
+use imap::IMAPConnection;
 use lazy_static::lazy_static;
-use log::{info, warn};
 use pop3::POP3Connection;
-use smtp::IncomingSMTPConnection;
-use std::{
-    env::{self, current_exe},
-    fs,
-};
-
-use trust_dns_resolver::config::*;
-use trust_dns_resolver::{TokioAsyncResolver, TokioHandle};
+use smtp::{start_queue_worker, IncomingSMTPConnection};
 
+#[cfg(not(test))]
 lazy_static! {
     // Load the configuration into a global static variable
     static ref CONFIG: Config = {
-        let config_path = match env::var("CONFIG_PATH") {
+        let config_path = match std::env::var("CONFIG_PATH") {
             Ok(path) => path,
             Err(_) => {
                 // Look for the file in the same working directory as the executable
-                let mut path = current_exe().unwrap();
+                let mut path = std::env::current_exe().unwrap();
                 path.set_file_name("config.toml");
                 path.as_path().to_str().unwrap().to_owned()
             }
         };
 
         toml::from_str(
-            fs::read_to_string(&config_path)
+            std::fs::read_to_string(&config_path)
             .expect(&format!("Couldn't find config file at {}", &config_path)).as_str()
         ).expect("Invalid configuration")
     };
 }
 
+#[cfg(test)]
+lazy_static! {
+    // Tests use a configuration of their own, with a new database
+    static ref CONFIG: Config = testing::config();
+}
+
 #[tokio::main]
 async fn main() {
     // Use clap to parse the command line arguments
@@ -58,6 +67,9 @@ async fn main() {
         None => run().await,
         Some(s) => match s {
             ("config", _args) => config_editor::run_config_editor(),
+            ("apop", args) => configure_apop(args).await,
+            ("queue", _args) => print_queue().await,
+            ("changes", args) => print_changes(args).await,
             (s, _args) => panic!("Subcommand {} not recognized", s),
         },
     }
@@ -77,33 +89,69 @@ async fn run() {
         return;
     }
 
+    let tls = match tls::acceptor(&CONFIG.domains) {
+        Ok(tls) => tls,
+        Err(e) => {
+            println!("Couldn't load TLS certificate: {}. Exiting.", e);
+            return;
+        }
+    };
+
+    dkim::load_keys();
+
     initialize_db().await.unwrap();
 
-    print_gmail_mx_record().await;
+    // POP3 and SMTP clients can upgrade to TLS with STLS and STARTTLS
+    let starttls = match &tls {
+        Some(tls) => TlsMode::StartTls(tls.clone()),
+        None => TlsMode::Disabled,
+    };
 
-    let pop3_handle = POP3Connection::start_listening(110).await;
+    let pop3_handle = POP3Connection::start_listening(110, starttls.clone()).await;
 
-    let smtp_handle = IncomingSMTPConnection::start_listening(3309).await;
+    let smtp_handle = IncomingSMTPConnection::start_listening(3309, starttls).await;
 
-    // Wait for the threads to finish
-    pop3_handle.await.unwrap();
-    smtp_handle.await.unwrap();
-}
+    let imap_handle = IMAPConnection::start_listening(143, TlsMode::Disabled).await;
+
+    let queue_handle = start_queue_worker();
+
+    let log_handle = events::add_subscriber("log", |event| async move {
+        log::trace!("{}", event);
+    });
+
+    let mut handles = vec![
+        pop3_handle,
+        smtp_handle,
+        imap_handle,
+        queue_handle,
+        log_handle,
+    ];
+
+    // Implicit TLS ports, if any domain has a certificate
+    if let Some(tls) = tls {
+        handles.push(POP3Connection::start_listening(995, TlsMode::Implicit(tls.clone())).await);
+        handles.push(
+            IncomingSMTPConnection::start_listening(465, TlsMode::Implicit(tls.clone())).await,
+        );
+        handles.push(IMAPConnection::start_listening(993, TlsMode::Implicit(tls)).await);
 
-/// This function serves no purpose. It will eventually be deleted.
-async fn print_gmail_mx_record() {
-    let dns_resolver = TokioAsyncResolver::new(
-        ResolverConfig::default(),
-        ResolverOpts::default(),
-        TokioHandle,
-    )
-    .unwrap();
-
-    let response = dns_resolver.mx_lookup("gmail.com").await.unwrap();
-    for addr in response.iter() {
-        warn!("{:?}", addr);
+        // Pick up certificates that are renewed on disk
+        handles.push(tls::start_certificate_reloader());
+    }
+
+    // Certificates from the ACME server, and the HTTP server that proves
+    // to it that we control the domains
+    if acme::is_enabled() {
+        handles.push(
+            acme::Http01Connection::start_listening(CONFIG.acme.http_port, TlsMode::Disabled).await,
+        );
+        handles.push(acme::start_acme_worker());
+    }
+
+    // Wait for the threads to finish
+    for handle in handles {
+        handle.await.unwrap();
     }
-    info!("Done printing mx record");
 }
 
 fn init_logger() {
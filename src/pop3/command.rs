@@ -57,6 +57,17 @@ pub enum POP3Command {
 
     /// `APOP`; A more secure authentication method.
     APop { username: Bytes, md5_digest: Bytes },
+
+    /// `AUTH`; Authenticate with a SASL mechanism (RFC 5034). The client
+    /// can send a base64 encoded initial response with the command.
+    Auth {
+        mechanism: Bytes,
+        initial_response: Option<Bytes>,
+    },
+
+    /// `STLS`; Start TLS on the connection (RFC 2595 section 4). Only
+    /// allowed in AUTHORIZATION state.
+    Stls,
 }
 
 impl POP3Command {
@@ -147,6 +158,11 @@ impl TryFrom<Bytes> for POP3Command {
                     username: bytes_arg(1)?,
                     md5_digest: bytes_arg(2)?,
                 },
+                b"AUTH" => Auth {
+                    mechanism: bytes_arg(1)?,
+                    initial_response: bytes_arg(2).ok(),
+                },
+                b"STLS" => Stls,
                 _ => return Err(UnknownCommand(s.clone())),
             },
             None => return Err(InvalidSyntax),
@@ -242,6 +258,24 @@ fn parse_command() {
             md5_digest: "c4c9334bac560ecc979e58001b3e22fb".into(),
         }
     );
+    assert_eq!(
+        POP3Command::try_from(Bytes::from("AUTH PLAIN\r\n")).unwrap(),
+        Auth {
+            mechanism: "PLAIN".into(),
+            initial_response: None,
+        }
+    );
+    assert_eq!(
+        POP3Command::try_from(Bytes::from("AUTH PLAIN dGVzdAB0ZXN0AHRlc3Q=\r\n")).unwrap(),
+        Auth {
+            mechanism: "PLAIN".into(),
+            initial_response: Some("dGVzdAB0ZXN0AHRlc3Q=".into()),
+        }
+    );
+    assert_eq!(
+        POP3Command::try_from(Bytes::from("stls\r\n")).unwrap(),
+        Stls
+    );
 }
 
 #[test]
@@ -1,32 +1,71 @@
-//! Manages the POP3 connection by wrapping a TCP stream and parsing
+//! Manages the POP3 connection by wrapping a TCP or TLS stream and parsing
 //! every command from the client. Also responsible for sending responses
 //! from the server.
 
 use std::error::Error;
+use std::net::SocketAddr;
 use std::str::{self, FromStr};
 
 use bytes::{Bytes, BytesMut};
 use email_address::EmailAddress;
-use log::trace;
+use log::{trace, warn};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
 
-use crate::connection_handler::ConnectionHandler;
-use crate::pop3::{err::POP3CommandErr, POP3Command, POP3Response};
+use crate::config_helpers::{domain_config, requires_tls};
+use crate::connection_handler::{start_tls, AsyncStream, ConnectionHandler, TlsState};
+use crate::pop3::{err::POP3CommandErr, sessions, POP3Command, POP3Response, POP3ResponseCode};
+use crate::sasl::{self, SaslError};
+use crate::CONFIG;
 use POP3Command::*;
 
 // use std::future::Future;
 
 use crate::database::*;
 
+/// The longest command line accepted from the client, including the CRLF.
+/// RFC 2449 section 4 allows commands of up to 255 octets.
+const MAX_COMMAND_LINE: usize = 512;
+
+/// The longest response to a SASL challenge accepted from the client,
+/// including the CRLF. RFC 5034 section 4 doesn't limit their length.
+const MAX_AUTH_LINE: usize = 12288;
+
+/// How long to wait for the client to send a command before closing the
+/// connection. RFC 1939 section 3 requires at least 10 minutes.
+const AUTOLOGOUT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
 pub struct POP3Connection {
     // Socket state
-    stream: TcpStream,
+    stream: Box<dyn AsyncStream>,
     buffer: BytesMut,
 
+    /// Whether the connection is encrypted, or can be with `STLS`
+    tls: TlsState,
+
     // Connection state
+    /// The mailbox given with `USER`, waiting for `PASS`
     username: Option<EmailAddress>,
     user: Option<user::Model>,
+    /// The failed logins on this connection
+    logins: sasl::LoginAttempts,
+    /// The timestamp sent in the greeting, which the client hashes with
+    /// its secret for APOP
+    timestamp: String,
+
+    /// A snapshot of the user's maildrop, taken when the TRANSACTION state
+    /// begins. Message number `n` is `maildrop[n - 1]`.
+    maildrop: Vec<mail::Model>,
+    /// `deleted[n - 1]` is true if message `n` has been marked as deleted
+    deleted: Vec<bool>,
+    /// `retrieved[n - 1]` is true if message `n` has been downloaded with
+    /// `RETR` during this session
+    retrieved: Vec<bool>,
+    /// How many days the user's messages are kept after they are
+    /// downloaded. `None` if they are kept forever.
+    expire: Option<u32>,
+    /// Held for as long as the session is in the TRANSACTION state
+    maildrop_lock: Option<sessions::MaildropLock>,
 }
 
 impl ConnectionHandler for POP3Connection {
@@ -34,12 +73,20 @@ impl ConnectionHandler for POP3Connection {
         "POP3"
     }
 
-    fn from_stream(socket: TcpStream) -> Self {
+    fn from_stream<S: AsyncStream + 'static>(stream: S, peer: SocketAddr, tls: TlsState) -> Self {
         Self {
-            stream: socket,
+            tls,
+            stream: Box::new(stream),
             buffer: BytesMut::new(),
             username: None,
             user: None,
+            logins: sasl::LoginAttempts::new(peer.ip()),
+            timestamp: sasl::generate_timestamp(),
+            maildrop: vec![],
+            deleted: vec![],
+            retrieved: vec![],
+            expire: None,
+            maildrop_lock: None,
         }
     }
 
@@ -58,6 +105,23 @@ impl ConnectionHandler for POP3Connection {
     }
 }
 
+/// SASL challenges are sent as "+ " followed by the base64 encoded
+/// challenge (RFC 5034 section 4)
+impl sasl::Transport for POP3Connection {
+    async fn send_challenge(&mut self, challenge: &str) -> io::Result<()> {
+        let line = format!("+ {}\r\n", challenge);
+        self.stream.write_all(line.as_bytes()).await
+    }
+
+    async fn read_response(&mut self) -> io::Result<String> {
+        self.read_line().await
+    }
+
+    fn login_attempts(&mut self) -> &mut sasl::LoginAttempts {
+        &mut self.logins
+    }
+}
+
 impl POP3Connection {
     /// Send a response or greeting to the client
     pub async fn send_response(&mut self, response: POP3Response) -> Result<(), io::Error> {
@@ -67,140 +131,566 @@ impl POP3Connection {
         Ok(())
     }
 
-    /// Read a POP3Command from the client.
+    /// Read a POP3Command from the client. Commands that can't be parsed
+    /// are answered with a negative response and skipped.
     ///
-    /// If the client sends two commands in a row without the server
-    /// calling this method, Things Will Break.
+    /// Only one line is taken from the buffer at a time, so the client can
+    /// send several commands without waiting for the responses (the
+    /// PIPELINING capability from RFC 2449).
     pub async fn read_command(&mut self) -> Result<POP3Command, io::Error> {
         loop {
-            // Write the bytes from the client into the buffer
-            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+            let line = self.read_raw_line(MAX_COMMAND_LINE).await?;
+
+            let message = match POP3Command::parse(line) {
+                Ok(command) => return Ok(command),
+                Err(POP3CommandErr::UnknownCommand(_)) => "unknown command",
+                Err(POP3CommandErr::InvalidArguments) => "invalid arguments",
+                Err(_) => "invalid command syntax",
+            };
+            self.send_response(POP3Response::negative(message)).await?;
+        }
+    }
+
+    /// Read a line from the client, without the CRLF. Used for the
+    /// client's responses during a SASL exchange, which aren't commands.
+    async fn read_line(&mut self) -> Result<String, io::Error> {
+        let line = self.read_raw_line(MAX_AUTH_LINE).await?;
+        Ok(String::from_utf8_lossy(&line[..line.len() - 2]).into_owned())
+    }
+
+    /// Take the next line from the buffer, including the CRLF, reading
+    /// from the client until there is one.
+    ///
+    /// If the line is longer than `max_len`, the client is told so and the
+    /// connection is closed. If the client doesn't send anything for
+    /// `AUTOLOGOUT_TIMEOUT`, the connection is closed without a response
+    /// and without removing any messages (RFC 1939 section 3).
+    async fn read_raw_line(&mut self, max_len: usize) -> Result<Bytes, io::Error> {
+        loop {
+            let end = self.buffer.windows(2).position(|w| w == b"\r\n");
+            let len = end.map_or(self.buffer.len(), |end| end + 2);
+
+            if len > max_len {
+                self.send_response(POP3Response::negative("line too long"))
+                    .await?;
                 self.close().await?;
-                return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
+                return Err(io::Error::from(io::ErrorKind::InvalidData));
             }
 
-            match POP3Command::parse(self.buffer.clone().freeze()) {
-                Ok(command) => {
-                    self.buffer.clear();
-                    return Ok(command);
+            if end.is_some() {
+                return Ok(self.buffer.split_to(len).freeze());
+            }
+
+            match timeout(AUTOLOGOUT_TIMEOUT, self.stream.read_buf(&mut self.buffer)).await {
+                Ok(Ok(0)) => {
+                    self.close().await?;
+                    return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
                 }
-                Err(POP3CommandErr::IncompleteResponse) => (),
-                Err(POP3CommandErr::UnknownCommand(_)) => {
-                    self.buffer.clear();
-                    self.send_response(POP3Response::negative("unknown command"))
-                        .await?;
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    self.close().await?;
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
                 }
-                Err(_) => self.buffer.clear(),
-            };
+            }
         }
     }
 
     /// Authentication phase of the POP3 connection. During this phase, the
     /// server verifies the identity of the client.
     ///
-    /// TODO: return authenticated user information
+    /// Returns `true` if the client logged in and the maildrop was opened.
     pub async fn authenticate(&mut self) -> Result<bool, Box<dyn Error>> {
-        // Greet the client
-        self.send_response(POP3Response::positive("hello")).await?;
+        // Greet the client. The timestamp is needed for APOP
+        self.send_response(POP3Response::positive(format!(
+            "mailroom POP3 server ready {}",
+            self.timestamp
+        )))
+        .await?;
 
         loop {
             let command = self.read_command().await?;
 
-            // Handle the commands valid in the authentication state
-            match command {
+            // Handle the commands valid in the authentication state. The
+            // commands that log in give the user if the credentials were
+            // correct.
+            let login = match command {
                 Username { username } => {
-                    // Parse the bytes into a string and remember them
-                    self.username = match str::from_utf8(&username) {
-                        Ok(s) => EmailAddress::from_str(s).ok(),
-                        Err(_) => None,
+                    // Parse the bytes into an address and remember it
+                    self.username = str::from_utf8(&username)
+                        .ok()
+                        .and_then(|s| EmailAddress::from_str(s).ok());
+
+                    // Refuse before the client sends its password in plain text
+                    let response = match &self.username {
+                        Some(address) if self.tls_required(address) => {
+                            self.username = None;
+                            tls_required_response()
+                        }
+                        Some(_) => POP3Response::positive(""),
+                        None => POP3Response::negative("username must be an email address"),
                     };
-                    self.send_response(POP3Response::positive("")).await?
+                    self.send_response(response).await?;
+                    continue;
                 }
                 Password { password } => {
-                    if let Ok(password) = str::from_utf8(&password) {
-                        // Check the username and password combination
-                        self.user = user_database::authenticate_user(
-                            &self.username.as_ref().unwrap(),
-                            password,
-                        )
-                        .await?;
-
-                        // User is authenticated, so exit the authentication phase
-                        if self.user.is_some() {
-                            self.send_response(POP3Response::positive("Authenticated"))
+                    // The client has to send USER again after a failed PASS
+                    let address = match self.username.take() {
+                        Some(address) => address,
+                        None => {
+                            self.send_response(POP3Response::negative("send USER first"))
                                 .await?;
-                            return Ok(true);
+                            continue;
                         }
-                    }
+                    };
 
-                    self.send_response(POP3Response::negative("Username or password is not valid"))
-                        .await?;
+                    let password = str::from_utf8(&password).ok();
+                    self.logins
+                        .check(Some(&address), async {
+                            match password {
+                                Some(password) => {
+                                    user_database::authenticate_user(&address, password).await
+                                }
+                                None => Ok(None),
+                            }
+                        })
+                        .await
                 }
                 APop {
-                    username: _,
-                    md5_digest: _,
+                    username,
+                    md5_digest,
                 } => {
-                    self.send_response(POP3Response::positive("")).await?;
-                    return Ok(false);
+                    let address = str::from_utf8(&username)
+                        .ok()
+                        .and_then(|s| EmailAddress::from_str(s).ok());
+                    let digest = str::from_utf8(&md5_digest).ok();
+
+                    let timestamp = &self.timestamp;
+                    self.logins
+                        .check(address.as_ref(), async {
+                            match (&address, digest) {
+                                (Some(address), Some(digest)) => {
+                                    user_database::authenticate_user_apop(
+                                        address, timestamp, digest,
+                                    )
+                                    .await
+                                }
+                                _ => Ok(None),
+                            }
+                        })
+                        .await
+                }
+                Auth {
+                    mechanism,
+                    initial_response,
+                } => {
+                    let mechanism = String::from_utf8_lossy(&mechanism).into_owned();
+                    let initial_response =
+                        initial_response.map(|r| String::from_utf8_lossy(&r).into_owned());
+
+                    let encrypted = self.tls.is_active();
+                    sasl::authenticate(self, &mechanism, initial_response.as_deref(), encrypted)
+                        .await
                 }
                 Quit => {
+                    self.send_response(POP3Response::positive("signing off"))
+                        .await?;
                     self.close().await?;
                     return Ok(false);
                 }
-                // TODO: Update CAPA list as more features are implemented
                 Capabilities => {
-                    self.send_response(POP3Response::positive("\r\nUSER"))
-                        .await?
+                    self.send_response(self.capabilities()).await?;
+                    continue;
+                }
+                Stls => {
+                    let acceptor = match &self.tls {
+                        TlsState::Available(acceptor) => acceptor.clone(),
+                        TlsState::Active => {
+                            self.send_response(POP3Response::negative("TLS already started"))
+                                .await?;
+                            continue;
+                        }
+                        TlsState::Unavailable => {
+                            self.send_response(POP3Response::negative("TLS not available"))
+                                .await?;
+                            continue;
+                        }
+                    };
+
+                    // Anything the client sent before the handshake could
+                    // have been injected by an attacker, so throw it away
+                    // (RFC 2595 section 4)
+                    self.buffer.clear();
+                    self.send_response(POP3Response::positive("begin TLS negotiation"))
+                        .await?;
+                    start_tls(&mut self.stream, &acceptor).await?;
+                    self.tls = TlsState::Active;
+                    self.username = None;
+                    continue;
                 }
                 _ => {
                     self.send_response(POP3Response::negative(
                         "command not valid during authentication",
                     ))
-                    .await?
+                    .await?;
+                    continue;
+                }
+            };
+
+            let user = match login {
+                Ok(user) => user,
+                Err(SaslError::Io(e)) => return Err(e.into()),
+                Err(SaslError::Database(e)) => return Err(e.into()),
+                Err(SaslError::EncryptionRequired) => {
+                    self.send_response(tls_required_response()).await?;
+                    continue;
+                }
+                Err(e) => {
+                    let response = match e {
+                        SaslError::UnsupportedMechanism => {
+                            POP3Response::negative("unsupported authentication mechanism")
+                        }
+                        SaslError::Cancelled => POP3Response::negative("authentication cancelled"),
+                        SaslError::MalformedResponse => {
+                            POP3Response::negative("invalid authentication response")
+                        }
+                        SaslError::LockedOut => POP3Response::negative_with_code(
+                            POP3ResponseCode::SysTemp,
+                            "too many failed logins, try again later",
+                        ),
+                        _ => POP3Response::negative_with_code(
+                            POP3ResponseCode::Auth,
+                            "authentication failed",
+                        ),
+                    };
+
+                    if self.logins.exhausted() {
+                        self.send_response(POP3Response::negative(
+                            "too many failed logins, closing connection",
+                        ))
+                        .await?;
+                        self.close().await?;
+                        return Ok(false);
+                    }
+
+                    self.send_response(response).await?;
+                    continue;
                 }
+            };
+
+            // The login is only recorded once the maildrop is open, so a
+            // login that fails with [IN-USE] doesn't start the delay
+            let login_delay = Duration::from_secs(CONFIG.login.login_delay);
+            if !login_delay.is_zero()
+                && sessions::logged_in_within(&user.email_address, login_delay)
+            {
+                self.send_response(POP3Response::negative_with_code(
+                    POP3ResponseCode::LoginDelay,
+                    "logged in too recently, try again later",
+                ))
+                .await?;
+                continue;
+            }
+
+            return Ok(self.open_maildrop(user).await?);
+        }
+    }
+
+    /// Check whether `account` belongs to a domain that only allows logins
+    /// over TLS, and the connection isn't encrypted
+    fn tls_required(&self, account: &EmailAddress) -> bool {
+        !self.tls.is_active() && requires_tls(account.domain())
+    }
+
+    /// Take a snapshot of the authenticated user's maildrop and tell the
+    /// client whether it worked. Returns `true` if the connection can move
+    /// on to the TRANSACTION state.
+    async fn open_maildrop(&mut self, user: user::Model) -> Result<bool, io::Error> {
+        let address = EmailAddress::new_unchecked(&user.email_address);
+
+        // Only one session at a time can have the maildrop open
+        self.maildrop_lock = sessions::MaildropLock::acquire(address.as_str());
+        if self.maildrop_lock.is_none() {
+            self.send_response(POP3Response::negative_with_code(
+                POP3ResponseCode::InUse,
+                "maildrop already locked",
+            ))
+            .await?;
+            return Ok(false);
+        }
+
+        // Remove the messages that have expired since the last session
+        self.expire = domain_config(address.domain()).and_then(|d| d.expire);
+        if let Some(days) = self.expire.filter(|days| *days > 0) {
+            let cutoff = chrono::Utc::now().timestamp() - i64::from(days) * 24 * 60 * 60;
+            if let Err(e) = mail_database::expire_messages(&address, cutoff).await {
+                warn!("Couldn't expire messages for {}: {}", address, e);
             }
         }
+
+        self.maildrop = match mail_database::get_mailbox(&address).await {
+            Ok(maildrop) => maildrop,
+            Err(e) => {
+                warn!("Couldn't open maildrop for {}: {}", address, e);
+                self.maildrop_lock = None;
+                self.send_response(POP3Response::negative_with_code(
+                    POP3ResponseCode::SysTemp,
+                    "unable to open maildrop",
+                ))
+                .await?;
+                return Ok(false);
+            }
+        };
+        sessions::record_login(&user.email_address);
+        self.user = Some(user);
+
+        self.deleted = vec![false; self.maildrop.len()];
+        self.retrieved = vec![false; self.maildrop.len()];
+
+        self.send_response(POP3Response::positive(format!(
+            "maildrop has {} messages",
+            self.maildrop.len()
+        )))
+        .await?;
+        Ok(true)
     }
 
     pub async fn transaction(&mut self) -> Result<(), io::Error> {
         loop {
             let command = self.read_command().await?;
 
-            self.send_response(match command {
+            let response = match command {
                 Stat => self.stat(),
-                List { message_number: _ } => self.list(),
-                Retrieve { message_number: _ } => self.retrieve(),
-                Delete { message_number: _ } => POP3Response::positive(""),
+                List { message_number } => self.list(message_number),
+                Retrieve { message_number } => self.retrieve(message_number).await,
+                Delete { message_number } => self.delete(message_number),
                 NoOp => POP3Response::positive(""),
-                Reset => POP3Response::positive(""),
+                Reset => self.reset(),
                 Quit => {
-                    // TODO: delete messages marked for deletion
+                    let response = self.update().await;
+                    self.maildrop_lock = None;
+                    self.send_response(response).await?;
                     self.close().await?;
                     return Ok(());
                 }
-                Top {
-                    message_number: _,
-                    n: _,
-                } => POP3Response::negative("unsupported"),
-                UniqueIDListing { message_number: _ } => POP3Response::negative("unsupported"),
-                // TODO: Update CAPA list as more features are implemented
-                Capabilities => POP3Response::positive("\r\nUSER"),
+                Top { message_number, n } => self.top(message_number, n),
+                UniqueIDListing { message_number } => self.unique_id_listing(message_number),
+                Capabilities => self.capabilities(),
                 _ => POP3Response::negative("command not valid during transaction"),
-            })
-            .await?;
+            };
+
+            self.send_response(response).await?;
+        }
+    }
+
+    /// Get a message from the maildrop by its message number. Message
+    /// numbers start at 1. Messages marked as deleted can't be accessed.
+    fn message(&self, message_number: usize) -> Option<&mail::Model> {
+        let index = message_number.checked_sub(1)?;
+        match self.deleted.get(index) {
+            Some(false) => self.maildrop.get(index),
+            _ => None,
         }
     }
 
+    /// Iterate over the messages that aren't marked as deleted, along with
+    /// their message numbers
+    fn messages(&self) -> impl Iterator<Item = (usize, &mail::Model)> {
+        self.maildrop
+            .iter()
+            .zip(&self.deleted)
+            .enumerate()
+            .filter(|(_, (_, deleted))| !**deleted)
+            .map(|(i, (m, _))| (i + 1, m))
+    }
+
+    /// The number of messages that aren't marked as deleted and their
+    /// total size
+    fn maildrop_size(&self) -> (usize, usize) {
+        self.messages().fold((0, 0), |(count, size), (_, m)| {
+            (count + 1, size + message_size(&m.content))
+        })
+    }
+
     fn stat(&self) -> POP3Response {
-        POP3Response::positive("1 100")
+        let (count, size) = self.maildrop_size();
+        POP3Response::positive(format!("{} {}", count, size))
     }
 
-    fn list(&self) -> POP3Response {
-        POP3Response::positive("\r\n1 100")
+    fn list(&self, message_number: Option<usize>) -> POP3Response {
+        match message_number {
+            Some(n) => match self.message(n) {
+                Some(m) => POP3Response::positive(format!("{} {}", n, message_size(&m.content))),
+                None => POP3Response::negative("no such message"),
+            },
+            None => {
+                let mut listing = String::new();
+                for (n, m) in self.messages() {
+                    listing += &format!("{} {}\r\n", n, message_size(&m.content));
+                }
+
+                let (count, size) = self.maildrop_size();
+                POP3Response::multiline(format!("{} messages ({} octets)", count, size), &listing)
+            }
+        }
     }
 
-    fn retrieve(&self) -> POP3Response {
-        POP3Response::positive("\r\nTo: mghebrialjr@localhost\r\nSubject: this is a message")
+    /// Send a whole message. The message gets the `\Seen` flag, so that
+    /// IMAP clients know that it has been read.
+    async fn retrieve(&mut self, message_number: usize) -> POP3Response {
+        let (response, message_id) = match self.message(message_number) {
+            Some(m) => (
+                POP3Response::multiline(format!("{} octets", message_size(&m.content)), &m.content),
+                m.message_id.clone(),
+            ),
+            None => return POP3Response::negative("no such message"),
+        };
+
+        if let Err(e) =
+            flag_database::add_flags(&[message_id], &[flag_database::SEEN.to_owned()]).await
+        {
+            warn!("Couldn't mark message as seen: {}", e);
+        }
+
+        self.retrieved[message_number - 1] = true;
+        response
+    }
+
+    /// Send the header of a message and the first `n` lines of its body
+    fn top(&self, message_number: usize, n: usize) -> POP3Response {
+        let content = match self.message(message_number) {
+            Some(m) => &m.content,
+            None => return POP3Response::negative("no such message"),
+        };
+
+        let mut out = String::new();
+        let mut lines = content.split_inclusive('\n');
+
+        // The header ends at the first empty line
+        for line in lines.by_ref() {
+            out += line;
+            if line.trim_end_matches(['\r', '\n']).is_empty() {
+                break;
+            }
+        }
+        for line in lines.take(n) {
+            out += line;
+        }
+
+        POP3Response::multiline("top of message follows", &out)
+    }
+
+    /// Get the unique-id of a message, or of every message (RFC 1939
+    /// section 7)
+    fn unique_id_listing(&self, message_number: Option<usize>) -> POP3Response {
+        match message_number {
+            Some(n) => match self.message(n) {
+                Some(m) => POP3Response::positive(format!("{} {}", n, m.uid)),
+                None => POP3Response::negative("no such message"),
+            },
+            None => {
+                let mut listing = String::new();
+                for (n, m) in self.messages() {
+                    listing += &format!("{} {}\r\n", n, m.uid);
+                }
+
+                POP3Response::multiline("unique-id listing follows", &listing)
+            }
+        }
+    }
+
+    /// Mark a message as deleted. It isn't removed from the database until
+    /// the client sends `QUIT`.
+    fn delete(&mut self, message_number: usize) -> POP3Response {
+        if self.message(message_number).is_none() {
+            return POP3Response::negative("no such message");
+        }
+
+        self.deleted[message_number - 1] = true;
+        POP3Response::positive(format!("message {} deleted", message_number))
+    }
+
+    /// Unmark every message marked as deleted
+    fn reset(&mut self) -> POP3Response {
+        self.deleted.fill(false);
+
+        let (count, size) = self.maildrop_size();
+        POP3Response::positive(format!("maildrop has {} messages ({} octets)", count, size))
+    }
+
+    /// The UPDATE state (RFC 1939 section 6). Remove the messages marked as
+    /// deleted from the database, either all of them or none of them.
+    async fn update(&mut self) -> POP3Response {
+        // With EXPIRE 0, every message that was downloaded is deleted
+        // (RFC 2449 section 6.7)
+        let expire_now = self.expire == Some(0);
+
+        let mut deleted_ids = vec![];
+        let mut retrieved_ids = vec![];
+        for ((m, deleted), retrieved) in
+            self.maildrop.iter().zip(&self.deleted).zip(&self.retrieved)
+        {
+            if *deleted || (expire_now && *retrieved) {
+                deleted_ids.push(m.message_id.clone());
+            } else if *retrieved {
+                retrieved_ids.push(m.message_id.clone());
+            }
+        }
+
+        if let Err(e) = mail_database::delete_messages(&deleted_ids).await {
+            warn!("Couldn't delete messages: {}", e);
+            return POP3Response::negative_with_code(
+                POP3ResponseCode::SysTemp,
+                "some deleted messages not removed",
+            );
+        }
+
+        // Start counting down to the messages' expiry
+        if let Err(e) = mail_database::mark_retrieved(&retrieved_ids).await {
+            warn!("Couldn't mark messages as retrieved: {}", e);
+        }
+
+        POP3Response::positive(format!(
+            "signing off ({} messages left)",
+            self.maildrop.len() - deleted_ids.len()
+        ))
+    }
+
+    /// List the extensions supported by this server (RFC 2449). Some of
+    /// them depend on the configuration and on whether the client has
+    /// logged in.
+    fn capabilities(&self) -> POP3Response {
+        let mut capabilities = vec![
+            "TOP".to_owned(),
+            "UIDL".to_owned(),
+            "RESP-CODES".to_owned(),
+            "AUTH-RESP-CODE".to_owned(),
+            "PIPELINING".to_owned(),
+        ];
+
+        match self.user {
+            // AUTHORIZATION state. The EXPIRE policy depends on the user's
+            // domain, which isn't known yet
+            None => {
+                if let TlsState::Available(_) = self.tls {
+                    capabilities.push("STLS".to_owned());
+                }
+                capabilities.push("USER".to_owned());
+                capabilities.push(format!("SASL {}", sasl::MECHANISMS.join(" ")));
+                let policies: Vec<Option<u32>> = CONFIG.domains.iter().map(|d| d.expire).collect();
+                capabilities.push(expire_capability(&policies));
+            }
+            // TRANSACTION state
+            Some(_) => capabilities.push(expire_capability(&[self.expire])),
+        }
+
+        if CONFIG.login.login_delay > 0 {
+            capabilities.push(format!("LOGIN-DELAY {}", CONFIG.login.login_delay));
+        }
+
+        let mut body = capabilities.join("\r\n");
+        body += "\r\n";
+        POP3Response::multiline("Capability list follows", &body)
     }
 
     /// Close the connection
@@ -209,3 +699,292 @@ impl POP3Connection {
         Ok(())
     }
 }
+
+/// The response to a login to an account that is only allowed over TLS
+fn tls_required_response() -> POP3Response {
+    POP3Response::negative_with_code(POP3ResponseCode::Auth, "TLS required, use STLS first")
+}
+
+/// The EXPIRE capability for a set of expiry policies, in days (`None`
+/// means never). If the policies differ, the shortest one is given with
+/// the "USER" tag, which tells the client that the policy depends on the
+/// user.
+fn expire_capability(policies: &[Option<u32>]) -> String {
+    let shortest = policies.iter().flatten().min();
+    let value = match shortest {
+        Some(days) => days.to_string(),
+        None => "NEVER".to_owned(),
+    };
+
+    if policies.windows(2).any(|w| w[0] != w[1]) {
+        format!("EXPIRE {} USER", value)
+    } else {
+        format!("EXPIRE {}", value)
+    }
+}
+
+/// The size of a message in octets, exactly as RETR sends it: every line
+/// ends in CRLF, including the last one, and lines that start with "."
+/// are byte-stuffed. The empty first line of the response isn't counted.
+fn message_size(content: &str) -> usize {
+    POP3Response::multiline("", content).message.len() - "\r\n".len()
+}
+
+#[test]
+fn message_size_counts_crlf() {
+    assert_eq!(message_size(""), 0);
+    assert_eq!(message_size("Subject: hi\r\n\r\nhello\r\n"), 22);
+    assert_eq!(message_size("Subject: hi\n\nhello\n"), 22);
+    // The last line gets a CRLF when it's sent
+    assert_eq!(message_size("Subject: hi\r\n\r\nhello"), 22);
+    // The "." is doubled
+    assert_eq!(message_size("Subject: hi\n\n.hello\n"), 24);
+}
+
+#[test]
+fn expire_capability_policies() {
+    assert_eq!(expire_capability(&[]), "EXPIRE NEVER");
+    assert_eq!(expire_capability(&[None, None]), "EXPIRE NEVER");
+    assert_eq!(expire_capability(&[Some(0)]), "EXPIRE 0");
+    assert_eq!(expire_capability(&[Some(30), Some(30)]), "EXPIRE 30");
+    assert_eq!(
+        expire_capability(&[None, Some(30), Some(7)]),
+        "EXPIRE 7 USER"
+    );
+}
+
+/// Deliver two messages to `account`, log in over POP3, mark the first
+/// one as deleted, and let `finish` end the session. Returns how many of
+/// the messages are left afterwards.
+#[cfg(test)]
+async fn delete_first_message<F, Fut>(account: &str, finish: F) -> usize
+where
+    F: FnOnce(crate::testing::Client) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    use crate::testing::session;
+
+    let address = EmailAddress::from_str(account).unwrap();
+    for subject in ["one", "two"] {
+        let message = format!("Subject: {}\r\n\r\nhello\r\n", subject);
+        mail_database::deliver_mail(std::slice::from_ref(&address), message)
+            .await
+            .unwrap();
+    }
+
+    session::<POP3Connection, _, _>(TlsState::Unavailable, |mut client| async move {
+        assert!(client.read_line().await.starts_with("+OK "));
+        assert!(client
+            .command(&format!("USER {}", account))
+            .await
+            .starts_with("+OK"));
+        assert_eq!(
+            client.command("PASS password").await,
+            "+OK maildrop has 2 messages"
+        );
+        assert!(client.command("DELE 1").await.starts_with("+OK"));
+        assert_eq!(client.command("STAT").await, "+OK 1 23");
+        finish(client).await;
+    })
+    .await;
+
+    mail_database::get_mailbox(&address).await.unwrap().len()
+}
+
+#[test]
+fn deleted_messages_are_removed_on_quit() {
+    crate::testing::run(async {
+        let remaining = delete_first_message("pop3-quit@example.com", |mut client| async move {
+            assert!(client.command("QUIT").await.starts_with("+OK"));
+        })
+        .await;
+        assert_eq!(remaining, 1);
+    });
+}
+
+#[test]
+fn reset_undeletes_messages() {
+    crate::testing::run(async {
+        let remaining = delete_first_message("pop3-reset@example.com", |mut client| async move {
+            assert!(client.command("RSET").await.starts_with("+OK"));
+            assert_eq!(client.command("STAT").await, "+OK 2 46");
+            assert!(client.command("QUIT").await.starts_with("+OK"));
+        })
+        .await;
+        assert_eq!(remaining, 2);
+    });
+}
+
+#[test]
+fn dropped_connection_keeps_messages() {
+    crate::testing::run(async {
+        // The client goes away without sending QUIT, so the session never
+        // reaches the UPDATE state (RFC 1939 section 6)
+        let remaining = delete_first_message("pop3-dropped@example.com", |client| async move {
+            drop(client);
+        })
+        .await;
+        assert_eq!(remaining, 2);
+    });
+}
+
+#[test]
+fn login_delay_starts_when_maildrop_opens() {
+    use crate::testing::session;
+
+    let login = |expected: &'static str| {
+        session::<POP3Connection, _, _>(TlsState::Unavailable, move |mut client| async move {
+            client.read_line().await;
+            client.command("USER pop3-delay@example.com").await;
+            assert_eq!(client.command("PASS password").await, expected);
+        })
+    };
+
+    crate::testing::run(async {
+        // Another session has the maildrop open
+        let lock = sessions::MaildropLock::acquire("pop3-delay@example.com").unwrap();
+        login("-ERR [IN-USE] maildrop already locked").await;
+        drop(lock);
+
+        login("+OK maildrop has 0 messages").await;
+        login("-ERR [LOGIN-DELAY] logged in too recently, try again later").await;
+    });
+}
+
+#[test]
+fn long_lines_close_the_connection() {
+    use crate::testing::{run, session};
+
+    run(session::<POP3Connection, _, _>(
+        TlsState::Unavailable,
+        |mut client| async move {
+            client.read_line().await;
+            let line = format!("USER {}@example.com", "a".repeat(MAX_COMMAND_LINE));
+            client.send(&line).await;
+            assert_eq!(client.read_line().await, "-ERR line too long");
+            assert_eq!(client.read_line().await, "");
+        },
+    ));
+}
+
+/// Log in to `account` over POP3 and check that its maildrop has `count`
+/// messages
+#[cfg(test)]
+async fn log_in(client: &mut crate::testing::Client, account: &str, count: usize) {
+    assert!(client.read_line().await.starts_with("+OK "));
+    assert!(client
+        .command(&format!("USER {}", account))
+        .await
+        .starts_with("+OK"));
+    assert_eq!(
+        client.command("PASS password").await,
+        format!("+OK maildrop has {} messages", count)
+    );
+}
+
+#[test]
+fn top_sends_header_and_some_body_lines() {
+    use crate::testing::session;
+
+    crate::testing::run(async {
+        let address = EmailAddress::from_str("pop3-top@example.com").unwrap();
+        let message = "Subject: top\r\nFrom: a@example.com\r\n\r\none\r\n.two\r\nthree\r\n";
+        mail_database::deliver_mail(&[address], message.to_owned())
+            .await
+            .unwrap();
+
+        session::<POP3Connection, _, _>(TlsState::Unavailable, |mut client| async move {
+            log_in(&mut client, "pop3-top@example.com", 1).await;
+
+            let header = ["Subject: top", "From: a@example.com", ""];
+            assert_eq!(
+                client.command("TOP 1 0").await,
+                "+OK top of message follows"
+            );
+            assert_eq!(client.read_multiline().await, header);
+
+            // The line that starts with "." is byte-stuffed
+            assert!(client.command("TOP 1 2").await.starts_with("+OK"));
+            assert_eq!(
+                client.read_multiline().await,
+                [&header[..], &["one", "..two"]].concat()
+            );
+
+            // Asking for more lines than there are sends the whole body
+            assert!(client.command("TOP 1 10").await.starts_with("+OK"));
+            assert_eq!(
+                client.read_multiline().await,
+                [&header[..], &["one", "..two", "three"]].concat()
+            );
+
+            assert_eq!(client.command("TOP 2 0").await, "-ERR no such message");
+            assert!(client.command("DELE 1").await.starts_with("+OK"));
+            assert_eq!(client.command("TOP 1 0").await, "-ERR no such message");
+        })
+        .await;
+    });
+}
+
+#[test]
+fn unique_ids_last_between_sessions() {
+    use crate::testing::{session, Client};
+
+    let account = "pop3-uidl@example.com";
+
+    // List the unique IDs, check them against the single-message form,
+    // and check that a deleted message doesn't have one
+    let list = |mut client: Client| async move {
+        log_in(&mut client, account, 2).await;
+
+        assert_eq!(
+            client.command("UIDL").await,
+            "+OK unique-id listing follows"
+        );
+        let listing = client.read_multiline().await;
+        assert_eq!(listing.len(), 2);
+        assert_eq!(
+            client.command("UIDL 1").await,
+            format!("+OK {}", listing[0])
+        );
+        assert_eq!(
+            client.command("UIDL 2").await,
+            format!("+OK {}", listing[1])
+        );
+        assert_ne!(listing[0], listing[1]);
+
+        assert_eq!(client.command("UIDL 3").await, "-ERR no such message");
+        assert!(client.command("DELE 1").await.starts_with("+OK"));
+        assert_eq!(client.command("UIDL 1").await, "-ERR no such message");
+        assert!(client.command("UIDL").await.starts_with("+OK"));
+        assert_eq!(client.read_multiline().await, &listing[1..]);
+
+        // Leave without QUIT, so the message isn't removed
+        listing
+    };
+
+    crate::testing::run(async {
+        let address = EmailAddress::from_str(account).unwrap();
+        for subject in ["one", "two"] {
+            let message = format!("Subject: {}\r\n\r\nhello\r\n", subject);
+            mail_database::deliver_mail(std::slice::from_ref(&address), message)
+                .await
+                .unwrap();
+        }
+
+        let mut first = vec![];
+        session::<POP3Connection, _, _>(TlsState::Unavailable, |client| async {
+            first = list(client).await;
+        })
+        .await;
+
+        sessions::forget_login(account);
+
+        let mut second = vec![];
+        session::<POP3Connection, _, _>(TlsState::Unavailable, |client| async {
+            second = list(client).await;
+        })
+        .await;
+
+        assert_eq!(first, second);
+    });
+}
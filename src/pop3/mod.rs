@@ -8,3 +8,5 @@ pub mod err;
 
 mod response;
 pub use response::*;
+
+mod sessions;
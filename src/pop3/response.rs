@@ -1,5 +1,6 @@
 use crate::pop3::err::POP3ResponseErr;
 use bytes::{Bytes, BytesMut};
+use std::fmt;
 
 use POP3ResponseStatus::*;
 
@@ -14,6 +15,37 @@ pub enum POP3ResponseStatus {
     Negative,
 }
 
+/// Extended response codes, which tell the client why a command failed.
+/// They are sent in square brackets at the start of a negative response.
+/// See RFC 2449 section 8 and RFC 3206.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum POP3ResponseCode {
+    /// `[IN-USE]`; The maildrop is locked by another POP3 session
+    InUse,
+    /// `[LOGIN-DELAY]`; The user logged in too recently
+    LoginDelay,
+    /// `[SYS/TEMP]`; A temporary problem on the server. The client should
+    /// try again later
+    SysTemp,
+    /// `[AUTH]`; The client's credentials are wrong
+    Auth,
+}
+
+impl fmt::Display for POP3ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use POP3ResponseCode::*;
+
+        let code = match self {
+            InUse => "IN-USE",
+            LoginDelay => "LOGIN-DELAY",
+            SysTemp => "SYS/TEMP",
+            Auth => "AUTH",
+        };
+
+        write!(f, "[{}]", code)
+    }
+}
+
 /// Represents a POP3 server response, encapsulating the status indicator
 /// and the message.
 #[derive(PartialEq, Debug)]
@@ -38,6 +70,45 @@ impl POP3Response {
         Self::new(Negative, message.into())
     }
 
+    /// Create a negative POP3Response that starts with an extended
+    /// response code
+    pub fn negative_with_code<T: Into<Bytes>>(code: POP3ResponseCode, message: T) -> Self {
+        let mut out = BytesMut::new();
+        out.extend_from_slice(code.to_string().as_bytes());
+
+        let message = message.into();
+        if !message.is_empty() {
+            out.extend_from_slice(b" ");
+            out.extend_from_slice(&message);
+        }
+
+        Self::negative(out)
+    }
+
+    /// Create a positive multiline POP3Response. `first_line` follows the
+    /// "+OK" and `body` is sent on the lines after it. Lines in `body`
+    /// that start with "." are byte-stuffed (RFC 1939 section 3), and bare
+    /// LFs are converted to CRLF.
+    pub fn multiline<T: Into<Bytes>>(first_line: T, body: &str) -> Self {
+        let mut message = BytesMut::new();
+        message.extend_from_slice(&first_line.into());
+        message.extend_from_slice(b"\r\n");
+
+        if !body.is_empty() {
+            let body = body.strip_suffix('\n').unwrap_or(body);
+            for line in body.split('\n') {
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                if line.starts_with('.') {
+                    message.extend_from_slice(b".");
+                }
+                message.extend_from_slice(line.as_bytes());
+                message.extend_from_slice(b"\r\n");
+            }
+        }
+
+        Self::positive(message)
+    }
+
     /// Parse Bytes into a multiline POP3Response
     pub fn parse_multiline(mut bytes: Bytes) -> Result<Self, POP3ResponseErr> {
         use POP3ResponseErr::*;
@@ -137,7 +208,10 @@ impl From<POP3Response> for Bytes {
         }
 
         // Check if the message is multiline...
-        if contains_crlf(&response.message) {
+        if response.message.ends_with(b"\r\n") {
+            // ...and already ends with a line terminator
+            out.extend_from_slice(b".\r\n");
+        } else if contains_crlf(&response.message) {
             // ...if so, add the multiline terminator
             out.extend_from_slice(b"\r\n.\r\n");
         } else {
@@ -225,4 +299,29 @@ fn pop3_response_to_bytes() {
         Bytes::from(POP3Response::negative("this is a\r\nmultiline test")),
         Bytes::from("-ERR this is a\r\nmultiline test\r\n.\r\n")
     );
+    assert_eq!(
+        Bytes::from(POP3Response::negative_with_code(
+            POP3ResponseCode::InUse,
+            "maildrop already locked"
+        )),
+        Bytes::from("-ERR [IN-USE] maildrop already locked\r\n")
+    );
+    assert_eq!(
+        Bytes::from(POP3Response::negative_with_code(
+            POP3ResponseCode::SysTemp,
+            ""
+        )),
+        Bytes::from("-ERR [SYS/TEMP]\r\n")
+    );
+    assert_eq!(
+        Bytes::from(POP3Response::multiline("0 messages", "")),
+        Bytes::from("+OK 0 messages\r\n.\r\n")
+    );
+    assert_eq!(
+        Bytes::from(POP3Response::multiline(
+            "message follows",
+            "Subject: hi\r\n\r\n.hidden\n..\r\nend\r\n"
+        )),
+        Bytes::from("+OK message follows\r\nSubject: hi\r\n\r\n..hidden\r\n...\r\nend\r\n.\r\n")
+    );
 }
@@ -0,0 +1,96 @@
+//! State that is shared between all the POP3 sessions in this process.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// When each account last logged in
+    static ref LAST_LOGINS: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+
+    /// The accounts whose maildrops are locked by a session
+    static ref LOCKED_MAILDROPS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// An exclusive lock on a user's maildrop. RFC 1939 section 8 requires the
+/// maildrop to be locked for as long as a session is in the TRANSACTION
+/// state, so that two sessions can't delete each other's messages.
+///
+/// The lock is released when it is dropped, which also happens when the
+/// connection is dropped without a `QUIT`.
+#[derive(Debug)]
+pub struct MaildropLock {
+    account: String,
+}
+
+impl MaildropLock {
+    /// Lock the maildrop of `account`. Returns `None` if another session
+    /// already holds the lock.
+    pub fn acquire(account: &str) -> Option<Self> {
+        let account = account.to_ascii_lowercase();
+        if !LOCKED_MAILDROPS.lock().unwrap().insert(account.clone()) {
+            return None;
+        }
+        Some(Self { account })
+    }
+}
+
+impl Drop for MaildropLock {
+    fn drop(&mut self) {
+        LOCKED_MAILDROPS.lock().unwrap().remove(&self.account);
+    }
+}
+
+/// Check whether `account` logged in during the last `delay` (the
+/// LOGIN-DELAY capability from RFC 2449)
+pub fn logged_in_within(account: &str, delay: Duration) -> bool {
+    let mut last_logins = LAST_LOGINS.lock().unwrap();
+    let now = Instant::now();
+
+    // Forget logins that are too old to matter
+    last_logins.retain(|_, last| now.duration_since(*last) < delay);
+
+    last_logins.contains_key(&account.to_ascii_lowercase())
+}
+
+/// Record that `account` logged in just now. Only logins that got as far
+/// as opening the maildrop count.
+pub fn record_login(account: &str) {
+    LAST_LOGINS
+        .lock()
+        .unwrap()
+        .insert(account.to_ascii_lowercase(), Instant::now());
+}
+
+/// Forget that `account` logged in, so that a test can log in again
+/// without waiting for the LOGIN-DELAY
+#[cfg(test)]
+pub fn forget_login(account: &str) {
+    LAST_LOGINS
+        .lock()
+        .unwrap()
+        .remove(&account.to_ascii_lowercase());
+}
+
+#[test]
+fn maildrop_lock_is_exclusive() {
+    let lock = MaildropLock::acquire("tim@example.com").unwrap();
+    assert!(MaildropLock::acquire("Tim@Example.com").is_none());
+    assert!(MaildropLock::acquire("mrose@example.com").is_some());
+
+    drop(lock);
+    assert!(MaildropLock::acquire("tim@example.com").is_some());
+}
+
+#[test]
+fn login_delay_starts_when_recorded() {
+    let delay = Duration::from_secs(60);
+    assert!(!logged_in_within("delay@example.com", delay));
+    assert!(!logged_in_within("delay@example.com", delay));
+
+    record_login("delay@example.com");
+    assert!(logged_in_within("Delay@Example.com", delay));
+    assert!(!logged_in_within("delay@example.com", Duration::ZERO));
+}
@@ -0,0 +1,87 @@
+use std::future::Future;
+use std::net::IpAddr;
+
+use email_address::EmailAddress;
+use log::warn;
+use sea_orm::DbErr;
+use tokio::time::{sleep, Duration};
+
+use super::SaslError;
+use crate::database::{login_database, user};
+use crate::CONFIG;
+
+/// The failed logins on one connection. Every login, whatever the
+/// protocol or command, goes through [`LoginAttempts::check`], so that
+/// the limits in the `[login]` section of the configuration apply to all
+/// of them.
+pub struct LoginAttempts {
+    /// The client's IP address, which failed logins are recorded against
+    ip: IpAddr,
+    /// The number of failed logins on this connection
+    failures: u32,
+}
+
+impl LoginAttempts {
+    pub fn new(ip: IpAddr) -> Self {
+        Self { ip, failures: 0 }
+    }
+
+    /// Log in to `account` if `verify` gives a user. `verify` checks the
+    /// client's credentials, and isn't run if the client is locked out of
+    /// the account. `account` is `None` if the client didn't give a valid
+    /// address.
+    ///
+    /// A failed login is recorded, which can lock the client out of the
+    /// account, and the result is only returned after the failure delay.
+    /// Failures during a lockout don't extend it.
+    pub async fn check<F>(
+        &mut self,
+        account: Option<&EmailAddress>,
+        verify: F,
+    ) -> Result<user::Model, SaslError>
+    where
+        F: Future<Output = Result<Option<user::Model>, DbErr>>,
+    {
+        let locked_out = match account {
+            Some(account) => login_database::is_locked_out(self.ip, account).await?,
+            None => false,
+        };
+
+        let error = if locked_out {
+            SaslError::LockedOut
+        } else {
+            match verify.await? {
+                Some(user) => {
+                    if let Some(account) = account {
+                        login_database::clear_failed_logins(self.ip, account).await?;
+                    }
+                    return Ok(user);
+                }
+                None => {
+                    if let Some(account) = account {
+                        if login_database::record_failed_login(self.ip, account).await? {
+                            warn!(
+                                "Locked {} out of {} after too many failed logins",
+                                self.ip, account
+                            );
+                        }
+                    }
+                    SaslError::AuthenticationFailed
+                }
+            }
+        };
+
+        self.failures += 1;
+
+        // Slow down clients that are guessing passwords
+        sleep(Duration::from_secs(CONFIG.login.failure_delay)).await;
+
+        Err(error)
+    }
+
+    /// Whether the client has failed to log in too many times. The
+    /// connection should be closed once this is true.
+    pub fn exhausted(&self) -> bool {
+        self.failures >= CONFIG.login.max_attempts
+    }
+}
@@ -0,0 +1,64 @@
+use std::str;
+
+use super::*;
+
+/// The CRAM-MD5 mechanism ([RFC 2195](https://datatracker.ietf.org/doc/html/rfc2195)).
+/// The server sends a unique challenge, and the client answers with its
+/// username and an HMAC-MD5 digest of the challenge keyed with its shared
+/// secret. Only users who have set a shared secret can use it.
+#[derive(Default)]
+pub struct CramMD5 {
+    challenge: Option<String>,
+}
+
+impl Mechanism for CramMD5 {
+    fn step(&mut self, response: Option<&[u8]>) -> Result<Step, SaslError> {
+        let challenge = match &self.challenge {
+            Some(challenge) => challenge,
+            None => {
+                // The client speaks second, so it can't send an initial response
+                if !response.unwrap_or_default().is_empty() {
+                    return Err(SaslError::MalformedResponse);
+                }
+
+                let challenge = generate_timestamp();
+                self.challenge = Some(challenge.clone());
+                return Ok(Step::Challenge(challenge.into_bytes()));
+            }
+        };
+
+        let response = match response {
+            Some(r) => str::from_utf8(r).map_err(|_| SaslError::MalformedResponse)?,
+            None => return Err(SaslError::MalformedResponse),
+        };
+
+        // The username can contain spaces, but the digest can't
+        match response.rsplit_once(' ') {
+            Some((username, digest)) => Ok(Step::Done(Credentials::Digest {
+                username: username.to_owned(),
+                challenge: challenge.clone(),
+                digest: digest.to_owned(),
+            })),
+            None => Err(SaslError::MalformedResponse),
+        }
+    }
+}
+
+#[test]
+fn cram_md5_mechanism() {
+    let mut cram_md5 = CramMD5 {
+        challenge: Some("<1896.697170952@postoffice.example.net>".to_owned()),
+    };
+
+    assert_eq!(
+        cram_md5
+            .step(Some(b"tim@example.net b913a602c7eda7a495b4e6e7334d3890"))
+            .unwrap(),
+        Step::Done(Credentials::Digest {
+            username: "tim@example.net".to_owned(),
+            challenge: "<1896.697170952@postoffice.example.net>".to_owned(),
+            digest: "b913a602c7eda7a495b4e6e7334d3890".to_owned(),
+        })
+    );
+    assert!(cram_md5.step(Some(b"nodigest")).is_err());
+}
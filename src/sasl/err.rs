@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use sea_orm::DbErr;
+
+#[derive(Debug)]
+pub enum SaslError {
+    /// The client asked for a mechanism that this server doesn't support
+    UnsupportedMechanism,
+    /// The client cancelled the exchange by sending "*"
+    Cancelled,
+    /// The client's response isn't valid base64 or doesn't follow the
+    /// mechanism's format
+    MalformedResponse,
+    /// The client's credentials are wrong
+    AuthenticationFailed,
+    /// The client failed to log in to the account too many times, and
+    /// can't try again until its lockout is over
+    LockedOut,
+    /// The user's domain only allows logins over TLS, and the connection
+    /// isn't encrypted
+    EncryptionRequired,
+    Io(io::Error),
+    Database(DbErr),
+}
+
+impl Error for SaslError {}
+
+impl fmt::Display for SaslError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SaslError::*;
+
+        let err_message = match self {
+            UnsupportedMechanism => "SASL mechanism is not supported".to_string(),
+            Cancelled => "SASL exchange was cancelled by the client".to_string(),
+            MalformedResponse => "SASL response from the client is malformed".to_string(),
+            AuthenticationFailed => "SASL credentials are invalid".to_string(),
+            LockedOut => "the client is locked out of the account".to_string(),
+            EncryptionRequired => "the user may only log in over TLS".to_string(),
+            Io(e) => format!("I/O error during SASL exchange: {}", e),
+            Database(e) => format!("database error during SASL exchange: {}", e),
+        };
+
+        write!(f, "{}", err_message)
+    }
+}
+
+impl From<io::Error> for SaslError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<DbErr> for SaslError {
+    fn from(e: DbErr) -> Self {
+        Self::Database(e)
+    }
+}
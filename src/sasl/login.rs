@@ -0,0 +1,57 @@
+use std::str;
+
+use super::*;
+
+/// The LOGIN mechanism. It is obsolete, but some clients still use it.
+/// The server asks for the username and then the password.
+#[derive(Default)]
+pub struct Login {
+    username: Option<String>,
+}
+
+impl Mechanism for Login {
+    fn step(&mut self, response: Option<&[u8]>) -> Result<Step, SaslError> {
+        let response = match response {
+            Some(r) => str::from_utf8(r).map_err(|_| SaslError::MalformedResponse)?,
+            None => return Ok(Step::Challenge(b"Username:".to_vec())),
+        };
+
+        match &self.username {
+            None => {
+                self.username = Some(response.to_owned());
+                Ok(Step::Challenge(b"Password:".to_vec()))
+            }
+            Some(username) => Ok(Step::Done(Credentials::Password {
+                username: username.clone(),
+                password: response.to_owned(),
+            })),
+        }
+    }
+}
+
+#[test]
+fn login_mechanism() {
+    let mut login = Login::default();
+    assert_eq!(
+        login.step(None).unwrap(),
+        Step::Challenge(b"Username:".to_vec())
+    );
+    assert_eq!(
+        login.step(Some(b"tim@example.com")).unwrap(),
+        Step::Challenge(b"Password:".to_vec())
+    );
+    assert_eq!(
+        login.step(Some(b"tanstaaf")).unwrap(),
+        Step::Done(Credentials::Password {
+            username: "tim@example.com".to_owned(),
+            password: "tanstaaf".to_owned(),
+        })
+    );
+
+    // The username can be sent as the initial response
+    let mut login = Login::default();
+    assert_eq!(
+        login.step(Some(b"tim@example.com")).unwrap(),
+        Step::Challenge(b"Password:".to_vec())
+    );
+}
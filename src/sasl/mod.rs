@@ -0,0 +1,215 @@
+//! The Simple Authentication and Security Layer
+//! ([RFC 4422](https://datatracker.ietf.org/doc/html/rfc4422)). Used by the
+//! POP3 `AUTH` command ([RFC 5034](https://datatracker.ietf.org/doc/html/rfc5034)),
+//! the SMTP `AUTH` command ([RFC 4954](https://datatracker.ietf.org/doc/html/rfc4954)),
+//! and the IMAP `AUTHENTICATE` command ([RFC 3501](https://datatracker.ietf.org/doc/html/rfc3501)).
+//!
+//! Each mechanism is a state machine that turns the client's responses
+//! into challenges and, at the end, into credentials. The protocol
+//! handler only has to implement [`Transport`], which sends challenges and
+//! reads responses in the protocol's own syntax.
+
+use std::future::Future;
+use std::io;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use email_address::EmailAddress;
+use sea_orm::DbErr;
+
+use crate::config_helpers::{requires_tls, server_hostname};
+use crate::database::{user, user_database};
+
+mod attempts;
+pub use attempts::*;
+
+mod cram_md5;
+pub use cram_md5::*;
+
+mod err;
+pub use err::*;
+
+mod login;
+pub use login::*;
+
+mod plain;
+pub use plain::*;
+
+/// The names of the supported mechanisms, in order of preference
+pub const MECHANISMS: [&str; 3] = ["PLAIN", "LOGIN", "CRAM-MD5"];
+
+/// Get the mechanism with the given name (case insensitive)
+pub fn mechanism(name: &str) -> Option<Box<dyn Mechanism>> {
+    match name.to_ascii_uppercase().as_str() {
+        "PLAIN" => Some(Box::new(Plain)),
+        "LOGIN" => Some(Box::new(Login::default())),
+        "CRAM-MD5" => Some(Box::new(CramMD5::default())),
+        _ => None,
+    }
+}
+
+/// A SASL authentication mechanism
+pub trait Mechanism: Send {
+    /// Process the next response from the client. The first call gets the
+    /// initial response sent with the `AUTH` command, or `None` if the
+    /// client didn't send one.
+    fn step(&mut self, response: Option<&[u8]>) -> Result<Step, SaslError>;
+}
+
+/// What a mechanism needs after processing a response
+#[derive(PartialEq, Debug)]
+pub enum Step {
+    /// Send this challenge to the client and wait for its response
+    Challenge(Vec<u8>),
+
+    /// The exchange is over. These credentials need to be checked
+    Done(Credentials),
+}
+
+/// The credentials that the client gave during a SASL exchange
+#[derive(PartialEq, Debug)]
+pub enum Credentials {
+    /// A username and a plaintext password (PLAIN and LOGIN)
+    Password { username: String, password: String },
+
+    /// A username and an HMAC-MD5 digest of `challenge` keyed with the
+    /// user's shared secret (CRAM-MD5)
+    Digest {
+        username: String,
+        challenge: String,
+        digest: String,
+    },
+}
+
+impl Credentials {
+    /// The username that the client is trying to log in as
+    pub fn username(&self) -> &str {
+        match self {
+            Credentials::Password { username, .. } | Credentials::Digest { username, .. } => {
+                username
+            }
+        }
+    }
+}
+
+/// Sends challenges to the client and reads its responses, in the syntax
+/// of a particular protocol
+pub trait Transport {
+    /// Send a base64 encoded challenge to the client
+    fn send_challenge(&mut self, challenge: &str) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Read the client's response to a challenge, without the line
+    /// terminator
+    fn read_response(&mut self) -> impl Future<Output = io::Result<String>> + Send;
+
+    /// The failed logins on the connection
+    fn login_attempts(&mut self) -> &mut LoginAttempts;
+}
+
+/// Carry out a SASL exchange with the client and check the credentials it
+/// gives. `initial_response` is the base64 encoded response sent with the
+/// `AUTH` command, if there was one. `encrypted` is whether the connection
+/// uses TLS; users whose domain requires it are refused if it doesn't.
+///
+/// The login counts towards the limits on failed logins (see
+/// [`LoginAttempts`]). Returns the authenticated user.
+pub async fn authenticate<T: Transport + Send>(
+    transport: &mut T,
+    mechanism_name: &str,
+    initial_response: Option<&str>,
+    encrypted: bool,
+) -> Result<user::Model, SaslError> {
+    let credentials = exchange(transport, mechanism_name, initial_response).await?;
+
+    let domain = credentials.username().rsplit_once('@').map(|(_, d)| d);
+    if !encrypted && domain.is_some_and(requires_tls) {
+        return Err(SaslError::EncryptionRequired);
+    }
+
+    let account = EmailAddress::from_str(credentials.username()).ok();
+    transport
+        .login_attempts()
+        .check(account.as_ref(), verify(credentials))
+        .await
+}
+
+/// Carry out a SASL exchange with the client, without checking the
+/// credentials it gives
+pub async fn exchange<T: Transport + Send>(
+    transport: &mut T,
+    mechanism_name: &str,
+    initial_response: Option<&str>,
+) -> Result<Credentials, SaslError> {
+    let mut mechanism = mechanism(mechanism_name).ok_or(SaslError::UnsupportedMechanism)?;
+
+    let mut response = match initial_response {
+        // "=" is an initial response of zero length
+        Some("=") => Some(vec![]),
+        Some(r) => Some(decode(r)?),
+        None => None,
+    };
+
+    loop {
+        match mechanism.step(response.as_deref())? {
+            Step::Challenge(challenge) => {
+                transport.send_challenge(&BASE64.encode(challenge)).await?;
+
+                let line = transport.read_response().await?;
+                if line == "*" {
+                    return Err(SaslError::Cancelled);
+                }
+                response = Some(decode(&line)?);
+            }
+            Step::Done(credentials) => return Ok(credentials),
+        }
+    }
+}
+
+/// Check credentials against the user database. Returns `None` if they
+/// are wrong.
+pub async fn verify(credentials: Credentials) -> Result<Option<user::Model>, DbErr> {
+    match credentials {
+        Credentials::Password { username, password } => match EmailAddress::from_str(&username) {
+            Ok(address) => user_database::authenticate_user(&address, &password).await,
+            Err(_) => Ok(None),
+        },
+        Credentials::Digest {
+            username,
+            challenge,
+            digest,
+        } => match EmailAddress::from_str(&username) {
+            Ok(address) => {
+                user_database::authenticate_user_cram_md5(&address, &challenge, &digest).await
+            }
+            Err(_) => Ok(None),
+        },
+    }
+}
+
+fn decode(response: &str) -> Result<Vec<u8>, SaslError> {
+    BASE64
+        .decode(response.trim())
+        .map_err(|_| SaslError::MalformedResponse)
+}
+
+/// Generate a string of the form `<process-ID.clock@hostname>` that is
+/// different every time. Used as the APOP timestamp (RFC 1939 section 7)
+/// and the CRAM-MD5 challenge (RFC 2195).
+pub fn generate_timestamp() -> String {
+    static LAST_CLOCK: AtomicI64 = AtomicI64::new(0);
+
+    // Make sure the clock is never the same twice, even if two timestamps
+    // are generated in the same microsecond
+    let now = chrono::Utc::now().timestamp_micros();
+    let previous = LAST_CLOCK
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .unwrap_or_default();
+    let clock = now.max(previous + 1);
+
+    format!("<{}.{}@{}>", process::id(), clock, server_hostname())
+}
@@ -0,0 +1,64 @@
+use std::str;
+
+use super::*;
+
+/// The PLAIN mechanism ([RFC 4616](https://datatracker.ietf.org/doc/html/rfc4616)).
+/// The client sends its username and password in a single response.
+pub struct Plain;
+
+impl Mechanism for Plain {
+    fn step(&mut self, response: Option<&[u8]>) -> Result<Step, SaslError> {
+        let response = match response {
+            Some(r) => r,
+            // Ask the client for its credentials with an empty challenge
+            None => return Ok(Step::Challenge(vec![])),
+        };
+
+        // message = [authzid] NUL authcid NUL passwd
+        let fields: Vec<&[u8]> = response.split(|b| *b == 0).collect();
+        let (authzid, authcid, passwd) = match fields[..] {
+            [authzid, authcid, passwd] => (authzid, authcid, passwd),
+            _ => return Err(SaslError::MalformedResponse),
+        };
+
+        let utf8 = |bytes| str::from_utf8(bytes).map_err(|_| SaslError::MalformedResponse);
+        let (authzid, authcid, passwd) = (utf8(authzid)?, utf8(authcid)?, utf8(passwd)?);
+
+        // Logging in as a different user isn't supported
+        if !authzid.is_empty() && authzid != authcid {
+            return Err(SaslError::AuthenticationFailed);
+        }
+
+        Ok(Step::Done(Credentials::Password {
+            username: authcid.to_owned(),
+            password: passwd.to_owned(),
+        }))
+    }
+}
+
+#[test]
+fn plain_mechanism() {
+    assert_eq!(Plain.step(None).unwrap(), Step::Challenge(vec![]));
+
+    let credentials = Step::Done(Credentials::Password {
+        username: "tim@example.com".to_owned(),
+        password: "tanstaaftanstaaf".to_owned(),
+    });
+    assert_eq!(
+        Plain
+            .step(Some(b"\0tim@example.com\0tanstaaftanstaaf"))
+            .unwrap(),
+        credentials
+    );
+    assert_eq!(
+        Plain
+            .step(Some(b"tim@example.com\0tim@example.com\0tanstaaftanstaaf"))
+            .unwrap(),
+        credentials
+    );
+
+    assert!(Plain.step(Some(b"tim@example.com\0tanstaaf")).is_err());
+    assert!(Plain
+        .step(Some(b"admin@example.com\0tim@example.com\0tanstaaf"))
+        .is_err());
+}
@@ -0,0 +1,224 @@
+//! Delivery status notifications ("bounces") for messages that couldn't
+//! be delivered (RFC 3464).
+
+use std::slice;
+
+use chrono::{DateTime, Local, Utc};
+use email_address::EmailAddress;
+use log::info;
+use rand_core::{OsRng, RngCore};
+use sea_orm::DbErr;
+
+use super::{DeliveryError, DeliveryStatus, EnhancedStatusCode};
+use crate::config_helpers::{find_local_address, server_hostname};
+use crate::database::{mail_database, queue_database};
+
+/// Tell `sender` that their message couldn't be delivered to some of its
+/// recipients.
+///
+/// The notification is stored in the sender's mailbox if the sender is a
+/// local user, and queued for delivery otherwise. Either way it has a null
+/// reverse-path, so a notification can never cause another one.
+pub async fn send_bounce(
+    sender: &EmailAddress,
+    message: &str,
+    arrival_date: DateTime<Utc>,
+    failures: &[(EmailAddress, DeliveryStatus)],
+) -> Result<(), DbErr> {
+    let notification =
+        delivery_status_notification(&server_hostname(), sender, message, arrival_date, failures);
+
+    match find_local_address(sender) {
+        Some(local_sender) => {
+            let notification = format!("Return-Path: <>\r\n{}", notification);
+            mail_database::deliver_mail(&[local_sender], notification).await?
+        }
+        None => queue_database::enqueue(None, slice::from_ref(sender), notification).await?,
+    }
+
+    info!("Sent a delivery status notification to {}", sender);
+
+    Ok(())
+}
+
+/// Build a `multipart/report` message that describes why a message wasn't
+/// delivered to each of the `failures`. The original message's headers
+/// are attached to the report. `hostname` is the name of this server.
+fn delivery_status_notification(
+    hostname: &str,
+    sender: &EmailAddress,
+    message: &str,
+    arrival_date: DateTime<Utc>,
+    failures: &[(EmailAddress, DeliveryStatus)],
+) -> String {
+    let boundary = format!(
+        "{}.{:08x}/{}",
+        Utc::now().timestamp_micros(),
+        OsRng.next_u32(),
+        hostname
+    );
+
+    let mut out = String::new();
+
+    // Message headers
+    out += &format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{}>\r\n",
+        hostname
+    );
+    out += &format!("To: <{}>\r\n", sender);
+    out += "Subject: Undelivered Mail Returned to Sender\r\n";
+    out += &format!("Date: {}\r\n", Local::now().to_rfc2822());
+    out += &format!(
+        "Message-ID: <{}.{:08x}@{}>\r\n",
+        Utc::now().timestamp_micros(),
+        OsRng.next_u32(),
+        hostname
+    );
+    out += "Auto-Submitted: auto-replied\r\n";
+    out += "MIME-Version: 1.0\r\n";
+    out += &format!(
+        "Content-Type: multipart/report; report-type=delivery-status;\r\n\tboundary=\"{}\"\r\n",
+        boundary
+    );
+    out += "\r\n";
+    out += "This is a MIME-encapsulated message.\r\n\r\n";
+
+    // Human readable explanation
+    out += &format!("--{}\r\n", boundary);
+    out += "Content-Type: text/plain; charset=utf-8\r\n\r\n";
+    out += &format!(
+        "This is the mail system at {}.\r\n\r\nYour message could not be delivered to the following recipients:\r\n\r\n",
+        hostname
+    );
+    for (recipient, status) in failures {
+        if let Some(error) = failure_reason(status) {
+            out += &format!("<{}>: {}\r\n", recipient, error);
+        }
+    }
+    out += "\r\n";
+
+    // Machine readable report (RFC 3464 section 2.1)
+    out += &format!("--{}\r\n", boundary);
+    out += "Content-Type: message/delivery-status\r\n\r\n";
+    out += &format!("Reporting-MTA: dns; {}\r\n", hostname);
+    out += &format!("Arrival-Date: {}\r\n", arrival_date.to_rfc2822());
+    for (recipient, status) in failures {
+        let error = match failure_reason(status) {
+            Some(error) => error,
+            None => continue,
+        };
+
+        out += "\r\n";
+        out += &format!("Final-Recipient: rfc822; {}\r\n", recipient);
+        out += "Action: failed\r\n";
+        out += &format!("Status: {}\r\n", status_code(status));
+        if let DeliveryError::Rejected(_) = error {
+            out += &format!("Diagnostic-Code: smtp; {}\r\n", error);
+        }
+    }
+    out += "\r\n";
+
+    // The original message's headers (RFC 6522 section 4)
+    out += &format!("--{}\r\n", boundary);
+    out += "Content-Type: text/rfc822-headers\r\n\r\n";
+    for line in message.lines() {
+        if line.is_empty() {
+            break;
+        }
+        out += line;
+        out += "\r\n";
+    }
+    out += "\r\n";
+
+    out += &format!("--{}--\r\n", boundary);
+
+    out
+}
+
+/// Get the reason that a message wasn't delivered. Returns `None` if it
+/// was delivered.
+fn failure_reason(status: &DeliveryStatus) -> Option<&DeliveryError> {
+    match status {
+        DeliveryStatus::Delivered => None,
+        DeliveryStatus::TemporaryFailure(error) | DeliveryStatus::PermanentFailure(error) => {
+            Some(error)
+        }
+    }
+}
+
+/// Get the RFC 3463 status code to report for a failed recipient. The
+/// remote server's enhanced status code is used if it sent one.
+fn status_code(status: &DeliveryStatus) -> EnhancedStatusCode {
+    let class = match status {
+        DeliveryStatus::PermanentFailure(_) => 5,
+        _ => 4,
+    };
+
+    match failure_reason(status) {
+        Some(DeliveryError::Rejected(reply)) => match &reply.enhanced_code {
+            Some(code) => *code,
+            None => EnhancedStatusCode::new(reply.code.class(), 0, 0),
+        },
+        // "Unable to route"
+        _ => EnhancedStatusCode::new(class, 4, 4),
+    }
+}
+
+#[test]
+fn generate_delivery_status_notification() {
+    use super::{SMTPReply, SMTPReplyCode};
+    use std::str::FromStr;
+
+    let sender = EmailAddress::from_str("alice@example.com").unwrap();
+    let failures = vec![
+        (
+            EmailAddress::from_str("bob@example.org").unwrap(),
+            DeliveryStatus::PermanentFailure(DeliveryError::Rejected(SMTPReply::enhanced(
+                SMTPReplyCode::MAILBOX_UNAVAILABLE,
+                EnhancedStatusCode::BAD_DESTINATION_MAILBOX,
+                "No such user",
+            ))),
+        ),
+        (
+            EmailAddress::from_str("carol@example.net").unwrap(),
+            DeliveryStatus::PermanentFailure(DeliveryError::Rejected(SMTPReply::new(
+                SMTPReplyCode::TRANSACTION_FAILED,
+                "Go away",
+            ))),
+        ),
+        (
+            EmailAddress::from_str("dave@example.invalid").unwrap(),
+            DeliveryStatus::PermanentFailure(DeliveryError::Unreachable(
+                "domain example.invalid does not exist".to_owned(),
+            )),
+        ),
+        (
+            EmailAddress::from_str("erin@example.org").unwrap(),
+            DeliveryStatus::Delivered,
+        ),
+    ];
+
+    let dsn = delivery_status_notification(
+        "mail.example.com",
+        &sender,
+        "Subject: Hello\r\nFrom: alice@example.com\r\n\r\nSecret body\r\n",
+        Utc::now(),
+        &failures,
+    );
+
+    assert!(dsn.contains("Content-Type: multipart/report; report-type=delivery-status;"));
+    assert!(dsn.contains("To: <alice@example.com>\r\n"));
+    assert!(dsn.contains("Reporting-MTA: dns; mail.example.com\r\n"));
+    assert!(dsn.contains(
+        "Final-Recipient: rfc822; bob@example.org\r\nAction: failed\r\nStatus: 5.1.1\r\nDiagnostic-Code: smtp; 550 5.1.1 No such user\r\n"
+    ));
+    assert!(dsn.contains(
+        "Final-Recipient: rfc822; carol@example.net\r\nAction: failed\r\nStatus: 5.0.0\r\nDiagnostic-Code: smtp; 554 Go away\r\n"
+    ));
+    assert!(dsn.contains(
+        "Final-Recipient: rfc822; dave@example.invalid\r\nAction: failed\r\nStatus: 5.4.4\r\n\r\n"
+    ));
+    assert!(!dsn.contains("erin@example.org"));
+    assert!(dsn.contains("Subject: Hello\r\nFrom: alice@example.com\r\n\r\n"));
+    assert!(!dsn.contains("Secret body"));
+}
@@ -1,28 +1,50 @@
 use std::error::Error;
-use std::str::FromStr;
+use std::fmt;
 
 use email_address::EmailAddress;
+use nom::Err::Incomplete;
 use sea_orm::strum::Display;
 
-#[derive(PartialEq, Debug)]
+use super::parser;
+
+#[derive(PartialEq, Debug, Clone)]
 pub enum SMTPCommand {
-    // TODO: source routes
+    /// `HELO`; Identify the client to the server. Only used by clients
+    /// that don't support the SMTP service extensions.
+    ///
+    /// https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.1.1
+    Hello { domain: String },
+
+    /// `EHLO`; Identify the client to the server and ask for the list of
+    /// supported service extensions. The domain may be an address literal
+    /// (e.g. `[192.0.2.1]`).
+    ///
+    /// https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.1.1
+    ExtendedHello { domain: String },
+
     /// `MAIL FROM:`; Initiate transaction and specify the address of the
     /// sender
     ///
     /// https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.1.2
-    MailFrom { sender: EmailAddress },
+    MailFrom {
+        sender: ReversePath,
+        parameters: Vec<ESMTPParameter>,
+    },
 
     /// `RCPT TO:`; Specify a recipient of the message. There's some extra
     /// nonsense I have to do here to get rid of "source roots" (see RFC 5321.4.1.1.3)
     ///
     /// https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.1.3
-    Recipient { recipient: EmailAddress },
+    Recipient {
+        recipient: ForwardPath,
+        parameters: Vec<ESMTPParameter>,
+    },
 
-    /// `DATA`; Indicates that mail data begins on the next line
+    /// `DATA`; Indicates that mail data begins on the next line. The mail
+    /// data itself is not part of the command.
     ///
     /// https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.1.4
-    Data { data: String },
+    Data,
 
     /// `RSET`; Abort the current mail transaction
     ///
@@ -43,7 +65,7 @@ pub enum SMTPCommand {
     /// `HELP`; Send helpful information to the client (not required to be implemented)
     ///
     /// https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.1.8
-    Help,
+    Help { topic: Option<String> },
 
     /// `NOOP`; Do nothing. May be followed by a string argument (make sure that
     /// doesn't crash the server)
@@ -55,6 +77,165 @@ pub enum SMTPCommand {
     ///
     /// https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.1.10
     Quit,
+
+    /// `AUTH`; Authenticate with a SASL mechanism. The client may send a
+    /// base64 encoded initial response with the command ("=" for an empty
+    /// one).
+    ///
+    /// https://datatracker.ietf.org/doc/html/rfc4954#section-4
+    Auth {
+        mechanism: String,
+        initial_response: Option<String>,
+    },
+
+    /// `STARTTLS`; Start a TLS handshake. Everything after the server's
+    /// reply is encrypted, and the session starts over.
+    ///
+    /// https://datatracker.ietf.org/doc/html/rfc3207#section-4
+    StartTls,
+}
+
+/// The argument of `MAIL FROM:`. The null reverse-path (`<>`) is used for
+/// notification messages like bounces, so that they never generate
+/// bounces of their own.
+///
+/// https://datatracker.ietf.org/doc/html/rfc5321#section-4.5.5
+#[derive(PartialEq, Debug, Clone)]
+pub enum ReversePath {
+    Null,
+    Path(Path),
+}
+
+/// The argument of `RCPT TO:`. Every SMTP server must accept mail for
+/// `<Postmaster>` without a domain.
+#[derive(PartialEq, Debug, Clone)]
+pub enum ForwardPath {
+    Postmaster,
+    Path(Path),
+}
+
+/// A mailbox, optionally preceded by a source route. Source routes are
+/// deprecated; RFC 5321 says servers SHOULD accept them but MAY ignore
+/// them, which is what mailroom does.
+///
+/// https://datatracker.ietf.org/doc/html/rfc5321#appendix-C
+#[derive(PartialEq, Debug, Clone)]
+pub struct Path {
+    /// The domains of the source route (without the "@"), in order
+    pub source_route: Vec<String>,
+    pub mailbox: EmailAddress,
+}
+
+/// An SMTP service extension parameter on a `MAIL FROM:` or `RCPT TO:`
+/// command, like `SIZE=1000` or `SMTPUTF8`. The keyword is always
+/// uppercase.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ESMTPParameter {
+    pub keyword: String,
+    pub value: Option<String>,
+}
+
+/// Format the command as it is sent over the wire, including the trailing
+/// CRLF
+impl fmt::Display for SMTPCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SMTPCommand::*;
+
+        match self {
+            Hello { domain } => write!(f, "HELO {}\r\n", domain),
+            ExtendedHello { domain } => write!(f, "EHLO {}\r\n", domain),
+            MailFrom { sender, parameters } => {
+                write!(f, "MAIL FROM:{}", sender)?;
+                for parameter in parameters {
+                    write!(f, " {}", parameter)?;
+                }
+                write!(f, "\r\n")
+            }
+            Recipient {
+                recipient,
+                parameters,
+            } => {
+                write!(f, "RCPT TO:{}", recipient)?;
+                for parameter in parameters {
+                    write!(f, " {}", parameter)?;
+                }
+                write!(f, "\r\n")
+            }
+            Data => write!(f, "DATA\r\n"),
+            Reset => write!(f, "RSET\r\n"),
+            Verify { address } => write!(f, "VRFY {}\r\n", quote_string(address)),
+            Expand { mailing_list } => write!(f, "EXPN {}\r\n", quote_string(mailing_list)),
+            Help { topic: None } => write!(f, "HELP\r\n"),
+            Help { topic: Some(topic) } => write!(f, "HELP {}\r\n", quote_string(topic)),
+            Noop => write!(f, "NOOP\r\n"),
+            Quit => write!(f, "QUIT\r\n"),
+            Auth {
+                mechanism,
+                initial_response: None,
+            } => write!(f, "AUTH {}\r\n", mechanism),
+            Auth {
+                mechanism,
+                initial_response: Some(response),
+            } => write!(f, "AUTH {} {}\r\n", mechanism, response),
+            StartTls => write!(f, "STARTTLS\r\n"),
+        }
+    }
+}
+
+impl fmt::Display for ReversePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReversePath::Null => write!(f, "<>"),
+            ReversePath::Path(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+impl fmt::Display for ForwardPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardPath::Postmaster => write!(f, "<Postmaster>"),
+            ForwardPath::Path(path) => write!(f, "{}", path),
+        }
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<")?;
+        if !self.source_route.is_empty() {
+            let route: Vec<String> = self
+                .source_route
+                .iter()
+                .map(|d| format!("@{}", d))
+                .collect();
+            write!(f, "{}:", route.join(","))?;
+        }
+        write!(f, "{}>", self.mailbox)
+    }
+}
+
+impl fmt::Display for ESMTPParameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={}", self.keyword, value),
+            None => write!(f, "{}", self.keyword),
+        }
+    }
+}
+
+/// Turn a string into an SMTP `String` argument, which is either an atom
+/// or a quoted string.
+fn quote_string(s: &str) -> String {
+    let is_atom = !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c));
+
+    if is_atom {
+        s.to_owned()
+    } else {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
 }
 
 #[derive(PartialEq, Debug, Display)]
@@ -67,53 +248,231 @@ pub enum SMTPCommandParseError {
 impl Error for SMTPCommandParseError {}
 
 /// Try to convert a string to an `SMTPCommand`. Fails if the string
-/// is not an SMTP command.
+/// is not exactly one CRLF terminated SMTP command.
 impl TryFrom<&str> for SMTPCommand {
     type Error = SMTPCommandParseError;
 
     fn try_from(input: &str) -> Result<Self, Self::Error> {
-        let input = input.trim();
-        let words: Vec<&str> = input.split_whitespace().collect();
-
-        match words.get(0) {
-            None => return Err(SMTPCommandParseError::IncompleteCommand),
-            Some(s) => match s.to_ascii_uppercase().as_str() {
-                "MAIL" => {
-                    return Ok(SMTPCommand::MailFrom {
-                        sender: EmailAddress::from_str("TODO@TODO.com").unwrap(),
-                    })
-                }
-                "RCPT" => {
-                    todo!()
-                }
-                "DATA" => {
-                    // Make sure the input ends in "CRLF.CRLF"
-                    if &input[input.len() - 5..input.len()] != "\r\n.\r\n" {
-                        return Err(SMTPCommandParseError::IncompleteCommand);
-                    }
+        use SMTPCommandParseError::*;
 
-                    let data = input[9..].to_string();
+        let verb = match parser::verb(input) {
+            Ok((_, verb)) => verb,
+            Err(Incomplete(_)) => return Err(IncompleteCommand),
+            Err(_) => return Err(InvalidCommand),
+        };
 
-                    return Ok(SMTPCommand::Data { data });
-                }
-                "RSET" => {
-                    return Ok(SMTPCommand::Reset); // TODO: verify that these commands are complete before returning Ok
-                }
-                "VRFY" => {
-                    return Ok(SMTPCommand::Verify {
-                        address: "TODO TODO TODO".to_string(),
-                    });
-                }
-                "EXPN" => {
-                    todo!()
-                }
-                "HELP" => return Ok(SMTPCommand::Help),
-                "NOOP" => {
-                    return Ok(SMTPCommand::Noop);
-                }
-                "QUIT" => return Ok(SMTPCommand::Quit),
-                _ => return Err(SMTPCommandParseError::InvalidCommand),
-            },
+        let command_parser = match verb.to_ascii_uppercase().as_str() {
+            "EHLO" => parser::ehlo,
+            "HELO" => parser::helo,
+            "MAIL" => parser::mail,
+            "RCPT" => parser::rcpt,
+            "DATA" => parser::data,
+            "RSET" => parser::rset,
+            "VRFY" => parser::vrfy,
+            "EXPN" => parser::expn,
+            "HELP" => parser::help,
+            "NOOP" => parser::noop,
+            "QUIT" => parser::quit,
+            "AUTH" => parser::auth,
+            "STARTTLS" => parser::starttls,
+            _ => return Err(InvalidCommand),
+        };
+
+        match command_parser(input) {
+            Ok(("", command)) => Ok(command),
+            // There is something after the CRLF
+            Ok(_) => Err(InvalidArguments),
+            Err(Incomplete(_)) => Err(IncompleteCommand),
+            Err(_) => Err(InvalidArguments),
         }
     }
 }
+
+#[test]
+fn parse_smtp_command() {
+    use std::str::FromStr;
+    use SMTPCommandParseError::*;
+
+    let address = |s: &str| EmailAddress::from_str(s).unwrap();
+
+    let tests: Vec<(&str, Result<SMTPCommand, SMTPCommandParseError>)> = vec![
+        (
+            "EHLO mail.example.com\r\n",
+            Ok(SMTPCommand::ExtendedHello {
+                domain: "mail.example.com".to_owned(),
+            }),
+        ),
+        (
+            "ehlo [192.0.2.1]\r\n",
+            Ok(SMTPCommand::ExtendedHello {
+                domain: "[192.0.2.1]".to_owned(),
+            }),
+        ),
+        (
+            "HELO example.com\r\n",
+            Ok(SMTPCommand::Hello {
+                domain: "example.com".to_owned(),
+            }),
+        ),
+        ("HELO [192.0.2.1]\r\n", Err(InvalidArguments)),
+        (
+            "MAIL FROM:<jdoe@example.com>\r\n",
+            Ok(SMTPCommand::MailFrom {
+                sender: ReversePath::Path(Path {
+                    source_route: vec![],
+                    mailbox: address("jdoe@example.com"),
+                }),
+                parameters: vec![],
+            }),
+        ),
+        (
+            "MAIL FROM:<> SIZE=512\r\n",
+            Ok(SMTPCommand::MailFrom {
+                sender: ReversePath::Null,
+                parameters: vec![ESMTPParameter {
+                    keyword: "SIZE".to_owned(),
+                    value: Some("512".to_owned()),
+                }],
+            }),
+        ),
+        (
+            "RCPT TO:<@relay.example:mary@example.net>\r\n",
+            Ok(SMTPCommand::Recipient {
+                recipient: ForwardPath::Path(Path {
+                    source_route: vec!["relay.example".to_owned()],
+                    mailbox: address("mary@example.net"),
+                }),
+                parameters: vec![],
+            }),
+        ),
+        (
+            "RCPT TO:<Postmaster>\r\n",
+            Ok(SMTPCommand::Recipient {
+                recipient: ForwardPath::Postmaster,
+                parameters: vec![],
+            }),
+        ),
+        ("RCPT TO:<>\r\n", Err(InvalidArguments)),
+        ("RCPT TO:mary@example.net\r\n", Err(InvalidArguments)),
+        ("DATA\r\n", Ok(SMTPCommand::Data)),
+        ("DATA now\r\n", Err(InvalidArguments)),
+        ("RSET\r\n", Ok(SMTPCommand::Reset)),
+        (
+            "VRFY \"Smith, John\"\r\n",
+            Ok(SMTPCommand::Verify {
+                address: "Smith, John".to_owned(),
+            }),
+        ),
+        (
+            "EXPN staff\r\n",
+            Ok(SMTPCommand::Expand {
+                mailing_list: "staff".to_owned(),
+            }),
+        ),
+        ("EXPN\r\n", Err(InvalidArguments)),
+        ("HELP\r\n", Ok(SMTPCommand::Help { topic: None })),
+        (
+            "HELP MAIL\r\n",
+            Ok(SMTPCommand::Help {
+                topic: Some("MAIL".to_owned()),
+            }),
+        ),
+        ("NOOP\r\n", Ok(SMTPCommand::Noop)),
+        ("NOOP whatever\r\n", Ok(SMTPCommand::Noop)),
+        ("QUIT\r\n", Ok(SMTPCommand::Quit)),
+        ("QUIT\r\nNOOP\r\n", Err(InvalidArguments)),
+        (
+            "AUTH plain\r\n",
+            Ok(SMTPCommand::Auth {
+                mechanism: "PLAIN".to_owned(),
+                initial_response: None,
+            }),
+        ),
+        (
+            "AUTH PLAIN AHRpbQB0YW5zdGFhZnRhbnN0YWFm\r\n",
+            Ok(SMTPCommand::Auth {
+                mechanism: "PLAIN".to_owned(),
+                initial_response: Some("AHRpbQB0YW5zdGFhZnRhbnN0YWFm".to_owned()),
+            }),
+        ),
+        (
+            "AUTH EXTERNAL =\r\n",
+            Ok(SMTPCommand::Auth {
+                mechanism: "EXTERNAL".to_owned(),
+                initial_response: Some("=".to_owned()),
+            }),
+        ),
+        ("AUTH\r\n", Err(InvalidArguments)),
+        ("starttls\r\n", Ok(SMTPCommand::StartTls)),
+        ("STARTTLS now\r\n", Err(InvalidArguments)),
+        ("", Err(IncompleteCommand)),
+        ("QUI", Err(IncompleteCommand)),
+        ("QUIT", Err(IncompleteCommand)),
+        ("MAIL FROM:<jdoe@exa", Err(IncompleteCommand)),
+        ("FOO bar\r\n", Err(InvalidCommand)),
+        ("\r\n", Err(InvalidCommand)),
+    ];
+
+    for test in tests {
+        assert_eq!(SMTPCommand::try_from(test.0), test.1, "{:?}", test.0);
+    }
+}
+
+#[test]
+fn smtp_command_to_string() {
+    use std::str::FromStr;
+
+    let commands = vec![
+        SMTPCommand::ExtendedHello {
+            domain: "mail.example.com".to_owned(),
+        },
+        SMTPCommand::MailFrom {
+            sender: ReversePath::Null,
+            parameters: vec![
+                ESMTPParameter {
+                    keyword: "SIZE".to_owned(),
+                    value: Some("512".to_owned()),
+                },
+                ESMTPParameter {
+                    keyword: "SMTPUTF8".to_owned(),
+                    value: None,
+                },
+            ],
+        },
+        SMTPCommand::Recipient {
+            recipient: ForwardPath::Path(Path {
+                source_route: vec!["a.example".to_owned(), "b.example".to_owned()],
+                mailbox: EmailAddress::from_str("mary@example.net").unwrap(),
+            }),
+            parameters: vec![],
+        },
+        SMTPCommand::Verify {
+            address: "Smith, \"John\"".to_owned(),
+        },
+        SMTPCommand::Help { topic: None },
+        SMTPCommand::Quit,
+        SMTPCommand::Auth {
+            mechanism: "PLAIN".to_owned(),
+            initial_response: Some("AHRpbQB0YW5zdGFhZg==".to_owned()),
+        },
+        SMTPCommand::StartTls,
+    ];
+
+    assert_eq!(
+        commands[1].to_string(),
+        "MAIL FROM:<> SIZE=512 SMTPUTF8\r\n"
+    );
+    assert_eq!(
+        commands[2].to_string(),
+        "RCPT TO:<@a.example,@b.example:mary@example.net>\r\n"
+    );
+    assert_eq!(commands[3].to_string(), "VRFY \"Smith, \\\"John\\\"\"\r\n");
+
+    // Formatting and then parsing a command should give back the same command
+    for command in commands {
+        assert_eq!(
+            SMTPCommand::try_from(command.to_string().as_str()),
+            Ok(command)
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
 
 #[derive(PartialEq, Debug)]
 pub enum SMTPReplyParseError {
@@ -39,3 +40,39 @@ impl fmt::Display for SMTPReplyParseError {
         write!(f, "{}", err_message)
     }
 }
+
+/// Errors that end an outgoing SMTP session early
+#[derive(Debug)]
+pub enum SMTPClientError {
+    /// Couldn't read from or write to the remote server, or the server took
+    /// too long to reply
+    Io(io::Error),
+
+    /// The remote server sent something that isn't a valid SMTP reply
+    InvalidReply(SMTPReplyParseError),
+}
+
+impl Error for SMTPClientError {}
+
+impl fmt::Display for SMTPClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SMTPClientError::*;
+
+        match self {
+            Io(e) => write!(f, "SMTP connection failed: {}", e),
+            InvalidReply(e) => write!(f, "invalid reply from SMTP server: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for SMTPClientError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<SMTPReplyParseError> for SMTPClientError {
+    fn from(e: SMTPReplyParseError) -> Self {
+        Self::InvalidReply(e)
+    }
+}
@@ -1,18 +1,92 @@
-use bytes::BytesMut;
-use tokio::io::{self, AsyncWriteExt};
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use bytes::{Bytes, BytesMut};
+use email_address::EmailAddress;
+use log::{info, trace, warn};
+use sea_orm::DbErr;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
 
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::str;
 
-use crate::connection_handler::ConnectionHandler;
+use crate::config_helpers::{find_local_address, is_local_domain, primary_domain, server_hostname};
+use crate::connection_handler::{start_tls, AsyncStream, ConnectionHandler, TlsState};
+use crate::database::{mail_database, queue_database, user};
+use crate::sasl::{self, SaslError};
 
-use super::{SMTPCommand, SMTPCommandParseError};
+use super::{
+    EnhancedStatusCode, ForwardPath, ReversePath, SMTPCommand, SMTPCommandParseError, SMTPReply,
+    SMTPReplyCode,
+};
+
+/// The longest command line accepted from the client, including the CRLF.
+/// RFC 5321 section 4.5.3.1.4 requires at least 512 octets.
+const MAX_COMMAND_LINE: usize = 512;
+
+/// The longest response to a SASL challenge accepted from the client,
+/// including the CRLF. RFC 4954 section 4 requires at least 12288 octets.
+const MAX_AUTH_LINE: usize = 12288;
+
+/// The largest message accepted from the client, in octets.
+const MAX_MESSAGE_SIZE: usize = 25 * 1024 * 1024;
+
+/// How long to wait for the client to send a command before giving up.
+/// RFC 5321 section 4.5.3.2.7 recommends at least 5 minutes.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
 /// Handles an incoming SMTP connection from another email server or an email client.
 pub struct IncomingSMTPConnection {
     // Socket state
-    stream: TcpStream,
-    buffer: String,
+    stream: Box<dyn AsyncStream>,
+    buffer: BytesMut,
+    /// The client's IP address, which goes in the `Received` header
+    ip: IpAddr,
+    /// Whether the connection is encrypted, or can be with `STARTTLS`
+    tls: TlsState,
+
+    // Session state
+    /// The domain the client gave in its `HELO` or `EHLO` command. `None`
+    /// until the client has greeted the server.
+    client_domain: Option<String>,
+    /// The mail transaction in progress, if any.
+    transaction: Option<MailTransaction>,
+    /// The user that the client authenticated as with `AUTH`, if any.
+    /// Authenticated users may send mail from their own address to other
+    /// domains.
+    user: Option<user::Model>,
+    /// The failed `AUTH` attempts on this connection
+    logins: sasl::LoginAttempts,
+}
+
+/// The envelope of a message that is being received. A transaction starts
+/// with `MAIL FROM:` and ends when the message data has been received or
+/// when the client sends `RSET`, `HELO`, or `EHLO`.
+struct MailTransaction {
+    sender: ReversePath,
+    /// The local users that the message will be delivered to
+    recipients: Vec<EmailAddress>,
+    /// The recipients in other domains, which the message will be queued
+    /// for. Only authenticated users can add these, when the sender is
+    /// their own address.
+    remote_recipients: Vec<EmailAddress>,
+}
+
+impl MailTransaction {
+    fn has_recipients(&self) -> bool {
+        !self.recipients.is_empty() || !self.remote_recipients.is_empty()
+    }
+
+    /// Check whether the sender is `user`'s own address. The null
+    /// reverse-path isn't anyone's address.
+    fn sent_by(&self, user: &user::Model) -> bool {
+        match &self.sender {
+            ReversePath::Path(path) => path
+                .mailbox
+                .as_str()
+                .eq_ignore_ascii_case(&user.email_address),
+            ReversePath::Null => false,
+        }
+    }
 }
 
 impl ConnectionHandler for IncomingSMTPConnection {
@@ -20,52 +94,636 @@ impl ConnectionHandler for IncomingSMTPConnection {
         "SMTP"
     }
 
-    fn from_stream(stream: TcpStream) -> Self {
-        Self {
-            stream,
-            buffer: String::new(),
-        }
+    fn from_stream<S: AsyncStream + 'static>(stream: S, peer: SocketAddr, tls: TlsState) -> Self {
+        Self::new(stream, peer, tls)
     }
 
     async fn begin(&mut self) -> Result<(), Box<dyn Error>> {
-        let command = self.read_command().await.unwrap();
+        use SMTPCommand::*;
 
-        println!("RECEIVED SMTP COMMAND: {:?}", command);
+        self.send_reply(SMTPReply::new(
+            SMTPReplyCode::SERVICE_READY,
+            format!("{} ESMTP mailroom", server_hostname()),
+        ))
+        .await?;
 
-        Ok(())
+        loop {
+            let line = self.read_line(MAX_COMMAND_LINE).await?;
+            let line = String::from_utf8_lossy(&line);
+
+            let command = match SMTPCommand::try_from(line.as_ref()) {
+                Ok(command) => command,
+                Err(SMTPCommandParseError::InvalidCommand) => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::COMMAND_UNRECOGNIZED,
+                        EnhancedStatusCode::INVALID_COMMAND,
+                        "Syntax error, command unrecognized",
+                    ))
+                    .await?;
+                    continue;
+                }
+                Err(_) => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::SYNTAX_ERROR_IN_PARAMETERS,
+                        EnhancedStatusCode::SYNTAX_ERROR,
+                        "Syntax error in parameters or arguments",
+                    ))
+                    .await?;
+                    continue;
+                }
+            };
+
+            trace!("Received SMTP command: {:?}", command);
+
+            match command {
+                Hello { domain } => {
+                    self.transaction = None;
+                    self.client_domain = Some(domain);
+                    self.send_reply(SMTPReply::new(SMTPReplyCode::OK, server_hostname()))
+                        .await?;
+                }
+                ExtendedHello { domain } => {
+                    self.transaction = None;
+                    self.client_domain = Some(domain);
+                    // STARTTLS is only advertised before TLS has started
+                    // (RFC 3207 section 4.2)
+                    let starttls = match self.tls {
+                        TlsState::Available(_) => "\r\nSTARTTLS",
+                        _ => "",
+                    };
+                    self.send_reply(SMTPReply::new(
+                        SMTPReplyCode::OK,
+                        format!(
+                            "{}\r\n8BITMIME\r\nENHANCEDSTATUSCODES\r\nSIZE {}\r\nAUTH {}{}\r\nHELP",
+                            server_hostname(),
+                            MAX_MESSAGE_SIZE,
+                            sasl::MECHANISMS.join(" "),
+                            starttls
+                        ),
+                    ))
+                    .await?;
+                }
+                MailFrom { sender, parameters } => {
+                    if self.client_domain.is_none() {
+                        self.send_reply(SMTPReply::enhanced(
+                            SMTPReplyCode::BAD_SEQUENCE,
+                            EnhancedStatusCode::INVALID_COMMAND,
+                            "Send HELO or EHLO first",
+                        ))
+                        .await?;
+                    } else if self.transaction.is_some() {
+                        self.send_reply(SMTPReply::enhanced(
+                            SMTPReplyCode::BAD_SEQUENCE,
+                            EnhancedStatusCode::INVALID_COMMAND,
+                            "Nested MAIL command",
+                        ))
+                        .await?;
+                    } else if parameters.iter().any(|p| {
+                        p.keyword == "SIZE"
+                            && p.value
+                                .as_ref()
+                                .and_then(|v| v.parse::<usize>().ok())
+                                .is_some_and(|size| size > MAX_MESSAGE_SIZE)
+                    }) {
+                        self.send_reply(SMTPReply::enhanced(
+                            SMTPReplyCode::EXCEEDED_STORAGE,
+                            EnhancedStatusCode::MESSAGE_TOO_BIG,
+                            "Message size exceeds fixed maximum message size",
+                        ))
+                        .await?;
+                    } else {
+                        self.transaction = Some(MailTransaction {
+                            sender,
+                            recipients: vec![],
+                            remote_recipients: vec![],
+                        });
+                        self.send_reply(SMTPReply::enhanced(
+                            SMTPReplyCode::OK,
+                            EnhancedStatusCode::SENDER_OK,
+                            "OK",
+                        ))
+                        .await?;
+                    }
+                }
+                Recipient { recipient, .. } => {
+                    let transaction = match self.transaction.as_mut() {
+                        Some(t) => t,
+                        None => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::BAD_SEQUENCE,
+                                EnhancedStatusCode::INVALID_COMMAND,
+                                "Need MAIL command",
+                            ))
+                            .await?;
+                            continue;
+                        }
+                    };
+
+                    let address = match recipient {
+                        ForwardPath::Path(path) => path.mailbox,
+                        ForwardPath::Postmaster => {
+                            EmailAddress::new_unchecked(format!("postmaster@{}", primary_domain()))
+                        }
+                    };
+
+                    match find_local_address(&address) {
+                        Some(address) => {
+                            if !transaction.recipients.contains(&address) {
+                                transaction.recipients.push(address);
+                            }
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::OK,
+                                EnhancedStatusCode::DESTINATION_VALID,
+                                "OK",
+                            ))
+                            .await?;
+                        }
+                        None if is_local_domain(address.domain()) => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::MAILBOX_UNAVAILABLE,
+                                EnhancedStatusCode::BAD_DESTINATION_MAILBOX,
+                                "No such user here",
+                            ))
+                            .await?
+                        }
+                        // Authenticated users may only relay mail that they
+                        // send themselves
+                        None if self.user.as_ref().is_some_and(|u| !transaction.sent_by(u)) => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::MAILBOX_UNAVAILABLE,
+                                EnhancedStatusCode::DELIVERY_NOT_AUTHORIZED,
+                                "Sender address does not belong to the authenticated user",
+                            ))
+                            .await?
+                        }
+                        None if self.user.is_some() => {
+                            if !transaction.remote_recipients.contains(&address) {
+                                transaction.remote_recipients.push(address);
+                            }
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::OK,
+                                EnhancedStatusCode::DESTINATION_VALID,
+                                "OK",
+                            ))
+                            .await?;
+                        }
+                        None => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::MAILBOX_UNAVAILABLE,
+                                EnhancedStatusCode::DELIVERY_NOT_AUTHORIZED,
+                                "Relaying not permitted",
+                            ))
+                            .await?
+                        }
+                    }
+                }
+                Data => {
+                    let transaction = match self.transaction.take() {
+                        Some(t) if t.has_recipients() => t,
+                        Some(t) => {
+                            self.transaction = Some(t);
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::TRANSACTION_FAILED,
+                                EnhancedStatusCode::INVALID_COMMAND,
+                                "No valid recipients",
+                            ))
+                            .await?;
+                            continue;
+                        }
+                        None => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::BAD_SEQUENCE,
+                                EnhancedStatusCode::INVALID_COMMAND,
+                                "Need MAIL command",
+                            ))
+                            .await?;
+                            continue;
+                        }
+                    };
+
+                    self.send_reply(SMTPReply::new(
+                        SMTPReplyCode::START_MAIL_INPUT,
+                        "Start mail input; end with <CRLF>.<CRLF>",
+                    ))
+                    .await?;
+
+                    let content = match self.read_data().await? {
+                        Ok(content) => content,
+                        Err(reply) => {
+                            self.send_reply(reply).await?;
+                            continue;
+                        }
+                    };
+
+                    let message = format!("{}{}", self.received_header(), content);
+                    match self.deliver(&transaction, message).await {
+                        Ok(()) => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::OK,
+                                EnhancedStatusCode::MESSAGE_ACCEPTED,
+                                "OK",
+                            ))
+                            .await?
+                        }
+                        Err(e) => {
+                            warn!("Couldn't store incoming message: {}", e);
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::LOCAL_ERROR,
+                                EnhancedStatusCode::TEMPORARY_SYSTEM_ERROR,
+                                "Local error in processing",
+                            ))
+                            .await?;
+                        }
+                    }
+                }
+                Reset => {
+                    self.transaction = None;
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::OK,
+                        EnhancedStatusCode::SUCCESS,
+                        "OK",
+                    ))
+                    .await?;
+                }
+                Verify { .. } => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::CANNOT_VERIFY_USER,
+                        EnhancedStatusCode::SUCCESS,
+                        "Cannot VRFY user, but will accept message",
+                    ))
+                    .await?
+                }
+                Expand { .. } => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::COMMAND_NOT_IMPLEMENTED,
+                        EnhancedStatusCode::INVALID_COMMAND,
+                        "Command not implemented",
+                    ))
+                    .await?
+                }
+                Help { .. } => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::HELP_MESSAGE,
+                        EnhancedStatusCode::SUCCESS,
+                        "Commands: HELO EHLO STARTTLS AUTH MAIL RCPT DATA RSET NOOP QUIT VRFY HELP",
+                    ))
+                    .await?
+                }
+                Noop => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::OK,
+                        EnhancedStatusCode::SUCCESS,
+                        "OK",
+                    ))
+                    .await?
+                }
+                Auth {
+                    mechanism,
+                    initial_response,
+                } => {
+                    let reply = if self.client_domain.is_none() {
+                        SMTPReply::enhanced(
+                            SMTPReplyCode::BAD_SEQUENCE,
+                            EnhancedStatusCode::INVALID_COMMAND,
+                            "Send EHLO first",
+                        )
+                    } else if self.user.is_some() {
+                        SMTPReply::enhanced(
+                            SMTPReplyCode::BAD_SEQUENCE,
+                            EnhancedStatusCode::INVALID_COMMAND,
+                            "Already authenticated",
+                        )
+                    } else if self.transaction.is_some() {
+                        SMTPReply::enhanced(
+                            SMTPReplyCode::BAD_SEQUENCE,
+                            EnhancedStatusCode::INVALID_COMMAND,
+                            "AUTH not permitted during a mail transaction",
+                        )
+                    } else {
+                        self.authenticate(&mechanism, initial_response.as_deref())
+                            .await?
+                    };
+
+                    self.send_reply(reply).await?;
+
+                    if self.logins.exhausted() {
+                        self.send_reply(SMTPReply::enhanced(
+                            SMTPReplyCode::SERVICE_NOT_AVAILABLE,
+                            EnhancedStatusCode::TEMPORARY_AUTHENTICATION_FAILURE,
+                            "Too many failed authentication attempts, closing connection",
+                        ))
+                        .await?;
+                        self.close().await?;
+                        return Ok(());
+                    }
+                }
+                StartTls => {
+                    let acceptor = match &self.tls {
+                        TlsState::Available(acceptor) => acceptor.clone(),
+                        TlsState::Active => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::BAD_SEQUENCE,
+                                EnhancedStatusCode::INVALID_COMMAND,
+                                "TLS already active",
+                            ))
+                            .await?;
+                            continue;
+                        }
+                        TlsState::Unavailable => {
+                            self.send_reply(SMTPReply::enhanced(
+                                SMTPReplyCode::TLS_NOT_AVAILABLE,
+                                EnhancedStatusCode::TEMPORARY_SYSTEM_ERROR,
+                                "TLS not available",
+                            ))
+                            .await?;
+                            continue;
+                        }
+                    };
+
+                    // Anything the client pipelined after STARTTLS was sent
+                    // in plain text, and could have been injected by an
+                    // attacker, so throw it away
+                    self.buffer.clear();
+                    self.send_reply(SMTPReply::new(
+                        SMTPReplyCode::SERVICE_READY,
+                        "Ready to start TLS",
+                    ))
+                    .await?;
+                    start_tls(&mut self.stream, &acceptor).await?;
+                    self.tls = TlsState::Active;
+
+                    // The client has to greet the server again, and forget
+                    // everything it learned before TLS (RFC 3207 section 4.2)
+                    self.client_domain = None;
+                    self.transaction = None;
+                    self.user = None;
+                }
+                Quit => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::SERVICE_CLOSING,
+                        EnhancedStatusCode::SUCCESS,
+                        format!("{} closing connection", server_hostname()),
+                    ))
+                    .await?;
+                    self.close().await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// SASL challenges are sent as 334 replies, and the client's responses
+/// are single lines (RFC 4954 section 4)
+impl sasl::Transport for IncomingSMTPConnection {
+    async fn send_challenge(&mut self, challenge: &str) -> io::Result<()> {
+        // The space after the code is required even if the challenge is
+        // empty, so this can't be an SMTPReply
+        let line = format!("{} {}\r\n", SMTPReplyCode::SERVER_CHALLENGE, challenge);
+        self.stream.write_all(line.as_bytes()).await
+    }
+
+    async fn read_response(&mut self) -> io::Result<String> {
+        let line = self.read_line(MAX_AUTH_LINE).await?;
+        Ok(String::from_utf8_lossy(&line)
+            .trim_end_matches(['\r', '\n'])
+            .to_owned())
+    }
+
+    fn login_attempts(&mut self) -> &mut sasl::LoginAttempts {
+        &mut self.logins
     }
 }
 
 impl IncomingSMTPConnection {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new<S: AsyncStream + 'static>(stream: S, peer: SocketAddr, tls: TlsState) -> Self {
         Self {
-            stream,
-            buffer: String::new(),
+            stream: Box::new(stream),
+            buffer: BytesMut::new(),
+            ip: peer.ip(),
+            tls,
+            client_domain: None,
+            transaction: None,
+            user: None,
+            logins: sasl::LoginAttempts::new(peer.ip()),
+        }
+    }
+
+    /// Send a reply to the client. Failure replies are logged.
+    pub async fn send_reply(&mut self, reply: SMTPReply) -> Result<(), io::Error> {
+        if reply.code.class() >= 4 {
+            info!(
+                "Sending SMTP failure reply: {}",
+                reply.to_string().trim_end()
+            );
         }
+
+        self.stream.write_all(String::from(reply).as_bytes()).await
     }
 
-    pub async fn read_command(&mut self) -> Result<SMTPCommand, io::Error> {
+    /// Read one line from the client, including the line terminator.
+    ///
+    /// If the line is longer than `max_len`, the client is told so and the
+    /// connection is closed. If the client doesn't send anything for
+    /// `COMMAND_TIMEOUT`, the connection is closed too.
+    pub async fn read_line(&mut self, max_len: usize) -> Result<Bytes, io::Error> {
         loop {
-            if self.stream.read_to_string(&mut self.buffer).await? == 0 {
-                // Connection aborted
+            if let Some(i) = self.buffer.iter().position(|b| *b == b'\n') {
+                return Ok(self.buffer.split_to(i + 1).freeze());
+            }
+
+            if self.buffer.len() > max_len {
+                self.send_reply(SMTPReply::enhanced(
+                    SMTPReplyCode::COMMAND_UNRECOGNIZED,
+                    EnhancedStatusCode::PROTOCOL_ERROR,
+                    "Line too long",
+                ))
+                .await?;
                 self.close().await?;
-                return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
-            };
+                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            }
 
-            match SMTPCommand::try_from(self.buffer.as_str()) {
-                Ok(command) => {
-                    self.buffer.clear();
-                    return Ok(command);
+            match timeout(COMMAND_TIMEOUT, self.stream.read_buf(&mut self.buffer)).await {
+                Ok(Ok(0)) => {
+                    // Connection aborted
+                    return Err(io::Error::from(io::ErrorKind::ConnectionAborted));
                 }
-                Err(SMTPCommandParseError::IncompleteCommand) => {
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    self.send_reply(SMTPReply::enhanced(
+                        SMTPReplyCode::SERVICE_NOT_AVAILABLE,
+                        EnhancedStatusCode::BAD_CONNECTION,
+                        "Timeout exceeded, closing connection",
+                    ))
+                    .await?;
+                    self.close().await?;
+                    return Err(io::Error::from(io::ErrorKind::TimedOut));
+                }
+            }
+        }
+    }
+
+    /// Read the mail data that follows the `DATA` command, up to and
+    /// excluding the terminating "CRLF.CRLF". Leading dots are removed as
+    /// described in RFC 5321 section 4.5.2.
+    ///
+    /// Gives the reply to refuse the message with if it's larger than
+    /// `MAX_MESSAGE_SIZE`, or if it isn't valid UTF-8. Messages are stored
+    /// as text, so 8-bit data in another charset is refused rather than
+    /// changed. The rest of the message is still read so that the session
+    /// can go on.
+    async fn read_data(&mut self) -> Result<Result<String, SMTPReply>, io::Error> {
+        let mut content = String::new();
+        let mut refusal = None;
+
+        loop {
+            let line = self.read_line(MAX_MESSAGE_SIZE).await?;
+
+            if &line[..] == b".\r\n" || &line[..] == b".\n" {
+                break;
+            }
+
+            if refusal.is_some() {
+                continue;
+            }
+
+            let line = line.strip_prefix(b".").unwrap_or(&line);
+            match str::from_utf8(line) {
+                Ok(line) => content.push_str(line),
+                Err(_) => {
+                    refusal = Some(SMTPReply::enhanced(
+                        SMTPReplyCode::TRANSACTION_FAILED,
+                        EnhancedStatusCode::MEDIA_ERROR,
+                        "Message data must be UTF-8",
+                    ));
+                    content.clear();
                     continue;
                 }
-                Err(e) => println!("SMTP command parse error: {:?}", e),
+            }
+
+            if content.len() > MAX_MESSAGE_SIZE {
+                refusal = Some(SMTPReply::enhanced(
+                    SMTPReplyCode::EXCEEDED_STORAGE,
+                    EnhancedStatusCode::MESSAGE_TOO_BIG,
+                    "Message size exceeds fixed maximum message size",
+                ));
+                content.clear();
             }
         }
 
-        // TODO: Fix unreachable
-        // Ok(SMTPCommand::Noop)
+        match refusal {
+            Some(reply) => Ok(Err(reply)),
+            None => Ok(Ok(content)),
+        }
+    }
+
+    /// Carry out a SASL exchange with the client (RFC 4954) and get the
+    /// reply to send when it's over. Only I/O errors are returned.
+    async fn authenticate(
+        &mut self,
+        mechanism: &str,
+        initial_response: Option<&str>,
+    ) -> Result<SMTPReply, io::Error> {
+        let encrypted = self.tls.is_active();
+        let reply = match sasl::authenticate(self, mechanism, initial_response, encrypted).await {
+            Ok(user) => {
+                info!("SMTP client authenticated as {}", user.email_address);
+                self.user = Some(user);
+                SMTPReply::enhanced(
+                    SMTPReplyCode::AUTHENTICATION_SUCCEEDED,
+                    EnhancedStatusCode::AUTHENTICATION_SUCCEEDED,
+                    "Authentication successful",
+                )
+            }
+            Err(SaslError::Io(e)) => return Err(e),
+            Err(SaslError::Database(e)) => {
+                warn!("Couldn't authenticate SMTP client: {}", e);
+                SMTPReply::enhanced(
+                    SMTPReplyCode::TEMPORARY_AUTHENTICATION_FAILURE,
+                    EnhancedStatusCode::TEMPORARY_AUTHENTICATION_FAILURE,
+                    "Temporary authentication failure",
+                )
+            }
+            Err(SaslError::UnsupportedMechanism) => SMTPReply::enhanced(
+                SMTPReplyCode::PARAMETER_NOT_IMPLEMENTED,
+                EnhancedStatusCode::INVALID_ARGUMENTS,
+                "Unrecognized authentication type",
+            ),
+            Err(SaslError::Cancelled) => SMTPReply::enhanced(
+                SMTPReplyCode::SYNTAX_ERROR_IN_PARAMETERS,
+                EnhancedStatusCode::SECURITY_ERROR,
+                "Authentication cancelled",
+            ),
+            Err(SaslError::MalformedResponse) => SMTPReply::enhanced(
+                SMTPReplyCode::SYNTAX_ERROR_IN_PARAMETERS,
+                EnhancedStatusCode::SYNTAX_ERROR,
+                "Invalid authentication response",
+            ),
+            Err(SaslError::AuthenticationFailed) => SMTPReply::enhanced(
+                SMTPReplyCode::AUTHENTICATION_CREDENTIALS_INVALID,
+                EnhancedStatusCode::INVALID_CREDENTIALS,
+                "Authentication credentials invalid",
+            ),
+            Err(SaslError::LockedOut) => SMTPReply::enhanced(
+                SMTPReplyCode::TEMPORARY_AUTHENTICATION_FAILURE,
+                EnhancedStatusCode::TEMPORARY_AUTHENTICATION_FAILURE,
+                "Too many failed authentication attempts, try again later",
+            ),
+            Err(SaslError::EncryptionRequired) => SMTPReply::enhanced(
+                SMTPReplyCode::ENCRYPTION_REQUIRED,
+                EnhancedStatusCode::ENCRYPTION_REQUIRED,
+                "Encryption required, use STARTTLS first",
+            ),
+        };
+
+        Ok(reply)
+    }
+
+    /// Store a received message in the mailboxes of its local recipients,
+    /// and queue it for its recipients in other domains.
+    async fn deliver(&self, transaction: &MailTransaction, message: String) -> Result<(), DbErr> {
+        if !transaction.remote_recipients.is_empty() {
+            let sender = match &transaction.sender {
+                ReversePath::Null => None,
+                ReversePath::Path(path) => Some(&path.mailbox),
+            };
+            queue_database::enqueue(sender, &transaction.remote_recipients, message.clone())
+                .await?;
+        }
+
+        if !transaction.recipients.is_empty() {
+            // The Return-Path header is added at final delivery (RFC 5321
+            // section 4.4)
+            let return_path = match &transaction.sender {
+                ReversePath::Null => "<>".to_owned(),
+                ReversePath::Path(path) => format!("<{}>", path.mailbox),
+            };
+            let message = format!("Return-Path: {}\r\n{}", return_path, message);
+            mail_database::deliver_mail(&transaction.recipients, message).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Generate the `Received` header that is added to the top of every
+    /// message received by this server (see RFC 5321 section 4.4). The
+    /// protocol has an "S" if the connection is encrypted, and an "A" if
+    /// the client authenticated (RFC 3848).
+    fn received_header(&self) -> String {
+        format!(
+            "Received: from {} ([{}])\r\n\tby {} with {};\r\n\t{}\r\n",
+            self.client_domain.as_deref().unwrap_or("unknown"),
+            self.ip,
+            server_hostname(),
+            match (self.tls.is_active(), self.user.is_some()) {
+                (true, true) => "ESMTPSA",
+                (true, false) => "ESMTPS",
+                (false, true) => "ESMTPA",
+                (false, false) => "SMTP",
+            },
+            chrono::Local::now().to_rfc2822()
+        )
     }
 
     /// Close the connection
@@ -74,3 +732,229 @@ impl IncomingSMTPConnection {
         Ok(())
     }
 }
+
+#[test]
+fn commands_out_of_order_are_rejected() {
+    use crate::testing::{run, session};
+
+    run(session::<IncomingSMTPConnection, _, _>(
+        TlsState::Unavailable,
+        |mut client| async move {
+            assert!(client.read_line().await.starts_with("220 "));
+
+            assert_eq!(
+                client.command("MAIL FROM:<a@example.org>").await,
+                "503 5.5.1 Send HELO or EHLO first"
+            );
+            assert!(client
+                .command("HELO client.example.org")
+                .await
+                .starts_with("250 "));
+            assert_eq!(
+                client.command("RCPT TO:<smtp@example.com>").await,
+                "503 5.5.1 Need MAIL command"
+            );
+            assert_eq!(client.command("DATA").await, "503 5.5.1 Need MAIL command");
+
+            assert_eq!(
+                client.command("MAIL FROM:<a@example.org>").await,
+                "250 2.1.0 OK"
+            );
+            assert_eq!(
+                client.command("MAIL FROM:<a@example.org>").await,
+                "503 5.5.1 Nested MAIL command"
+            );
+
+            // RSET and HELO both end the transaction
+            assert_eq!(client.command("RSET").await, "250 2.0.0 OK");
+            assert_eq!(
+                client.command("RCPT TO:<smtp@example.com>").await,
+                "503 5.5.1 Need MAIL command"
+            );
+            assert_eq!(
+                client.command("MAIL FROM:<a@example.org>").await,
+                "250 2.1.0 OK"
+            );
+            assert!(client
+                .command("HELO client.example.org")
+                .await
+                .starts_with("250 "));
+            assert_eq!(
+                client.command("RCPT TO:<smtp@example.com>").await,
+                "503 5.5.1 Need MAIL command"
+            );
+        },
+    ));
+}
+
+#[test]
+fn recipients_are_checked() {
+    use crate::testing::{run, session};
+
+    run(session::<IncomingSMTPConnection, _, _>(
+        TlsState::Unavailable,
+        |mut client| async move {
+            client.read_line().await;
+            client.command("HELO client.example.org").await;
+            client.command("MAIL FROM:<a@example.org>").await;
+
+            assert_eq!(
+                client.command("RCPT TO:<nobody@example.com>").await,
+                "550 5.1.1 No such user here"
+            );
+            assert_eq!(
+                client.command("RCPT TO:<someone@example.org>").await,
+                "550 5.7.1 Relaying not permitted"
+            );
+            // The transaction is still open, but nobody can receive it
+            assert_eq!(
+                client.command("DATA").await,
+                "554 5.5.1 No valid recipients"
+            );
+
+            assert_eq!(
+                client.command("RCPT TO:<SMTP@example.com>").await,
+                "250 2.1.5 OK"
+            );
+            assert!(client.command("DATA").await.starts_with("354 "));
+        },
+    ));
+}
+
+#[test]
+fn failed_auth_is_limited() {
+    use crate::testing::{run, session};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let plain = |password: &str| {
+        format!(
+            "AUTH PLAIN {}",
+            STANDARD.encode(format!("\0smtp-auth@example.com\0{}", password))
+        )
+    };
+    let failed = "535 5.7.8 Authentication credentials invalid";
+    let closing = "421 4.7.0 Too many failed authentication attempts, closing connection";
+
+    run(async {
+        // The connection is closed after `max_attempts` failures
+        session::<IncomingSMTPConnection, _, _>(TlsState::Unavailable, |mut client| async move {
+            client.read_line().await;
+            client.command("HELO client.example.org").await;
+            assert_eq!(client.command(&plain("wrong")).await, failed);
+            assert_eq!(client.command(&plain("wrong")).await, failed);
+            assert_eq!(client.command(&plain("wrong")).await, failed);
+            assert_eq!(client.read_line().await, closing);
+            assert_eq!(client.read_line().await, "");
+        })
+        .await;
+
+        // The failures add up across connections until the client is
+        // locked out, and then even the right password doesn't work
+        session::<IncomingSMTPConnection, _, _>(TlsState::Unavailable, |mut client| async move {
+            client.read_line().await;
+            client.command("HELO client.example.org").await;
+            assert_eq!(client.command(&plain("wrong")).await, failed);
+            assert_eq!(client.command(&plain("wrong")).await, failed);
+            assert_eq!(
+                client.command(&plain("password")).await,
+                "454 4.7.0 Too many failed authentication attempts, try again later"
+            );
+            assert_eq!(client.read_line().await, closing);
+        })
+        .await;
+    });
+}
+
+#[test]
+fn relaying_needs_own_sender_address() {
+    use crate::testing::{run, session};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    run(session::<IncomingSMTPConnection, _, _>(
+        TlsState::Unavailable,
+        |mut client| async move {
+            client.read_line().await;
+            client.command("HELO client.example.org").await;
+
+            let auth = format!(
+                "AUTH PLAIN {}",
+                STANDARD.encode("\0relay@example.com\0password")
+            );
+            assert_eq!(
+                client.command(&auth).await,
+                "235 2.7.0 Authentication successful"
+            );
+
+            let not_owned = "550 5.7.1 Sender address does not belong to the authenticated user";
+            for sender in ["<smtp@example.com>", "<someone@example.org>", "<>"] {
+                client.command(&format!("MAIL FROM:{}", sender)).await;
+                assert_eq!(
+                    client.command("RCPT TO:<friend@example.org>").await,
+                    not_owned
+                );
+                // Local recipients don't need relaying
+                assert_eq!(
+                    client.command("RCPT TO:<smtp@example.com>").await,
+                    "250 2.1.5 OK"
+                );
+                client.command("RSET").await;
+            }
+
+            client.command("MAIL FROM:<Relay@example.com>").await;
+            assert_eq!(
+                client.command("RCPT TO:<friend@example.org>").await,
+                "250 2.1.5 OK"
+            );
+        },
+    ));
+}
+
+#[test]
+fn non_utf8_data_is_refused() {
+    use crate::testing::{run, session};
+
+    let account = "smtp-8bit@example.com";
+
+    run(async {
+        session::<IncomingSMTPConnection, _, _>(TlsState::Unavailable, |mut client| async move {
+            client.read_line().await;
+            client.command("HELO client.example.org").await;
+
+            assert_eq!(
+                client
+                    .command("MAIL FROM:<a@example.org> BODY=8BITMIME")
+                    .await,
+                "250 2.1.0 OK"
+            );
+            assert_eq!(
+                client.command(&format!("RCPT TO:<{}>", account)).await,
+                "250 2.1.5 OK"
+            );
+            assert!(client.command("DATA").await.starts_with("354 "));
+            client.send("Subject: menu").await;
+            client.send("").await;
+            // "café" in Latin-1
+            client.send(b"caf\xe9").await;
+            assert_eq!(
+                client.command(".").await,
+                "554 5.6.0 Message data must be UTF-8"
+            );
+
+            // The session goes on, and UTF-8 is accepted
+            client
+                .command("MAIL FROM:<a@example.org> BODY=8BITMIME")
+                .await;
+            client.command(&format!("RCPT TO:<{}>", account)).await;
+            assert!(client.command("DATA").await.starts_with("354 "));
+            client.send("").await;
+            client.send("caf\u{e9}").await;
+            assert_eq!(client.command(".").await, "250 2.6.0 OK");
+        })
+        .await;
+
+        let address = EmailAddress::new_unchecked(account);
+        let mailbox = mail_database::get_mailbox(&address).await.unwrap();
+        assert_eq!(mailbox.len(), 1);
+        assert!(mailbox[0].content.ends_with("\r\ncaf\u{e9}\r\n"));
+    });
+}
@@ -1,3 +1,6 @@
+mod bounce;
+pub use bounce::*;
+
 mod command;
 pub use command::*;
 
@@ -10,6 +13,9 @@ pub use incoming_connection::*;
 mod outgoing_connection;
 pub use outgoing_connection::*;
 
+mod queue_worker;
+pub use queue_worker::*;
+
 mod reply;
 pub use reply::*;
 
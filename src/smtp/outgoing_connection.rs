@@ -1,44 +1,536 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use bytes::BytesMut;
+use email_address::EmailAddress;
+use log::{info, trace, warn};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::TokioAsyncResolver;
 
+use super::err::{SMTPClientError, SMTPReplyParseError};
 use super::reply::*;
-use crate::imf::Mail;
+use super::{ESMTPParameter, ForwardPath, Path, ReversePath, SMTPCommand};
+use crate::config_helpers::server_hostname;
+
+/// The port that mail exchangers listen on
+const SMTP_PORT: u16 = 25;
+
+/// How long to wait for a TCP connection to a mail exchanger
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a reply to a command. RFC 5321 section 4.5.3.2
+/// asks for at least 5 minutes for most commands.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait for the reply after the message data has been sent
+/// (RFC 5321 section 4.5.3.2.6)
+const DATA_TERMINATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The longest reply accepted from the server, in octets
+const MAX_REPLY_SIZE: usize = 64 * 1024;
+
+/// The outcome of trying to deliver a message to one recipient
+#[derive(PartialEq, Debug, Clone)]
+pub enum DeliveryStatus {
+    /// The recipient's server accepted the message
+    Delivered,
+
+    /// The message wasn't delivered, but it may be delivered if it is tried
+    /// again later (e.g. a 4xx reply or a network failure)
+    TemporaryFailure(DeliveryError),
+
+    /// The message wasn't delivered and should not be tried again (e.g. a
+    /// 5xx reply)
+    PermanentFailure(DeliveryError),
+}
+
+/// The reason a message wasn't delivered
+#[derive(PartialEq, Debug, Clone)]
+pub enum DeliveryError {
+    /// The remote server refused the message with this reply
+    Rejected(SMTPReply),
+
+    /// The message never made it to a server that could refuse it, e.g.
+    /// because of a DNS or network failure
+    Unreachable(String),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Put a multiline reply on one line
+            Self::Rejected(reply) => {
+                write!(f, "{}", reply.to_string().trim_end().replace("\r\n", " "))
+            }
+            Self::Unreachable(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl DeliveryStatus {
+    /// Get the delivery status that corresponds to a reply to `RCPT TO:` or
+    /// to the end of the message data.
+    fn from_reply(reply: &SMTPReply) -> Self {
+        match reply.code.class() {
+            2 => Self::Delivered,
+            5 => Self::PermanentFailure(DeliveryError::Rejected(reply.clone())),
+            _ => Self::TemporaryFailure(DeliveryError::Rejected(reply.clone())),
+        }
+    }
+}
 
 /// Handles an outgoing SMTP connection for sending email to another
 /// domain.
 pub struct OutgoingSMTPConnection {
-    outgoing_message: Mail,
+    // Socket state
+    stream: TcpStream,
+    buffer: BytesMut,
 }
 
 impl OutgoingSMTPConnection {
-    fn new(message: Mail) {}
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::new(),
+        }
+    }
 
-    async fn begin(&mut self) {
-        // TODO: Port 587
-        let mut socket = TcpStream::connect(self.outgoing_message.headers.get("To").unwrap()).await;
+    /// Deliver a message to every recipient in `recipients`. The mail
+    /// exchangers of each recipient's domain are looked up in DNS and tried
+    /// in order of preference.
+    ///
+    /// `message` is the whole RFC 5322 message, headers included. Returns
+    /// the outcome for every recipient.
+    pub async fn deliver(
+        sender: &ReversePath,
+        recipients: &[EmailAddress],
+        message: &str,
+    ) -> Vec<(EmailAddress, DeliveryStatus)> {
+        // Group the recipients by domain, so that each domain's servers are
+        // only contacted once
+        let mut domains: Vec<(String, Vec<EmailAddress>)> = vec![];
+        for recipient in recipients {
+            let domain = recipient.domain().to_ascii_lowercase();
+            match domains.iter_mut().find(|(d, _)| *d == domain) {
+                Some((_, list)) => list.push(recipient.clone()),
+                None => domains.push((domain, vec![recipient.clone()])),
+            }
+        }
 
+        let mut results = vec![];
+        for (domain, recipients) in domains {
+            results.extend(Self::deliver_to_domain(&domain, sender, &recipients, message).await);
+        }
+
+        results
+    }
+
+    /// Deliver a message to recipients that all belong to `domain`.
+    async fn deliver_to_domain(
+        domain: &str,
+        sender: &ReversePath,
+        recipients: &[EmailAddress],
+        message: &str,
+    ) -> Vec<(EmailAddress, DeliveryStatus)> {
+        let fail_all = |status: DeliveryStatus| -> Vec<(EmailAddress, DeliveryStatus)> {
+            recipients
+                .iter()
+                .map(|r| (r.clone(), status.clone()))
+                .collect()
+        };
+
+        let addresses = match mail_exchanger_addresses(domain).await {
+            Ok(addresses) => addresses,
+            Err(status) => return fail_all(status),
+        };
+
+        let mut last_error = DeliveryError::Unreachable(format!(
+            "no mail exchanger for {} has an IP address",
+            domain
+        ));
+
+        for address in addresses {
+            trace!("Connecting to mail exchanger {} for {}", address, domain);
+
+            let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect((address, SMTP_PORT)))
+                .await
+            {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    last_error = DeliveryError::Unreachable(format!(
+                        "couldn't connect to {}: {}",
+                        address, e
+                    ));
+                    continue;
+                }
+                Err(_) => {
+                    last_error =
+                        DeliveryError::Unreachable(format!("timed out connecting to {}", address));
+                    continue;
+                }
+            };
+
+            let mut connection = Self::new(stream);
+            match connection.send_mail(sender, recipients, message).await {
+                Ok(results) => {
+                    info!("Finished SMTP session with {} for {}", address, domain);
+                    return results;
+                }
+                Err(SessionError::Refused(reply)) => {
+                    warn!(
+                        "Mail exchanger {} refused the session: {:?}",
+                        address, reply
+                    );
+                    last_error = DeliveryError::Rejected(reply);
+                }
+                Err(SessionError::Client(e)) => {
+                    warn!("SMTP session with {} failed: {}", address, e);
+                    last_error = DeliveryError::Unreachable(e.to_string());
+                }
+            }
+        }
+
+        // None of the mail exchangers would take the message, so try again
+        // later
+        fail_all(DeliveryStatus::TemporaryFailure(last_error))
+    }
+
+    /// Send a message over an open connection, from the server's greeting
+    /// to `QUIT`.
+    ///
+    /// Returns an error if the session can't be carried out with this
+    /// server, in which case the next mail exchanger should be tried.
+    async fn send_mail(
+        &mut self,
+        sender: &ReversePath,
+        recipients: &[EmailAddress],
+        message: &str,
+    ) -> Result<Vec<(EmailAddress, DeliveryStatus)>, SessionError> {
         // Await opening message
+        let greeting = self.await_reply(REPLY_TIMEOUT).await?;
+        if greeting.code.class() != 2 {
+            self.quit().await;
+            return Err(SessionError::Refused(greeting));
+        }
+
+        let extensions = self.hello().await?;
+        let has_extension =
+            |name: &str| extensions.iter().any(|e| e.split(' ').next() == Some(name));
+
+        let message = dot_stuff(message);
 
-        // Send EHLO message
-        // Await response
-        // If the response is "command not recognised", then send HELO
+        let mut parameters = vec![];
+        if has_extension("SIZE") {
+            parameters.push(ESMTPParameter {
+                keyword: "SIZE".to_owned(),
+                value: Some(message.len().to_string()),
+            });
+        }
+        if has_extension("8BITMIME") && !message.is_ascii() {
+            parameters.push(ESMTPParameter {
+                keyword: "BODY".to_owned(),
+                value: Some("8BITMIME".to_owned()),
+            });
+        }
 
-        // Send MAIL_FROM command
-        // Await response
+        let mut results = vec![];
 
-        // Send RCPT TO commands
-        // Await response
+        let reply = self
+            .send_command(SMTPCommand::MailFrom {
+                sender: sender.clone(),
+                parameters,
+            })
+            .await?;
+        if reply.code.class() != 2 {
+            // The sender was refused, so none of the recipients get the message
+            self.quit().await;
+            let status = DeliveryStatus::from_reply(&reply);
+            return Ok(recipients
+                .iter()
+                .map(|r| (r.clone(), status.clone()))
+                .collect());
+        }
 
-        // Send DATA command
-        // Await response
-        // Send message contents
-        // Await response
+        let mut accepted = vec![];
+        for recipient in recipients {
+            let reply = self
+                .send_command(SMTPCommand::Recipient {
+                    recipient: ForwardPath::Path(Path {
+                        source_route: vec![],
+                        mailbox: recipient.clone(),
+                    }),
+                    parameters: vec![],
+                })
+                .await?;
+
+            match DeliveryStatus::from_reply(&reply) {
+                DeliveryStatus::Delivered => accepted.push(recipient.clone()),
+                status => results.push((recipient.clone(), status)),
+            }
+        }
+
+        if accepted.is_empty() {
+            self.quit().await;
+            return Ok(results);
+        }
+
+        let reply = self.send_command(SMTPCommand::Data).await?;
+        let final_reply = if reply.code == SMTPReplyCode::START_MAIL_INPUT {
+            self.stream.write_all(message.as_bytes()).await?;
+            self.await_reply(DATA_TERMINATION_TIMEOUT).await?
+        } else {
+            reply
+        };
+
+        let status = DeliveryStatus::from_reply(&final_reply);
+        results.extend(accepted.into_iter().map(|r| (r, status.clone())));
+
+        self.quit().await;
+
+        Ok(results)
+    }
+
+    /// Introduce ourselves to the server with `EHLO`, or with `HELO` if the
+    /// server doesn't support `EHLO`. Returns the service extensions the
+    /// server supports (e.g. "SIZE 1000000" or "8BITMIME"), in uppercase.
+    async fn hello(&mut self) -> Result<Vec<String>, SessionError> {
+        let reply = self
+            .send_command(SMTPCommand::ExtendedHello {
+                domain: server_hostname(),
+            })
+            .await?;
+        if reply.code.class() == 2 {
+            // The first line is the server's name, and every other line is
+            // an extension
+            return Ok(reply
+                .text
+                .split("\r\n")
+                .skip(1)
+                .map(|e| e.to_ascii_uppercase())
+                .collect());
+        }
+
+        let reply = self
+            .send_command(SMTPCommand::Hello {
+                domain: server_hostname(),
+            })
+            .await?;
+        if reply.code.class() == 2 {
+            Ok(vec![])
+        } else {
+            self.quit().await;
+            Err(SessionError::Refused(reply))
+        }
+    }
 
-        // Send QUIT command
-        // Let the other server close the connection
+    /// Send a command to the server and wait for the reply
+    async fn send_command(&mut self, command: SMTPCommand) -> Result<SMTPReply, SMTPClientError> {
+        trace!("Sending SMTP command: {:?}", command);
+        self.stream
+            .write_all(command.to_string().as_bytes())
+            .await?;
+        self.await_reply(REPLY_TIMEOUT).await
     }
 
-    async fn await_response(&mut self) -> Result<SMTPReply, ()> {
-        todo!();
+    /// End the session. Errors are ignored because the session is over
+    /// anyway.
+    async fn quit(&mut self) {
+        if self.send_command(SMTPCommand::Quit).await.is_ok() {
+            let _ = self.stream.shutdown().await;
+        }
     }
+
+    /// Read a (possibly multiline) reply from the server
+    async fn await_reply(&mut self, duration: Duration) -> Result<SMTPReply, SMTPClientError> {
+        let mut reply = String::new();
+
+        loop {
+            let line = self.read_line(duration).await?;
+            let line = String::from_utf8_lossy(&line);
+            reply.push_str(line.trim_end_matches(['\r', '\n']));
+            reply.push_str("\r\n");
+
+            // The last line of a reply doesn't have a "-" after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+
+            if reply.len() > MAX_REPLY_SIZE {
+                return Err(SMTPReplyParseError::InvalidMultilineResponse.into());
+            }
+        }
+
+        let reply = SMTPReply::try_from(reply.as_str())?;
+        trace!("Received SMTP reply: {:?}", reply);
+        Ok(reply)
+    }
+
+    /// Read one line from the server, including the line terminator
+    async fn read_line(&mut self, duration: Duration) -> Result<BytesMut, io::Error> {
+        loop {
+            if let Some(i) = self.buffer.iter().position(|b| *b == b'\n') {
+                return Ok(self.buffer.split_to(i + 1));
+            }
+
+            if self.buffer.len() > MAX_REPLY_SIZE {
+                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            }
+
+            match timeout(duration, self.stream.read_buf(&mut self.buffer)).await {
+                Ok(Ok(0)) => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+            }
+        }
+    }
+}
+
+/// Errors that end a session with one mail exchanger
+enum SessionError {
+    /// The server refused to talk to us, either in its greeting or in its
+    /// reply to `EHLO` and `HELO`
+    Refused(SMTPReply),
+
+    Client(SMTPClientError),
+}
+
+impl<T: Into<SMTPClientError>> From<T> for SessionError {
+    fn from(e: T) -> Self {
+        Self::Client(e.into())
+    }
+}
+
+/// Look up the IP addresses of the mail exchangers for `domain`, in the
+/// order they should be tried (RFC 5321 section 5.1).
+///
+/// If the domain has no MX records, the domain itself is the mail
+/// exchanger. If the domain can't receive mail, the returned error is the
+/// status that every recipient at the domain should get.
+async fn mail_exchanger_addresses(domain: &str) -> Result<Vec<IpAddr>, DeliveryStatus> {
+    let unreachable = |reason: String| DeliveryError::Unreachable(reason);
+
+    // Address literals (e.g. "[192.0.2.1]") don't need a DNS lookup
+    if let Some(literal) = domain.strip_prefix('[').and_then(|d| d.strip_suffix(']')) {
+        let literal = literal.strip_prefix("IPv6:").unwrap_or(literal);
+        return match literal.parse::<IpAddr>() {
+            Ok(address) => Ok(vec![address]),
+            Err(_) => Err(DeliveryStatus::PermanentFailure(unreachable(format!(
+                "unsupported address literal {}",
+                domain
+            )))),
+        };
+    }
+
+    let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+        .map_err(|e| DeliveryStatus::TemporaryFailure(unreachable(e.to_string())))?;
+
+    let hosts: Vec<String> = match resolver.mx_lookup(domain).await {
+        Ok(response) => {
+            let mut records: Vec<_> = response.iter().collect();
+
+            // A single MX record for "." means that the domain doesn't accept
+            // mail (RFC 7505)
+            if records.len() == 1 && records[0].exchange().is_root() {
+                return Err(DeliveryStatus::PermanentFailure(unreachable(format!(
+                    "{} does not accept mail",
+                    domain
+                ))));
+            }
+
+            records.sort_by_key(|mx| mx.preference());
+            records
+                .iter()
+                .filter(|mx| !mx.exchange().is_root())
+                .map(|mx| mx.exchange().to_string())
+                .collect()
+        }
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound {
+                response_code: ResponseCode::NXDomain,
+                ..
+            } => {
+                return Err(DeliveryStatus::PermanentFailure(unreachable(format!(
+                    "domain {} does not exist",
+                    domain
+                ))))
+            }
+            // Fall back to the domain's A and AAAA records
+            ResolveErrorKind::NoRecordsFound { .. } => vec![domain.to_owned()],
+            _ => return Err(DeliveryStatus::TemporaryFailure(unreachable(e.to_string()))),
+        },
+    };
+
+    let mut addresses = vec![];
+    for host in hosts {
+        match resolver.lookup_ip(host.as_str()).await {
+            Ok(response) => addresses.extend(response.iter()),
+            Err(e) => trace!("Couldn't look up mail exchanger {}: {}", host, e),
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// Make every line of a message end in CRLF, including the last one. Bare
+/// LFs from clients that don't follow RFC 5321 section 2.3.8 become CRLF.
+/// A message is sent, and signed, in this form.
+pub fn to_crlf(message: &str) -> String {
+    let mut out = String::with_capacity(message.len() + 2);
+
+    let message = message.strip_suffix('\n').unwrap_or(message);
+    let message = message.strip_suffix('\r').unwrap_or(message);
+
+    if !message.is_empty() {
+        for line in message.split('\n') {
+            out.push_str(line.strip_suffix('\r').unwrap_or(line));
+            out.push_str("\r\n");
+        }
+    }
+
+    out
+}
+
+/// Prepare a message to be sent after the `DATA` command: make sure that
+/// every line ends in CRLF, double any dot at the start of a line, and
+/// add the terminating "." line (RFC 5321 section 4.5.2).
+fn dot_stuff(message: &str) -> String {
+    let mut out = String::with_capacity(message.len() + 5);
+
+    for line in to_crlf(message).split_inclusive("\r\n") {
+        if line.starts_with('.') {
+            out.push('.');
+        }
+        out.push_str(line);
+    }
+
+    out.push_str(".\r\n");
+    out
+}
+
+#[test]
+fn message_dot_stuffing() {
+    assert_eq!(
+        dot_stuff("Subject: hi\r\n\r\nhello"),
+        "Subject: hi\r\n\r\nhello\r\n.\r\n"
+    );
+    assert_eq!(dot_stuff("hello\r\n"), "hello\r\n.\r\n");
+    assert_eq!(
+        dot_stuff("a\n.b\r\n..c\r\n."),
+        "a\r\n..b\r\n...c\r\n..\r\n.\r\n"
+    );
+    assert_eq!(dot_stuff(""), ".\r\n");
+}
+
+#[test]
+fn line_endings_become_crlf() {
+    assert_eq!(to_crlf("a\nb\r\nc"), "a\r\nb\r\nc\r\n");
+    assert_eq!(to_crlf("a\n\nb\n"), "a\r\n\r\nb\r\n");
+    assert_eq!(to_crlf("a\r\n"), "a\r\n");
+    assert_eq!(to_crlf(""), "");
 }
@@ -1,14 +1,479 @@
 //! nom parsers for SMTP
 //!
-//! See RFC 5321 for the SMTP syntax specifications
+//! See RFC 5321 for the SMTP syntax specifications. The grammar is
+//! reproduced above each parser. All the parsers are streaming parsers,
+//! so they return `nom::Err::Incomplete` if the input ends before the
+//! terminating CRLF.
+//!
+//! https://datatracker.ietf.org/doc/html/rfc5321#section-4.1.2
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use email_address::EmailAddress;
+use nom::{
+    branch::alt,
+    bytes::streaming::{tag, tag_no_case, take_while, take_while1},
+    character::streaming::{char, satisfy},
+    combinator::{map, map_res, opt, recognize, value, verify},
+    multi::{many0, separated_list1},
+    sequence::{delimited, preceded, terminated},
+    IResult, Parser,
+};
+
+use super::{ESMTPParameter, ForwardPath, Path, ReversePath, SMTPCommand};
+
+/// `CRLF = %d13.10`
+pub fn crlf(s: &str) -> IResult<&str, &str> {
+    tag("\r\n").parse(s)
+}
+
+/// `SP = %x20`
+pub fn sp(s: &str) -> IResult<&str, char> {
+    char(' ').parse(s)
+}
+
+/// The command verb at the start of a line: four letters for every command
+/// in RFC 5321. Used to decide which command parser to run.
+pub fn verb(s: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_alphabetic()).parse(s)
+}
+
+/// `ehlo = "EHLO" SP ( Domain / address-literal ) CRLF`
+pub fn ehlo(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited(
+            (tag_no_case("EHLO"), sp),
+            alt((domain, address_literal)),
+            crlf,
+        ),
+        |domain| SMTPCommand::ExtendedHello {
+            domain: domain.to_owned(),
+        },
+    )
+    .parse(s)
+}
+
+/// `helo = "HELO" SP Domain CRLF`
+pub fn helo(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited((tag_no_case("HELO"), sp), domain, crlf),
+        |domain| SMTPCommand::Hello {
+            domain: domain.to_owned(),
+        },
+    )
+    .parse(s)
+}
+
+/// `mail = "MAIL FROM:" Reverse-path [SP Mail-parameters] CRLF`
+pub fn mail(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited(
+            tag_no_case("MAIL FROM:"),
+            (reverse_path, opt(preceded(sp, esmtp_parameters))),
+            crlf,
+        ),
+        |(sender, parameters)| SMTPCommand::MailFrom {
+            sender,
+            parameters: parameters.unwrap_or_default(),
+        },
+    )
+    .parse(s)
+}
+
+/// ```text
+/// rcpt = "RCPT TO:" ( "<Postmaster@" Domain ">" / "<Postmaster>" /
+///        Forward-path ) [SP Rcpt-parameters] CRLF
+/// ```
+///
+/// `<Postmaster@domain>` is a valid `Path`, so it doesn't get special
+/// treatment here.
+pub fn rcpt(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited(
+            tag_no_case("RCPT TO:"),
+            (
+                alt((
+                    value(ForwardPath::Postmaster, tag_no_case("<Postmaster>")),
+                    map(path, ForwardPath::Path),
+                )),
+                opt(preceded(sp, esmtp_parameters)),
+            ),
+            crlf,
+        ),
+        |(recipient, parameters)| SMTPCommand::Recipient {
+            recipient,
+            parameters: parameters.unwrap_or_default(),
+        },
+    )
+    .parse(s)
+}
+
+/// `data = "DATA" CRLF`
+pub fn data(s: &str) -> IResult<&str, SMTPCommand> {
+    value(SMTPCommand::Data, (tag_no_case("DATA"), crlf)).parse(s)
+}
+
+/// `rset = "RSET" CRLF`
+pub fn rset(s: &str) -> IResult<&str, SMTPCommand> {
+    value(SMTPCommand::Reset, (tag_no_case("RSET"), crlf)).parse(s)
+}
+
+/// `vrfy = "VRFY" SP String CRLF`
+pub fn vrfy(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited((tag_no_case("VRFY"), sp), string, crlf),
+        |address| SMTPCommand::Verify { address },
+    )
+    .parse(s)
+}
+
+/// `expn = "EXPN" SP String CRLF`
+pub fn expn(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited((tag_no_case("EXPN"), sp), string, crlf),
+        |mailing_list| SMTPCommand::Expand { mailing_list },
+    )
+    .parse(s)
+}
+
+/// `help = "HELP" [ SP String ] CRLF`
+pub fn help(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited(tag_no_case("HELP"), opt(preceded(sp, string)), crlf),
+        |topic| SMTPCommand::Help { topic },
+    )
+    .parse(s)
+}
+
+/// `noop = "NOOP" [ SP String ] CRLF`
+///
+/// The argument has no meaning, so it is thrown away.
+pub fn noop(s: &str) -> IResult<&str, SMTPCommand> {
+    value(
+        SMTPCommand::Noop,
+        (tag_no_case("NOOP"), opt(preceded(sp, string)), crlf),
+    )
+    .parse(s)
+}
+
+/// `quit = "QUIT" CRLF`
+pub fn quit(s: &str) -> IResult<&str, SMTPCommand> {
+    value(SMTPCommand::Quit, (tag_no_case("QUIT"), crlf)).parse(s)
+}
+
+/// `starttls = "STARTTLS" CRLF` (RFC 3207 section 4)
+pub fn starttls(s: &str) -> IResult<&str, SMTPCommand> {
+    value(SMTPCommand::StartTls, (tag_no_case("STARTTLS"), crlf)).parse(s)
+}
+
+/// ```text
+/// auth-command = "AUTH" SP sasl-mech [SP initial-response] CRLF
+/// sasl-mech = 1*20mech-char
+/// mech-char = UPPER-ALPHA / DIGIT / HYPHEN / UNDERSCORE
+/// initial-response = base64 / "="
+/// ```
+///
+/// See RFC 4954 section 4. The mechanism name is accepted in any case and
+/// converted to uppercase.
+pub fn auth(s: &str) -> IResult<&str, SMTPCommand> {
+    map(
+        delimited(
+            (tag_no_case("AUTH"), sp),
+            (
+                verify(
+                    take_while1(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                    |mechanism: &str| mechanism.len() <= 20,
+                ),
+                opt(preceded(
+                    sp,
+                    take_while1(|c: char| c.is_ascii_alphanumeric() || "+/=".contains(c)),
+                )),
+            ),
+            crlf,
+        ),
+        |(mechanism, initial_response): (&str, Option<&str>)| SMTPCommand::Auth {
+            mechanism: mechanism.to_ascii_uppercase(),
+            initial_response: initial_response.map(str::to_owned),
+        },
+    )
+    .parse(s)
+}
+
+/// `Reverse-path = Path / "<>"`
+pub fn reverse_path(s: &str) -> IResult<&str, ReversePath> {
+    alt((
+        value(ReversePath::Null, tag("<>")),
+        map(path, ReversePath::Path),
+    ))
+    .parse(s)
+}
+
+/// ```text
+/// Path = "<" [ A-d-l ":" ] Mailbox ">"
+/// A-d-l = At-domain *( "," At-domain )
+/// At-domain = "@" Domain
+/// ```
+pub fn path(s: &str) -> IResult<&str, Path> {
+    map(
+        delimited(
+            char('<'),
+            (
+                opt(terminated(
+                    separated_list1(char(','), preceded(char('@'), domain)),
+                    char(':'),
+                )),
+                mailbox,
+            ),
+            char('>'),
+        ),
+        |(source_route, mailbox)| Path {
+            source_route: source_route
+                .unwrap_or_default()
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            mailbox,
+        },
+    )
+    .parse(s)
+}
+
+/// `Mailbox = Local-part "@" ( Domain / address-literal )`
+pub fn mailbox(s: &str) -> IResult<&str, EmailAddress> {
+    map_res(
+        recognize((local_part, char('@'), alt((domain, address_literal)))),
+        EmailAddress::from_str,
+    )
+    .parse(s)
+}
+
+/// `Local-part = Dot-string / Quoted-string`
+fn local_part(s: &str) -> IResult<&str, &str> {
+    alt((dot_string, recognize(quoted_string))).parse(s)
+}
+
+/// `Dot-string = Atom *("." Atom)`
+fn dot_string(s: &str) -> IResult<&str, &str> {
+    recognize(separated_list1(char('.'), atom)).parse(s)
+}
+
+/// `Atom = 1*atext`
+fn atom(s: &str) -> IResult<&str, &str> {
+    take_while1(is_atext).parse(s)
+}
+
+/// `atext` as defined in RFC 5322 section 3.2.3
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+/// ```text
+/// Quoted-string = DQUOTE *QcontentSMTP DQUOTE
+/// QcontentSMTP = qtextSMTP / quoted-pairSMTP
+/// quoted-pairSMTP = %d92 %d32-126
+/// qtextSMTP = %d32-33 / %d35-91 / %d93-126
+/// ```
+///
+/// Returns the contents of the string with the quoting removed.
+fn quoted_string(s: &str) -> IResult<&str, String> {
+    map(
+        delimited(
+            char('"'),
+            many0(alt((
+                preceded(char('\\'), satisfy(|c| (' '..='~').contains(&c))),
+                satisfy(|c| (' '..='~').contains(&c) && c != '"' && c != '\\'),
+            ))),
+            char('"'),
+        ),
+        |chars| chars.into_iter().collect(),
+    )
+    .parse(s)
+}
+
+/// `String = Atom / Quoted-string`
+fn string(s: &str) -> IResult<&str, String> {
+    alt((map(atom, str::to_owned), quoted_string)).parse(s)
+}
+
+/// `Domain = sub-domain *("." sub-domain)`
+pub fn domain(s: &str) -> IResult<&str, &str> {
+    recognize(separated_list1(char('.'), sub_domain)).parse(s)
+}
+
+/// ```text
+/// sub-domain = Let-dig [Ldh-str]
+/// Let-dig = ALPHA / DIGIT
+/// Ldh-str = *( ALPHA / DIGIT / "-" ) Let-dig
+/// ```
+fn sub_domain(s: &str) -> IResult<&str, &str> {
+    verify(
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '-'),
+        |sub: &str| !sub.starts_with('-') && !sub.ends_with('-'),
+    )
+    .parse(s)
+}
+
+/// ```text
+/// address-literal = "[" ( IPv4-address-literal /
+///                   IPv6-address-literal /
+///                   General-address-literal ) "]"
+/// IPv6-address-literal = "IPv6:" IPv6-addr
+/// General-address-literal = Standardized-tag ":" 1*dcontent
+/// dcontent = %d33-90 / %d94-126
+/// ```
+///
+/// The address itself is checked with the standard library's IP address
+/// parsers rather than with the (very long) grammar from the RFC.
+pub fn address_literal(s: &str) -> IResult<&str, &str> {
+    recognize(delimited(
+        char('['),
+        verify(
+            take_while1(|c: char| ('!'..='Z').contains(&c) || ('^'..='~').contains(&c)),
+            is_valid_address_literal,
+        ),
+        char(']'),
+    ))
+    .parse(s)
+}
+
+fn is_valid_address_literal(literal: &str) -> bool {
+    if let Some(ipv6) = literal.strip_prefix("IPv6:") {
+        return Ipv6Addr::from_str(ipv6).is_ok();
+    }
+    if Ipv4Addr::from_str(literal).is_ok() {
+        return true;
+    }
+
+    // General-address-literal. The tag is an Ldh-str
+    match literal.split_once(':') {
+        Some((tag, content)) => {
+            !tag.is_empty()
+                && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !tag.ends_with('-')
+                && !content.is_empty()
+        }
+        None => false,
+    }
+}
+
+/// ```text
+/// Mail-parameters = esmtp-param *(SP esmtp-param)
+/// Rcpt-parameters = esmtp-param *(SP esmtp-param)
+/// ```
+pub fn esmtp_parameters(s: &str) -> IResult<&str, Vec<ESMTPParameter>> {
+    separated_list1(sp, esmtp_parameter).parse(s)
+}
+
+/// ```text
+/// esmtp-param = esmtp-keyword ["=" esmtp-value]
+/// esmtp-keyword = (ALPHA / DIGIT) *(ALPHA / DIGIT / "-")
+/// esmtp-value = 1*(%d33-60 / %d62-126)
+/// ```
+fn esmtp_parameter(s: &str) -> IResult<&str, ESMTPParameter> {
+    map(
+        (
+            recognize((
+                satisfy(|c| c.is_ascii_alphanumeric()),
+                take_while(|c: char| c.is_ascii_alphanumeric() || c == '-'),
+            )),
+            opt(preceded(
+                char('='),
+                take_while1(|c: char| ('!'..='~').contains(&c) && c != '='),
+            )),
+        ),
+        |(keyword, value): (&str, Option<&str>)| ESMTPParameter {
+            keyword: keyword.to_ascii_uppercase(),
+            value: value.map(str::to_owned),
+        },
+    )
+    .parse(s)
+}
+
+#[test]
+fn parse_domain() {
+    assert_eq!(domain("example.com>"), Ok((">", "example.com")));
+    assert_eq!(
+        domain("mail-1.example.com\r\n"),
+        Ok(("\r\n", "mail-1.example.com"))
+    );
+    assert!(domain("-example.com>").is_err());
+    assert!(domain("example-.com>").is_err());
+    assert!(matches!(
+        domain("example.com"),
+        Err(nom::Err::Incomplete(_))
+    ));
+}
 
-use abnf_core::streaming::sp;
-use nom::{bytes::streaming::tag, IResult};
+#[test]
+fn parse_address_literal() {
+    assert_eq!(
+        address_literal("[127.0.0.1]\r\n"),
+        Ok(("\r\n", "[127.0.0.1]"))
+    );
+    assert_eq!(
+        address_literal("[IPv6:::1]\r\n"),
+        Ok(("\r\n", "[IPv6:::1]"))
+    );
+    assert_eq!(
+        address_literal("[x-tag:stuff]\r\n"),
+        Ok(("\r\n", "[x-tag:stuff]"))
+    );
+    assert!(address_literal("[127.0.0]\r\n").is_err());
+    assert!(address_literal("[IPv6:not an address]\r\n").is_err());
+}
 
-// fn crlf(s: &str) -> IResult<&str, &str> {
-//     tag("\r\n")(s)
-// }
+#[test]
+fn parse_path() {
+    assert_eq!(
+        path("<jdoe@example.com>"),
+        Ok((
+            "",
+            Path {
+                source_route: vec![],
+                mailbox: EmailAddress::from_str("jdoe@example.com").unwrap()
+            }
+        ))
+    );
+    assert_eq!(
+        path("<@one.example,@two.example:jdoe@example.com>"),
+        Ok((
+            "",
+            Path {
+                source_route: vec!["one.example".to_owned(), "two.example".to_owned()],
+                mailbox: EmailAddress::from_str("jdoe@example.com").unwrap()
+            }
+        ))
+    );
+    assert_eq!(
+        path("<\"john doe\"@example.com>").map(|(_, p)| p.mailbox.local_part().to_owned()),
+        Ok("\"john doe\"".to_owned())
+    );
+    assert!(path("<jdoe>").is_err());
+    assert!(path("jdoe@example.com").is_err());
+    assert_eq!(reverse_path("<>"), Ok(("", ReversePath::Null)));
+}
 
-// fn sp(s: &str) -> IResult<&str, &str> {
-//     tag(" ")(s)
-// }
+#[test]
+fn parse_esmtp_parameters() {
+    assert_eq!(
+        esmtp_parameters("SIZE=1000 body=8BITMIME SMTPUTF8\r\n"),
+        Ok((
+            "\r\n",
+            vec![
+                ESMTPParameter {
+                    keyword: "SIZE".to_owned(),
+                    value: Some("1000".to_owned())
+                },
+                ESMTPParameter {
+                    keyword: "BODY".to_owned(),
+                    value: Some("8BITMIME".to_owned())
+                },
+                ESMTPParameter {
+                    keyword: "SMTPUTF8".to_owned(),
+                    value: None
+                },
+            ]
+        ))
+    );
+}
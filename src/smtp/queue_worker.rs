@@ -0,0 +1,181 @@
+//! Delivers queued messages to other domains in the background.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use email_address::EmailAddress;
+use log::{error, info, warn};
+use sea_orm::DbErr;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration};
+
+use super::{send_bounce, DeliveryStatus, OutgoingSMTPConnection, Path, ReversePath};
+use crate::database::{queue, queue_database};
+use crate::dkim;
+use crate::CONFIG;
+
+/// How often the queue is checked for messages that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before retrying a message for the first time. The
+/// wait doubles after every failed attempt.
+const FIRST_RETRY_DELAY_MINUTES: i64 = 5;
+
+/// The longest wait between two attempts
+const MAX_RETRY_DELAY_MINUTES: i64 = 4 * 60;
+
+/// Start delivering queued messages in a new tokio thread.
+///
+/// Returns a handle to the worker thread, which never finishes.
+pub fn start_queue_worker() -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // The messages that are currently being delivered, so that a slow
+        // delivery isn't started a second time
+        let in_progress: Arc<Mutex<HashSet<i64>>> = Arc::default();
+
+        let mut interval = interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let due = match queue_database::due_messages().await {
+                Ok(due) => due,
+                Err(e) => {
+                    warn!("Couldn't read the outgoing mail queue: {}", e);
+                    continue;
+                }
+            };
+
+            for message in due {
+                if !in_progress.lock().unwrap().insert(message.id) {
+                    continue;
+                }
+
+                let in_progress = in_progress.clone();
+                tokio::spawn(async move {
+                    let id = message.id;
+                    if let Err(e) = attempt_delivery(message).await {
+                        warn!("Couldn't update queued message {}: {}", id, e);
+                    }
+                    in_progress.lock().unwrap().remove(&id);
+                });
+            }
+        }
+    })
+}
+
+/// Try to deliver a queued message to its remaining recipients, then
+/// remove it from the queue or schedule the next attempt.
+async fn attempt_delivery(message: queue::Model) -> Result<(), DbErr> {
+    let sender = match queue_database::sender(&message) {
+        Some(mailbox) => ReversePath::Path(Path {
+            source_route: vec![],
+            mailbox,
+        }),
+        None => ReversePath::Null,
+    };
+    let recipients = queue_database::recipients(&message);
+
+    // Messages are signed on every attempt rather than when they're
+    // queued, so that they're sent with the domain's current key
+    let signed = dkim::sign_message(&message.content);
+    let results = OutgoingSMTPConnection::deliver(&sender, &recipients, &signed).await;
+
+    // The recipients that won't get the message, and the recipients that
+    // will be tried again
+    let mut failures = vec![];
+    let mut deferred = vec![];
+    for (recipient, status) in results {
+        match &status {
+            DeliveryStatus::Delivered => {
+                info!("Delivered queued message {} to {}", message.id, recipient)
+            }
+            DeliveryStatus::PermanentFailure(e) => {
+                warn!(
+                    "Couldn't deliver queued message {} to {}: {}",
+                    message.id, recipient, e
+                );
+                failures.push((recipient, status));
+            }
+            DeliveryStatus::TemporaryFailure(e) => {
+                info!(
+                    "Delivery of queued message {} to {} was deferred: {}",
+                    message.id, recipient, e
+                );
+                deferred.push((recipient, status));
+            }
+        }
+    }
+
+    let now = Utc::now();
+    let lifetime = chrono::Duration::hours(CONFIG.queue.message_lifetime as i64);
+    let expired =
+        !deferred.is_empty() && now.timestamp() - message.queued_at >= lifetime.num_seconds();
+
+    if expired {
+        error!(
+            "Giving up on queued message {} after {} attempts. It was never delivered to {}: {}",
+            message.id,
+            message.attempts + 1,
+            deferred
+                .iter()
+                .map(|(r, _)| r.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+            last_error(&deferred)
+        );
+        failures.append(&mut deferred);
+    }
+
+    // Tell the sender about the recipients that won't get the message. A
+    // message with a null reverse-path is itself a bounce, so it doesn't
+    // get one.
+    if !failures.is_empty() {
+        if let Some(sender) = queue_database::sender(&message) {
+            let arrival_date = DateTime::from_timestamp(message.queued_at, 0).unwrap_or(now);
+            if let Err(e) = send_bounce(&sender, &message.content, arrival_date, &failures).await {
+                warn!(
+                    "Couldn't send a delivery status notification for queued message {}: {}",
+                    message.id, e
+                );
+            }
+        }
+    }
+
+    if deferred.is_empty() {
+        return queue_database::remove(message.id).await;
+    }
+
+    let remaining: Vec<EmailAddress> = deferred.iter().map(|(r, _)| r.clone()).collect();
+    let next_attempt = now + retry_delay(message.attempts + 1);
+    queue_database::record_failed_attempt(message, &remaining, next_attempt, last_error(&deferred))
+        .await
+}
+
+/// Describe the reason that the last of the `deferred` recipients wasn't
+/// delivered to
+fn last_error(deferred: &[(EmailAddress, DeliveryStatus)]) -> String {
+    match deferred.last() {
+        Some((_, DeliveryStatus::TemporaryFailure(e))) => e.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// How long to wait after the `attempts`th failed attempt before trying
+/// again
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    let doublings = (attempts - 1).clamp(0, 32) as u32;
+    let minutes = FIRST_RETRY_DELAY_MINUTES
+        .saturating_mul(2_i64.pow(doublings))
+        .min(MAX_RETRY_DELAY_MINUTES);
+    chrono::Duration::minutes(minutes)
+}
+
+#[test]
+fn exponential_retry_delay() {
+    assert_eq!(retry_delay(1), chrono::Duration::minutes(5));
+    assert_eq!(retry_delay(2), chrono::Duration::minutes(10));
+    assert_eq!(retry_delay(3), chrono::Duration::minutes(20));
+    assert_eq!(retry_delay(7), chrono::Duration::hours(4));
+    assert_eq!(retry_delay(1000), chrono::Duration::hours(4));
+}
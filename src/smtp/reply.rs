@@ -1,12 +1,85 @@
-use crate::smtp::{reply, SMTPReplyParseError};
+use std::fmt;
+
+use crate::smtp::SMTPReplyParseError;
 
 /// Represents an SMTP reply. See Section 4.2 of [RFC 5321](https://datatracker.ietf.org/doc/html/rfc5321#section-4.2)
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct SMTPReply {
     /// The three digit numeric code
-    code: SMTPReplyCode,
+    pub code: SMTPReplyCode,
+
+    /// The optional enhanced status code (e.g. "5.1.1") at the start of
+    /// each line of the reply text. See RFC 2034 and RFC 3463.
+    pub enhanced_code: Option<EnhancedStatusCode>,
+
+    /// The text of the reply, without the enhanced status code. The lines of a multiline reply are separated
+    /// by CRLF pairs.
+    pub text: String,
+}
+
+impl SMTPReply {
+    /// Create a new SMTPReply. If `text` contains CRLF pairs, then the reply
+    /// is sent as a multiline reply.
+    pub fn new<T: Into<String>>(code: SMTPReplyCode, text: T) -> Self {
+        Self {
+            code,
+            enhanced_code: None,
+            text: text.into(),
+        }
+    }
+
+    /// Create a new SMTPReply with an enhanced status code. The enhanced
+    /// code's class should match the first digit of the reply code.
+    pub fn enhanced<T: Into<String>>(
+        code: SMTPReplyCode,
+        enhanced_code: EnhancedStatusCode,
+        text: T,
+    ) -> Self {
+        Self {
+            code,
+            enhanced_code: Some(enhanced_code),
+            text: text.into(),
+        }
+    }
+}
 
-    text: String,
+/// Convert an SMTPReply to the string that is sent over the wire
+impl From<SMTPReply> for String {
+    fn from(reply: SMTPReply) -> String {
+        reply.to_string()
+    }
+}
+
+impl fmt::Display for SMTPReply {
+    /// Format the reply as it is sent over the wire, including the trailing
+    /// CRLF. Every line of a multiline reply except the last one has a "-"
+    /// after the reply code. If there is an enhanced status code, it is
+    /// repeated at the start of every line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<String> = self
+            .text
+            .split("\r\n")
+            .map(|line| match (&self.enhanced_code, line) {
+                (Some(enhanced), "") => enhanced.to_string(),
+                (Some(enhanced), line) => format!("{} {}", enhanced, line),
+                (None, line) => line.to_owned(),
+            })
+            .collect();
+
+        for (i, line) in lines.iter().enumerate() {
+            let is_last_line = i == lines.len() - 1;
+
+            if is_last_line && line.is_empty() {
+                write!(f, "{}\r\n", self.code)?;
+            } else if is_last_line {
+                write!(f, "{} {}\r\n", self.code, line)?;
+            } else {
+                write!(f, "{}-{}\r\n", self.code, line)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl TryFrom<&str> for SMTPReply {
@@ -32,6 +105,7 @@ impl TryFrom<&str> for SMTPReply {
         // TODO: Check that the response has a trailing CRLF. If not, return an incomplete response error.
 
         let mut reply_code: Option<SMTPReplyCode> = None;
+        let mut enhanced_code: Option<EnhancedStatusCode> = None;
         let mut reply_text = String::new();
 
         let mut number_of_lines_parsed = 0;
@@ -54,6 +128,22 @@ impl TryFrom<&str> for SMTPReply {
                 }
             }
 
+            // Remove the enhanced status code from the start of the line. It
+            // only counts as an enhanced status code if its class matches
+            // the reply code (RFC 3463 section 2)
+            let text = match (text, &reply_code) {
+                (Some(text), Some(reply_code)) => match split_enhanced_code(text) {
+                    Some((code, rest)) if code.class == reply_code.class() => {
+                        if number_of_lines_parsed == 1 {
+                            enhanced_code = Some(code);
+                        }
+                        Some(rest)
+                    }
+                    _ => Some(text),
+                },
+                (text, _) => text,
+            };
+
             // Append the text on the current SMTP response line to the return string
             if let Some(s) = text {
                 reply_text.push_str(s);
@@ -83,11 +173,131 @@ impl TryFrom<&str> for SMTPReply {
 
         Ok(Self {
             code: reply_code.unwrap(), // TODO: Eliminate this unwrap
+            enhanced_code,
             text: reply_text.trim_end().to_owned(),
         })
     }
 }
 
+/// Split an enhanced status code off of the start of a line of reply text.
+/// Returns `None` if the line doesn't start with an enhanced status code.
+fn split_enhanced_code(text: &str) -> Option<(EnhancedStatusCode, &str)> {
+    let (code, rest) = match text.split_once(' ') {
+        Some((code, rest)) => (code, rest),
+        None => (text, ""),
+    };
+
+    Some((code.try_into().ok()?, rest))
+}
+
+/// Represents an enhanced mail system status code, which is a more
+/// detailed version of the three digit reply code. Enhanced status codes
+/// are in the form "class.subject.detail", e.g. "5.1.1" for "bad
+/// destination mailbox address". See
+/// [RFC 3463](https://datatracker.ietf.org/doc/html/rfc3463)
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct EnhancedStatusCode {
+    /// 2 for success, 4 for persistent transient failure, 5 for permanent
+    /// failure
+    pub class: u16,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedStatusCode {
+    pub const fn new(class: u16, subject: u16, detail: u16) -> Self {
+        Self {
+            class,
+            subject,
+            detail,
+        }
+    }
+
+    /// 2.0.0 Other undefined status (success)
+    pub const SUCCESS: Self = Self::new(2, 0, 0);
+    /// 2.1.0 Other address status (e.g. the sender address is OK)
+    pub const SENDER_OK: Self = Self::new(2, 1, 0);
+    /// 2.1.5 Destination address valid
+    pub const DESTINATION_VALID: Self = Self::new(2, 1, 5);
+    /// 2.6.0 Other or undefined media error (the message was accepted)
+    pub const MESSAGE_ACCEPTED: Self = Self::new(2, 6, 0);
+
+    /// 4.3.0 Other or undefined mail system status
+    pub const TEMPORARY_SYSTEM_ERROR: Self = Self::new(4, 3, 0);
+    /// 4.4.2 Bad connection
+    pub const BAD_CONNECTION: Self = Self::new(4, 4, 2);
+
+    /// 5.1.1 Bad destination mailbox address
+    pub const BAD_DESTINATION_MAILBOX: Self = Self::new(5, 1, 1);
+    /// 5.3.4 Message too big for system
+    pub const MESSAGE_TOO_BIG: Self = Self::new(5, 3, 4);
+    /// 5.5.0 Other or undefined protocol status
+    pub const PROTOCOL_ERROR: Self = Self::new(5, 5, 0);
+    /// 5.5.1 Invalid command
+    pub const INVALID_COMMAND: Self = Self::new(5, 5, 1);
+    /// 5.5.2 Syntax error
+    pub const SYNTAX_ERROR: Self = Self::new(5, 5, 2);
+    /// 5.5.4 Invalid command arguments
+    pub const INVALID_ARGUMENTS: Self = Self::new(5, 5, 4);
+    /// 5.6.0 Other or undefined media error
+    pub const MEDIA_ERROR: Self = Self::new(5, 6, 0);
+    /// 5.7.0 Other or undefined security status
+    pub const SECURITY_ERROR: Self = Self::new(5, 7, 0);
+    /// 5.7.1 Delivery not authorized, message refused (e.g. relaying
+    /// denied)
+    pub const DELIVERY_NOT_AUTHORIZED: Self = Self::new(5, 7, 1);
+}
+
+/// Enhanced status codes for authentication from
+/// [RFC 4954](https://datatracker.ietf.org/doc/html/rfc4954#section-6)
+impl EnhancedStatusCode {
+    /// 2.7.0 Authentication succeeded
+    pub const AUTHENTICATION_SUCCEEDED: Self = Self::new(2, 7, 0);
+    /// 4.7.0 Temporary authentication failure
+    pub const TEMPORARY_AUTHENTICATION_FAILURE: Self = Self::new(4, 7, 0);
+    /// 5.7.8 Authentication credentials invalid
+    pub const INVALID_CREDENTIALS: Self = Self::new(5, 7, 8);
+    /// 5.7.11 Encryption required for requested authentication mechanism
+    pub const ENCRYPTION_REQUIRED: Self = Self::new(5, 7, 11);
+}
+
+impl fmt::Display for EnhancedStatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+impl TryFrom<&str> for EnhancedStatusCode {
+    type Error = SMTPReplyParseError;
+
+    /// Parse an enhanced status code. The subject and detail may be up to
+    /// three digits long.
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let parts: Vec<&str> = s.split('.').collect();
+
+        let [class, subject, detail] = parts[..] else {
+            return Err(SMTPReplyParseError::InvalidSyntax);
+        };
+
+        let parse = |part: &str, max_digits: usize| -> Result<u16, SMTPReplyParseError> {
+            if part.is_empty()
+                || part.len() > max_digits
+                || !part.chars().all(|c| c.is_ascii_digit())
+            {
+                return Err(SMTPReplyParseError::InvalidSyntax);
+            }
+            part.parse().map_err(|_| SMTPReplyParseError::InvalidSyntax)
+        };
+
+        let class = parse(class, 1)?;
+        if ![2, 4, 5].contains(&class) {
+            return Err(SMTPReplyParseError::InvalidSyntax);
+        }
+
+        Ok(Self::new(class, parse(subject, 3)?, parse(detail, 3)?))
+    }
+}
+
 /// Represents the three digit code in SMTP replies.
 /// The u16 member holds the last two digits of the code.
 ///
@@ -101,7 +311,7 @@ impl TryFrom<&str> for SMTPReply {
 ///
 /// The suggested reply texts for each reply code are specfied in Section 4.4.2 of
 /// [RFC 5321](https://datatracker.ietf.org/doc/html/rfc5321#section-4.2.2)
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum SMTPReplyCode {
     /// Indicates positive completion (i.e. the request completed successfully)
     TwoHundredCode(u16),
@@ -116,6 +326,113 @@ pub enum SMTPReplyCode {
     FiveHundredCode(u16),
 }
 
+/// Named reply codes from Section 4.2.2 of
+/// [RFC 5321](https://datatracker.ietf.org/doc/html/rfc5321#section-4.2.2)
+impl SMTPReplyCode {
+    /// 211 System status, or system help reply
+    pub const SYSTEM_STATUS: Self = Self::TwoHundredCode(11);
+    /// 214 Help message
+    pub const HELP_MESSAGE: Self = Self::TwoHundredCode(14);
+    /// 220 `<domain>` Service ready
+    pub const SERVICE_READY: Self = Self::TwoHundredCode(20);
+    /// 221 `<domain>` Service closing transmission channel
+    pub const SERVICE_CLOSING: Self = Self::TwoHundredCode(21);
+    /// 250 Requested mail action okay, completed
+    pub const OK: Self = Self::TwoHundredCode(50);
+    /// 251 User not local; will forward to `<forward-path>`
+    pub const USER_NOT_LOCAL_WILL_FORWARD: Self = Self::TwoHundredCode(51);
+    /// 252 Cannot VRFY user, but will accept message and attempt delivery
+    pub const CANNOT_VERIFY_USER: Self = Self::TwoHundredCode(52);
+
+    /// 354 Start mail input; end with `<CRLF>.<CRLF>`
+    pub const START_MAIL_INPUT: Self = Self::ThreeHundredCode(54);
+
+    /// 421 `<domain>` Service not available, closing transmission channel
+    pub const SERVICE_NOT_AVAILABLE: Self = Self::FourHundredCode(21);
+    /// 450 Requested mail action not taken: mailbox unavailable (e.g.,
+    /// mailbox busy or temporarily blocked)
+    pub const MAILBOX_TEMPORARILY_UNAVAILABLE: Self = Self::FourHundredCode(50);
+    /// 451 Requested action aborted: local error in processing
+    pub const LOCAL_ERROR: Self = Self::FourHundredCode(51);
+    /// 452 Requested action not taken: insufficient system storage
+    pub const INSUFFICIENT_STORAGE: Self = Self::FourHundredCode(52);
+    /// 455 Server unable to accommodate parameters
+    pub const UNABLE_TO_ACCOMMODATE_PARAMETERS: Self = Self::FourHundredCode(55);
+
+    /// 500 Syntax error, command unrecognized
+    pub const COMMAND_UNRECOGNIZED: Self = Self::FiveHundredCode(0);
+    /// 501 Syntax error in parameters or arguments
+    pub const SYNTAX_ERROR_IN_PARAMETERS: Self = Self::FiveHundredCode(1);
+    /// 502 Command not implemented
+    pub const COMMAND_NOT_IMPLEMENTED: Self = Self::FiveHundredCode(2);
+    /// 503 Bad sequence of commands
+    pub const BAD_SEQUENCE: Self = Self::FiveHundredCode(3);
+    /// 504 Command parameter not implemented
+    pub const PARAMETER_NOT_IMPLEMENTED: Self = Self::FiveHundredCode(4);
+    /// 550 Requested action not taken: mailbox unavailable (e.g., mailbox
+    /// not found, no access, or command rejected for policy reasons)
+    pub const MAILBOX_UNAVAILABLE: Self = Self::FiveHundredCode(50);
+    /// 551 User not local; please try `<forward-path>`
+    pub const USER_NOT_LOCAL: Self = Self::FiveHundredCode(51);
+    /// 552 Requested mail action aborted: exceeded storage allocation
+    pub const EXCEEDED_STORAGE: Self = Self::FiveHundredCode(52);
+    /// 553 Requested action not taken: mailbox name not allowed
+    pub const MAILBOX_NAME_NOT_ALLOWED: Self = Self::FiveHundredCode(53);
+    /// 554 Transaction failed
+    pub const TRANSACTION_FAILED: Self = Self::FiveHundredCode(54);
+    /// 555 MAIL FROM/RCPT TO parameters not recognized or not implemented
+    pub const PARAMETERS_NOT_RECOGNIZED: Self = Self::FiveHundredCode(55);
+}
+
+/// Reply codes for the `AUTH` command from
+/// [RFC 4954](https://datatracker.ietf.org/doc/html/rfc4954#section-6)
+impl SMTPReplyCode {
+    /// 235 Authentication succeeded
+    pub const AUTHENTICATION_SUCCEEDED: Self = Self::TwoHundredCode(35);
+    /// 334 A base64 encoded server challenge follows
+    pub const SERVER_CHALLENGE: Self = Self::ThreeHundredCode(34);
+    /// 454 Temporary authentication failure
+    pub const TEMPORARY_AUTHENTICATION_FAILURE: Self = Self::FourHundredCode(54);
+    /// 535 Authentication credentials invalid
+    pub const AUTHENTICATION_CREDENTIALS_INVALID: Self = Self::FiveHundredCode(35);
+    /// 538 Encryption required for requested authentication mechanism
+    pub const ENCRYPTION_REQUIRED: Self = Self::FiveHundredCode(38);
+}
+
+/// Reply codes for the `STARTTLS` command from
+/// [RFC 3207](https://datatracker.ietf.org/doc/html/rfc3207#section-4)
+impl SMTPReplyCode {
+    /// 454 TLS not available due to temporary reason
+    pub const TLS_NOT_AVAILABLE: Self = Self::FourHundredCode(54);
+}
+
+/// Convert an SMTPReplyCode to its three digit numeric value
+impl From<SMTPReplyCode> for u16 {
+    fn from(code: SMTPReplyCode) -> u16 {
+        use SMTPReplyCode::*;
+
+        match code {
+            TwoHundredCode(n) => 200 + n,
+            ThreeHundredCode(n) => 300 + n,
+            FourHundredCode(n) => 400 + n,
+            FiveHundredCode(n) => 500 + n,
+        }
+    }
+}
+
+impl SMTPReplyCode {
+    /// The first digit of the reply code
+    pub fn class(&self) -> u16 {
+        u16::from(*self) / 100
+    }
+}
+
+impl fmt::Display for SMTPReplyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", u16::from(*self))
+    }
+}
+
 impl TryFrom<u16> for SMTPReplyCode {
     type Error = SMTPReplyParseError;
 
@@ -187,7 +504,7 @@ fn str_to_smtp_reply_code() {
             Err(SMTPReplyParseError::InvalidSyntax),
         ),
         ("200-Word", Err(SMTPReplyParseError::InvalidSyntax)),
-        // Extended reply codes are not supported
+        // Enhanced status codes are parsed by `SMTPReply`, not `SMTPReplyCode`
         ("250 2.1.0", Err(SMTPReplyParseError::InvalidSyntax)),
     ];
 
@@ -210,6 +527,7 @@ fn parse_smtp_reply() {
 
     let expected_reply = SMTPReply {
         code: SMTPReplyCode::TwoHundredCode(20),
+        enhanced_code: None,
         text: "smtp.example.com ESMTP Postfix".to_owned(),
     };
 
@@ -218,3 +536,85 @@ fn parse_smtp_reply() {
         Ok(expected_reply),
     )
 }
+
+#[test]
+fn smtp_reply_to_string() {
+    assert_eq!(
+        String::from(SMTPReply::new(SMTPReplyCode::OK, "OK")),
+        "250 OK\r\n"
+    );
+    assert_eq!(
+        String::from(SMTPReply::new(SMTPReplyCode::START_MAIL_INPUT, "")),
+        "354\r\n"
+    );
+    assert_eq!(
+        String::from(SMTPReply::new(
+            SMTPReplyCode::OK,
+            "mail.example.com\r\n8BITMIME\r\nHELP"
+        )),
+        "250-mail.example.com\r\n250-8BITMIME\r\n250 HELP\r\n"
+    );
+
+    // Serializing and then parsing a reply should give back the same reply
+    let reply = SMTPReply::new(SMTPReplyCode::MAILBOX_UNAVAILABLE, "No\r\nsuch\r\nuser");
+    assert_eq!(reply.to_string().as_str().try_into(), Ok(reply));
+}
+
+#[test]
+fn parse_enhanced_status_code() {
+    assert_eq!(
+        SMTPReply::try_from("250 2.1.0 Sender OK\r\n"),
+        Ok(SMTPReply::enhanced(
+            SMTPReplyCode::OK,
+            EnhancedStatusCode::SENDER_OK,
+            "Sender OK"
+        ))
+    );
+    assert_eq!(
+        SMTPReply::try_from("550-5.1.1 The email account that you tried to reach does\r\n550-5.1.1 not exist.\r\n550 5.1.1 Please try again\r\n"),
+        Ok(SMTPReply::enhanced(
+            SMTPReplyCode::MAILBOX_UNAVAILABLE,
+            EnhancedStatusCode::BAD_DESTINATION_MAILBOX,
+            "The email account that you tried to reach does\r\nnot exist.\r\nPlease try again"
+        ))
+    );
+    assert_eq!(
+        SMTPReply::try_from("250 2.0.0\r\n"),
+        Ok(SMTPReply::enhanced(
+            SMTPReplyCode::OK,
+            EnhancedStatusCode::SUCCESS,
+            ""
+        ))
+    );
+
+    // The class of the enhanced status code must match the reply code
+    assert_eq!(
+        SMTPReply::try_from("250 5.1.1 OK\r\n"),
+        Ok(SMTPReply::new(SMTPReplyCode::OK, "5.1.1 OK"))
+    );
+
+    // Version numbers and the like are not enhanced status codes
+    assert_eq!(
+        SMTPReply::try_from("220 1.2.3 ready\r\n"),
+        Ok(SMTPReply::new(SMTPReplyCode::SERVICE_READY, "1.2.3 ready"))
+    );
+
+    assert_eq!(
+        EnhancedStatusCode::try_from("4.123.456"),
+        Ok(EnhancedStatusCode::new(4, 123, 456))
+    );
+    assert!(EnhancedStatusCode::try_from("4.1234.5").is_err());
+    assert!(EnhancedStatusCode::try_from("3.1.1").is_err());
+    assert!(EnhancedStatusCode::try_from("5.1").is_err());
+
+    let reply = SMTPReply::enhanced(
+        SMTPReplyCode::MAILBOX_UNAVAILABLE,
+        EnhancedStatusCode::DELIVERY_NOT_AUTHORIZED,
+        "Relaying\r\nnot permitted",
+    );
+    assert_eq!(
+        reply.to_string(),
+        "550-5.7.1 Relaying\r\n550 5.7.1 not permitted\r\n"
+    );
+    assert_eq!(reply.to_string().as_str().try_into(), Ok(reply));
+}
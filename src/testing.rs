@@ -0,0 +1,159 @@
+//! Helpers for tests that need the configuration, the database, or a
+//! client to talk to a connection handler.
+//!
+//! Every test shares one configuration and one database, in a new file in
+//! the temporary directory. Tests run at the same time, so each one should
+//! use accounts that no other test uses.
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+use sea_orm::{ConnectionTrait, EntityTrait, Schema};
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::sync::OnceCell;
+
+use crate::config::Config;
+use crate::connection_handler::{ConnectionHandler, TlsState};
+use crate::database::user_database::{db_connection, initialize_db};
+use crate::database::*;
+
+/// The configuration that tests run with. Failed logins aren't slowed
+/// down, so that tests don't have to wait.
+pub fn config() -> Config {
+    let database = std::env::temp_dir().join(format!("mailroom-test-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&database);
+
+    toml::from_str(&format!(
+        r#"
+        bind_address = "127.0.0.1"
+
+        [database]
+        url = "sqlite:{}?mode=rwc"
+
+        [login]
+        max_attempts = 3
+        failure_delay = 0
+        lockout_threshold = 5
+        login_delay = 60
+
+        [[domains]]
+        name = "example.com"
+        selector = "mail"
+        tls_settings = "disabled"
+        users = ["smtp", "smtp-auth", "smtp-8bit", "relay", "pop3-quit", "pop3-reset", "pop3-dropped", "pop3-delay", "pop3-top", "pop3-uidl", "flags", "modseq"]
+
+        [[domains]]
+        name = "secure.example"
+        tls_settings = "disabled"
+        users = ["alice"]
+        require_tls = true
+        "#,
+        database.display()
+    ))
+    .expect("Invalid test configuration")
+}
+
+static DATABASE: OnceCell<()> = OnceCell::const_new();
+
+/// Create the tables and the users in the configuration, if that hasn't
+/// been done yet. Every user's password is "password".
+pub async fn database() {
+    DATABASE
+        .get_or_init(|| async {
+            let db = db_connection().await.unwrap();
+            create_table(&db, User).await;
+            create_table(&db, Mailbox).await;
+            create_table(&db, Mail).await;
+            create_table(&db, MailFlag).await;
+            create_table(&db, MailTombstone).await;
+            create_table(&db, ModSequence).await;
+            create_table(&db, Queue).await;
+            create_table(&db, LoginFailure).await;
+            initialize_db().await.unwrap();
+        })
+        .await;
+}
+
+async fn create_table<E: EntityTrait>(db: &sea_orm::DatabaseConnection, entity: E) {
+    let backend = db.get_database_backend();
+    let statement = Schema::new(backend).create_table_from_entity(entity);
+    db.execute(backend.build(&statement)).await.unwrap();
+}
+
+/// Run a test on a new runtime, once the database is ready
+pub fn run<F: Future>(test: F) -> F::Output {
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        database().await;
+        test.await
+    })
+}
+
+/// The client end of a connection to a connection handler
+pub struct Client {
+    stream: BufReader<DuplexStream>,
+}
+
+impl Client {
+    /// Send a line to the server. The CRLF is added.
+    pub async fn send(&mut self, line: impl AsRef<[u8]>) {
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_ref()).await.unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+    }
+
+    /// Read a line from the server, without the CRLF. Returns an empty
+    /// string if the server closed the connection.
+    pub async fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.stream.read_line(&mut line).await.unwrap();
+        line.trim_end_matches("\r\n").to_owned()
+    }
+
+    /// Read the rest of a multiline response, after its first line. The
+    /// terminating "." line isn't included.
+    pub async fn read_multiline(&mut self) -> Vec<String> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            let read = self.stream.read_line(&mut line).await.unwrap();
+            assert!(read > 0, "connection closed during a multiline response");
+
+            match line.trim_end_matches("\r\n") {
+                "." => return lines,
+                line => lines.push(line.to_owned()),
+            }
+        }
+    }
+
+    /// Send a line and read the first line of the response
+    pub async fn command(&mut self, line: &str) -> String {
+        self.send(line).await;
+        self.read_line().await
+    }
+}
+
+/// Connect a client to a new connection handler, and run `script` with
+/// it. The connection is closed when the script is done, if the server
+/// didn't close it first.
+pub async fn session<H, F, Fut>(tls: TlsState, script: F)
+where
+    H: ConnectionHandler,
+    F: FnOnce(Client) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let (client, server) = io::duplex(64 * 1024);
+    let peer: SocketAddr = "192.0.2.1:49152".parse().unwrap();
+    let mut handler = H::from_stream(server, peer, tls);
+
+    let client = Client {
+        stream: BufReader::new(client),
+    };
+    tokio::join!(
+        async {
+            let _ = handler.begin().await;
+            // Dropping the handler closes the server's end
+            drop(handler);
+        },
+        script(client)
+    );
+}
@@ -0,0 +1,40 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use tokio_rustls::rustls;
+
+/// Reasons that a domain's certificate couldn't be loaded
+#[derive(Debug)]
+pub enum TlsError {
+    /// Couldn't read a certificate or key file
+    Io { path: String, error: io::Error },
+    /// The certificate file doesn't have any PEM certificates in it
+    NoCertificates { path: String },
+    /// The domain's certificate isn't a valid X.509 certificate
+    InvalidCertificate { path: String, error: String },
+    /// The key file doesn't have a PEM private key in it
+    NoPrivateKey { path: String },
+    /// The private key isn't supported, or doesn't belong to the certificate
+    InvalidKey { path: String, error: rustls::Error },
+}
+
+impl Error for TlsError {}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use TlsError::*;
+
+        match self {
+            Io { path, error } => write!(f, "couldn't read {}: {}", path, error),
+            NoCertificates { path } => write!(f, "no certificates found in {}", path),
+            InvalidCertificate { path, error } => {
+                write!(f, "certificate in {} is invalid: {}", path, error)
+            }
+            NoPrivateKey { path } => write!(f, "no private key found in {}", path),
+            InvalidKey { path, error } => {
+                write!(f, "private key in {} is invalid: {}", path, error)
+            }
+        }
+    }
+}
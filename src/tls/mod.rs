@@ -0,0 +1,196 @@
+//! TLS for incoming connections, using rustls. Each domain in the
+//! configuration file can have its own certificate. The one that is sent
+//! to a client is picked by the host name that the client asks for with
+//! SNI.
+//!
+//! Every listener shares the same [`CertStore`], so certificates can be
+//! replaced while the server is running. They are reloaded from disk when
+//! their files change, or when the server gets SIGHUP.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::info;
+use tokio_rustls::rustls::{
+    crypto::ring, pki_types::CertificateDer, sign::CertifiedKey, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::acme;
+use crate::config::{DomainCfg, TlsSettings};
+
+mod err;
+pub use err::*;
+
+mod reload;
+pub use reload::*;
+
+mod store;
+pub use store::*;
+
+lazy_static! {
+    /// The certificates of every domain, shared by all listeners
+    static ref CERT_STORE: Arc<CertStore> = Arc::new(CertStore::default());
+}
+
+/// Load the certificates of every domain that has TLS enabled. Returns
+/// `None` if none of them do.
+///
+/// Domains that get their certificate with ACME use the one that was
+/// stored last time, or a self-signed one until the first is issued.
+pub fn acceptor(domains: &[DomainCfg]) -> Result<Option<TlsAcceptor>, TlsError> {
+    for domain in domains {
+        let key = match &domain.tls_settings {
+            TlsSettings::Disabled => continue,
+            TlsSettings::Files {
+                certificate,
+                private_key,
+            } => load_certified_key(certificate, private_key)?,
+            TlsSettings::Acme => acme::stored_or_placeholder(domain),
+        };
+        match expiry(&key) {
+            Some(expiry) => info!(
+                "Loaded the TLS certificate of {}, which expires on {}",
+                domain.name, expiry
+            ),
+            None => info!("Loaded the TLS certificate of {}", domain.name),
+        }
+
+        install_certificate(domain, key);
+    }
+
+    if CERT_STORE.is_empty() {
+        return Ok(None);
+    }
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("the default protocol versions are supported")
+        .with_no_client_auth()
+        .with_cert_resolver(CERT_STORE.clone());
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Read a certificate chain and its private key from PEM files. The
+/// domain's own certificate, which comes first, has to be a valid X.509
+/// certificate.
+pub fn load_certified_key(certificate: &str, private_key: &str) -> Result<CertifiedKey, TlsError> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|error| TlsError::Io {
+                path: path.to_owned(),
+                error,
+            })
+    };
+
+    let chain = rustls_pemfile::certs(&mut open(certificate)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|error| TlsError::Io {
+            path: certificate.to_owned(),
+            error,
+        })?;
+    if chain.is_empty() {
+        return Err(TlsError::NoCertificates {
+            path: certificate.to_owned(),
+        });
+    }
+    if let Err(e) = x509_parser::parse_x509_certificate(&chain[0]) {
+        return Err(TlsError::InvalidCertificate {
+            path: certificate.to_owned(),
+            error: e.to_string(),
+        });
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(private_key)?)
+        .map_err(|error| TlsError::Io {
+            path: private_key.to_owned(),
+            error,
+        })?
+        .ok_or_else(|| TlsError::NoPrivateKey {
+            path: private_key.to_owned(),
+        })?;
+
+    let invalid_key = |error| TlsError::InvalidKey {
+        path: private_key.to_owned(),
+        error,
+    };
+    let key = CertifiedKey::new(
+        chain,
+        ring::sign::any_supported_type(&key).map_err(invalid_key)?,
+    );
+    key.keys_match().map_err(invalid_key)?;
+
+    Ok(key)
+}
+
+/// Start sending `key` to clients that ask for `domain`, replacing its
+/// old certificate if it had one. Returns `false` if `key` is the
+/// certificate that was already being sent.
+pub fn install_certificate(domain: &DomainCfg, key: CertifiedKey) -> bool {
+    CERT_STORE.install(host_names(domain), key)
+}
+
+/// Find out when the first certificate in a PEM file expires. Returns
+/// `None` if there isn't a certificate that can be parsed.
+pub fn certificate_expiry(pem: &[u8]) -> Option<DateTime<Utc>> {
+    let der = rustls_pemfile::certs(&mut &pem[..]).next()?.ok()?;
+    der_expiry(&der)
+}
+
+/// Find out when the domain's own certificate in a chain expires
+pub fn expiry(key: &CertifiedKey) -> Option<DateTime<Utc>> {
+    der_expiry(key.end_entity_cert().ok()?)
+}
+
+fn der_expiry(der: &CertificateDer) -> Option<DateTime<Utc>> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der).ok()?;
+    DateTime::from_timestamp(certificate.validity().not_after.timestamp(), 0)
+}
+
+/// The host names that a domain's certificate is sent for: the domain
+/// itself, and the mail server's host name if it has a selector
+pub fn host_names(domain: &DomainCfg) -> Vec<String> {
+    let mut names = vec![domain.name.to_ascii_lowercase()];
+    if let Some(selector) = &domain.selector {
+        names.push(format!("{}.{}", selector, domain.name).to_ascii_lowercase());
+    }
+    names
+}
+
+#[test]
+fn domain_host_names() {
+    let domain = |selector: Option<&str>| DomainCfg {
+        name: "Example.com".to_owned(),
+        selector: selector.map(str::to_owned),
+        tls_settings: TlsSettings::Disabled,
+        users: vec![],
+        expire: None,
+        require_tls: false,
+        dkim: None,
+    };
+
+    assert_eq!(host_names(&domain(None)), ["example.com"]);
+    assert_eq!(
+        host_names(&domain(Some("mail"))),
+        ["example.com", "mail.example.com"]
+    );
+}
+
+#[test]
+fn unparseable_certificate_rejected() {
+    let dir = std::env::temp_dir();
+    let certificate = dir.join("mailroom-unparseable-certificate.pem");
+    std::fs::write(
+        &certificate,
+        "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n",
+    )
+    .unwrap();
+
+    let result = load_certified_key(certificate.to_str().unwrap(), "unused.key");
+    assert!(matches!(result, Err(TlsError::InvalidCertificate { .. })));
+}
@@ -0,0 +1,219 @@
+//! Reloads certificates from disk while the server is running, so that a
+//! certificate renewed by certbot or by hand is used without a restart.
+//! A domain's certificate is reloaded when its files change, and every
+//! certificate is reloaded when the server gets SIGHUP.
+//!
+//! A certificate that can't be loaded is rejected, and the old one stays
+//! in use.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+use super::{expiry, install_certificate, load_certified_key};
+use crate::acme;
+use crate::config::{DomainCfg, TlsSettings};
+use crate::CONFIG;
+
+/// How long to wait for more changes after a file changes. Certificates
+/// and keys are often written one after the other, and loading them in
+/// between would fail.
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+/// Start watching the certificate files of every domain in a new tokio
+/// thread, and listening for SIGHUP.
+///
+/// Returns a handle to the thread, which never finishes.
+pub fn start_certificate_reloader() -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let (sender, mut changes) = mpsc::unbounded_channel();
+
+        // The watcher stops when it is dropped, so it's kept for as long
+        // as the thread runs
+        let _watcher = match watch_certificate_files(sender) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("Couldn't watch the TLS certificate files: {}", e);
+                None
+            }
+        };
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => Some(hangups),
+            Err(e) => {
+                warn!("Couldn't listen for SIGHUP: {}", e);
+                None
+            }
+        };
+
+        loop {
+            let domains: Vec<&'static DomainCfg> = tokio::select! {
+                Some(path) = changes.recv() => {
+                    sleep(SETTLE_DELAY).await;
+
+                    let mut changed = HashSet::from([path]);
+                    while let Ok(path) = changes.try_recv() {
+                        changed.insert(path);
+                    }
+                    domains_using(&changed)
+                }
+                Some(()) = recv_hangup(&mut hangups) => {
+                    info!("Got SIGHUP, reloading TLS certificates");
+                    CONFIG
+                        .domains
+                        .iter()
+                        .filter(|d| certificate_files(d).is_some())
+                        .collect()
+                }
+                else => return,
+            };
+
+            for domain in domains {
+                reload_certificate(domain);
+            }
+        }
+    })
+}
+
+async fn recv_hangup(hangups: &mut Option<tokio::signal::unix::Signal>) -> Option<()> {
+    match hangups {
+        Some(hangups) => hangups.recv().await,
+        None => None,
+    }
+}
+
+/// Load a domain's certificate from disk again, and use it if it is
+/// different from the one in use
+fn reload_certificate(domain: &DomainCfg) {
+    let Some((certificate, private_key)) = certificate_files(domain) else {
+        return;
+    };
+
+    let key = match load_certified_key(&certificate, &private_key) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!(
+                "Couldn't reload the TLS certificate of {}, so the old one is still used: {}",
+                domain.name, e
+            );
+            return;
+        }
+    };
+
+    let expires = expiry(&key);
+    if install_certificate(domain, key) {
+        match expires {
+            Some(expiry) => info!(
+                "Reloaded the TLS certificate of {}, which expires on {}",
+                domain.name, expiry
+            ),
+            None => info!("Reloaded the TLS certificate of {}", domain.name),
+        }
+    }
+}
+
+/// The certificate and private key files of a domain, if it has them.
+/// Domains that use ACME only have them once the first certificate has
+/// been issued.
+fn certificate_files(domain: &DomainCfg) -> Option<(String, String)> {
+    match &domain.tls_settings {
+        TlsSettings::Disabled => None,
+        TlsSettings::Files {
+            certificate,
+            private_key,
+        } => Some((certificate.clone(), private_key.clone())),
+        TlsSettings::Acme => {
+            let (certificate, private_key) = acme::certificate_paths(domain);
+            certificate.exists().then(|| {
+                (
+                    certificate.display().to_string(),
+                    private_key.display().to_string(),
+                )
+            })
+        }
+    }
+}
+
+/// Watch the directories that hold certificate files, and send the path of
+/// every file in them that changes. Directories are watched instead of the
+/// files, because renewing a certificate usually replaces its file (or
+/// the symbolic link to it) rather than writing to it.
+fn watch_certificate_files(
+    sender: mpsc::UnboundedSender<PathBuf>,
+) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading a file is an event too, so only changes are sent
+        if let Ok(event) = event {
+            if event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove() {
+                for path in event.paths {
+                    let _ = sender.send(path);
+                }
+            }
+        }
+    })?;
+
+    let directories: HashSet<PathBuf> = CONFIG
+        .domains
+        .iter()
+        .filter_map(certificate_files)
+        .flat_map(|(certificate, private_key)| [certificate, private_key])
+        .filter_map(|file| watched_path(Path::new(&file)))
+        .filter_map(|file| file.parent().map(Path::to_path_buf))
+        .collect();
+    for directory in directories {
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    }
+
+    Ok(watcher)
+}
+
+/// The domains whose certificate or private key is one of `changed`
+fn domains_using(changed: &HashSet<PathBuf>) -> Vec<&'static DomainCfg> {
+    CONFIG
+        .domains
+        .iter()
+        .filter(|domain| {
+            certificate_files(domain).is_some_and(|(certificate, private_key)| {
+                [certificate, private_key]
+                    .iter()
+                    .filter_map(|file| watched_path(Path::new(file)))
+                    .any(|file| changed.contains(&file))
+            })
+        })
+        .collect()
+}
+
+/// The path that the watcher reports changes to a file with: the file name
+/// in the canonical path of its directory. The file itself isn't
+/// canonicalized, because it may be a symbolic link that is replaced.
+fn watched_path(file: &Path) -> Option<PathBuf> {
+    let directory = match file.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    Some(directory.canonicalize().ok()?.join(file.file_name()?))
+}
+
+#[test]
+fn watched_path_of_relative_file() {
+    let current = std::env::current_dir().unwrap().canonicalize().unwrap();
+
+    assert_eq!(
+        watched_path(Path::new("fullchain.pem")),
+        Some(current.join("fullchain.pem"))
+    );
+    assert_eq!(
+        watched_path(Path::new("./src/../fullchain.pem")),
+        Some(current.join("fullchain.pem"))
+    );
+    assert_eq!(
+        watched_path(Path::new("/nonexistent/directory/key.pem")),
+        None
+    );
+}
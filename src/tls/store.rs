@@ -0,0 +1,102 @@
+use std::sync::{Arc, RwLock};
+
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+/// The certificates of every domain, shared by all listeners. A
+/// certificate can be replaced at any time: handshakes that start
+/// afterwards get the new one, and connections that have already finished
+/// their handshake carry on with the old one.
+///
+/// The certificate that is sent to a client is picked by the host name
+/// that it asked for with SNI. Clients that don't use SNI, or ask for a
+/// host that isn't configured, get the certificate of the first domain.
+#[derive(Debug, Default)]
+pub struct CertStore {
+    /// The host names of each domain, and its certificate
+    certificates: RwLock<Vec<(Vec<String>, Arc<CertifiedKey>)>>,
+}
+
+impl CertStore {
+    /// Start sending `key` to clients that ask for any of `names`,
+    /// replacing the certificate they were sent before.
+    ///
+    /// Returns `false` if the certificate chain is the same as the one
+    /// that was already being sent, in which case nothing is changed.
+    pub fn install(&self, names: Vec<String>, key: CertifiedKey) -> bool {
+        let mut certificates = self.certificates.write().unwrap();
+        match certificates.iter_mut().find(|(n, _)| *n == names) {
+            Some((_, old)) if old.cert == key.cert => false,
+            Some((_, old)) => {
+                *old = Arc::new(key);
+                true
+            }
+            None => {
+                certificates.push((names, Arc::new(key)));
+                true
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certificates.read().unwrap().is_empty()
+    }
+
+    /// Find the certificate for a host name
+    fn get(&self, host: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = host.map(str::to_ascii_lowercase);
+
+        let certificates = self.certificates.read().unwrap();
+        certificates
+            .iter()
+            .find(|(names, _)| host.as_ref().is_some_and(|host| names.contains(host)))
+            .or(certificates.first())
+            .map(|(_, key)| key.clone())
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.get(client_hello.server_name())
+    }
+}
+
+#[test]
+fn install_and_replace() {
+    use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    // The store doesn't look inside the certificates, so they don't have
+    // to be real ones
+    let signing_key = any_supported_type(&PrivateKeyDer::Pkcs8(
+        rcgen::KeyPair::generate().unwrap().serialize_der().into(),
+    ))
+    .unwrap();
+    let key = |certificate: &[u8]| {
+        CertifiedKey::new(
+            vec![CertificateDer::from(certificate.to_vec())],
+            signing_key.clone(),
+        )
+    };
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let sent = |store: &CertStore, host| store.get(host).unwrap().cert[0].to_vec();
+
+    let store = CertStore::default();
+    assert!(store.is_empty());
+    assert!(store.get(Some("example.com")).is_none());
+
+    assert!(store.install(names(&["example.com", "mail.example.com"]), key(b"one")));
+    assert!(store.install(names(&["example.org"]), key(b"two")));
+    assert_eq!(sent(&store, Some("MAIL.example.com")), b"one");
+    assert_eq!(sent(&store, Some("example.org")), b"two");
+    assert_eq!(sent(&store, Some("unknown.net")), b"one");
+    assert_eq!(sent(&store, None), b"one");
+
+    // The same certificate again doesn't count as a change
+    assert!(!store.install(names(&["example.org"]), key(b"two")));
+    assert!(store.install(names(&["example.org"]), key(b"three")));
+    assert_eq!(sent(&store, Some("example.org")), b"three");
+    assert_eq!(sent(&store, Some("example.com")), b"one");
+}